@@ -0,0 +1,58 @@
+//! Compares minor (nursery-only) and major (whole heap) collection pauses.
+//!
+//! Run with `cargo bench --bench gc`. A minor pause only depends on the nursery
+//! and the root set, while a major pause also compacts every tenured object.
+
+use n::heap::Heap;
+use n::types::compiler::{HeapObject, Value};
+use std::time::{Duration, Instant};
+
+const NURSERY_OBJECTS: usize = 1_000;
+const ROUNDS: u32 = 20;
+
+fn build_heap(tenured: usize) -> (Heap, Vec<Value>) {
+    let mut heap = Heap::new();
+    let mut live = Vec::with_capacity(tenured);
+    for i in 0..tenured {
+        let idx = heap.allocate(HeapObject::String(format!("tenured-{}", i)));
+        live.push(Value::HeapPointer(idx));
+    }
+    let mut roots: Vec<&mut Value> = live.iter_mut().collect();
+    heap.collect_minor(&mut roots);
+    (heap, live)
+}
+
+fn fill_nursery(heap: &mut Heap) {
+    for i in 0..NURSERY_OBJECTS {
+        heap.allocate(HeapObject::Array(vec![HeapObject::Number(i as f64)].into()));
+    }
+}
+
+fn measure(tenured: usize, major: bool) -> Duration {
+    let (mut heap, mut live) = build_heap(tenured);
+    let mut total = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        fill_nursery(&mut heap);
+        let mut roots: Vec<&mut Value> = live.iter_mut().collect();
+        let start = Instant::now();
+        if major {
+            heap.collect_major(&mut roots);
+        } else {
+            heap.collect_minor(&mut roots);
+        }
+        total += start.elapsed();
+    }
+    total / ROUNDS
+}
+
+fn main() {
+    println!(
+        "{:>10} {:>14} {:>14}",
+        "tenured", "minor pause", "major pause"
+    );
+    for tenured in [1_000, 10_000, 100_000, 500_000] {
+        let minor = measure(tenured, false);
+        let major = measure(tenured, true);
+        println!("{:>10} {:>14?} {:>14?}", tenured, minor, major);
+    }
+}
@@ -0,0 +1,60 @@
+//! Counts heap allocations made while lexing, so token creation stays cheap.
+//!
+//! Run with `cargo bench --bench lexer_alloc`. Keywords and punctuation must not
+//! allocate and repeated identifiers or strings must reuse their interned
+//! symbol, so the count only grows with the number of distinct symbols. The
+//! run fails if the budget below is exceeded.
+
+use n::lexer::Lexer;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const LINES: usize = 10_000;
+// Seven distinct symbols, plus growth of the interner's table.
+const ALLOCATION_BUDGET: usize = 16;
+
+fn main() {
+    let source = "let total = price * quantity + 1 // note\nfunc f(a) { a |> show(\"done\") }\n"
+        .repeat(LINES);
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let mut lexer = Lexer::new(&source);
+    let tokens = lexer.by_ref().count();
+    let elapsed = start.elapsed();
+    drop(lexer);
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+
+    println!(
+        "lexed {} tokens in {:?}: {} allocation(s), {:.5} per token",
+        tokens,
+        elapsed,
+        allocations,
+        allocations as f64 / tokens as f64
+    );
+    assert!(
+        allocations <= ALLOCATION_BUDGET,
+        "lexer allocated {} times, budget is {}",
+        allocations,
+        ALLOCATION_BUDGET
+    );
+}
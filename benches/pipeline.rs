@@ -0,0 +1,22 @@
+//! Times each stage of the pipeline on representative programs.
+//!
+//! Run with `cargo bench --bench pipeline`. The programs come from
+//! `n::bench::programs`, so a regression in lexing, parsing, compiling or
+//! running shows up in its own column.
+
+use n::bench;
+
+const ROUNDS: u32 = 10;
+
+fn main() {
+    println!(
+        "{:<14} {:>12} {:>12} {:>12} {:>12} {:>12}",
+        "program", "lex", "parse", "compile", "run", "total"
+    );
+    for (name, source) in bench::programs() {
+        match bench::measure(&source, ROUNDS) {
+            Ok(times) => println!("{:<14} {}", name, times),
+            Err(e) => panic!("{} failed: {}", name, e),
+        }
+    }
+}
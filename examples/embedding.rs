@@ -0,0 +1,75 @@
+//! Renders a small HTML page from a template script.
+//!
+//! Run with `cargo run --example embedding`. The host registers an `Html`
+//! module of natives, pre-sets the `title` and `user` globals, and captures
+//! everything the script prints into a buffer instead of stdout.
+
+use n::compiler::Compiler;
+use n::interpreter::VirtualMachine;
+use n::runtime::{self, Capture};
+use n::types::compiler::Value;
+
+const TEMPLATE: &str = r#"
+func item(text) {
+    Html.tag("li", Html.escape(text))
+}
+
+IO.print(Html.tag("h1", title))
+IO.print(Html.tag("p", "Signed in as " ++ Html.escape(user)))
+IO.print("<ul>")
+IO.print(item("Profile"))
+IO.print(item("Orders & returns"))
+IO.print("</ul>")
+"#;
+
+fn string_arg(args: &[Value], index: usize) -> Result<&str, String> {
+    match args.get(index) {
+        Some(Value::String(s)) => Ok(s),
+        Some(other) => Err(format!(
+            "expected a string, got {}",
+            other.type_name_stack()
+        )),
+        None => Err("missing argument".to_string()),
+    }
+}
+
+fn render(title: &str, user: &str) -> Result<String, String> {
+    let mut compiler = Compiler::new();
+    compiler.natives.register("Html.tag", Some(2), |_, args| {
+        let tag = string_arg(args, 0)?;
+        let body = string_arg(args, 1)?;
+        Ok(Value::String(format!("<{tag}>{body}</{tag}>")))
+    });
+    compiler
+        .natives
+        .register("Html.escape", Some(1), |_, args| {
+            let escaped = string_arg(args, 0)?
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;");
+            Ok(Value::String(escaped))
+        });
+    let title_index = compiler.declare_global("title");
+    let user_index = compiler.declare_global("user");
+
+    let bytecode = runtime::compile_with(&mut compiler, TEMPLATE)?;
+
+    let capture = Capture::default();
+    let mut vm = VirtualMachine::new(bytecode, compiler);
+    vm.set_output(Box::new(capture.clone()));
+    vm.set_global(title_index, Value::String(title.to_string()));
+    vm.set_global(user_index, Value::String(user.to_string()));
+    vm.run()?;
+
+    Ok(capture.text())
+}
+
+fn main() {
+    match render("Account", "<admin>") {
+        Ok(page) => print!("{}", page),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,431 @@
+//! The parse tree as JSON, for editors and tools written in other languages.
+//! Every node is an object whose `kind` is its `Stmt`, `Expr` or `Pattern`
+//! variant, followed by its fields; statements also carry their `line`.
+//!
+//! ```text
+//! {"kind":"Program","doc":null,"statements":[{"kind":"Let","line":1,"name":"x",
+//!  "value":{"kind":"Binary","op":"+","left":{"kind":"Number","value":1},...}}]}
+//! ```
+
+use crate::stdlib::json;
+use crate::types::ast::{Attribute, Expr, ExprId, ForeignFunction, Pattern, Program, Stmt};
+use crate::types::compiler::HeapObject;
+use crate::types::interner::Symbol;
+
+pub fn to_json(program: &Program) -> String {
+    json::stringify(&program_node(program))
+}
+
+fn program_node(program: &Program) -> HeapObject {
+    node(
+        "Program",
+        vec![
+            ("doc", optional_text(program.doc.as_deref())),
+            ("statements", statements(program, &program.statements)),
+        ],
+    )
+}
+
+/// An object with `kind` first, then `fields` in order.
+fn node(kind: &str, fields: Vec<(&str, HeapObject)>) -> HeapObject {
+    HeapObject::Record {
+        type_name: String::new(),
+        fields: std::iter::once(("kind".to_string(), text(kind)))
+            .chain(
+                fields
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), value)),
+            )
+            .collect(),
+    }
+}
+
+fn text(s: &str) -> HeapObject {
+    HeapObject::String(s.to_string())
+}
+
+fn optional_text(s: Option<&str>) -> HeapObject {
+    s.map_or(HeapObject::Null, text)
+}
+
+fn names(names: &[Symbol]) -> HeapObject {
+    HeapObject::Array(names.iter().map(|name| text(name)).collect())
+}
+
+fn statements(program: &Program, statements: &[Stmt]) -> HeapObject {
+    HeapObject::Array(
+        statements
+            .iter()
+            .map(|stmt| stmt_node(program, stmt))
+            .collect(),
+    )
+}
+
+fn exprs(program: &Program, ids: &[ExprId]) -> HeapObject {
+    HeapObject::Array(ids.iter().map(|id| expr_node(program, *id)).collect())
+}
+
+fn attributes(attributes: &[Attribute]) -> HeapObject {
+    HeapObject::Array(
+        attributes
+            .iter()
+            .map(|attribute| {
+                node(
+                    "Attribute",
+                    vec![
+                        ("name", text(&attribute.name)),
+                        ("argument", optional_text(attribute.argument.as_deref())),
+                    ],
+                )
+            })
+            .collect(),
+    )
+}
+
+fn foreign_functions(functions: &[ForeignFunction]) -> HeapObject {
+    HeapObject::Array(
+        functions
+            .iter()
+            .map(|function| {
+                let params = function.params.iter().map(|(name, param_type)| {
+                    node(
+                        "Param",
+                        vec![("name", text(name)), ("type", text(param_type.name()))],
+                    )
+                });
+                node(
+                    "ForeignFunction",
+                    vec![
+                        ("name", text(&function.name)),
+                        ("params", HeapObject::Array(params.collect())),
+                        ("returns", text(function.returns.name())),
+                    ],
+                )
+            })
+            .collect(),
+    )
+}
+
+fn stmt_node(program: &Program, stmt: &Stmt) -> HeapObject {
+    let expr = |id: &ExprId| expr_node(program, *id);
+    let (kind, fields) = match stmt {
+        Stmt::Let { name, value, .. } => {
+            ("Let", vec![("name", text(name)), ("value", expr(value))])
+        }
+        Stmt::Const {
+            name, value, doc, ..
+        } => (
+            "Const",
+            vec![
+                ("name", text(name)),
+                ("value", expr(value)),
+                ("doc", optional_text(doc.as_deref())),
+            ],
+        ),
+        Stmt::LetPattern { pattern, value, .. } => (
+            "LetPattern",
+            vec![
+                ("pattern", pattern_node(program, pattern)),
+                ("value", expr(value)),
+            ],
+        ),
+        Stmt::Func {
+            name,
+            params,
+            defaults,
+            variadic,
+            body,
+            doc,
+            attributes: attrs,
+            ..
+        } => (
+            "Func",
+            vec![
+                ("name", text(name)),
+                ("params", names(params)),
+                ("defaults", exprs(program, defaults)),
+                ("variadic", HeapObject::Boolean(*variadic)),
+                ("body", statements(program, body)),
+                ("doc", optional_text(doc.as_deref())),
+                ("attributes", attributes(attrs)),
+            ],
+        ),
+        Stmt::Import { module, .. } => ("Import", vec![("module", text(module))]),
+        Stmt::Extern {
+            library, functions, ..
+        } => (
+            "Extern",
+            vec![
+                ("library", text(library)),
+                ("functions", foreign_functions(functions)),
+            ],
+        ),
+        Stmt::Struct {
+            name, fields, doc, ..
+        } => (
+            "Struct",
+            vec![
+                ("name", text(name)),
+                ("fields", names(fields)),
+                ("doc", optional_text(doc.as_deref())),
+            ],
+        ),
+        Stmt::Impl {
+            name,
+            trait_name,
+            methods,
+            constants,
+            ..
+        } => (
+            "Impl",
+            vec![
+                ("name", text(name)),
+                ("trait", optional_text(trait_name.as_deref())),
+                ("methods", statements(program, methods)),
+                ("constants", statements(program, constants)),
+            ],
+        ),
+        Stmt::Trait { name, methods, .. } => (
+            "Trait",
+            vec![
+                ("name", text(name)),
+                (
+                    "methods",
+                    HeapObject::Array(
+                        methods
+                            .iter()
+                            .map(|(method, params)| {
+                                node(
+                                    "Method",
+                                    vec![("name", text(method)), ("params", names(params))],
+                                )
+                            })
+                            .collect(),
+                    ),
+                ),
+            ],
+        ),
+        Stmt::Enum {
+            name,
+            variants,
+            doc,
+            attributes: attrs,
+            ..
+        } => (
+            "Enum",
+            vec![
+                ("name", text(name)),
+                (
+                    "variants",
+                    HeapObject::Array(
+                        variants
+                            .iter()
+                            .map(|(variant, fields)| {
+                                node(
+                                    "Variant",
+                                    vec![("name", text(variant)), ("fields", names(fields))],
+                                )
+                            })
+                            .collect(),
+                    ),
+                ),
+                ("doc", optional_text(doc.as_deref())),
+                ("attributes", attributes(attrs)),
+            ],
+        ),
+        Stmt::For {
+            name,
+            iterable,
+            body,
+            ..
+        } => (
+            "For",
+            vec![
+                ("name", text(name)),
+                ("iterable", expr(iterable)),
+                ("body", statements(program, body)),
+            ],
+        ),
+        Stmt::Expr(value, _) => ("Expr", vec![("value", expr(value))]),
+    };
+    let line = ("line", HeapObject::Number(stmt.line() as f64));
+    node(kind, std::iter::once(line).chain(fields).collect())
+}
+
+fn expr_node(program: &Program, id: ExprId) -> HeapObject {
+    let expr = |id: &ExprId| expr_node(program, *id);
+    match program.expr(id) {
+        Expr::Identifier(name) => node("Identifier", vec![("name", text(name))]),
+        Expr::Number(n) => node("Number", vec![("value", HeapObject::Number(*n))]),
+        Expr::String(s) => node("String", vec![("value", text(s))]),
+        Expr::Boolean(b) => node("Boolean", vec![("value", HeapObject::Boolean(*b))]),
+        Expr::Update { left, right } => {
+            node("Update", vec![("left", expr(left)), ("right", expr(right))])
+        }
+        Expr::Unary { op, right } => node(
+            "Unary",
+            vec![("op", text(op.symbol())), ("right", expr(right))],
+        ),
+        Expr::Binary { left, op, right } => node(
+            "Binary",
+            vec![
+                ("op", text(op.symbol())),
+                ("left", expr(left)),
+                ("right", expr(right)),
+            ],
+        ),
+        Expr::Call { func, args } => node(
+            "Call",
+            vec![("func", expr(func)), ("args", exprs(program, args))],
+        ),
+        Expr::Member { object, property } => node(
+            "Member",
+            vec![("object", expr(object)), ("property", text(property))],
+        ),
+        Expr::Pipeline { left, right } => node(
+            "Pipeline",
+            vec![("left", expr(left)), ("right", expr(right))],
+        ),
+        Expr::Array { elements } => node("Array", vec![("elements", exprs(program, elements))]),
+        Expr::Tuple(elements) => node("Tuple", vec![("elements", exprs(program, elements))]),
+        Expr::Spread(value) => node("Spread", vec![("value", expr(value))]),
+        Expr::NamedArg { name, value } => node(
+            "NamedArg",
+            vec![("name", text(name)), ("value", expr(value))],
+        ),
+        Expr::Lambda { params, body } => node(
+            "Lambda",
+            vec![("params", names(params)), ("body", expr(body))],
+        ),
+        Expr::Record { name, fields } => node(
+            "Record",
+            vec![
+                ("name", optional_text(name.as_deref())),
+                (
+                    "fields",
+                    HeapObject::Array(
+                        fields
+                            .iter()
+                            .map(|(field, value)| {
+                                node("Field", vec![("name", text(field)), ("value", expr(value))])
+                            })
+                            .collect(),
+                    ),
+                ),
+            ],
+        ),
+        Expr::Path {
+            type_name,
+            member,
+            args,
+        } => node(
+            "Path",
+            vec![
+                ("type", text(type_name)),
+                ("member", text(member)),
+                (
+                    "args",
+                    args.as_deref()
+                        .map_or(HeapObject::Null, |args| exprs(program, args)),
+                ),
+            ],
+        ),
+        Expr::Match { subject, arms } => node(
+            "Match",
+            vec![
+                ("subject", expr(subject)),
+                (
+                    "arms",
+                    HeapObject::Array(
+                        arms.iter()
+                            .map(|(pattern, value)| {
+                                node(
+                                    "Arm",
+                                    vec![
+                                        ("pattern", pattern_node(program, pattern)),
+                                        ("value", expr(value)),
+                                    ],
+                                )
+                            })
+                            .collect(),
+                    ),
+                ),
+            ],
+        ),
+        Expr::Yield(value) => node("Yield", vec![("value", expr(value))]),
+        Expr::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => node(
+            "If",
+            vec![
+                ("condition", expr(condition)),
+                ("then", statements(program, then_branch)),
+                (
+                    "else",
+                    else_branch
+                        .as_deref()
+                        .map_or(HeapObject::Null, |branch| statements(program, branch)),
+                ),
+            ],
+        ),
+        Expr::Block(body) => node("Block", vec![("statements", statements(program, body))]),
+    }
+}
+
+fn pattern_node(program: &Program, pattern: &Pattern) -> HeapObject {
+    let patterns = |patterns: &[Pattern]| {
+        HeapObject::Array(
+            patterns
+                .iter()
+                .map(|pattern| pattern_node(program, pattern))
+                .collect(),
+        )
+    };
+    match pattern {
+        Pattern::Wildcard => node("Wildcard", Vec::new()),
+        Pattern::Binding(name) => node("Binding", vec![("name", text(name))]),
+        Pattern::Literal(value) => node("Literal", vec![("value", expr_node(program, *value))]),
+        Pattern::Variant {
+            enum_name,
+            variant,
+            fields,
+        } => node(
+            "Variant",
+            vec![
+                ("enum", text(enum_name)),
+                ("variant", text(variant)),
+                ("fields", patterns(fields)),
+            ],
+        ),
+        Pattern::Tuple(elements) => node("Tuple", vec![("elements", patterns(elements))]),
+        Pattern::List { elements, rest } => node(
+            "List",
+            vec![
+                ("elements", patterns(elements)),
+                ("rest", optional_text(rest.as_deref())),
+            ],
+        ),
+        Pattern::Record(fields) => node(
+            "Record",
+            vec![(
+                "fields",
+                HeapObject::Array(
+                    fields
+                        .iter()
+                        .map(|(field, pattern)| {
+                            node(
+                                "Field",
+                                vec![
+                                    ("name", text(field)),
+                                    ("pattern", pattern_node(program, pattern)),
+                                ],
+                            )
+                        })
+                        .collect(),
+                ),
+            )],
+        ),
+    }
+}
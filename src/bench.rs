@@ -0,0 +1,164 @@
+use crate::compiler::Compiler;
+use crate::interpreter::VirtualMachine;
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Average time each stage of the pipeline took on one program.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StageTimes {
+    pub lex: Duration,
+    pub parse: Duration,
+    pub compile: Duration,
+    pub run: Duration,
+}
+
+impl StageTimes {
+    pub fn total(&self) -> Duration {
+        self.lex + self.parse + self.compile + self.run
+    }
+}
+
+impl fmt::Display for StageTimes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>12?} {:>12?} {:>12?} {:>12?} {:>12?}",
+            self.lex,
+            self.parse,
+            self.compile,
+            self.run,
+            self.total()
+        )
+    }
+}
+
+/// Lexes, parses, compiles and runs `source` `rounds` times, timing each
+/// stage separately. Each stage works on the previous stage's output from the
+/// same round, so a slow stage does not hide behind another. Script output
+/// is discarded.
+pub fn measure(source: &str, rounds: u32) -> Result<StageTimes, String> {
+    let rounds = rounds.max(1);
+    let mut times = StageTimes::default();
+    for _ in 0..rounds {
+        let start = Instant::now();
+        let tokens = Lexer::new(source).tokenize();
+        times.lex += start.elapsed();
+
+        let start = Instant::now();
+        let program = Parser::new(tokens).parse()?;
+        times.parse += start.elapsed();
+
+        let start = Instant::now();
+        let mut compiler = Compiler::new();
+        let bytecode = compiler.compile(&program).map_err(|e| e.to_string())?;
+        times.compile += start.elapsed();
+
+        let start = Instant::now();
+        let mut vm = VirtualMachine::new(bytecode, compiler);
+        vm.set_output(Box::new(std::io::sink()));
+        vm.run()?;
+        times.run += start.elapsed();
+    }
+    times.lex /= rounds;
+    times.parse /= rounds;
+    times.compile /= rounds;
+    times.run /= rounds;
+    Ok(times)
+}
+
+/// Programs that stress different stages: deep recursion and straight-line
+/// arithmetic for the VM, wide records for allocation and field access,
+/// long interpolated strings for string building, and many small functions
+/// for the parser and compiler's symbol tables.
+pub fn programs() -> Vec<(&'static str, String)> {
+    vec![
+        ("fibonacci", fibonacci(20)),
+        ("big structs", big_structs(64, 2_000)),
+        ("10k functions", many_functions(10_000)),
+        ("arithmetic", arithmetic(20_000)),
+        ("templating", templating(32, 2_000)),
+    ]
+}
+
+/// Naive recursive `fib(n)`.
+pub fn fibonacci(n: usize) -> String {
+    format!(
+        "func fib(n) {{ if n < 2 {{ n }} else {{ fib(n - 1) + fib(n - 2) }} }}\nfib({})\n",
+        n
+    )
+}
+
+/// A `struct` with `fields` fields, built and read `count` times in a loop.
+pub fn big_structs(fields: usize, count: usize) -> String {
+    let names: Vec<String> = (0..fields).map(|i| format!("f{}", i)).collect();
+    let values: Vec<String> = names.iter().map(|name| format!("{} = i", name)).collect();
+    format!(
+        "struct Big {{ {} }}\n\
+         for i in unfold(0, fn(n) => if n == {} {{ [] }} else {{ [n, n + 1] }}) {{\n\
+         let big = Big {{ {} }}\n\
+         big.{}\n\
+         }}\n",
+        names.join(", "),
+        count,
+        values.join(", "),
+        names[fields - 1]
+    )
+}
+
+/// `count` one-line functions, the last of which is called.
+pub fn many_functions(count: usize) -> String {
+    let mut source: String = (0..count)
+        .map(|i| format!("func f{}(x) {{ x + {} }}\n", i, i))
+        .collect();
+    source.push_str(&format!("f{}(1)\n", count - 1));
+    source
+}
+
+/// A loop of `count` calls of a function doing straight-line arithmetic on
+/// local variables.
+pub fn arithmetic(count: usize) -> String {
+    let mut body = String::from("let a0 = i + 1\n");
+    for n in 1..32 {
+        body.push_str(&format!(
+            "let k{} = {}\nlet a{} = a{} + {}\n",
+            n,
+            n,
+            n,
+            n - 1,
+            n
+        ));
+    }
+    format!(
+        "func step(i) {{\n{}a31\n}}\n\
+         for i in unfold(0, fn(n) => if n == {} {{ [] }} else {{ [n, n + 1] }}) {{\n\
+         step(i)\n\
+         }}\n",
+        body, count
+    )
+}
+
+/// A table row of `columns` cells rendered `count` times, once from an
+/// interpolated string and once by adding strings up.
+pub fn templating(columns: usize, count: usize) -> String {
+    let cells: String = (0..columns)
+        .map(|n| format!("<td class=c{}>{{i + {}}}</td>", n, n))
+        .collect();
+    let sums: Vec<String> = (0..columns)
+        .map(|n| format!("\"<td>\" + name + \"{}</td>\"", n))
+        .collect();
+    format!(
+        "func row(i, name) {{\n\
+         let cells = $\"<tr>{}</tr>\"\n\
+         let sums = {}\n\
+         cells ++ sums\n\
+         }}\n\
+         for i in unfold(0, fn(n) => if n == {} {{ [] }} else {{ [n, n + 1] }}) {{\n\
+         row(i, \"item\")\n\
+         }}\n",
+        cells,
+        sums.join(" + "),
+        count
+    )
+}
@@ -0,0 +1,852 @@
+//! Binary `.nb` encoding of compiled programs. See `docs/BYTECODE.md` for the layout.
+
+use crate::natives::NativeRegistry;
+use crate::types::compiler::{ByteCode, EnumDef, Exports, Instruction, Value, VariantDef};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+pub const MAGIC: &[u8; 2] = b"NB";
+pub const VERSION: u16 = 4;
+/// Oldest version `decode` still reads, migrating it to `VERSION`.
+pub const MIN_VERSION: u16 = 2;
+pub const HEADER_SIZE: usize = 8;
+
+const TAG_STRING: u8 = 0;
+const TAG_NUMBER: u8 = 1;
+const TAG_BOOLEAN: u8 = 2;
+const TAG_FUNCTION: u8 = 4;
+/// Constant table only: a whole number as a zigzag varint.
+const TAG_INTEGER: u8 = 5;
+/// Constant table only: the same string as the earlier constant at a varint
+/// index.
+const TAG_REPEAT: u8 = 6;
+
+/// Largest magnitude up to which every whole `f64` is exact, so it survives
+/// the trip through `TAG_INTEGER`.
+const MAX_EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+/// Bytes of a variant descriptor: name index and field count.
+const VARIANT_DESCRIPTOR_SIZE: usize = 5;
+
+/// Prefix making the index operands of the next instruction u32 instead of
+/// u16. The encoder adds it only where an index does not fit in 16 bits.
+const WIDE: u8 = 0xFF;
+
+impl Instruction {
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Instruction::StoreVar(..) => 0x01,
+            Instruction::LoadVar(..) => 0x02,
+            Instruction::LoadArg(_) => 0x03,
+            Instruction::Call(_) => 0x04,
+            Instruction::Return => 0x05,
+            Instruction::LoadConst(_) => 0x06,
+            Instruction::CallNative(..) => 0x07,
+            Instruction::MakeClosure(..) => 0x08,
+            Instruction::CallValue(_) => 0x09,
+            Instruction::CallMethod(_) => 0x0A,
+            Instruction::Apply => 0x0B,
+            Instruction::Yield => 0x0C,
+            Instruction::Invoke(..) => 0x0D,
+            Instruction::DefineMethod(_) => 0x0E,
+            Instruction::NoMatch => 0x0F,
+            Instruction::Add => 0x10,
+            Instruction::Sub => 0x11,
+            Instruction::Div => 0x12,
+            Instruction::Mul => 0x13,
+            Instruction::Equal => 0x14,
+            Instruction::Less => 0x15,
+            Instruction::Greater => 0x16,
+            Instruction::Not => 0x17,
+            Instruction::CreateArray(_) => 0x18,
+            Instruction::ConcatArray => 0x19,
+            Instruction::Concat => 0x1A,
+            Instruction::MakeRecord(_) => 0x1B,
+            Instruction::GetField(_) => 0x1C,
+            Instruction::MakeVariant(..) => 0x1D,
+            Instruction::TestVariant(_) => 0x1E,
+            Instruction::MakeTuple(_) => 0x1F,
+            Instruction::Jump(_) => 0x20,
+            Instruction::JumpIfFalse(_) => 0x21,
+            Instruction::JumpIfTrue(_) => 0x22,
+            Instruction::Pop => 0x30,
+            Instruction::Push(_) => 0x31,
+            Instruction::Dup => 0x32,
+            Instruction::Halt => 0x33,
+            Instruction::TestTuple(_) => 0x40,
+            Instruction::TestList(..) => 0x41,
+            Instruction::HasField(_) => 0x42,
+            Instruction::ListFrom(_) => 0x43,
+            Instruction::LoadGlobal(_) => 0x44,
+            Instruction::ConcatN(_) => 0x45,
+        }
+    }
+
+    /// Whether an index operand is too large for 16 bits, so the encoded
+    /// instruction needs the `WIDE` prefix.
+    fn is_wide(&self) -> bool {
+        let largest = match self {
+            Instruction::StoreVar(depth, index) | Instruction::LoadVar(depth, index) => {
+                *depth.max(index)
+            }
+            Instruction::LoadArg(n)
+            | Instruction::Call(n)
+            | Instruction::LoadConst(n)
+            | Instruction::CallMethod(n)
+            | Instruction::DefineMethod(n)
+            | Instruction::MakeRecord(n)
+            | Instruction::GetField(n)
+            | Instruction::TestVariant(n)
+            | Instruction::MakeTuple(n)
+            | Instruction::TestTuple(n)
+            | Instruction::HasField(n)
+            | Instruction::ListFrom(n)
+            | Instruction::LoadGlobal(n)
+            | Instruction::ConcatN(n)
+            | Instruction::CreateArray(n)
+            | Instruction::CallNative(n, _)
+            | Instruction::MakeClosure(n, _)
+            | Instruction::Invoke(n, _)
+            | Instruction::MakeVariant(n, _)
+            | Instruction::TestList(n, _) => *n,
+            _ => 0,
+        };
+        largest > u16::MAX as usize
+    }
+}
+
+/// Byte ranges of each section in an encoded file, as reported by `inspect`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SectionSizes {
+    pub header: usize,
+    pub constants: usize,
+    pub functions: usize,
+    pub enums: usize,
+    pub instructions: usize,
+    pub lines: usize,
+    pub exports: usize,
+}
+
+pub fn encode(bytecode: &ByteCode) -> Result<Vec<u8>, String> {
+    let mut w = Writer::default();
+    w.bytes.extend_from_slice(MAGIC);
+    w.u16(VERSION);
+    w.u16(bytecode.flags);
+    w.u16(0); // Reserved
+
+    w.constants(&bytecode.constants)?;
+
+    w.u32(count_u32(bytecode.functions.len(), "functions")?);
+    for function in &bytecode.functions {
+        let Value::Function { params, offset } = function else {
+            return Err(format!(
+                "Function table holds a {}",
+                function.type_name_stack()
+            ));
+        };
+        w.u8(count_u8(params.len(), "parameters")?);
+        for param in params {
+            w.string(param)?;
+        }
+        w.u32(count_u32(*offset, "function offset")?);
+    }
+
+    w.u32(count_u32(bytecode.enums.len(), "enums")?);
+    let mut descriptor_offset = 0;
+    for def in &bytecode.enums {
+        w.u32(count_u32(def.name, "enum name index")?);
+        w.u8(count_u8(def.variants.len(), "variants")?);
+        w.u32(count_u32(descriptor_offset, "variant descriptor offset")?);
+        descriptor_offset += def.variants.len() * VARIANT_DESCRIPTOR_SIZE;
+    }
+    for variant in bytecode.enums.iter().flat_map(|def| &def.variants) {
+        w.u32(count_u32(variant.name, "variant name index")?);
+        w.u8(count_u8(variant.field_count, "variant fields")?);
+    }
+
+    w.u32(count_u32(bytecode.instructions.len(), "instructions")?);
+    for instruction in &bytecode.instructions {
+        w.instruction(instruction)?;
+    }
+
+    w.u32(count_u32(bytecode.instruction_lines.len(), "line entries")?);
+    for line in &bytecode.instruction_lines {
+        w.u32(count_u32(*line, "line number")?);
+    }
+
+    let exports = &bytecode.exports;
+    w.u32(count_u32(exports.natives.len(), "natives")?);
+    for name in &exports.natives {
+        w.string(name)?;
+    }
+    for names in [&exports.functions, &exports.globals] {
+        w.u32(count_u32(names.len(), "exports")?);
+        for (name, index) in names {
+            w.string(name)?;
+            w.u32(count_u32(*index, "export index")?);
+        }
+    }
+
+    Ok(w.bytes)
+}
+
+/// Decodes and verifies an encoded file, so malformed bytecode is rejected
+/// here rather than failing part way through a run.
+pub fn decode(bytes: &[u8]) -> Result<ByteCode, String> {
+    let (bytecode, _) = decode_with_sizes(bytes)?;
+    crate::verify::verify(&bytecode)?;
+    Ok(bytecode)
+}
+
+pub fn decode_with_sizes(bytes: &[u8]) -> Result<(ByteCode, SectionSizes), String> {
+    let mut r = Reader {
+        bytes,
+        pos: 0,
+        wide: false,
+    };
+    let mut sizes = SectionSizes::default();
+
+    if r.take(2)? != MAGIC {
+        return Err("Not an n bytecode file (bad magic)".to_string());
+    }
+    let version = r.u16()?;
+    if version > VERSION {
+        return Err(format!(
+            "Bytecode version {} is newer than the {} this build of n reads; upgrade n or rebuild the file",
+            version, VERSION
+        ));
+    }
+    if version < MIN_VERSION {
+        return Err(format!(
+            "Bytecode version {} is too old, the oldest this build of n reads is {}; rebuild the file with `n build`",
+            version, MIN_VERSION
+        ));
+    }
+    let flags = r.u16()?;
+    r.u16()?; // Reserved
+    sizes.header = r.pos;
+
+    let start = r.pos;
+    let constants = match version {
+        2 | 3 => (0..r.u32()?)
+            .map(|_| r.value())
+            .collect::<Result<Vec<_>, _>>()?,
+        _ => r.constants()?,
+    };
+    sizes.constants = r.pos - start;
+
+    let start = r.pos;
+    let mut functions = Vec::new();
+    for _ in 0..r.u32()? {
+        let params = (0..r.u8()?)
+            .map(|_| r.string())
+            .collect::<Result<Vec<_>, _>>()?;
+        let offset = r.u32()? as usize;
+        functions.push(Value::Function { params, offset });
+    }
+    sizes.functions = r.pos - start;
+
+    let start = r.pos;
+    let entries = (0..r.u32()?)
+        .map(|_| Ok((r.u32()? as usize, r.u8()? as usize, r.u32()? as usize)))
+        .collect::<Result<Vec<_>, String>>()?;
+    let region = r.pos;
+    let mut enums = Vec::new();
+    let mut descriptor_end = region;
+    for (name, variant_count, offset) in entries {
+        r.pos = region + offset;
+        let variants = (0..variant_count)
+            .map(|_| {
+                Ok(VariantDef {
+                    name: r.u32()? as usize,
+                    field_count: r.u8()? as usize,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        descriptor_end = descriptor_end.max(r.pos);
+        enums.push(EnumDef { name, variants });
+    }
+    r.pos = descriptor_end;
+    sizes.enums = r.pos - start;
+
+    let start = r.pos;
+    let instructions = (0..r.u32()?)
+        .map(|_| r.instruction())
+        .collect::<Result<Vec<_>, _>>()?;
+    sizes.instructions = r.pos - start;
+
+    let start = r.pos;
+    let instruction_lines = (0..r.u32()?)
+        .map(|_| r.u32().map(|line| line as usize))
+        .collect::<Result<Vec<_>, _>>()?;
+    sizes.lines = r.pos - start;
+
+    let start = r.pos;
+    let exports = match version {
+        2 => Exports::default(),
+        _ => {
+            let natives = (0..r.u32()?)
+                .map(|_| r.string())
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut names = || {
+                (0..r.u32()?)
+                    .map(|_| Ok((r.string()?, r.u32()? as usize)))
+                    .collect::<Result<Vec<_>, String>>()
+            };
+            Exports {
+                natives,
+                functions: names()?,
+                globals: names()?,
+            }
+        }
+    };
+    sizes.exports = r.pos - start;
+
+    if r.pos != bytes.len() {
+        return Err(format!(
+            "{} trailing byte(s) after the last table",
+            bytes.len() - r.pos
+        ));
+    }
+
+    let mut bytecode = ByteCode {
+        flags,
+        constants,
+        functions,
+        enums,
+        instructions,
+        instruction_lines,
+        exports,
+    };
+    migrate(version, &mut bytecode);
+    Ok((bytecode, sizes))
+}
+
+/// Brings a program read from a file of an older `version` up to what the
+/// current one holds. Each step takes it one version further, so supporting
+/// a new version only needs the step from the one before. v4 only changed
+/// how the constant table is laid out, which `decode_with_sizes` reads
+/// either way, so it has no step.
+fn migrate(version: u16, bytecode: &mut ByteCode) {
+    if version < 3 {
+        // v2 had no export table. Its `CALL_NATIVE`s name the builtins by
+        // their registration order; those of standard modules cannot be
+        // told apart, so they stay unnamed and the file cannot be linked
+        // if it uses them.
+        let builtins = NativeRegistry::with_builtins();
+        bytecode.exports.natives = builtins
+            .functions()
+            .iter()
+            .map(|f| f.name.clone())
+            .collect();
+    }
+}
+
+/// Renders the header, every table and a hex + mnemonic listing of the
+/// instruction stream of an encoded file.
+pub fn inspect(bytes: &[u8]) -> Result<String, String> {
+    let (bytecode, sizes) = decode_with_sizes(bytes)?;
+    let mut out = String::new();
+
+    let _ = writeln!(out, "=== HEADER ===");
+    let _ = writeln!(out, "  magic:   {}", hex(&bytes[0..2]));
+    let version = u16::from_le_bytes([bytes[2], bytes[3]]);
+    match version {
+        VERSION => {
+            let _ = writeln!(out, "  version: {}", version);
+        }
+        _ => {
+            let _ = writeln!(out, "  version: {} (read as {})", version, VERSION);
+        }
+    }
+    let _ = writeln!(out, "  flags:   0x{:04X}", bytecode.flags);
+    let _ = writeln!(out, "  size:    {} bytes", bytes.len());
+
+    let _ = writeln!(out, "\n=== SECTIONS ===");
+    for (name, size) in [
+        ("header", sizes.header),
+        ("constants", sizes.constants),
+        ("functions", sizes.functions),
+        ("enums", sizes.enums),
+        ("instructions", sizes.instructions),
+        ("lines", sizes.lines),
+        ("exports", sizes.exports),
+    ] {
+        let _ = writeln!(out, "  {:<12} {:>6} bytes", name, size);
+    }
+
+    let _ = writeln!(out, "\n=== CONSTANTS ({}) ===", bytecode.constants.len());
+    for (i, constant) in bytecode.constants.iter().enumerate() {
+        let _ = writeln!(out, "  [{}] {}", i, constant);
+    }
+
+    let _ = writeln!(out, "\n=== FUNCTIONS ({}) ===", bytecode.functions.len());
+    for (i, function) in bytecode.functions.iter().enumerate() {
+        let _ = writeln!(out, "  [{}] {}", i, function);
+    }
+
+    let _ = writeln!(out, "\n=== ENUMS ({}) ===", bytecode.enums.len());
+    let name = |index: usize| match bytecode.constants.get(index) {
+        Some(Value::String(name)) => name.clone(),
+        _ => format!("#{}", index),
+    };
+    for (i, def) in bytecode.enums.iter().enumerate() {
+        let variants: Vec<String> = def
+            .variants
+            .iter()
+            .map(|variant| format!("{}/{}", name(variant.name), variant.field_count))
+            .collect();
+        let _ = writeln!(
+            out,
+            "  [{}] {} {{ {} }}",
+            i,
+            name(def.name),
+            variants.join(", ")
+        );
+    }
+
+    let exports = &bytecode.exports;
+    let _ = writeln!(out, "\n=== EXPORTS ===");
+    let _ = writeln!(out, "  natives:   {}", exports.natives.join(", "));
+    for (title, names) in [
+        ("functions", &exports.functions),
+        ("globals", &exports.globals),
+    ] {
+        let names: Vec<String> = names
+            .iter()
+            .map(|(name, index)| format!("{}={}", name, index))
+            .collect();
+        let _ = writeln!(out, "  {:<10} {}", format!("{}:", title), names.join(", "));
+    }
+
+    let _ = writeln!(
+        out,
+        "\n=== INSTRUCTIONS ({}) ===",
+        bytecode.instructions.len()
+    );
+    // Instruction bytes start after the 4-byte count of the instruction section
+    let mut offset = sizes.header + sizes.constants + sizes.functions + sizes.enums + 4;
+    for (i, instruction) in bytecode.instructions.iter().enumerate() {
+        let mut w = Writer::default();
+        w.instruction(instruction)?;
+        let line = bytecode.instruction_lines.get(i).copied().unwrap_or(0);
+        let _ = writeln!(
+            out,
+            "  {:04} {:06X}  {:<24} {:<28} ; line {}",
+            i,
+            offset,
+            hex(&w.bytes),
+            instruction.to_string(),
+            line
+        );
+        offset += w.bytes.len();
+    }
+
+    Ok(out)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Length in bytes of the longest common prefix of `a` and `b` that ends on a
+/// character boundary.
+fn shared_prefix(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .find(|((_, x), y)| x != y)
+        .map(|((at, _), _)| at)
+        .unwrap_or_else(|| a.len().min(b.len()))
+}
+
+fn count_u8(n: usize, what: &str) -> Result<u8, String> {
+    u8::try_from(n).map_err(|_| format!("Too many {} to encode ({})", what, n))
+}
+
+fn count_u16(n: usize, what: &str) -> Result<u16, String> {
+    u16::try_from(n).map_err(|_| format!("Too many {} to encode ({})", what, n))
+}
+
+fn count_u32(n: usize, what: &str) -> Result<u32, String> {
+    u32::try_from(n).map_err(|_| format!("{} out of range ({})", what, n))
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+    /// Set while writing the operands of a `WIDE` instruction.
+    wide: bool,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.bytes.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    /// LEB128: seven bits per byte, low bits first, the high bit set on all
+    /// but the last byte.
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.u8(v as u8 | 0x80);
+            v >>= 7;
+        }
+        self.u8(v as u8);
+    }
+
+    /// The constant table: a varint count, then each constant as `value`
+    /// writes it, except that whole numbers are varints, a string repeating
+    /// an earlier one refers to it, and other strings share their leading
+    /// bytes with the string before them.
+    fn constants(&mut self, constants: &[Value]) -> Result<(), String> {
+        self.varint(constants.len() as u64);
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        let mut previous = "";
+        for (i, constant) in constants.iter().enumerate() {
+            match constant {
+                Value::String(s) => {
+                    if let Some(&first) = seen.get(s.as_str()) {
+                        self.u8(TAG_REPEAT);
+                        self.varint(first as u64);
+                        continue;
+                    }
+                    seen.insert(s, i);
+                    let shared = shared_prefix(previous, s);
+                    self.u8(TAG_STRING);
+                    self.varint(shared as u64);
+                    self.varint((s.len() - shared) as u64);
+                    self.bytes.extend_from_slice(&s.as_bytes()[shared..]);
+                    previous = s;
+                }
+                Value::Number(n)
+                    if n.fract() == 0.0
+                        && n.abs() <= MAX_EXACT_INTEGER
+                        && !(*n == 0.0 && n.is_sign_negative()) =>
+                {
+                    let n = *n as i64;
+                    self.u8(TAG_INTEGER);
+                    self.varint(((n << 1) ^ (n >> 63)) as u64);
+                }
+                other => self.value(other)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn index(&mut self, v: usize) -> Result<(), String> {
+        match self.wide {
+            true => self.u32(count_u32(v, "index")?),
+            false => self.u16(count_u16(v, "index")?),
+        }
+        Ok(())
+    }
+
+    fn string(&mut self, s: &str) -> Result<(), String> {
+        self.u16(count_u16(s.len(), "string bytes")?);
+        self.bytes.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+
+    fn value(&mut self, value: &Value) -> Result<(), String> {
+        match value {
+            Value::String(s) => {
+                self.u8(TAG_STRING);
+                self.string(s)?;
+            }
+            Value::Number(n) => {
+                self.u8(TAG_NUMBER);
+                self.bytes.extend_from_slice(&n.to_le_bytes());
+            }
+            Value::Boolean(b) => {
+                self.u8(TAG_BOOLEAN);
+                self.u8(*b as u8);
+            }
+            Value::Function { params, offset } => {
+                self.u8(TAG_FUNCTION);
+                self.u8(count_u8(params.len(), "parameters")?);
+                for param in params {
+                    self.string(param)?;
+                }
+                self.u32(count_u32(*offset, "function offset")?);
+            }
+            Value::HeapPointer(_) => return Err("Cannot encode a heap pointer".to_string()),
+            Value::Closure { .. } => return Err("Cannot encode a closure".to_string()),
+            Value::Task(_) => return Err("Cannot encode a task".to_string()),
+            Value::Channel(_) => return Err("Cannot encode a channel".to_string()),
+            Value::Generator(_) => return Err("Cannot encode a generator".to_string()),
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) -> Result<(), String> {
+        self.wide = instruction.is_wide();
+        if self.wide {
+            self.u8(WIDE);
+        }
+        self.u8(instruction.opcode());
+        let operands = self.operands(instruction);
+        self.wide = false;
+        operands
+    }
+
+    fn operands(&mut self, instruction: &Instruction) -> Result<(), String> {
+        match instruction {
+            Instruction::StoreVar(depth, index) | Instruction::LoadVar(depth, index) => {
+                self.index(*depth)?;
+                self.index(*index)?;
+            }
+            Instruction::LoadArg(n)
+            | Instruction::Call(n)
+            | Instruction::LoadConst(n)
+            | Instruction::CallMethod(n)
+            | Instruction::DefineMethod(n)
+            | Instruction::MakeRecord(n)
+            | Instruction::GetField(n)
+            | Instruction::TestVariant(n)
+            | Instruction::MakeTuple(n)
+            | Instruction::TestTuple(n)
+            | Instruction::HasField(n)
+            | Instruction::ListFrom(n)
+            | Instruction::LoadGlobal(n)
+            | Instruction::ConcatN(n)
+            | Instruction::CreateArray(n) => self.index(*n)?,
+            Instruction::CallNative(index, argc)
+            | Instruction::MakeClosure(index, argc)
+            | Instruction::Invoke(index, argc)
+            | Instruction::MakeVariant(index, argc) => {
+                self.index(*index)?;
+                self.u8(count_u8(*argc, "arguments")?);
+            }
+            Instruction::TestList(count, at_least) => {
+                self.index(*count)?;
+                self.u8(*at_least as u8);
+            }
+            Instruction::CallValue(argc) => self.u8(count_u8(*argc, "arguments")?),
+            Instruction::Jump(target)
+            | Instruction::JumpIfFalse(target)
+            | Instruction::JumpIfTrue(target) => self.u32(count_u32(*target, "jump target")?),
+            Instruction::Push(value) => self.value(value)?,
+            Instruction::Return
+            | Instruction::Apply
+            | Instruction::Yield
+            | Instruction::NoMatch
+            | Instruction::Add
+            | Instruction::Sub
+            | Instruction::Div
+            | Instruction::Mul
+            | Instruction::Equal
+            | Instruction::Less
+            | Instruction::Greater
+            | Instruction::Not
+            | Instruction::ConcatArray
+            | Instruction::Concat
+            | Instruction::Pop
+            | Instruction::Dup
+            | Instruction::Halt => {}
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// Set while reading the operands of a `WIDE` instruction.
+    wide: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let slice = self
+            .pos
+            .checked_add(n)
+            .and_then(|end| self.bytes.get(self.pos..end))
+            .ok_or_else(|| format!("Unexpected end of file at byte {}", self.pos))?;
+        let end = self.pos + n;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let start = self.pos;
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(format!("Varint too long at byte {}", start))
+    }
+
+    fn varint_usize(&mut self) -> Result<usize, String> {
+        let start = self.pos;
+        let value = self.varint()?;
+        usize::try_from(value).map_err(|_| format!("Varint out of range at byte {}", start))
+    }
+
+    /// The constant table `Writer::constants` writes.
+    fn constants(&mut self) -> Result<Vec<Value>, String> {
+        let count = self.varint()?;
+        let mut constants = Vec::new();
+        let mut previous = String::new();
+        for _ in 0..count {
+            let start = self.pos;
+            let constant = match self.u8()? {
+                TAG_STRING => {
+                    let shared = self.varint_usize()?;
+                    let len = self.varint_usize()?;
+                    let prefix = previous
+                        .get(..shared)
+                        .ok_or_else(|| format!("Bad shared string prefix at byte {}", start))?;
+                    let suffix = std::str::from_utf8(self.take(len)?)
+                        .map_err(|_| format!("Invalid UTF-8 in string at byte {}", start))?;
+                    previous = format!("{}{}", prefix, suffix);
+                    Value::String(previous.clone())
+                }
+                TAG_INTEGER => {
+                    let n = self.varint()?;
+                    Value::Number(((n >> 1) as i64 ^ -((n & 1) as i64)) as f64)
+                }
+                TAG_REPEAT => match constants.get(self.varint_usize()?) {
+                    Some(Value::String(s)) => Value::String(s.clone()),
+                    _ => return Err(format!("Bad string reference at byte {}", start)),
+                },
+                _ => {
+                    self.pos = start;
+                    self.value()?
+                }
+            };
+            constants.push(constant);
+        }
+        Ok(constants)
+    }
+
+    fn index(&mut self) -> Result<usize, String> {
+        match self.wide {
+            true => self.u32().map(|v| v as usize),
+            false => self.u16().map(usize::from),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u16()? as usize;
+        let start = self.pos;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| format!("Invalid UTF-8 in string at byte {}", start))
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        match self.u8()? {
+            TAG_STRING => Ok(Value::String(self.string()?)),
+            TAG_NUMBER => {
+                let b = self.take(8)?;
+                let mut raw = [0; 8];
+                raw.copy_from_slice(b);
+                Ok(Value::Number(f64::from_le_bytes(raw)))
+            }
+            TAG_BOOLEAN => Ok(Value::Boolean(self.u8()? != 0)),
+            TAG_FUNCTION => {
+                let params = (0..self.u8()?)
+                    .map(|_| self.string())
+                    .collect::<Result<Vec<_>, _>>()?;
+                let offset = self.u32()? as usize;
+                Ok(Value::Function { params, offset })
+            }
+            tag => Err(format!("Unknown value tag {} at byte {}", tag, start)),
+        }
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, String> {
+        let start = self.pos;
+        let mut opcode = self.u8()?;
+        self.wide = opcode == WIDE;
+        if self.wide {
+            opcode = self.u8()?;
+        }
+        let instruction = self.operands(opcode, start);
+        self.wide = false;
+        instruction
+    }
+
+    fn operands(&mut self, opcode: u8, start: usize) -> Result<Instruction, String> {
+        let instruction = match opcode {
+            0x01 => Instruction::StoreVar(self.index()?, self.index()?),
+            0x02 => Instruction::LoadVar(self.index()?, self.index()?),
+            0x03 => Instruction::LoadArg(self.index()?),
+            0x04 => Instruction::Call(self.index()?),
+            0x05 => Instruction::Return,
+            0x06 => Instruction::LoadConst(self.index()?),
+            0x07 => Instruction::CallNative(self.index()?, self.u8()? as usize),
+            0x08 => Instruction::MakeClosure(self.index()?, self.u8()? as usize),
+            0x09 => Instruction::CallValue(self.u8()? as usize),
+            0x0A => Instruction::CallMethod(self.index()?),
+            0x0B => Instruction::Apply,
+            0x0C => Instruction::Yield,
+            0x0D => Instruction::Invoke(self.index()?, self.u8()? as usize),
+            0x0E => Instruction::DefineMethod(self.index()?),
+            0x0F => Instruction::NoMatch,
+            0x10 => Instruction::Add,
+            0x11 => Instruction::Sub,
+            0x12 => Instruction::Div,
+            0x13 => Instruction::Mul,
+            0x14 => Instruction::Equal,
+            0x15 => Instruction::Less,
+            0x16 => Instruction::Greater,
+            0x17 => Instruction::Not,
+            0x18 => Instruction::CreateArray(self.index()?),
+            0x19 => Instruction::ConcatArray,
+            0x1A => Instruction::Concat,
+            0x1B => Instruction::MakeRecord(self.index()?),
+            0x1C => Instruction::GetField(self.index()?),
+            0x1D => Instruction::MakeVariant(self.index()?, self.u8()? as usize),
+            0x1E => Instruction::TestVariant(self.index()?),
+            0x1F => Instruction::MakeTuple(self.index()?),
+            0x20 => Instruction::Jump(self.u32()? as usize),
+            0x21 => Instruction::JumpIfFalse(self.u32()? as usize),
+            0x22 => Instruction::JumpIfTrue(self.u32()? as usize),
+            0x30 => Instruction::Pop,
+            0x31 => Instruction::Push(self.value()?),
+            0x32 => Instruction::Dup,
+            0x33 => Instruction::Halt,
+            0x40 => Instruction::TestTuple(self.index()?),
+            0x41 => Instruction::TestList(self.index()?, self.u8()? != 0),
+            0x42 => Instruction::HasField(self.index()?),
+            0x43 => Instruction::ListFrom(self.index()?),
+            0x44 => Instruction::LoadGlobal(self.index()?),
+            0x45 => Instruction::ConcatN(self.index()?),
+            opcode => {
+                return Err(format!("Unknown opcode 0x{:02X} at byte {}", opcode, start));
+            }
+        };
+        Ok(instruction)
+    }
+}
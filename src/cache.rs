@@ -0,0 +1,142 @@
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::types::ast::Program;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Parsed modules keyed by a hash of their source, so a module whose text has
+/// not changed is not lexed and parsed again. Clones share the same entries:
+/// a compiler and its clones (one per `Engine::eval`, for instance) all hit the
+/// same cache, and hosts can hand one cache to several compilers.
+///
+/// Each entry keeps its source, and a hit only counts when the text matches,
+/// so two modules whose hashes collide are never confused. Past `capacity`
+/// entries, the least recently used one is dropped.
+///
+/// Code generation still runs for every compile, because a module's code is
+/// linked into the importing program at the variable and function indices
+/// that program has reached.
+#[derive(Debug, Clone, Default)]
+pub struct ModuleCache {
+    inner: Arc<Mutex<CacheState>>,
+}
+
+/// Entries a `ModuleCache::new` cache holds before evicting.
+pub const DEFAULT_CAPACITY: usize = 256;
+
+#[derive(Debug)]
+struct CacheState {
+    modules: HashMap<u64, Entry>,
+    capacity: usize,
+    /// Ticks once per `parse`, to order entries by their last use.
+    clock: u64,
+    hits: usize,
+}
+
+impl Default for CacheState {
+    fn default() -> Self {
+        Self {
+            modules: HashMap::new(),
+            capacity: DEFAULT_CAPACITY,
+            clock: 0,
+            hits: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    source: String,
+    program: Arc<Program>,
+    last_used: u64,
+}
+
+impl ModuleCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A cache holding at most `capacity` modules, at least one.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let cache = Self::default();
+        cache.lock().capacity = capacity.max(1);
+        cache
+    }
+
+    /// Parsed form of `source`, from the cache when the same text was parsed
+    /// before. Parse errors are not cached.
+    pub fn parse(&self, source: &str) -> Result<Arc<Program>, String> {
+        let key = content_hash(source);
+        {
+            let mut state = self.lock();
+            state.clock += 1;
+            let now = state.clock;
+            if let Some(entry) = state.modules.get_mut(&key)
+                && entry.source == source
+            {
+                entry.last_used = now;
+                let program = entry.program.clone();
+                state.hits += 1;
+                return Ok(program);
+            }
+        }
+        let tokens = Lexer::new(source).tokenize();
+        let program = Arc::new(Parser::new(tokens).parse()?);
+
+        let mut state = self.lock();
+        if !state.modules.contains_key(&key) && state.modules.len() >= state.capacity {
+            let oldest = state
+                .modules
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(&key, _)| key);
+            if let Some(oldest) = oldest {
+                state.modules.remove(&oldest);
+            }
+        }
+        let last_used = state.clock;
+        // A colliding entry for other text is replaced
+        state.modules.insert(
+            key,
+            Entry {
+                source: source.to_string(),
+                program: program.clone(),
+                last_used,
+            },
+        );
+        Ok(program)
+    }
+
+    /// Number of cached modules.
+    pub fn len(&self) -> usize {
+        self.lock().modules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many `parse` calls were answered from the cache.
+    pub fn hits(&self) -> usize {
+        self.lock().hits
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.lock();
+        state.modules.clear();
+        state.clock = 0;
+        state.hits = 0;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        // The state stays consistent even if a holder panicked
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// 64-bit FNV-1a; stable across runs and platforms, unlike `DefaultHasher`.
+pub fn content_hash(source: &str) -> u64 {
+    source.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -1,19 +1,118 @@
+use crate::cache::ModuleCache;
+use crate::methods::{self, METHODS, Method};
+use crate::natives::NativeRegistry;
+use crate::stdlib;
 use crate::types::ast::*;
-use std::collections::HashMap;
+use crate::types::interner::Symbol;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 use crate::types::compiler::*;
 
+#[derive(Clone)]
 pub struct Compiler {
-    pub constants: Vec<Value>,
-    pub functions: HashMap<String, usize>,
+    pub constants: ConstantPool,
+    pub functions: HashMap<Symbol, usize>,
     pub function_table: Vec<Value>,
-    pub variables: Vec<HashMap<String, usize>>,
+    pub natives: NativeRegistry,
+    pub variables: Vec<HashMap<Symbol, usize>>,
+    /// Next free variable slot of each depth. Slots are never reused within a
+    /// function, so a name declared in a block cannot overwrite one it hides.
+    slot_counts: Vec<usize>,
+    /// Blocks being compiled, innermost last.
+    blocks: Vec<BlockScope>,
+    /// `LOAD_GLOBAL`s of names a function read before any top-level `let`
+    /// declared them, by instruction position, patched once the program is
+    /// compiled.
+    unresolved_globals: Vec<(usize, Symbol)>,
+    /// Values of the `const`s declared so far, loaded as constants wherever
+    /// their names are read.
+    consts: HashMap<Symbol, Value>,
+    /// Functions and enums marked `@deprecated`, with the reason given.
+    deprecated: HashMap<Symbol, Option<Symbol>>,
+    /// Warnings of the last `compile`, such as uses of `@deprecated`
+    /// declarations. They do not stop the program from compiling unless
+    /// `CompileOptions::deny_warnings` is set.
+    pub warnings: Vec<Warning>,
+    /// Warnings `@allow`ed by the functions being compiled.
+    allowed: Vec<Symbol>,
+    /// `let`s of the functions being compiled, to warn about those never read.
+    locals: Vec<Local>,
+    /// Line of the statement being compiled, given to the instructions of its
+    /// expressions so runtime errors point at the right line.
+    statement_line: usize,
     pub instructions: Vec<Instruction>,
     pub instruction_lines: Vec<usize>,
-    pub current_function: Option<String>,
+    /// Instructions compiled from the prelude and imported modules, whose
+    /// lines are in other files.
+    pub module_instructions: Vec<Range<usize>>,
+    pub current_function: Option<Symbol>,
     pub depth: usize,
-    pub in_new_function: bool,
+    pub options: CompileOptions,
+    /// Parsed prelude and imported modules, shared with clones.
+    pub module_cache: ModuleCache,
+    prelude_loaded: bool,
+    imported: HashSet<PathBuf>,
+    reloading: bool,
+    /// Constant indices of the parameter defaults of each function, by index.
+    function_defaults: HashMap<usize, Vec<usize>>,
+    /// Locals of the enclosing function that a nested `func` uses, by
+    /// function index. Like a lambda's, they are passed by value as leading
+    /// parameters, since a call's frame only holds its own locals.
+    captured: HashMap<usize, Vec<Symbol>>,
+    /// Nested `func`s whose declaration has not been compiled yet, so what
+    /// they capture is not known.
+    undeclared_nested: HashSet<usize>,
+    /// The function being compiled is a generator, so `yield` is allowed.
+    in_generator: bool,
+    /// Loops compiled so far, naming each loop's hidden iterator variable.
+    loop_count: usize,
+    /// Field names of each `struct`, in declaration order.
+    pub structs: HashMap<Symbol, Vec<Symbol>>,
+    /// Names of the methods defined in any `impl`, which `x.name()` calls
+    /// look up at run time even where a built-in method has the same name.
+    impl_methods: HashSet<Symbol>,
+    /// Methods each trait requires, with their parameter counts.
+    traits: HashMap<Symbol, Vec<(Symbol, usize)>>,
+    /// Variants of each `enum`, with their field counts.
+    pub enums: HashMap<Symbol, Vec<(Symbol, usize)>>,
+    /// The enum table emitted with the bytecode.
+    enum_defs: Vec<EnumDef>,
+    /// Values stored so far while matching patterns, naming each hidden
+    /// variable.
+    match_count: usize,
+}
+
+/// The names a block declared at the depth it was opened at, which stop being
+/// visible when it ends.
+#[derive(Clone)]
+struct BlockScope {
+    depth: usize,
+    declared: HashSet<Symbol>,
+}
+
+/// A variable declared by a `let` inside a function.
+#[derive(Debug, Clone)]
+struct Local {
+    name: Symbol,
+    depth: usize,
+    index: usize,
+    line: usize,
+    read: bool,
+}
+
+/// Built-in prelude, see `CompileOptions::prelude`.
+pub const PRELUDE: &str = include_str!("static/prelude.n");
+
+/// Environment variable holding extra module directories, separated like `PATH`.
+pub const MODULE_PATH_VAR: &str = "N_PATH";
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Compiler {
@@ -23,35 +122,227 @@ impl Compiler {
             .cloned()
             .ok_or_else(|| format!("Undefined function '{}'", name))
     }
+
+    /// Resolves `Module.function` callees, and bare names that are not script
+    /// functions, against the native registry.
+    /// Resolves callees the VM runs itself: `xs.map(f)` (returning the
+    /// receiver), a bare `map(xs, f)` not shadowed by a script function, and
+    /// `Task.join(t)`-style module functions.
+    fn resolve_method(
+        &self,
+        program: &Program,
+        func: ExprId,
+    ) -> Result<Option<(usize, Option<ExprId>)>, String> {
+        match program.expr(func) {
+            Expr::Identifier(name)
+                if self.get_variable(name).is_none() && !self.functions.contains_key(name) =>
+            {
+                Ok(methods::resolve(name).map(|method| (method, None)))
+            }
+            Expr::Member { object, property } if !self.is_module(program, *object) => {
+                match methods::resolve(property) {
+                    Some(method) if !self.impl_methods.contains(property) => {
+                        Ok(Some((method, Some(*object))))
+                    }
+                    _ => Ok(None),
+                }
+            }
+            Expr::Member { object, property } => {
+                let Expr::Identifier(module) = program.expr(*object) else {
+                    unreachable!("modules are identifiers");
+                };
+                let name = format!("{}.{}", module, property);
+                Ok(methods::resolve(&name).map(|method| (method, None)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Whether `expr` names a module, as in `IO.print`, rather than a value
+    /// whose methods are called.
+    fn is_module(&self, program: &Program, expr: ExprId) -> bool {
+        matches!(program.expr(expr), Expr::Identifier(name) if self.get_variable(name).is_none())
+    }
+
+    fn resolve_native(
+        &self,
+        program: &Program,
+        func: ExprId,
+        argc: usize,
+    ) -> Result<Option<usize>, String> {
+        let name = match program.expr(func) {
+            Expr::Identifier(name)
+                if !self.functions.contains_key(name) && self.get_variable(name).is_none() =>
+            {
+                match self.natives.resolve(name) {
+                    Some(_) => name.to_string(),
+                    None => return Ok(None),
+                }
+            }
+            Expr::Member { object, property } if self.is_module(program, *object) => {
+                let Expr::Identifier(module) = program.expr(*object) else {
+                    unreachable!("modules are identifiers");
+                };
+                format!("{}.{}", module, property)
+            }
+            _ => return Ok(None),
+        };
+
+        let index = self
+            .natives
+            .resolve(&name)
+            .ok_or_else(|| format!("Undefined native function '{}'", name))?;
+        if let Some(arity) = self.natives.get(index).and_then(|native| native.arity)
+            && arity != argc
+        {
+            return Err(format!(
+                "'{}' expects {} argument(s), got {}",
+                name, arity, argc
+            ));
+        }
+        Ok(Some(index))
+    }
+
     pub fn new() -> Self {
+        Self::with_options(CompileOptions::default())
+    }
+
+    pub fn with_options(options: CompileOptions) -> Self {
         Self {
-            constants: Vec::new(),
+            constants: ConstantPool::new(),
             functions: HashMap::new(),
             function_table: Vec::new(),
+            natives: NativeRegistry::with_builtins(),
             variables: Vec::new(),
+            slot_counts: Vec::new(),
+            blocks: Vec::new(),
+            unresolved_globals: Vec::new(),
+            consts: HashMap::new(),
+            deprecated: HashMap::new(),
+            warnings: Vec::new(),
+            allowed: Vec::new(),
+            locals: Vec::new(),
+            statement_line: 1,
             depth: 0,
             instructions: Vec::new(),
             instruction_lines: Vec::new(),
+            module_instructions: Vec::new(),
             current_function: None,
-            in_new_function: false,
+            options,
+            module_cache: ModuleCache::new(),
+            prelude_loaded: false,
+            imported: HashSet::new(),
+            reloading: false,
+            function_defaults: HashMap::new(),
+            captured: HashMap::new(),
+            undeclared_nested: HashSet::new(),
+            in_generator: false,
+            loop_count: 0,
+            structs: HashMap::new(),
+            impl_methods: HashSet::new(),
+            traits: HashMap::new(),
+            enums: HashMap::new(),
+            enum_defs: Vec::new(),
+            match_count: 0,
+        }
+    }
+
+    /// Makes `name` a top-level variable that scripts can read without
+    /// defining it. Returns the index to pass to `VirtualMachine::set_global`.
+    pub fn declare_global(&mut self, name: &str) -> usize {
+        let symbol = Symbol::from(name);
+        if let Some((index, 0)) = self.get_variable(name) {
+            return index;
+        }
+        let depth = std::mem::replace(&mut self.depth, 0);
+        let index = self.insert_variable(&symbol);
+        self.depth = depth;
+        index
+    }
+
+    /// Like `declare_global`, but always gives `name` a new index, hiding any
+    /// top-level variable of that name until `restore_global` is called with
+    /// the index this returns alongside the new one.
+    pub fn shadow_global(&mut self, name: &str) -> (usize, Option<usize>) {
+        let previous = match self.get_variable(name) {
+            Some((index, 0)) => Some(index),
+            _ => None,
+        };
+        let depth = std::mem::replace(&mut self.depth, 0);
+        let index = self.insert_variable(&Symbol::from(name));
+        self.depth = depth;
+        (index, previous)
+    }
+
+    /// Undoes `shadow_global`: `name` refers to its `previous` variable
+    /// again, or to none. The shadowing index is not reused.
+    pub fn restore_global(&mut self, name: &str, previous: Option<usize>) {
+        let Some(globals) = self.variables.first_mut() else {
+            return;
+        };
+        match previous {
+            Some(index) => globals.insert(Symbol::from(name), index),
+            None => globals.remove(name),
+        };
+    }
+
+    fn insert_variable(&mut self, name: &Symbol) -> usize {
+        self.ensure_scope();
+        let local_index = self.slot_counts[self.depth];
+        self.slot_counts[self.depth] += 1;
+        self.variables[self.depth].insert(name.clone(), local_index);
+        if let Some(block) = self.blocks.last_mut()
+            && block.depth == self.depth
+        {
+            block.declared.insert(name.clone());
         }
+
+        local_index
     }
 
-    fn insert_variable(&mut self, name: &str) -> usize {
+    fn ensure_scope(&mut self) {
         while self.variables.len() <= self.depth {
             self.variables.push(HashMap::new());
         }
-
-        if self.in_new_function {
-            self.variables[self.depth].clear();
-            self.in_new_function = false;
+        while self.slot_counts.len() <= self.depth {
+            self.slot_counts.push(0);
         }
+    }
 
-        let current_scope = &mut self.variables[self.depth];
-        let local_index = current_scope.len(); // Next available index in this scope
-        current_scope.insert(name.to_string(), local_index);
+    /// Starts the variable scope of a function body with nothing from the
+    /// previously compiled function left in it.
+    fn enter_function_scope(&mut self) {
+        self.ensure_scope();
+        self.variables[self.depth].clear();
+        self.slot_counts[self.depth] = 0;
+    }
 
-        local_index
+    /// Runs `compile` in a new block scope. Its `let`s may shadow names of
+    /// the enclosing scopes, and are no longer visible once it ends.
+    fn in_block<T>(
+        &mut self,
+        compile: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<T, String> {
+        self.ensure_scope();
+        let depth = self.depth;
+        let enclosing_scope = self.variables[depth].clone();
+        self.blocks.push(BlockScope {
+            depth,
+            declared: HashSet::new(),
+        });
+        let result = compile(self);
+        self.blocks.pop();
+        self.variables[depth] = enclosing_scope;
+        result
+    }
+
+    /// Whether `name` was declared by the innermost block, or by the function
+    /// body itself outside any block.
+    fn in_current_scope(&self, name: &str) -> bool {
+        match self.blocks.last() {
+            Some(block) if block.depth == self.depth => block.declared.contains(name),
+            _ => matches!(self.get_variable(name), Some((_, depth)) if depth == self.depth),
+        }
     }
 
     fn get_variable(&self, name: &str) -> Option<(usize, usize)> {
@@ -67,117 +358,802 @@ impl Compiler {
         result
     }
 
-    pub fn compile(&mut self, program: &Program) -> Result<ByteCode, String> {
-        self.collect_pass(&program.statements);
-        self.generate_instructions(&program.statements)?;
+    /// Compiles `program`, after the prelude on the first call. The maps of
+    /// this struct are only looked up, never iterated while emitting code, so
+    /// the same source and options always give byte-for-byte the same
+    /// bytecode.
+    pub fn compile(&mut self, program: &Program) -> Result<ByteCode, CompileError> {
+        self.warnings.clear();
+        self.allowed.clear();
+        self.locals.clear();
+        if !self.prelude_loaded {
+            self.compile_prelude().map_err(CompileError::Prelude)?;
+        }
+        self.collect_pass(program, &program.statements);
+        self.generate_instructions(program, &program.statements)
+            .map_err(|message| self.statement_error(message))?;
+        self.resolve_globals()?;
+        if self.options.call_main {
+            self.call_main(program)
+                .map_err(|message| self.statement_error(message))?;
+        }
+        if self.options.deny_warnings && !self.warnings.is_empty() {
+            return Err(CompileError::Warnings(self.warnings.clone()));
+        }
+        Ok(self.finish())
+    }
+
+    /// Records what `program` declares, as the first pass of `compile` does,
+    /// without compiling its code: functions with their parameters, structs,
+    /// enums and the modules it imports. For tools such as `complete` that
+    /// need the names of a program that may be unfinished, so errors are
+    /// skipped.
+    pub fn declare(&mut self, program: &Program) {
+        if !self.prelude_loaded {
+            let _ = self.compile_prelude();
+        }
+        self.collect_pass(program, &program.statements);
+        for stmt in &program.statements {
+            if let Stmt::Import { module, .. } = stmt {
+                let _ = match stdlib::MODULES.contains(&&**module) {
+                    true => self.import_stdlib(module),
+                    false => self.import_file(module),
+                };
+            }
+        }
+    }
+
+    /// `message` as the error of the statement being compiled, which a
+    /// failing `compile_statement` leaves `statement_line` at.
+    fn statement_error(&self, message: String) -> CompileError {
+        CompileError::Statement {
+            line: self.statement_line,
+            message,
+        }
+    }
+
+    /// Points the loads of names functions read before their top-level `let`
+    /// or `const` at the variable or value, which must exist by the end of
+    /// the program.
+    fn resolve_globals(&mut self) -> Result<(), CompileError> {
+        for (at, name) in std::mem::take(&mut self.unresolved_globals) {
+            if let Some(index) = self.variables.first().and_then(|scope| scope.get(&name)) {
+                self.instructions[at] = Instruction::LoadGlobal(*index);
+            } else if let Some(value) = self.consts.get(&name).cloned() {
+                self.instructions[at] = Instruction::LoadConst(self.const_index(value));
+            } else {
+                return Err(CompileError::UndefinedGlobal {
+                    line: self.instruction_lines[at],
+                    name: name.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Emits the call to the program's entry point, see `CompileOptions::call_main`.
+    fn call_main(&mut self, program: &Program) -> Result<(), String> {
+        let Some((params, line)) = program.statements.iter().find_map(|stmt| match stmt {
+            Stmt::Func {
+                name, params, line, ..
+            } if &**name == "main" => Some((params.len(), *line)),
+            _ => None,
+        }) else {
+            return Ok(());
+        };
+        self.statement_line = line;
+        match params {
+            0 => {}
+            1 => {
+                stdlib::os::register_args(&mut self.natives);
+                let args = self
+                    .natives
+                    .resolve("OS.args")
+                    .ok_or("OS.args is not registered")?;
+                self.push_with_line(Instruction::CallNative(args, 0), line);
+            }
+            _ => {
+                return Err(format!(
+                    "'main' takes no parameters or just 'args', found {} (line {})",
+                    params, line
+                ));
+            }
+        }
+        // The result stays on the stack as the program's result
+        let main = self.resolve_function_index("main")?;
+        self.push_with_line(Instruction::Call(main), line);
+        Ok(())
+    }
+
+    /// Recompiles the functions of the already imported module `name` from
+    /// its current source. They keep their function table slots, so existing
+    /// callers run the new bodies; the module's other top-level code is not
+    /// run again, leaving variables as they are.
+    pub fn reload_module(&mut self, name: &str) -> Result<ByteCode, CompileError> {
+        let path = self
+            .resolve_module_path(name)
+            .map_err(CompileError::Reload)?;
+        if !self.imported.contains(&self.module_key(&path)) {
+            return Err(CompileError::Reload(format!(
+                "Module '{}' has not been imported",
+                name
+            )));
+        }
+        let source = self.read_module(&path).map_err(CompileError::Reload)?;
+        let module = self
+            .module_cache
+            .parse(&source)
+            .map_err(CompileError::Reload)?;
+        let functions: Vec<Stmt> = module
+            .statements
+            .iter()
+            .filter(|stmt| matches!(stmt, Stmt::Func { .. }))
+            .cloned()
+            .collect();
+
+        self.reloading = true;
+        self.collect_pass(&module, &functions);
+        self.reloading = false;
+        let start = self.instructions.len();
+        self.generate_instructions(&module, &functions)
+            .map_err(|e| CompileError::Reload(format!("In module '{}': {}", path.display(), e)))?;
+        self.module_instructions
+            .push(start..self.instructions.len());
+        Ok(self.finish())
+    }
+
+    fn finish(&mut self) -> ByteCode {
         self.instructions.push(Instruction::Halt);
         self.instruction_lines.push(self.current_line());
 
-        Ok(ByteCode {
-            constants: self.constants.clone(),
+        let mut flags = 0;
+        if self.options.strict_concat {
+            flags |= FLAG_STRICT_CONCAT;
+        }
+
+        ByteCode {
+            flags,
+            constants: self.constants.values().to_vec(),
             functions: self.function_table.clone(),
+            enums: self.enum_defs.clone(),
             instructions: self.instructions.clone(),
             instruction_lines: self.instruction_lines.clone(),
-        })
+            exports: self.exports(),
+        }
+    }
+
+    fn exports(&self) -> Exports {
+        let sorted = |names: &HashMap<Symbol, usize>| {
+            let mut names: Vec<(String, usize)> = names
+                .iter()
+                .map(|(name, index)| (name.to_string(), *index))
+                .collect();
+            names.sort();
+            names
+        };
+        Exports {
+            natives: self
+                .natives
+                .functions()
+                .iter()
+                .map(|f| f.name.clone())
+                .collect(),
+            functions: sorted(&self.functions),
+            globals: self.variables.first().map(sorted).unwrap_or_default(),
+        }
+    }
+
+    /// Compiles the prelude's definitions in front of the first program. Like
+    /// any other top-level code it runs before the program itself.
+    fn compile_prelude(&mut self) -> Result<(), String> {
+        self.prelude_loaded = true;
+        let source = self.options.prelude.clone();
+        self.compile_module(source.as_deref().unwrap_or(PRELUDE))
+    }
+
+    /// Compiles a module's top-level code in place. Its trailing expression is
+    /// never kept, whatever the options say for the program.
+    fn compile_module(&mut self, source: &str) -> Result<(), String> {
+        let module = self.module_cache.parse(source)?;
+        let keep_last_value = std::mem::replace(&mut self.options.keep_last_value, false);
+        let start = self.instructions.len();
+        self.collect_pass(&module, &module.statements);
+        let result = self.generate_instructions(&module, &module.statements);
+        self.module_instructions
+            .push(start..self.instructions.len());
+        self.options.keep_last_value = keep_last_value;
+        result
+    }
+
+    /// Directories searched for file modules, in order.
+    pub fn module_search_path(&self) -> Vec<PathBuf> {
+        let mut paths = self.options.module_paths.clone();
+        if let Some(var) = std::env::var_os(MODULE_PATH_VAR) {
+            paths.extend(std::env::split_paths(&var).filter(|p| !p.as_os_str().is_empty()));
+        }
+        paths.push(PathBuf::from("."));
+        paths
+    }
+
+    /// Finds `name` (with `.n` added when it has no extension) in the module
+    /// search path, or in the `sandbox` map when there is one. The error
+    /// lists every path that was tried.
+    pub fn resolve_module_path(&self, name: &str) -> Result<PathBuf, String> {
+        let mut file = PathBuf::from(name);
+        if file.extension().is_none() {
+            file.set_extension("n");
+        }
+        if let Some(modules) = &self.options.sandbox {
+            let file = file.to_string_lossy();
+            return match [name, &file]
+                .into_iter()
+                .find(|key| modules.contains_key(*key))
+            {
+                Some(key) => Ok(PathBuf::from(key)),
+                None => Err(format!("Module '{}' is not in the sandbox", name)),
+            };
+        }
+        if file.is_absolute() {
+            return match file.is_file() {
+                true => Ok(file),
+                false => Err(format!("Module '{}' not found at {}", name, file.display())),
+            };
+        }
+        let candidates: Vec<PathBuf> = self
+            .module_search_path()
+            .iter()
+            .map(|dir| dir.join(&file))
+            .collect();
+        if let Some(found) = candidates.iter().find(|path| path.is_file()) {
+            return Ok(found.clone());
+        }
+        let searched: Vec<String> = candidates.iter().map(|p| p.display().to_string()).collect();
+        Err(format!(
+            "Module '{}' not found (searched: {})",
+            name,
+            searched.join(", ")
+        ))
+    }
+
+    /// Compiles a file module the first time it is imported; later imports of
+    /// the same file are no-ops, which also stops import cycles. A `.nb` file
+    /// is linked as it was compiled, see `link_module`.
+    fn import_file(&mut self, name: &str) -> Result<(), String> {
+        let precompiled = Path::new(name).extension().is_some_and(|ext| ext == "nb");
+        if precompiled && self.options.sandbox.is_some() {
+            return Err(format!(
+                "Precompiled module '{}' cannot be imported in a sandbox",
+                name
+            ));
+        }
+        let path = self.resolve_module_path(name)?;
+        if !self.imported.insert(self.module_key(&path)) {
+            return Ok(());
+        }
+        let linked = match precompiled {
+            true => read_module_bytes(&path)
+                .and_then(|bytes| crate::bytecode::decode(&bytes))
+                .and_then(|module| self.link_module(module)),
+            false => self
+                .read_module(&path)
+                .and_then(|source| self.compile_module(&source)),
+        };
+        linked.map_err(|e| format!("In module '{}': {}", path.display(), e))
+    }
+
+    /// Appends a compiled program's code in place, as `compile_module` does
+    /// with source, and declares its top-level functions and variables. Its
+    /// constants, functions, variables and natives get this program's
+    /// indices; the natives of a standard module are imported on the way.
+    fn link_module(&mut self, module: ByteCode) -> Result<(), String> {
+        let base = self.instructions.len();
+        let function_base = self.function_table.len();
+        let depth = std::mem::replace(&mut self.depth, 0);
+        self.ensure_scope();
+        self.depth = depth;
+        let global_base = self.slot_counts[0];
+        let mut global_count = 0;
+
+        let mut constants = Vec::with_capacity(module.constants.len());
+        for constant in module.constants.iter().cloned() {
+            constants.push(match constant {
+                Value::Number(_) | Value::String(_) | Value::Boolean(_) => {
+                    self.const_index(constant)
+                }
+                other => {
+                    return Err(format!(
+                        "Cannot link a {} constant",
+                        other.type_name_stack()
+                    ));
+                }
+            });
+        }
+        let constant = |index: usize| constants[index];
+        for function in module.functions {
+            let Value::Function { params, offset } = function else {
+                return Err("Function table holds a non-function".to_string());
+            };
+            self.function_table.push(Value::Function {
+                params,
+                offset: offset + base,
+            });
+        }
+        let name = |index: usize| match &module.constants[index] {
+            Value::String(name) => Symbol::from(name.as_str()),
+            _ => Symbol::from(""),
+        };
+        for def in &module.enums {
+            let variants = def
+                .variants
+                .iter()
+                .map(|variant| (name(variant.name), variant.field_count))
+                .collect();
+            self.enums.insert(name(def.name), variants);
+        }
+        for def in module.enums {
+            self.enum_defs.push(EnumDef {
+                name: constant(def.name),
+                variants: def
+                    .variants
+                    .into_iter()
+                    .map(|variant| VariantDef {
+                        name: constant(variant.name),
+                        field_count: variant.field_count,
+                    })
+                    .collect(),
+            });
+        }
+
+        // The module's own `HALT` is left out so the importer carries on
+        let code = module.instructions.len().saturating_sub(1);
+        let instructions = module.instructions.into_iter().take(code);
+        for (instruction, line) in instructions.zip(module.instruction_lines) {
+            let mut global = |index: usize| {
+                global_count = global_count.max(index + 1);
+                global_base + index
+            };
+            let linked = match instruction {
+                Instruction::Jump(target) => Instruction::Jump(target + base),
+                Instruction::JumpIfFalse(target) => Instruction::JumpIfFalse(target + base),
+                Instruction::JumpIfTrue(target) => Instruction::JumpIfTrue(target + base),
+                Instruction::LoadConst(index) => Instruction::LoadConst(constant(index)),
+                Instruction::GetField(index) => Instruction::GetField(constant(index)),
+                Instruction::HasField(index) => Instruction::HasField(constant(index)),
+                Instruction::DefineMethod(index) => Instruction::DefineMethod(constant(index)),
+                Instruction::TestVariant(index) => Instruction::TestVariant(constant(index)),
+                Instruction::Invoke(index, args) => Instruction::Invoke(constant(index), args),
+                Instruction::MakeVariant(index, fields) => {
+                    Instruction::MakeVariant(constant(index), fields)
+                }
+                Instruction::Call(function) => Instruction::Call(function + function_base),
+                Instruction::MakeClosure(function, bound) => {
+                    Instruction::MakeClosure(function + function_base, bound)
+                }
+                Instruction::CallNative(index, args) => {
+                    let name = module.exports.natives.get(index).ok_or_else(|| {
+                        format!("Native {} is not named in the export table", index)
+                    })?;
+                    Instruction::CallNative(self.link_native(name)?, args)
+                }
+                // Only top-level code addresses variables at depth 0
+                Instruction::StoreVar(0, index) => Instruction::StoreVar(0, global(index)),
+                Instruction::LoadVar(0, index) => Instruction::LoadVar(0, global(index)),
+                Instruction::LoadGlobal(index) => Instruction::LoadGlobal(global(index)),
+                other => other,
+            };
+            self.push_with_line(linked, line);
+        }
+        self.module_instructions.push(base..self.instructions.len());
+
+        for (name, slot) in &module.exports.globals {
+            global_count = global_count.max(slot + 1);
+            self.variables[0].insert(Symbol::from(name.as_str()), global_base + slot);
+        }
+        self.slot_counts[0] += global_count;
+        for (name, index) in module.exports.functions {
+            self.functions
+                .insert(Symbol::from(name.as_str()), function_base + index);
+        }
+        Ok(())
     }
 
-    fn collect_pass(&mut self, statements: &[Stmt]) {
+    /// This program's index of the native `name`, importing its standard
+    /// module if it has not been.
+    fn link_native(&mut self, name: &str) -> Result<usize, String> {
+        if let Some(index) = self.natives.resolve(name) {
+            return Ok(index);
+        }
+        if let Some((module, _)) = name.split_once('.')
+            && stdlib::MODULES.contains(&module)
+        {
+            self.import_stdlib(module)?;
+        }
+        self.natives
+            .resolve(name)
+            .ok_or_else(|| format!("Native '{}' is not registered", name))
+    }
+
+    /// Identifies an imported module, so it is compiled once however the
+    /// path to it is written.
+    fn module_key(&self, path: &Path) -> PathBuf {
+        match self.options.sandbox {
+            Some(_) => path.to_path_buf(),
+            None => path.canonicalize().unwrap_or_else(|_| path.to_path_buf()),
+        }
+    }
+
+    /// Source of a module `resolve_module_path` found.
+    fn read_module(&self, path: &Path) -> Result<String, String> {
+        match &self.options.sandbox {
+            Some(modules) => modules
+                .get(&*path.to_string_lossy())
+                .cloned()
+                .ok_or_else(|| format!("Module '{}' is not in the sandbox", path.display())),
+            None => read_module(path),
+        }
+    }
+
+    /// A standard module, one of `stdlib::SANDBOX_MODULES` in a sandbox.
+    fn import_stdlib(&mut self, module: &str) -> Result<(), String> {
+        if self.options.sandbox.is_some() && !stdlib::SANDBOX_MODULES.contains(&module) {
+            return Err(format!("Module '{}' is not available in a sandbox", module));
+        }
+        stdlib::import(&mut self.natives, module)
+    }
+
+    /// Registers the functions of an `extern` block as natives.
+    #[cfg(feature = "ffi")]
+    fn bind_extern(&mut self, library: &str, functions: &[ForeignFunction]) -> Result<(), String> {
+        if self.options.sandbox.is_some() {
+            return Err(format!(
+                "Cannot load library '{}': 'extern' is not allowed in a sandbox",
+                library
+            ));
+        }
+        crate::ffi::bind(&mut self.natives, library, functions)
+    }
+
+    #[cfg(not(feature = "ffi"))]
+    fn bind_extern(&mut self, library: &str, _: &[ForeignFunction]) -> Result<(), String> {
+        Err(format!(
+            "Cannot load library '{}': 'extern' needs the 'ffi' feature",
+            library
+        ))
+    }
+
+    fn collect_pass(&mut self, program: &Program, statements: &[Stmt]) {
         for stmt in statements {
             match stmt {
                 Stmt::Func {
-                    name, params, body, ..
+                    name,
+                    params,
+                    defaults,
+                    variadic,
+                    body,
+                    attributes,
+                    ..
                 } => {
-                    let function_index = self.function_table.len();
-                    self.functions.insert(name.clone(), function_index);
-
+                    self.note_deprecated(name, attributes);
+                    let mut param_names: Vec<String> =
+                        params.iter().map(|p| p.to_string()).collect();
+                    if *variadic && let Some(rest) = param_names.last_mut() {
+                        rest.insert_str(0, REST_PREFIX);
+                    }
                     let function_value = Value::Function {
-                        params: params.clone(),
+                        params: param_names,
                         offset: 0,
                     };
-                    self.function_table.push(function_value);
-                    self.collect_pass(body);
+                    // A reload keeps the slot, so compiled callers reach the new body
+                    let index = match self.functions.get(name) {
+                        Some(&index) if self.reloading => {
+                            self.function_table[index] = function_value;
+                            index
+                        }
+                        _ => {
+                            let index = self.function_table.len();
+                            self.functions.insert(name.clone(), index);
+                            self.function_table.push(function_value);
+                            index
+                        }
+                    };
+                    // The parser only accepts literal defaults
+                    let defaults = defaults
+                        .iter()
+                        .filter_map(|default| match program.expr(*default) {
+                            Expr::Number(n) => Some(self.constants.add_number(*n)),
+                            Expr::String(s) => Some(self.constants.add_string(s)),
+                            Expr::Boolean(b) => Some(self.constants.add_boolean(*b)),
+                            Expr::Unary { right, .. } => match program.expr(*right) {
+                                Expr::Number(n) => Some(self.constants.add_number(-n)),
+                                _ => None,
+                            },
+                            _ => None,
+                        })
+                        .collect();
+                    self.function_defaults.insert(index, defaults);
+                    let nested = self.function_table.len();
+                    self.collect_pass(program, body);
+                    self.undeclared_nested
+                        .extend(nested..self.function_table.len());
                 }
-                Stmt::Let { value, .. } => {
-                    self.collect_constants_from_expr(value);
+                Stmt::Let { value, .. } | Stmt::LetPattern { value, .. } => {
+                    self.collect_constants_from_expr(program, *value);
                 }
                 Stmt::Expr(expr, _) => {
-                    self.collect_constants_from_expr(expr);
+                    self.collect_constants_from_expr(program, *expr);
+                }
+                Stmt::For { iterable, body, .. } => {
+                    self.collect_constants_from_expr(program, *iterable);
+                    self.collect_pass(program, body);
+                }
+                Stmt::Struct { name, fields, .. } => {
+                    self.structs.insert(name.clone(), fields.clone());
+                }
+                Stmt::Trait { name, methods, .. } => {
+                    let methods = methods
+                        .iter()
+                        .map(|(method, params)| (method.clone(), params.len()))
+                        .collect();
+                    self.traits.insert(name.clone(), methods);
+                }
+                Stmt::Enum {
+                    name,
+                    variants,
+                    attributes,
+                    ..
+                } => {
+                    self.note_deprecated(name, attributes);
+                    let variants: Vec<(Symbol, usize)> = variants
+                        .iter()
+                        .map(|(variant, fields)| (variant.clone(), fields.len()))
+                        .collect();
+                    let def = EnumDef {
+                        name: self.constants.add_string(name),
+                        variants: variants
+                            .iter()
+                            .map(|(variant, field_count)| VariantDef {
+                                name: self.constants.add_string(variant),
+                                field_count: *field_count,
+                            })
+                            .collect(),
+                    };
+                    self.enum_defs.push(def);
+                    self.enums.insert(name.clone(), variants);
+                }
+                Stmt::Impl {
+                    methods, constants, ..
+                } => {
+                    for method in methods {
+                        if let Stmt::Func { name, .. } = method
+                            && let Some((_, method)) = name.split_once('.')
+                        {
+                            self.impl_methods.insert(Symbol::from(method));
+                        }
+                    }
+                    self.collect_pass(program, methods);
+                    self.collect_pass(program, constants);
                 }
+                Stmt::Import { .. } | Stmt::Extern { .. } | Stmt::Const { .. } => {}
+            }
+        }
+    }
+
+    fn note_deprecated(&mut self, name: &Symbol, attributes: &[Attribute]) {
+        if let Some(attribute) = find_attribute(attributes, "deprecated") {
+            self.deprecated
+                .insert(name.clone(), attribute.argument.clone());
+        }
+    }
+
+    /// Records a warning, unless a function being compiled `@allow`s it.
+    fn warn(&mut self, name: &'static str, line: usize, message: String) {
+        if !self.allowed.iter().any(|allowed| &**allowed == name) {
+            self.warnings.push(Warning {
+                name,
+                line,
+                message,
+            });
+        }
+    }
+
+    /// Records a warning if `name` is a `@deprecated` function or enum.
+    fn warn_if_deprecated(&mut self, name: &str) {
+        let Some(reason) = self.deprecated.get(name) else {
+            return;
+        };
+        let message = match reason {
+            Some(reason) => format!("'{}' is deprecated: {}", name, reason),
+            None => format!("'{}' is deprecated", name),
+        };
+        self.warn("deprecated", self.statement_line, message);
+    }
+
+    /// Warns about the `let`s of the function body at `depth`, which is
+    /// being left, that nothing read. Names starting with `_` are exempt.
+    fn warn_unused_locals(&mut self, depth: usize) {
+        let at = self
+            .locals
+            .iter()
+            .position(|local| local.depth >= depth)
+            .unwrap_or(self.locals.len());
+        for local in self.locals.split_off(at) {
+            if !local.read && !local.name.starts_with('_') {
+                let message = format!("Variable '{}' is never read", local.name);
+                self.warn("unused-variable", local.line, message);
             }
         }
     }
 
-    fn collect_constants_from_expr(&mut self, expr: &Expr) {
-        match expr {
+    fn collect_constants_from_expr(&mut self, program: &Program, id: ExprId) {
+        match program.expr(id) {
             Expr::Boolean(b) => {
-                let value = Value::Boolean(*b);
-                if !self.constants.iter().any(
-                    |c| matches!((c, &value), (Value::Boolean(a), Value::Boolean(b)) if a == b),
-                ) {
-                    self.constants.push(value);
-                }
+                self.constants.add_boolean(*b);
             }
             Expr::Number(n) => {
-                let value = Value::Number(*n);
-                if !self
-                    .constants
-                    .iter()
-                    .any(|c| matches!((c, &value), (Value::Number(a), Value::Number(b)) if a == b))
-                {
-                    self.constants.push(value);
-                }
+                self.constants.add_number(*n);
             }
             Expr::String(s) => {
-                let value = Value::String(s.clone());
-                if !self
-                    .constants
-                    .iter()
-                    .any(|c| matches!((c, &value), (Value::String(a), Value::String(b)) if a == b))
-                {
-                    self.constants.push(value);
-                }
+                self.constants.add_string(s);
             }
             Expr::Binary { left, right, .. } => {
-                self.collect_constants_from_expr(left);
-                self.collect_constants_from_expr(right);
+                self.collect_constants_from_expr(program, *left);
+                self.collect_constants_from_expr(program, *right);
+            }
+            Expr::Member { object, .. } => {
+                self.collect_constants_from_expr(program, *object);
             }
             Expr::Call { func, args } => {
-                self.collect_constants_from_expr(func);
+                self.collect_constants_from_expr(program, *func);
                 for arg in args {
-                    self.collect_constants_from_expr(arg);
+                    self.collect_constants_from_expr(program, *arg);
                 }
             }
             Expr::Pipeline { left, right } => {
-                self.collect_constants_from_expr(left);
-                self.collect_constants_from_expr(right);
+                self.collect_constants_from_expr(program, *left);
+                self.collect_constants_from_expr(program, *right);
             }
             Expr::Unary { right, .. } => {
-                self.collect_constants_from_expr(right);
+                self.collect_constants_from_expr(program, *right);
             }
             Expr::Update { left, right } => {
-                self.collect_constants_from_expr(left);
-                self.collect_constants_from_expr(right);
+                self.collect_constants_from_expr(program, *left);
+                self.collect_constants_from_expr(program, *right);
             }
-            Expr::Array { elements } => {
+            Expr::Array { elements } | Expr::Tuple(elements) => {
                 for element in elements {
-                    self.collect_constants_from_expr(element);
+                    self.collect_constants_from_expr(program, *element);
+                }
+            }
+            Expr::Lambda { body, .. }
+            | Expr::Spread(body)
+            | Expr::NamedArg { value: body, .. }
+            | Expr::Yield(body) => {
+                self.collect_constants_from_expr(program, *body);
+            }
+            Expr::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.collect_constants_from_expr(program, *condition);
+                self.collect_pass(program, then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.collect_pass(program, else_branch);
+                }
+            }
+            Expr::Block(statements) => self.collect_pass(program, statements),
+            Expr::Record { fields, .. } => {
+                for (_, value) in fields {
+                    self.collect_constants_from_expr(program, *value);
+                }
+            }
+            Expr::Path { args, .. } => {
+                for arg in args.iter().flatten() {
+                    self.collect_constants_from_expr(program, *arg);
+                }
+            }
+            Expr::Match { subject, arms } => {
+                self.collect_constants_from_expr(program, *subject);
+                for (_, value) in arms {
+                    self.collect_constants_from_expr(program, *value);
                 }
             }
             Expr::Identifier(_) => {}
         }
     }
 
-    fn generate_instructions(&mut self, statements: &[Stmt]) -> Result<(), String> {
+    fn generate_instructions(
+        &mut self,
+        program: &Program,
+        statements: &[Stmt],
+    ) -> Result<(), String> {
+        // Methods are defined before any other code runs, so every part of
+        // the program can call them
         for stmt in statements {
-            self.compile_statement(stmt, false)?;
+            if let Stmt::Impl {
+                name,
+                trait_name,
+                methods,
+                constants,
+                line,
+            } = stmt
+            {
+                self.statement_line = *line;
+                self.compile_impl(
+                    program,
+                    name,
+                    trait_name.as_ref(),
+                    methods,
+                    constants,
+                    *line,
+                )?;
+            }
+        }
+        for (i, stmt) in statements.iter().enumerate() {
+            if let Stmt::Impl { .. } = stmt {
+                continue;
+            }
+            let keep = self.options.keep_last_value
+                && i == statements.len() - 1
+                && matches!(stmt, Stmt::Expr(expr, _) if !is_statement_if(program, *expr));
+            self.compile_statement(program, stmt, keep)?;
         }
         Ok(())
     }
 
-    fn compile_statement(&mut self, stmt: &Stmt, last: bool) -> Result<(), String> {
+    fn compile_statement(
+        &mut self,
+        program: &Program,
+        stmt: &Stmt,
+        last: bool,
+    ) -> Result<(), String> {
+        // Statements nest in blocks and functions; the enclosing one's line
+        // applies again after them
+        let enclosing = std::mem::replace(&mut self.statement_line, stmt.line());
+        let result = self.compile_statement_at_line(program, stmt, last);
+        // On failure the line stays at the statement that failed
+        if result.is_ok() {
+            self.statement_line = enclosing;
+        }
+        result
+    }
+
+    fn compile_statement_at_line(
+        &mut self,
+        program: &Program,
+        stmt: &Stmt,
+        last: bool,
+    ) -> Result<(), String> {
         match stmt {
+            Stmt::Const {
+                name, value, line, ..
+            } => {
+                if self.depth > 0 || !self.blocks.is_empty() {
+                    return Err(format!(
+                        "'const' is only allowed at the top level (line {})",
+                        line
+                    ));
+                }
+                if self.consts.contains_key(name) || self.get_variable(name).is_some() {
+                    return Err(format!("'{}' is already defined (line {})", name, line));
+                }
+                let value = fold_constant(program, &self.consts, *value, &self.options)
+                    .map_err(|e| format!("Const '{}' {} (line {})", name, e, line))?;
+                self.consts.insert(name.clone(), value);
+                if last {
+                    self.push_with_line(Instruction::Push(Value::Number(0.0)), *line);
+                }
+            }
+            Stmt::Let { name, line, .. }
+                if self.consts.contains_key(name) && self.depth == 0 && self.blocks.is_empty() =>
+            {
+                return Err(format!(
+                    "'{}' is already defined as a const (line {})",
+                    name, line
+                ));
+            }
             Stmt::Let { name, value, line } => {
-                self.compile_expression(value)?;
+                self.compile_expression(program, *value)?;
                 let var_index = match self.get_or_create_variable_index(name) {
                     VarOutput::Created { index, .. } => index,
                     VarOutput::GotCurrentScope { .. } => {
@@ -188,190 +1164,1456 @@ impl Compiler {
                     }
                     VarOutput::GotOuterScope { .. } => self.insert_variable(name),
                 };
+                if self.depth > 0 {
+                    self.locals.push(Local {
+                        name: name.clone(),
+                        depth: self.depth,
+                        index: var_index,
+                        line: *line,
+                        read: false,
+                    });
+                }
 
                 self.push_with_line(Instruction::StoreVar(self.depth, var_index), *line);
                 if last {
                     self.push_with_line(Instruction::Push(Value::Number(0.0)), *line); // TEMP MEASURE, REPLACE THIS ONCE ENUMS ARE IMPLEMENTED PLEASE !!!
                 }
             }
+            Stmt::LetPattern {
+                pattern,
+                value,
+                line,
+            } => {
+                let mut names = Vec::new();
+                pattern_bindings(pattern, &mut names);
+                for (i, name) in names.iter().enumerate() {
+                    if names[..i].contains(name) || self.in_current_scope(name) {
+                        return Err(format!(
+                            "Variable '{}' is already defined in the current scope",
+                            name
+                        ));
+                    }
+                }
+                self.compile_expression(program, *value)?;
+                let slot = self.hidden_variable();
+                self.push_with_line(Instruction::StoreVar(self.depth, slot), *line);
+                let mut jumps_to_failure = Vec::new();
+                self.compile_pattern(program, slot, pattern, &mut jumps_to_failure)?;
+                if !jumps_to_failure.is_empty() {
+                    let jump_over_failure = self.instructions.len();
+                    self.push_with_line(Instruction::Jump(0), *line);
+                    let failure = self.instructions.len();
+                    self.push_with_line(Instruction::LoadVar(self.depth, slot), *line);
+                    self.push_with_line(Instruction::NoMatch, *line);
+                    for jump in jumps_to_failure {
+                        self.instructions[jump] = Instruction::JumpIfFalse(failure);
+                    }
+                    let end = self.instructions.len();
+                    self.instructions[jump_over_failure] = Instruction::Jump(end);
+                }
+                if last {
+                    self.push_with_line(Instruction::Push(Value::Number(0.0)), *line);
+                }
+            }
             Stmt::Func {
                 name,
                 params,
                 body,
                 line,
+                attributes,
+                ..
             } => {
+                let captured = self.capture_for(program, name, params, body);
                 let jump_over_function = self.instructions.len();
                 self.push_with_line(Instruction::Jump(0), *line);
                 self.depth += 1;
-                self.in_new_function = true;
-                if let Some(function_index) = self.functions.get(name).cloned() {
-                    if let Some(Value::Function { params, .. }) =
+                self.enter_function_scope();
+                let generator = yields(program, body);
+                if let Some(function_index) = self.functions.get(name).cloned()
+                    && let Some(Value::Function { params, .. }) =
                         self.function_table.get_mut(function_index)
-                    {
-                        let param_count = params.len();
-                        let params = params.clone();
-                        self.function_table[function_index] = Value::Function {
+                {
+                    if !captured.is_empty() {
+                        let names = captured.iter().map(|name| name.to_string());
+                        *params = names.chain(params.drain(..)).collect();
+                    }
+                    let param_count = params.len();
+                    let params = params.clone();
+                    self.function_table[function_index] = Value::Function {
+                        params: params.clone(),
+                        offset: self.instructions.len(),
+                    };
+                    if generator {
+                        // Calling a generator function only binds its arguments;
+                        // the body is a function of its own, run by `next`
+                        let body_index = self.function_table.len();
+                        self.push_with_line(
+                            Instruction::MakeClosure(body_index, param_count),
+                            *line,
+                        );
+                        self.push_with_line(Instruction::CallMethod(methods::GENERATOR_NEW), *line);
+                        self.push_with_line(Instruction::Return, *line);
+                        // Rest arguments arrive already collected
+                        let params = params
+                            .iter()
+                            .map(|param| param.trim_start_matches(REST_PREFIX).to_string())
+                            .collect();
+                        self.function_table.push(Value::Function {
                             params,
                             offset: self.instructions.len(),
-                        };
+                        });
+                    }
 
-                        if param_count > 0 {
-                            self.push_with_line(Instruction::LoadArg(param_count), *line);
-                        }
+                    if param_count > 0 {
+                        self.push_with_line(Instruction::LoadArg(param_count), *line);
                     }
                 }
 
                 let old_function = self.current_function.clone();
+                let old_generator = std::mem::replace(&mut self.in_generator, generator);
 
                 self.current_function = Some(name.clone());
+                let allowed = self.allowed.len();
+                self.allowed.extend(
+                    attributes
+                        .iter()
+                        .filter(|attribute| &*attribute.name == "allow")
+                        .filter_map(|attribute| attribute.argument.clone()),
+                );
 
-                for param_name in params.iter() {
-                    let _ = self.get_or_create_variable_index(param_name);
+                // Parameters, and what is captured before them, shadow
+                // variables of the same name around the function
+                for param_name in captured.iter().chain(params) {
+                    self.insert_variable(param_name);
                 }
 
                 for (i, body_stmt) in body.iter().enumerate() {
                     let last = i == body.len() - 1;
-                    self.compile_statement(body_stmt, last)?;
+                    self.compile_statement(program, body_stmt, last)?;
                 }
+                self.warn_unused_locals(self.depth);
+                self.allowed.truncate(allowed);
                 self.depth -= 1;
 
                 self.push_with_line(Instruction::Return, *line);
                 self.current_function = old_function;
+                self.in_generator = old_generator;
 
                 let after_function = self.instructions.len();
                 self.instructions[jump_over_function] = Instruction::Jump(after_function);
             }
-            Stmt::Expr(expr, line) => {
-                self.compile_expression(expr)?;
-                if !last {
-                    self.push_with_line(Instruction::Pop, *line);
+            Stmt::Import { module, line } => {
+                if self.depth > 0 {
+                    return Err(format!(
+                        "'import' is only allowed at the top level (line {})",
+                        line
+                    ));
                 }
-            }
-        }
-        Ok(())
-    }
-
-    fn compile_expression(&mut self, expr: &Expr) -> Result<(), String> {
-        match expr {
-            Expr::Boolean(b) => {
-                let const_index = self.get_constant_index(&Value::Boolean(*b));
-                self.push(Instruction::LoadConst(const_index));
-            }
-            Expr::Number(n) => {
-                let const_index = self.get_constant_index(&Value::Number(*n));
-                self.push(Instruction::LoadConst(const_index));
-            }
-            Expr::String(s) => {
-                let const_index = self.get_constant_index(&Value::String(s.clone()));
-                self.push(Instruction::LoadConst(const_index));
-            }
-            Expr::Identifier(name) => {
-                let (var_index, fetch_depth) = match self.get_or_create_variable_index(name) {
-                    VarOutput::Created { index, depth } => (index, depth),
-                    VarOutput::GotCurrentScope { index, depth } => (index, depth),
-                    VarOutput::GotOuterScope { index, depth } => (index, depth),
+                let imported = match stdlib::MODULES.contains(&&**module) {
+                    true => self.import_stdlib(module),
+                    false => self.import_file(module),
                 };
-                self.push(Instruction::LoadVar(fetch_depth, var_index));
+                imported.map_err(|e| format!("{} at line {}", e, line))?;
             }
-            Expr::Binary { left, op, right } => {
-                self.compile_expression(left)?;
-                self.compile_expression(right)?;
-                match op {
-                    BinaryOp::Add => self.push(Instruction::Add),
-                    BinaryOp::Sub => self.push(Instruction::Sub),
-                    BinaryOp::Mul => self.push(Instruction::Mul),
-                    BinaryOp::Div => self.push(Instruction::Div),
-                    BinaryOp::Eq => self.push(Instruction::Equal),
-                    BinaryOp::Lt => self.push(Instruction::Less),
-                    BinaryOp::Gt => self.push(Instruction::Greater),
-                    BinaryOp::Ne => {
-                        self.push(Instruction::Equal);
-                    }
-                    BinaryOp::Le => {
-                        self.push(Instruction::Greater);
-                    }
-                    BinaryOp::Ge => {
-                        self.push(Instruction::Less);
-                    }
+            Stmt::Extern {
+                library,
+                functions,
+                line,
+            } => {
+                if self.depth > 0 {
+                    return Err(format!(
+                        "'extern' is only allowed at the top level (line {})",
+                        line
+                    ));
                 }
+                self.bind_extern(library, functions)
+                    .map_err(|e| format!("{} at line {}", e, line))?;
             }
-            Expr::Call { func, args } => {
-                for arg in args.iter().rev() {
-                    self.compile_expression(arg)?;
+            Stmt::Struct {
+                name, fields, line, ..
+            } => {
+                if self.depth > 0 {
+                    return Err(format!(
+                        "'struct' is only allowed at the top level (line {})",
+                        line
+                    ));
                 }
-
-                if let Expr::Identifier(func_name) = func.as_ref() {
-                    let function_index = self.resolve_function_index(func_name)?;
-                    self.push(Instruction::Call(function_index));
-                } else {
-                    self.compile_expression(func)?;
+                for (i, field) in fields.iter().enumerate() {
+                    if fields[..i].contains(field) {
+                        return Err(format!(
+                            "Field '{}' is declared twice in struct '{}' (line {})",
+                            field, name, line
+                        ));
+                    }
                 }
             }
-            Expr::Pipeline { left, right } => {
-                self.compile_expression(left)?;
-
-                match right.as_ref() {
-                    Expr::Call { func, args } => {
-                        for arg in args.iter().rev() {
-                            self.compile_expression(arg)?;
-                        }
-                        if let Expr::Identifier(func_name) = func.as_ref() {
-                            let function_index = self.resolve_function_index(func_name)?;
-                            self.push(Instruction::Call(function_index));
-                        }
-                    }
-                    Expr::Identifier(func_name) => {
-                        let function_index = self.resolve_function_index(func_name)?;
-                        self.push(Instruction::Call(function_index));
-                    }
-                    _ => {
-                        println!("right: {:?}", right);
-                        self.compile_expression(right)?;
+            Stmt::Enum {
+                name,
+                variants,
+                line,
+                ..
+            } => {
+                if self.depth > 0 {
+                    return Err(format!(
+                        "'enum' is only allowed at the top level (line {})",
+                        line
+                    ));
+                }
+                for (i, (variant, _)) in variants.iter().enumerate() {
+                    if variants[..i].iter().any(|(other, _)| other == variant) {
+                        return Err(format!(
+                            "Variant '{}' is declared twice in enum '{}' (line {})",
+                            variant, name, line
+                        ));
                     }
                 }
             }
+            // Top-level ones are compiled first, see `generate_instructions`
+            Stmt::Impl { line, .. } => {
+                return Err(format!(
+                    "'impl' is only allowed at the top level (line {})",
+                    line
+                ));
+            }
+            Stmt::Trait { line, .. } if self.depth > 0 => {
+                return Err(format!(
+                    "'trait' is only allowed at the top level (line {})",
+                    line
+                ));
+            }
+            Stmt::Trait { .. } => {}
+            Stmt::For {
+                name,
+                iterable,
+                body,
+                line,
+            } => {
+                self.compile_for(program, name, *iterable, body, *line)?;
+                if last {
+                    // Like `let`, a loop has no value of its own
+                    self.push_with_line(Instruction::Push(Value::Number(0.0)), *line);
+                }
+            }
+            Stmt::Expr(expr, _) if !last && let Expr::If { .. } = program.expr(*expr) => {
+                // Only run for effect, so an `else` is optional
+                self.compile_if(program, *expr, false)?;
+            }
+            Stmt::Expr(expr, line) => {
+                self.compile_expression(program, *expr)?;
+                if !last {
+                    self.push_with_line(Instruction::Pop, *line);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_expression(&mut self, program: &Program, id: ExprId) -> Result<(), String> {
+        match program.expr(id) {
+            Expr::Boolean(b) => {
+                let const_index = self.constants.add_boolean(*b);
+                self.push(Instruction::LoadConst(const_index));
+            }
+            Expr::Number(n) => {
+                let const_index = self.constants.add_number(*n);
+                self.push(Instruction::LoadConst(const_index));
+            }
+            Expr::String(s) => {
+                let const_index = self.constants.add_string(s);
+                self.push(Instruction::LoadConst(const_index));
+            }
+            // A function named without calling it is a value
+            Expr::Identifier(name)
+                if self.get_variable(name).is_none() && self.functions.contains_key(name) =>
+            {
+                let function_index = self.resolve_function_index(name)?;
+                self.warn_if_deprecated(name);
+                self.function_closure(name, function_index)?;
+            }
+            Expr::Identifier(name)
+                if self.get_variable(name).is_none() && self.consts.contains_key(name) =>
+            {
+                let index = self.const_index(self.consts[name].clone());
+                self.push(Instruction::LoadConst(index));
+            }
+            Expr::Identifier(name) => self.load_variable(name)?,
+            Expr::If { .. } => self.compile_if(program, id, true)?,
+            Expr::Block(statements) => {
+                if !matches!(statements.last(), Some(Stmt::Expr(..))) {
+                    return Err("A block used as a value must end with an expression".to_string());
+                }
+                self.compile_block(program, statements, true)?;
+            }
+            Expr::Lambda { params, body } => self.compile_lambda(program, params, *body)?,
+            Expr::NamedArg { name, .. } => {
+                return Err(format!("Named argument '{}' outside of a call", name));
+            }
+            Expr::Spread(_) => {
+                return Err(
+                    "'...' can only be used in list literals and call arguments".to_string()
+                );
+            }
+            Expr::Yield(value) => {
+                if !self.in_generator {
+                    return Err("'yield' can only be used in a 'func' body".to_string());
+                }
+                self.compile_expression(program, *value)?;
+                self.push(Instruction::Yield);
+            }
+            Expr::Binary {
+                left,
+                op: op @ (BinaryOp::And | BinaryOp::Or),
+                right,
+            } => self.compile_logical(program, *left, *op, *right)?,
+            Expr::Binary {
+                op: BinaryOp::Concat,
+                ..
+            } => {
+                // `a ++ b ++ c` builds one string instead of one per `++`
+                let mut parts = Vec::new();
+                concat_parts(program, id, &mut parts);
+                for part in &parts {
+                    self.compile_expression(program, *part)?;
+                }
+                match parts.len() {
+                    2 => self.push(Instruction::Concat),
+                    count => self.push(Instruction::ConcatN(count)),
+                }
+            }
+            Expr::Binary { left, op, right } => {
+                if self.options.strict_concat
+                    && matches!(op, BinaryOp::Add)
+                    && (matches!(program.expr(*left), Expr::String(_))
+                        || matches!(program.expr(*right), Expr::String(_)))
+                {
+                    return Err("Use '++' to concatenate strings in strict mode".to_string());
+                }
+                self.compile_expression(program, *left)?;
+                self.compile_expression(program, *right)?;
+                self.push_binary_op(*op);
+            }
+            Expr::Call { func, args } => self.compile_call(program, *func, args)?,
+            Expr::Member { object, property } if self.is_module(program, *object) => {
+                return Err(format!(
+                    "'.{}' on a module must be called, as in '{}()'",
+                    property, property
+                ));
+            }
+            Expr::Member { object, property } => {
+                self.compile_expression(program, *object)?;
+                // A number constant reads by position, see `GET_FIELD`
+                let name = match property.parse::<usize>() {
+                    Ok(position) => self.constants.add_number(position as f64),
+                    Err(_) => self.constants.add_string(property),
+                };
+                self.push(Instruction::GetField(name));
+            }
+            Expr::Record { name, fields } => self.compile_record(program, name.as_ref(), fields)?,
+            Expr::Path {
+                type_name,
+                member,
+                args,
+            } => self.compile_path(program, type_name, member, args.as_deref())?,
+            Expr::Match { subject, arms } => self.compile_match(program, *subject, arms)?,
+            // `x |> f(a)` is `f(x, a)` and `x |> f` is `f(x)`
+            Expr::Pipeline { left, right } => match program.expr(*right) {
+                Expr::Call { func, args } => {
+                    let args: Vec<ExprId> =
+                        std::iter::once(*left).chain(args.iter().copied()).collect();
+                    self.compile_call(program, *func, &args)?;
+                }
+                Expr::Identifier(_) | Expr::Member { .. } | Expr::Lambda { .. } => {
+                    self.compile_call(program, *right, &[*left])?;
+                }
+                _ => {
+                    return Err("The right side of '|>' must be a function or a call".to_string());
+                }
+            },
             Expr::Unary { op, right } => match op {
                 UnaryOp::Neg => {
                     self.push(Instruction::Push(Value::Number(0.0)));
-                    self.compile_expression(right)?;
+                    self.compile_expression(program, *right)?;
                     self.push(Instruction::Sub);
                 }
                 UnaryOp::Not => {
-                    self.compile_expression(right)?;
+                    self.compile_expression(program, *right)?;
                     self.push(Instruction::Not);
                 }
             },
             Expr::Update { left, right } => {
                 // Compile left and right arrays onto the stack, then concatenate
-                self.compile_expression(left)?;
-                self.compile_expression(right)?;
+                self.compile_expression(program, *left)?;
+                self.compile_expression(program, *right)?;
                 self.push(Instruction::ConcatArray);
             }
-            Expr::Array { elements } => {
-                for element in elements.iter() {
-                    self.compile_expression(element)?;
+            Expr::Array { elements } => self.compile_list(program, elements)?,
+            Expr::Tuple(elements) => {
+                for element in elements {
+                    self.compile_expression(program, *element)?;
+                }
+                self.push(Instruction::MakeTuple(elements.len()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Pushes a list of `elements`. Runs of plain elements become
+    /// `CREATE_ARRAY`s, joined to each spread list with `CONCAT_ARRAY`.
+    fn compile_list(&mut self, program: &Program, elements: &[ExprId]) -> Result<(), String> {
+        let mut pending = 0;
+        let mut started = false;
+        for element in elements {
+            match program.expr(*element) {
+                Expr::Spread(list) => {
+                    if pending > 0 || !started {
+                        self.push(Instruction::CreateArray(pending));
+                        if started {
+                            self.push(Instruction::ConcatArray);
+                        }
+                    }
+                    self.compile_expression(program, *list)?;
+                    self.push(Instruction::ConcatArray);
+                    started = true;
+                    pending = 0;
+                }
+                _ => {
+                    self.compile_expression(program, *element)?;
+                    pending += 1;
+                }
+            }
+        }
+        if pending > 0 || !started {
+            self.push(Instruction::CreateArray(pending));
+            if started {
+                self.push(Instruction::ConcatArray);
+            }
+        }
+        Ok(())
+    }
+
+    /// A call with `...` among its arguments: they are gathered into one list
+    /// and the callee, as a closure, is applied to it.
+    fn compile_spread_call(
+        &mut self,
+        program: &Program,
+        func: ExprId,
+        args: &[ExprId],
+    ) -> Result<(), String> {
+        self.compile_list(program, args)?;
+        match program.expr(func) {
+            Expr::Identifier(name) if self.get_variable(name).is_none() => {
+                if !self.functions.contains_key(name) && self.natives.resolve(name).is_some() {
+                    return Err(format!("Cannot spread arguments into native '{}'", name));
+                }
+                let function_index = self.resolve_function_index(name)?;
+                self.warn_if_deprecated(name);
+                self.function_closure(name, function_index)?;
+            }
+            Expr::Member { property, .. } => {
+                return Err(format!("Cannot spread arguments into '{}'", property));
+            }
+            _ => self.compile_expression(program, func)?,
+        }
+        self.push(Instruction::Apply);
+        Ok(())
+    }
+
+    /// Arguments are pushed last to first, so the callee pops them in order.
+    fn compile_call(
+        &mut self,
+        program: &Program,
+        func: ExprId,
+        args: &[ExprId],
+    ) -> Result<(), String> {
+        if args
+            .iter()
+            .any(|arg| matches!(program.expr(*arg), Expr::Spread(_)))
+        {
+            return self.compile_spread_call(program, func, args);
+        }
+        if let Expr::Identifier(name) = program.expr(func)
+            && self.get_variable(name).is_none()
+            && let Some(&function_index) = self.functions.get(name)
+        {
+            return self.compile_function_call(program, name, function_index, args);
+        }
+        // `Type.function(...)` for functions of an impl
+        if let Expr::Member { object, property } = program.expr(func)
+            && self.is_module(program, *object)
+            && let Expr::Identifier(module) = program.expr(*object)
+            && let Some(&function_index) = self
+                .functions
+                .get(format!("{}.{}", module, property).as_str())
+        {
+            let name = format!("{}.{}", module, property);
+            return self.compile_function_call(program, &name, function_index, args);
+        }
+        if let Some(name) = args.iter().find_map(|arg| match program.expr(*arg) {
+            Expr::NamedArg { name, .. } => Some(name),
+            _ => None,
+        }) {
+            return Err(format!(
+                "Named argument '{}' needs a function called by name",
+                name
+            ));
+        }
+        if self.compile_comparison_assert(program, func, args)? {
+            return Ok(());
+        }
+        for arg in args.iter().rev() {
+            self.compile_expression(program, *arg)?;
+        }
+
+        if let Some((method, receiver)) = self.resolve_method(program, func)? {
+            let Method { name, arity } = METHODS[method];
+            // A receiver is not among the arguments written in parentheses
+            let expected = arity - receiver.is_some() as usize;
+            if args.len() != expected {
+                return Err(format!(
+                    "'{}' expects {} argument(s), got {}",
+                    name,
+                    expected,
+                    args.len()
+                ));
+            }
+            if let Some(receiver) = receiver {
+                self.compile_expression(program, receiver)?;
+            }
+            self.push(Instruction::CallMethod(method));
+            return Ok(());
+        }
+        // Other methods are found at run time from the receiver's type
+        if let Expr::Member { object, property } = program.expr(func)
+            && !self.is_module(program, *object)
+        {
+            self.compile_expression(program, *object)?;
+            let name = self.constants.add_string(property);
+            self.push(Instruction::Invoke(name, args.len()));
+            return Ok(());
+        }
+        if let Some(native_index) = self.resolve_native(program, func, args.len())? {
+            self.push(Instruction::CallNative(native_index, args.len()));
+            return Ok(());
+        }
+        match program.expr(func) {
+            Expr::Identifier(name) if self.get_variable(name).is_none() => {
+                return Err(format!("Undefined function '{}'", name));
+            }
+            // Variables and call results hold closures
+            _ => {
+                self.compile_expression(program, func)?;
+                self.push(Instruction::CallValue(args.len()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls script function `name` directly. Named arguments are matched to
+    /// parameters, defaults fill the parameters left over and surplus
+    /// positional arguments make up a rest parameter's list. Without named
+    /// arguments, stopping short of the parameters that have no default makes
+    /// a closure waiting for the rest.
+    fn compile_function_call(
+        &mut self,
+        program: &Program,
+        name: &str,
+        function_index: usize,
+        args: &[ExprId],
+    ) -> Result<(), String> {
+        self.warn_if_deprecated(name);
+        self.check_declared(name, function_index)?;
+        let Value::Function { mut params, .. } = self.function_table[function_index].clone() else {
+            unreachable!("function table only holds functions");
+        };
+        let captured = self
+            .captured
+            .get(&function_index)
+            .cloned()
+            .unwrap_or_default();
+        params.drain(..captured.len());
+        let defaults = self
+            .function_defaults
+            .get(&function_index)
+            .cloned()
+            .unwrap_or_default();
+        let (fixed, variadic) = arity(&params);
+        let required = fixed - defaults.len();
+
+        let mut slots: Vec<Option<ExprId>> = vec![None; fixed];
+        let mut rest = Vec::new();
+        let mut positional = 0;
+        let mut named = false;
+        for arg in args {
+            match program.expr(*arg) {
+                Expr::NamedArg { name: param, value } => {
+                    let slot = params
+                        .iter()
+                        .position(|p| p == &**param)
+                        .ok_or_else(|| format!("'{}' has no parameter '{}'", name, param))?;
+                    if slots[slot].is_some() {
+                        return Err(format!(
+                            "Argument '{}' is given twice in the call to '{}'",
+                            param, name
+                        ));
+                    }
+                    slots[slot] = Some(*value);
+                    named = true;
+                }
+                _ if named => {
+                    return Err(format!(
+                        "Positional arguments must come before named ones in the call to '{}'",
+                        name
+                    ));
+                }
+                _ if positional == fixed && variadic => rest.push(*arg),
+                _ if positional == fixed => {
+                    return Err(format!(
+                        "'{}' expects {} argument(s), got {}",
+                        name,
+                        params.len(),
+                        args.len()
+                    ));
+                }
+                _ => {
+                    slots[positional] = Some(*arg);
+                    positional += 1;
+                }
+            }
+        }
+
+        if !named && positional < required {
+            for arg in args.iter().rev() {
+                self.compile_expression(program, *arg)?;
+            }
+            for name in captured.iter().rev() {
+                self.load_variable(name)?;
+            }
+            let bound = captured.len() + args.len();
+            self.push(Instruction::MakeClosure(function_index, bound));
+            return Ok(());
+        }
+        if variadic {
+            for arg in &rest {
+                self.compile_expression(program, *arg)?;
+            }
+            self.push(Instruction::CreateArray(rest.len()));
+        }
+        for (i, slot) in slots.iter().enumerate().rev() {
+            match slot {
+                Some(arg) => self.compile_expression(program, *arg)?,
+                None if i >= required => {
+                    self.push(Instruction::LoadConst(defaults[i - required]));
+                }
+                None => {
+                    return Err(format!(
+                        "Missing argument '{}' in the call to '{}'",
+                        params[i], name
+                    ));
                 }
-                self.push(Instruction::CreateArray(elements.len()));
             }
         }
+        for name in captured.iter().rev() {
+            self.load_variable(name)?;
+        }
+        self.push(Instruction::Call(function_index));
+        Ok(())
+    }
+
+    /// Locals of the enclosing function that the nested `func` `name` uses,
+    /// recorded for its calls to pass. Top-level functions capture nothing.
+    fn capture_for(
+        &mut self,
+        program: &Program,
+        name: &Symbol,
+        params: &[Symbol],
+        body: &[Stmt],
+    ) -> Vec<Symbol> {
+        let Some(&function_index) = self.functions.get(name) else {
+            return Vec::new();
+        };
+        self.undeclared_nested.remove(&function_index);
+        let mut identifiers = Identifiers(Vec::new());
+        for stmt in body {
+            identifiers.visit_stmt(program, stmt);
+        }
+        let mut captured = identifiers.0;
+        captured.retain(|name| {
+            !params.contains(name)
+                && matches!(self.get_variable(name), Some((_, depth)) if depth > 0)
+        });
+        match captured.is_empty() {
+            true => self.captured.remove(&function_index),
+            false => self.captured.insert(function_index, captured.clone()),
+        };
+        captured
+    }
+
+    /// Fails for a nested `func` used before its declaration, when what it
+    /// captures is not known yet.
+    fn check_declared(&self, name: &str, function_index: usize) -> Result<(), String> {
+        match self.undeclared_nested.contains(&function_index) {
+            true => Err(format!(
+                "Nested function '{}' is used before its declaration",
+                name
+            )),
+            false => Ok(()),
+        }
+    }
+
+    /// Pushes function `function_index` as a closure, with what it captures.
+    fn function_closure(&mut self, name: &str, function_index: usize) -> Result<(), String> {
+        self.check_declared(name, function_index)?;
+        let captured = self
+            .captured
+            .get(&function_index)
+            .cloned()
+            .unwrap_or_default();
+        for name in captured.iter().rev() {
+            self.load_variable(name)?;
+        }
+        self.push(Instruction::MakeClosure(function_index, captured.len()));
         Ok(())
     }
 
-    fn get_constant_index(&self, value: &Value) -> usize {
-        self.constants
+    /// Compiles a lambda into an anonymous function and pushes a closure of
+    /// it. Variables of enclosing scopes that the body uses are captured by
+    /// value: they become leading parameters, bound when the closure is made.
+    fn compile_lambda(
+        &mut self,
+        program: &Program,
+        params: &[Symbol],
+        body: ExprId,
+    ) -> Result<(), String> {
+        let mut captured = collect_identifiers(program, body);
+        captured.retain(|name| !params.contains(name) && self.get_variable(name).is_some());
+        let all_params: Vec<Symbol> = captured.iter().chain(params).cloned().collect();
+
+        let jump_over = self.instructions.len();
+        self.push(Instruction::Jump(0));
+        let function_index = self.function_table.len();
+        self.function_table.push(Value::Function {
+            params: all_params.iter().map(|p| p.to_string()).collect(),
+            offset: self.instructions.len(),
+        });
+        if !all_params.is_empty() {
+            self.push(Instruction::LoadArg(all_params.len()));
+        }
+
+        let depth = self.depth;
+        let scopes = self.variables.len();
+        let enclosing_scope = self.variables.get(depth + 1).cloned();
+        let enclosing_slots = self.slot_counts.get(depth + 1).copied();
+        let in_generator = std::mem::replace(&mut self.in_generator, false);
+        self.depth += 1;
+        self.enter_function_scope();
+        for param in &all_params {
+            self.insert_variable(param);
+        }
+        let result = self.compile_expression(program, body);
+        self.warn_unused_locals(self.depth);
+        self.depth = depth;
+        self.in_generator = in_generator;
+        // Leave the scope above as it was, in case a function body is using it
+        match enclosing_scope {
+            Some(scope) => self.variables[depth + 1] = scope,
+            None => self.variables.truncate(scopes),
+        }
+        if let Some(slots) = enclosing_slots {
+            self.slot_counts[depth + 1] = slots;
+        }
+        result?;
+        self.push(Instruction::Return);
+        self.instructions[jump_over] = Instruction::Jump(self.instructions.len());
+
+        for name in captured.iter().rev() {
+            self.load_variable(name)?;
+        }
+        self.push(Instruction::MakeClosure(function_index, captured.len()));
+        Ok(())
+    }
+
+    /// Compiles the methods of an `impl` like functions named `Type.method`,
+    /// then registers each with the VM so `value.method()` finds it. The
+    /// type's constants become functions computing their value.
+    fn compile_impl(
+        &mut self,
+        program: &Program,
+        name: &Symbol,
+        trait_name: Option<&Symbol>,
+        methods: &[Stmt],
+        constants: &[Stmt],
+        line: usize,
+    ) -> Result<(), String> {
+        if !self.structs.contains_key(name) && !self.enums.contains_key(name) {
+            return Err(format!("Unknown type '{}' in impl (line {})", name, line));
+        }
+        for constant in constants {
+            self.compile_statement(program, constant, false)?;
+        }
+        if let Some(trait_name) = trait_name {
+            self.check_trait_impl(name, trait_name, methods)
+                .map_err(|e| format!("{} (line {})", e, line))?;
+        }
+        for method in methods {
+            self.compile_statement(program, method, false)?;
+        }
+        for method in methods {
+            let Stmt::Func { name, line, .. } = method else {
+                return Err(format!("'impl {}' may only contain functions", name));
+            };
+            let function_index = self.resolve_function_index(name)?;
+            self.push_with_line(Instruction::MakeClosure(function_index, 0), *line);
+            let name = self.constants.add_string(name);
+            self.push_with_line(Instruction::DefineMethod(name), *line);
+        }
+        Ok(())
+    }
+
+    /// Checks that an `impl Trait for Type` defines exactly the trait's
+    /// methods, each with the declared number of parameters.
+    fn check_trait_impl(
+        &self,
+        name: &Symbol,
+        trait_name: &Symbol,
+        methods: &[Stmt],
+    ) -> Result<(), String> {
+        let required = self
+            .traits
+            .get(trait_name)
+            .ok_or_else(|| format!("Unknown trait '{}'", trait_name))?;
+        let defined: Vec<(&str, usize)> = methods
             .iter()
-            .position(|c| match (c, value) {
-                (Value::Number(a), Value::Number(b)) => a == b,
-                (Value::String(a), Value::String(b)) => a == b,
-                (Value::Boolean(a), Value::Boolean(b)) => a == b,
-                _ => false,
+            .filter_map(|method| match method {
+                Stmt::Func {
+                    name: method,
+                    params,
+                    ..
+                } => Some((
+                    method.split_once('.').map_or(&**method, |(_, m)| m),
+                    params.len(),
+                )),
+                _ => None,
             })
-            .unwrap_or(0)
+            .collect();
+        for (method, param_count) in required {
+            match defined.iter().find(|(defined, _)| *defined == &**method) {
+                None => {
+                    return Err(format!(
+                        "'{}' is missing method '{}' of trait '{}'",
+                        name, method, trait_name
+                    ));
+                }
+                Some((_, count)) if count != param_count => {
+                    return Err(format!(
+                        "Method '{}' of '{}' takes {} parameter(s), trait '{}' declares {}",
+                        method, name, count, trait_name, param_count
+                    ));
+                }
+                Some(_) => {}
+            }
+        }
+        if let Some((method, _)) = defined
+            .iter()
+            .find(|(method, _)| !required.iter().any(|(required, _)| &**required == *method))
+        {
+            return Err(format!(
+                "'{}' is not a method of trait '{}'",
+                method, trait_name
+            ));
+        }
+        Ok(())
+    }
+
+    /// Pushes each field's name and value, then the type name, for
+    /// `MAKE_RECORD`. A typed record must give exactly the struct's fields;
+    /// they are stored in declaration order.
+    fn compile_record(
+        &mut self,
+        program: &Program,
+        name: Option<&Symbol>,
+        fields: &[(Symbol, ExprId)],
+    ) -> Result<(), String> {
+        for (i, (field, _)) in fields.iter().enumerate() {
+            if fields[..i].iter().any(|(other, _)| other == field) {
+                return Err(format!("Field '{}' is given twice", field));
+            }
+        }
+        let ordered: Vec<(Symbol, ExprId)> = match name {
+            None => fields.to_vec(),
+            Some(name) => {
+                let declared = self
+                    .structs
+                    .get(name)
+                    .ok_or_else(|| format!("Unknown struct '{}'", name))?;
+                if let Some((field, _)) = fields.iter().find(|(field, _)| !declared.contains(field))
+                {
+                    return Err(format!("Struct '{}' has no field '{}'", name, field));
+                }
+                declared
+                    .iter()
+                    .map(|field| {
+                        fields
+                            .iter()
+                            .find(|(given, _)| given == field)
+                            .cloned()
+                            .ok_or_else(|| format!("Missing field '{}' in '{}'", field, name))
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        for (field, value) in &ordered {
+            let field = self.constants.add_string(field);
+            self.push(Instruction::LoadConst(field));
+            self.compile_expression(program, *value)?;
+        }
+        let type_name = self.constants.add_string(name.unwrap_or(&Symbol::from("")));
+        self.push(Instruction::LoadConst(type_name));
+        self.push(Instruction::MakeRecord(ordered.len()));
+        Ok(())
     }
 
-    fn get_or_create_variable_index(&mut self, name: &str) -> VarOutput {
+    /// Compiles `Type::member`: a variant of an enum, an associated constant,
+    /// or a function of the type's `impl`, called when `args` are given and a
+    /// closure otherwise.
+    fn compile_path(
+        &mut self,
+        program: &Program,
+        type_name: &Symbol,
+        member: &Symbol,
+        args: Option<&[ExprId]>,
+    ) -> Result<(), String> {
+        let is_variant = self
+            .enums
+            .get(type_name)
+            .is_some_and(|variants| variants.iter().any(|(variant, _)| variant == member));
+        let constant = format!("{}::{}", type_name, member);
+        let function = format!("{}.{}", type_name, member);
+        if is_variant {
+            let args = args.unwrap_or_default();
+            let name = self.variant_name(type_name, member, args.len())?;
+            for arg in args {
+                self.compile_expression(program, *arg)?;
+            }
+            self.push(Instruction::MakeVariant(name, args.len()));
+        } else if let Some(&function_index) = self.functions.get(constant.as_str()) {
+            if args.is_some() {
+                return Err(format!("Constant '{}' cannot be called", constant));
+            }
+            self.push(Instruction::Call(function_index));
+        } else if let Some(&function_index) = self.functions.get(function.as_str()) {
+            match args {
+                Some(args) => {
+                    self.compile_function_call(program, &function, function_index, args)?
+                }
+                None => self.push(Instruction::MakeClosure(function_index, 0)),
+            }
+        } else if self.enums.contains_key(type_name) {
+            return Err(format!("Enum '{}' has no variant '{}'", type_name, member));
+        } else if self.structs.contains_key(type_name) {
+            return Err(format!("'{}' has no member '{}'", type_name, member));
+        } else {
+            return Err(format!("Unknown type '{}'", type_name));
+        }
+        Ok(())
+    }
+
+    /// Constant index of the name `Enum::Variant`, after checking that the
+    /// variant exists and has `field_count` fields.
+    fn variant_name(
+        &mut self,
+        enum_name: &Symbol,
+        variant: &Symbol,
+        field_count: usize,
+    ) -> Result<usize, String> {
+        let variants = self
+            .enums
+            .get(enum_name)
+            .ok_or_else(|| format!("Unknown enum '{}'", enum_name))?;
+        let (_, declared) = variants
+            .iter()
+            .find(|(name, _)| name == variant)
+            .ok_or_else(|| format!("Enum '{}' has no variant '{}'", enum_name, variant))?;
+        if *declared != field_count {
+            return Err(format!(
+                "'{}::{}' has {} field(s), got {}",
+                enum_name, variant, declared, field_count
+            ));
+        }
+        self.warn_if_deprecated(enum_name);
+        let name = format!("{}::{}", enum_name, variant);
+        Ok(self.constants.add_string(&Symbol::from(name.as_str())))
+    }
+
+    /// Compiles a `match`. The subject is kept in a hidden variable and each
+    /// arm's pattern is tested against it, falling through to the next arm
+    /// when any part does not match:
+    ///
+    /// ```text
+    /// subject; STORE_VAR it
+    /// arm: pattern tests, each JUMP_IF_FALSE next; value; JUMP end
+    /// next: ...
+    /// LOAD_VAR it; NO_MATCH
+    /// end:
+    /// ```
+    ///
+    /// Each arm is a block, holding the names its pattern binds.
+    fn compile_match(
+        &mut self,
+        program: &Program,
+        subject: ExprId,
+        arms: &[(Pattern, ExprId)],
+    ) -> Result<(), String> {
+        self.compile_expression(program, subject)?;
+        let slot = self.hidden_variable();
+        self.push(Instruction::StoreVar(self.depth, slot));
+        let mut jumps_to_end = Vec::new();
+        for (pattern, value) in arms {
+            let mut jumps_to_next = Vec::new();
+            self.in_block(|this| {
+                this.compile_pattern(program, slot, pattern, &mut jumps_to_next)?;
+                this.compile_expression(program, *value)
+            })?;
+            jumps_to_end.push(self.instructions.len());
+            self.push(Instruction::Jump(0));
+            let next = self.instructions.len();
+            for jump in jumps_to_next {
+                self.instructions[jump] = Instruction::JumpIfFalse(next);
+            }
+        }
+        self.push(Instruction::LoadVar(self.depth, slot));
+        self.push(Instruction::NoMatch);
+        let end = self.instructions.len();
+        for jump in jumps_to_end {
+            self.instructions[jump] = Instruction::Jump(end);
+        }
+        Ok(())
+    }
+
+    /// Tests the value in variable `slot` against `pattern`, binding its
+    /// names. Each failed test jumps to a location recorded in `jumps`.
+    fn compile_pattern(
+        &mut self,
+        program: &Program,
+        slot: usize,
+        pattern: &Pattern,
+        jumps: &mut Vec<usize>,
+    ) -> Result<(), String> {
+        match pattern {
+            Pattern::Wildcard => {}
+            Pattern::Binding(name) => {
+                let variable = self.pattern_variable(name);
+                self.push(Instruction::LoadVar(self.depth, slot));
+                self.push(Instruction::StoreVar(self.depth, variable));
+            }
+            Pattern::Literal(value) => {
+                self.push(Instruction::LoadVar(self.depth, slot));
+                self.compile_expression(program, *value)?;
+                self.push(Instruction::Equal);
+                jumps.push(self.instructions.len());
+                self.push(Instruction::JumpIfFalse(0));
+            }
+            Pattern::Variant {
+                enum_name,
+                variant,
+                fields,
+            } => {
+                let name = self.variant_name(enum_name, variant, fields.len())?;
+                self.test_pattern(slot, Instruction::TestVariant(name), jumps);
+                self.compile_positions(program, slot, fields, jumps)?;
+            }
+            Pattern::Tuple(elements) => {
+                self.test_pattern(slot, Instruction::TestTuple(elements.len()), jumps);
+                self.compile_positions(program, slot, elements, jumps)?;
+            }
+            Pattern::List { elements, rest } => {
+                let test = Instruction::TestList(elements.len(), rest.is_some());
+                self.test_pattern(slot, test, jumps);
+                self.compile_positions(program, slot, elements, jumps)?;
+                if let Some(rest) = rest {
+                    let variable = self.pattern_variable(rest);
+                    self.push(Instruction::LoadVar(self.depth, slot));
+                    self.push(Instruction::ListFrom(elements.len()));
+                    self.push(Instruction::StoreVar(self.depth, variable));
+                }
+            }
+            Pattern::Record(fields) => {
+                for (field, pattern) in fields {
+                    let name = self.constants.add_string(field);
+                    self.test_pattern(slot, Instruction::HasField(name), jumps);
+                    self.compile_part(program, slot, Instruction::GetField(name), pattern, jumps)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `test` on the value in `slot`, jumping away when it is false.
+    fn test_pattern(&mut self, slot: usize, test: Instruction, jumps: &mut Vec<usize>) {
+        self.push(Instruction::LoadVar(self.depth, slot));
+        self.push(test);
+        jumps.push(self.instructions.len());
+        self.push(Instruction::JumpIfFalse(0));
+    }
+
+    /// Matches each positional field of the value in `slot` to its pattern.
+    fn compile_positions(
+        &mut self,
+        program: &Program,
+        slot: usize,
+        patterns: &[Pattern],
+        jumps: &mut Vec<usize>,
+    ) -> Result<(), String> {
+        for (i, pattern) in patterns.iter().enumerate() {
+            let position = self.constants.add_number(i as f64);
+            self.compile_part(
+                program,
+                slot,
+                Instruction::GetField(position),
+                pattern,
+                jumps,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Matches the part of the value in `slot` that `access` reads to
+    /// `pattern`, keeping it in a variable of its own if it is taken apart.
+    fn compile_part(
+        &mut self,
+        program: &Program,
+        slot: usize,
+        access: Instruction,
+        pattern: &Pattern,
+        jumps: &mut Vec<usize>,
+    ) -> Result<(), String> {
+        if let Pattern::Wildcard = pattern {
+            return Ok(());
+        }
+        self.push(Instruction::LoadVar(self.depth, slot));
+        self.push(access);
+        match pattern {
+            Pattern::Binding(name) => {
+                let variable = self.pattern_variable(name);
+                self.push(Instruction::StoreVar(self.depth, variable));
+            }
+            _ => {
+                let part_slot = self.hidden_variable();
+                self.push(Instruction::StoreVar(self.depth, part_slot));
+                self.compile_pattern(program, part_slot, pattern, jumps)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Emits `op` on the two operands on top of the stack.
+    fn push_binary_op(&mut self, op: BinaryOp) {
+        match op {
+            BinaryOp::Add => self.push(Instruction::Add),
+            BinaryOp::Concat => self.push(Instruction::Concat),
+            BinaryOp::Sub => self.push(Instruction::Sub),
+            BinaryOp::Mul => self.push(Instruction::Mul),
+            BinaryOp::Div => self.push(Instruction::Div),
+            BinaryOp::Eq => self.push(Instruction::Equal),
+            BinaryOp::Lt => self.push(Instruction::Less),
+            BinaryOp::Gt => self.push(Instruction::Greater),
+            BinaryOp::Ne => {
+                self.push(Instruction::Equal);
+                self.push(Instruction::Not);
+            }
+            BinaryOp::Le => {
+                self.push(Instruction::Greater);
+                self.push(Instruction::Not);
+            }
+            BinaryOp::Ge => {
+                self.push(Instruction::Less);
+                self.push(Instruction::Not);
+            }
+            BinaryOp::And | BinaryOp::Or => unreachable!("compiled by compile_logical"),
+        }
+    }
+
+    /// Compiles `assert(a == b)`, or another comparison, with an optional
+    /// message, so that a failure shows both operands. Each is evaluated once
+    /// into a hidden variable and passed to `assert` after the result and the
+    /// operator, a form of the native only the compiler uses:
+    ///
+    /// ```text
+    /// a; STORE_VAR l; b; STORE_VAR r; [message]
+    /// LOAD_VAR r; LOAD_VAR l; LOAD_CONST op; LOAD_VAR l; LOAD_VAR r; op
+    /// CALL_NATIVE assert
+    /// ```
+    ///
+    /// Returns `false`, compiling nothing, for any other call.
+    fn compile_comparison_assert(
+        &mut self,
+        program: &Program,
+        func: ExprId,
+        args: &[ExprId],
+    ) -> Result<bool, String> {
+        let Expr::Identifier(name) = program.expr(func) else {
+            return Ok(false);
+        };
+        if &**name != "assert"
+            || self.functions.contains_key(name)
+            || self.get_variable(name).is_some()
+            || !(1..=2).contains(&args.len())
+        {
+            return Ok(false);
+        }
+        let Expr::Binary { left, op, right } = program.expr(args[0]) else {
+            return Ok(false);
+        };
+        let op = *op;
+        let Some(symbol) = comparison_symbol(op) else {
+            return Ok(false);
+        };
+        let Some(native) = self.natives.resolve("assert") else {
+            return Ok(false);
+        };
+        let (left, right) = (*left, *right);
+        let left_slot = self.hidden_variable();
+        let right_slot = self.hidden_variable();
+        self.compile_expression(program, left)?;
+        self.push(Instruction::StoreVar(self.depth, left_slot));
+        self.compile_expression(program, right)?;
+        self.push(Instruction::StoreVar(self.depth, right_slot));
+        if let Some(message) = args.get(1) {
+            self.compile_expression(program, *message)?;
+        }
+        self.push(Instruction::LoadVar(self.depth, right_slot));
+        self.push(Instruction::LoadVar(self.depth, left_slot));
+        let symbol = self.constants.add_string(&Symbol::from(symbol));
+        self.push(Instruction::LoadConst(symbol));
+        self.push(Instruction::LoadVar(self.depth, left_slot));
+        self.push(Instruction::LoadVar(self.depth, right_slot));
+        self.push_binary_op(op);
+        self.push(Instruction::CallNative(native, args.len() + 3));
+        Ok(true)
+    }
+
+    /// A fresh variable the program cannot name, holding a value being matched.
+    fn hidden_variable(&mut self) -> usize {
+        self.match_count += 1;
+        self.insert_variable(&Symbol::from(
+            format!("match#{}", self.match_count).as_str(),
+        ))
+    }
+
+    fn pattern_variable(&mut self, name: &Symbol) -> usize {
+        match self.get_or_create_variable_index(name) {
+            VarOutput::Created { index, .. } | VarOutput::GotCurrentScope { index, .. } => index,
+            VarOutput::GotOuterScope { .. } => self.insert_variable(name),
+        }
+    }
+
+    /// Compiles a `for` loop. The iterable becomes a generator, kept in a
+    /// hidden variable, and the loop runs until it is done:
+    ///
+    /// ```text
+    /// iterable; CALL_METHOD iter; STORE_VAR it
+    /// start: LOAD_VAR it; CALL_METHOD done; JUMP_IF_TRUE end
+    /// LOAD_VAR it; CALL_METHOD next; STORE_VAR name; body...; JUMP start
+    /// end:
+    /// ```
+    ///
+    /// The loop variable is only visible in the body.
+    fn compile_for(
+        &mut self,
+        program: &Program,
+        name: &Symbol,
+        iterable: ExprId,
+        body: &[Stmt],
+        line: usize,
+    ) -> Result<(), String> {
+        self.in_block(|this| this.compile_loop(program, name, iterable, body, line))
+    }
+
+    fn compile_loop(
+        &mut self,
+        program: &Program,
+        name: &Symbol,
+        iterable: ExprId,
+        body: &[Stmt],
+        line: usize,
+    ) -> Result<(), String> {
+        self.compile_expression(program, iterable)?;
+        self.push_with_line(Instruction::CallMethod(methods::ITER), line);
+        self.loop_count += 1;
+        let iterator =
+            self.insert_variable(&Symbol::from(format!("for#{}", self.loop_count).as_str()));
+        self.push_with_line(Instruction::StoreVar(self.depth, iterator), line);
+        let variable = match self.get_or_create_variable_index(name) {
+            VarOutput::Created { index, .. } | VarOutput::GotCurrentScope { index, .. } => index,
+            VarOutput::GotOuterScope { .. } => self.insert_variable(name),
+        };
+
+        let start = self.instructions.len();
+        self.push_with_line(Instruction::LoadVar(self.depth, iterator), line);
+        self.push_with_line(Instruction::CallMethod(methods::DONE), line);
+        let jump_to_end = self.instructions.len();
+        self.push_with_line(Instruction::JumpIfTrue(0), line);
+        self.push_with_line(Instruction::LoadVar(self.depth, iterator), line);
+        self.push_with_line(Instruction::CallMethod(methods::NEXT), line);
+        self.push_with_line(Instruction::StoreVar(self.depth, variable), line);
+        self.compile_block(program, body, false)?;
+        self.push_with_line(Instruction::Jump(start), line);
+        let end = self.instructions.len();
+        self.instructions[jump_to_end] = Instruction::JumpIfTrue(end);
+        Ok(())
+    }
+
+    /// Compiles an `if`, leaving the taken branch's value on the stack when
+    /// `keep_value` is set. A value needs both branches:
+    ///
+    /// ```text
+    /// condition; JUMP_IF_FALSE else; then...; JUMP end
+    /// else: else...
+    /// end:
+    /// ```
+    fn compile_if(
+        &mut self,
+        program: &Program,
+        id: ExprId,
+        keep_value: bool,
+    ) -> Result<(), String> {
+        let Expr::If {
+            condition,
+            then_branch,
+            else_branch,
+        } = program.expr(id)
+        else {
+            unreachable!("compile_if called on a non-if expression");
+        };
+        if keep_value && else_branch.is_none() {
+            return Err("'if' without 'else' cannot be used as a value".to_string());
+        }
+
+        self.compile_expression(program, *condition)?;
+        let jump_to_else = self.instructions.len();
+        self.push(Instruction::JumpIfFalse(0));
+        self.compile_block(program, then_branch, keep_value)?;
+        let jump_to_end = self.instructions.len();
+        self.push(Instruction::Jump(0));
+
+        let else_start = self.instructions.len();
+        if let Some(else_branch) = else_branch {
+            self.compile_block(program, else_branch, keep_value)?;
+        }
+        let end = self.instructions.len();
+        self.instructions[jump_to_else] = Instruction::JumpIfFalse(else_start);
+        self.instructions[jump_to_end] = Instruction::Jump(end);
+        Ok(())
+    }
+
+    /// Compiles a block's statements in a scope of their own. With
+    /// `keep_value` the last one must be an expression, and its value stays
+    /// on the stack.
+    fn compile_block(
+        &mut self,
+        program: &Program,
+        statements: &[Stmt],
+        keep_value: bool,
+    ) -> Result<(), String> {
+        self.in_block(|this| {
+            for (i, stmt) in statements.iter().enumerate() {
+                match stmt {
+                    Stmt::Expr(expr, line) if keep_value && i == statements.len() - 1 => {
+                        let enclosing = std::mem::replace(&mut this.statement_line, *line);
+                        this.compile_expression(program, *expr)?;
+                        this.statement_line = enclosing;
+                    }
+                    _ => this.compile_statement(program, stmt, false)?,
+                }
+            }
+            Ok(())
+        })?;
+        if keep_value && !matches!(statements.last(), Some(Stmt::Expr(..))) {
+            return Err("An 'if' branch used as a value must end with an expression".to_string());
+        }
+        Ok(())
+    }
+
+    /// `&&` and `||` only evaluate their right side when it decides the
+    /// result. Both sides must be booleans, and so is the result:
+    ///
+    /// ```text
+    /// left; JUMP_IF_FALSE short; right; JUMP_IF_FALSE short; PUSH true; JUMP end
+    /// short: PUSH false
+    /// end:
+    /// ```
+    ///
+    /// `||` is the same with `JUMP_IF_TRUE` and the pushed values swapped.
+    fn compile_logical(
+        &mut self,
+        program: &Program,
+        left: ExprId,
+        op: BinaryOp,
+        right: ExprId,
+    ) -> Result<(), String> {
+        let short_circuits_on = op == BinaryOp::Or;
+        let jump = |target| match short_circuits_on {
+            true => Instruction::JumpIfTrue(target),
+            false => Instruction::JumpIfFalse(target),
+        };
+
+        self.compile_expression(program, left)?;
+        let left_jump = self.instructions.len();
+        self.push(jump(0));
+        self.compile_expression(program, right)?;
+        let right_jump = self.instructions.len();
+        self.push(jump(0));
+        self.push(Instruction::Push(Value::Boolean(!short_circuits_on)));
+        let jump_to_end = self.instructions.len();
+        self.push(Instruction::Jump(0));
+
+        let short = self.instructions.len();
+        self.push(Instruction::Push(Value::Boolean(short_circuits_on)));
+        let end = self.instructions.len();
+        self.instructions[left_jump] = jump(short);
+        self.instructions[right_jump] = jump(short);
+        self.instructions[jump_to_end] = Instruction::Jump(end);
+        Ok(())
+    }
+
+    /// Index of a folded `const` value in the constant pool.
+    fn const_index(&mut self, value: Value) -> usize {
+        match value {
+            Value::Number(n) => self.constants.add_number(n),
+            Value::String(s) => self.constants.add_string(&Symbol::from(s.as_str())),
+            Value::Boolean(b) => self.constants.add_boolean(b),
+            _ => unreachable!("consts fold to numbers, strings and booleans"),
+        }
+    }
+
+    /// Pushes the value of a variable. Inside a function, top-level variables
+    /// are globals, which may also be declared after the function.
+    fn load_variable(&mut self, name: &Symbol) -> Result<(), String> {
+        match self.get_variable(name) {
+            Some((index, 0)) if self.depth > 0 => self.push(Instruction::LoadGlobal(index)),
+            Some((index, depth)) => {
+                if let Some(local) = self
+                    .locals
+                    .iter_mut()
+                    .rev()
+                    .find(|local| local.depth == depth && local.index == index)
+                {
+                    local.read = true;
+                }
+                self.push(Instruction::LoadVar(depth, index));
+            }
+            None if self.depth > 0 => {
+                self.unresolved_globals
+                    .push((self.instructions.len(), name.clone()));
+                self.push(Instruction::LoadGlobal(0));
+            }
+            None => return Err(format!("Undefined variable '{}'", name)),
+        }
+        Ok(())
+    }
+
+    fn get_or_create_variable_index(&mut self, name: &Symbol) -> VarOutput {
         if let Some((index, depth)) = self.get_variable(name) {
-            if depth == self.depth {
+            if depth == self.depth && self.in_current_scope(name) {
                 VarOutput::GotCurrentScope { index, depth }
             } else {
                 VarOutput::GotOuterScope { index, depth }
@@ -392,8 +2634,7 @@ impl Compiler {
     }
 
     fn push(&mut self, instr: Instruction) {
-        // We do not really care about line number in expressional contexts.
-        let line = self.current_line();
+        let line = self.statement_line;
         self.instructions.push(instr);
         self.instruction_lines.push(line);
     }
@@ -412,6 +2653,27 @@ impl fmt::Display for Instruction {
             Instruction::LoadVar(scope, idx) => write!(f, "LOAD_VAR {} {}", scope, idx),
             Instruction::LoadArg(idx) => write!(f, "LOAD_ARG {}", idx),
             Instruction::Call(idx) => write!(f, "CALL {}", idx),
+            Instruction::CallNative(idx, argc) => write!(f, "CALL_NATIVE {} {}", idx, argc),
+            Instruction::MakeClosure(idx, argc) => write!(f, "MAKE_CLOSURE {} {}", idx, argc),
+            Instruction::CallValue(argc) => write!(f, "CALL_VALUE {}", argc),
+            Instruction::CallMethod(method) => write!(f, "CALL_METHOD {}", method),
+            Instruction::Apply => write!(f, "APPLY"),
+            Instruction::Yield => write!(f, "YIELD"),
+            Instruction::Invoke(name, argc) => write!(f, "INVOKE {} {}", name, argc),
+            Instruction::DefineMethod(name) => write!(f, "DEFINE_METHOD {}", name),
+            Instruction::MakeRecord(count) => write!(f, "MAKE_RECORD {}", count),
+            Instruction::GetField(name) => write!(f, "GET_FIELD {}", name),
+            Instruction::MakeVariant(name, count) => write!(f, "MAKE_VARIANT {} {}", name, count),
+            Instruction::TestVariant(name) => write!(f, "TEST_VARIANT {}", name),
+            Instruction::MakeTuple(count) => write!(f, "MAKE_TUPLE {}", count),
+            Instruction::TestTuple(count) => write!(f, "TEST_TUPLE {}", count),
+            Instruction::TestList(count, at_least) => {
+                write!(f, "TEST_LIST {} {}", count, *at_least as u8)
+            }
+            Instruction::HasField(name) => write!(f, "HAS_FIELD {}", name),
+            Instruction::ListFrom(start) => write!(f, "LIST_FROM {}", start),
+            Instruction::LoadGlobal(idx) => write!(f, "LOAD_GLOBAL {}", idx),
+            Instruction::NoMatch => write!(f, "NO_MATCH"),
             Instruction::Return => write!(f, "RETURN"),
             Instruction::LoadConst(idx) => write!(f, "LOAD_CONST {}", idx),
             Instruction::Add => write!(f, "ADD"),
@@ -424,6 +2686,8 @@ impl fmt::Display for Instruction {
             Instruction::Not => write!(f, "NOT"),
             Instruction::CreateArray(size) => write!(f, "CREATE_ARRAY {}", size),
             Instruction::ConcatArray => write!(f, "CONCAT_ARRAY"),
+            Instruction::Concat => write!(f, "CONCAT"),
+            Instruction::ConcatN(count) => write!(f, "CONCAT_N {}", count),
             Instruction::Jump(addr) => write!(f, "JUMP {}", addr),
             Instruction::JumpIfFalse(addr) => write!(f, "JUMP_IF_FALSE {}", addr),
             Instruction::JumpIfTrue(addr) => write!(f, "JUMP_IF_TRUE {}", addr),
@@ -443,7 +2707,89 @@ impl fmt::Display for Value {
             Value::Function { params, offset } => {
                 write!(f, "fn({}) @{}", params.join(", "), offset)
             }
+            Value::Closure { function, bound } => {
+                let bound: Vec<String> = bound.iter().map(Value::to_string).collect();
+                write!(f, "closure {}({})", function, bound.join(", "))
+            }
             Value::HeapPointer(idx) => write!(f, "HEAP_POINTER {}", idx),
+            Value::Task(id) => write!(f, "task {}", id),
+            Value::Channel(id) => write!(f, "channel {}", id),
+            Value::Generator(id) => write!(f, "generator {}", id),
+        }
+    }
+}
+
+impl fmt::Display for HeapObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeapObject::String(s) => write!(f, "\"{}\"", s),
+            HeapObject::Number(n) => write!(f, "{}", n),
+            HeapObject::Boolean(b) => write!(f, "{}", b),
+            HeapObject::Null => write!(f, "null"),
+            HeapObject::Task(id) => write!(f, "task {}", id),
+            HeapObject::Channel(id) => write!(f, "channel {}", id),
+            HeapObject::Generator(id) => write!(f, "generator {}", id),
+            HeapObject::Opaque(opaque) => write!(f, "<{}>", opaque.type_name()),
+            HeapObject::Array(elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "]")
+            }
+            HeapObject::Variant { name, values } => {
+                write!(f, "{}", name)?;
+                if !values.is_empty() {
+                    write!(f, "(")?;
+                    for (i, value) in values.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "{}", value)?;
+                    }
+                    write!(f, ")")?;
+                }
+                Ok(())
+            }
+            HeapObject::Tuple(elements) => {
+                write!(f, "(")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                // `(1,)` rather than a parenthesized 1
+                if elements.len() == 1 {
+                    write!(f, ",")?;
+                }
+                write!(f, ")")
+            }
+            HeapObject::Record { type_name, fields } => {
+                write!(f, "{} {{", type_name)?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, " {} = {}", name, value)?;
+                }
+                write!(f, " }}")
+            }
+            HeapObject::Object(map) => {
+                let mut keys: Vec<_> = map.keys().collect();
+                keys.sort();
+                write!(f, "{{")?;
+                for (i, key) in keys.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key, map[*key])?;
+                }
+                write!(f, "}}")
+            }
         }
     }
 }
@@ -470,3 +2816,235 @@ impl fmt::Display for ByteCode {
         Ok(())
     }
 }
+
+#[cfg(feature = "fs")]
+fn read_module(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read module '{}': {}", path.display(), e))
+}
+
+#[cfg(not(feature = "fs"))]
+fn read_module(path: &Path) -> Result<String, String> {
+    Err(format!(
+        "Cannot read module '{}': file imports need the 'fs' feature",
+        path.display()
+    ))
+}
+
+#[cfg(feature = "fs")]
+fn read_module_bytes(path: &Path) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("Cannot read module '{}': {}", path.display(), e))
+}
+
+#[cfg(not(feature = "fs"))]
+fn read_module_bytes(path: &Path) -> Result<Vec<u8>, String> {
+    read_module(path).map(String::into_bytes)
+}
+
+/// An `if` without `else` in statement position: run for effect, no value.
+fn is_statement_if(program: &Program, expr: ExprId) -> bool {
+    matches!(
+        program.expr(expr),
+        Expr::If {
+            else_branch: None,
+            ..
+        }
+    )
+}
+
+/// Every identifier `expr` reads, in first-use order, without duplicates.
+fn collect_identifiers(program: &Program, expr: ExprId) -> Vec<Symbol> {
+    let mut identifiers = Identifiers(Vec::new());
+    identifiers.visit_expr(program, expr);
+    identifiers.0
+}
+
+struct Identifiers(Vec<Symbol>);
+
+impl Visitor for Identifiers {
+    fn visit_stmt(&mut self, program: &Program, stmt: &Stmt) {
+        if !is_declaration(stmt) {
+            walk_stmt(self, program, stmt);
+        }
+    }
+
+    fn visit_expr(&mut self, program: &Program, expr: ExprId) {
+        if let Expr::Identifier(name) = program.expr(expr)
+            && !self.0.contains(name)
+        {
+            self.0.push(name.clone());
+        }
+        walk_expr(self, program, expr);
+    }
+}
+
+/// Declarations nested in a block, which passes over the code around them
+/// leave alone.
+fn is_declaration(stmt: &Stmt) -> bool {
+    matches!(
+        stmt,
+        Stmt::Func { .. }
+            | Stmt::Const { .. }
+            | Stmt::Import { .. }
+            | Stmt::Extern { .. }
+            | Stmt::Struct { .. }
+            | Stmt::Impl { .. }
+            | Stmt::Trait { .. }
+            | Stmt::Enum { .. }
+    )
+}
+
+/// The operands of a chain of `++`, left to right.
+fn concat_parts(program: &Program, expr: ExprId, parts: &mut Vec<ExprId>) {
+    match program.expr(expr) {
+        Expr::Binary {
+            left,
+            op: BinaryOp::Concat,
+            right,
+        } => {
+            concat_parts(program, *left, parts);
+            concat_parts(program, *right, parts);
+        }
+        _ => parts.push(expr),
+    }
+}
+
+/// Names a pattern binds, in order.
+fn pattern_bindings(pattern: &Pattern, names: &mut Vec<Symbol>) {
+    match pattern {
+        Pattern::Wildcard | Pattern::Literal(_) => {}
+        Pattern::Binding(name) => names.push(name.clone()),
+        Pattern::Variant { fields, .. } | Pattern::Tuple(fields) => fields
+            .iter()
+            .for_each(|field| pattern_bindings(field, names)),
+        Pattern::List { elements, rest } => {
+            elements
+                .iter()
+                .for_each(|element| pattern_bindings(element, names));
+            names.extend(rest.iter().cloned());
+        }
+        Pattern::Record(fields) => fields
+            .iter()
+            .for_each(|(_, field)| pattern_bindings(field, names)),
+    }
+}
+
+/// Works out the value of a `const` from literals, other consts and
+/// operators, the way the VM would. Fails with the end of a sentence that
+/// starts with the const's name.
+fn fold_constant(
+    program: &Program,
+    consts: &HashMap<Symbol, Value>,
+    expr: ExprId,
+    options: &CompileOptions,
+) -> Result<Value, String> {
+    let fold = |id: &ExprId| fold_constant(program, consts, *id, options);
+    let wrong_types = |op: &str, a: &Value, b: &Value| {
+        format!(
+            "cannot apply '{}' to {} and {}",
+            op,
+            a.type_name_stack(),
+            b.type_name_stack()
+        )
+    };
+    let value = match program.expr(expr) {
+        Expr::Number(n) => Value::Number(*n),
+        Expr::String(s) => Value::String(s.to_string()),
+        Expr::Boolean(b) => Value::Boolean(*b),
+        Expr::Identifier(name) => consts
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("reads '{}', which is not a const", name))?,
+        Expr::Unary { op, right } => match (op, fold(right)?) {
+            (UnaryOp::Neg, Value::Number(n)) => Value::Number(-n),
+            (UnaryOp::Not, Value::Boolean(b)) => Value::Boolean(!b),
+            (UnaryOp::Neg, v) => return Err(format!("cannot negate a {}", v.type_name_stack())),
+            (UnaryOp::Not, v) => {
+                return Err(format!("cannot apply '!' to a {}", v.type_name_stack()));
+            }
+        },
+        Expr::Binary { left, op, right } => {
+            let (a, b) = (fold(left)?, fold(right)?);
+            match (op, &a, &b) {
+                (BinaryOp::Add, Value::Number(x), Value::Number(y)) => Value::Number(x + y),
+                (BinaryOp::Add, Value::String(x), Value::String(y)) if !options.strict_concat => {
+                    Value::String(format!("{}{}", x, y))
+                }
+                (BinaryOp::Sub, Value::Number(x), Value::Number(y)) => Value::Number(x - y),
+                (BinaryOp::Mul, Value::Number(x), Value::Number(y)) => Value::Number(x * y),
+                (BinaryOp::Div, Value::Number(_), Value::Number(y)) if *y == 0.0 => {
+                    return Err("divides by zero".to_string());
+                }
+                (BinaryOp::Div, Value::Number(x), Value::Number(y)) => Value::Number(x / y),
+                (BinaryOp::Concat, _, _) => {
+                    let text = |v: &Value| match v {
+                        Value::String(s) => s.clone(),
+                        other => other.to_string(),
+                    };
+                    Value::String(format!("{}{}", text(&a), text(&b)))
+                }
+                (BinaryOp::Eq, _, _) => Value::Boolean(a == b),
+                (BinaryOp::Ne, _, _) => Value::Boolean(a != b),
+                (BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge, _, _) => {
+                    let ordering = match (&a, &b) {
+                        (Value::Number(x), Value::Number(y)) => x.partial_cmp(y),
+                        (Value::String(x), Value::String(y)) => x.partial_cmp(y),
+                        _ => None,
+                    }
+                    .ok_or_else(|| wrong_types(op.symbol(), &a, &b))?;
+                    Value::Boolean(match op {
+                        BinaryOp::Lt => ordering.is_lt(),
+                        BinaryOp::Gt => ordering.is_gt(),
+                        BinaryOp::Le => ordering.is_le(),
+                        _ => ordering.is_ge(),
+                    })
+                }
+                (BinaryOp::And, Value::Boolean(x), Value::Boolean(y)) => Value::Boolean(*x && *y),
+                (BinaryOp::Or, Value::Boolean(x), Value::Boolean(y)) => Value::Boolean(*x || *y),
+                (op, a, b) => return Err(wrong_types(op.symbol(), a, b)),
+            }
+        }
+        _ => return Err("must be computable at compile time".to_string()),
+    };
+    Ok(value)
+}
+
+/// Whether a function body yields, making the function a generator. Lambdas
+/// and nested functions are not part of the body.
+fn yields(program: &Program, statements: &[Stmt]) -> bool {
+    let mut yields = Yields(false);
+    walk_stmts(&mut yields, program, statements);
+    yields.0
+}
+
+struct Yields(bool);
+
+impl Visitor for Yields {
+    fn visit_stmt(&mut self, program: &Program, stmt: &Stmt) {
+        if !self.0 && !is_declaration(stmt) {
+            walk_stmt(self, program, stmt);
+        }
+    }
+
+    fn visit_expr(&mut self, program: &Program, expr: ExprId) {
+        match program.expr(expr) {
+            Expr::Yield(_) => self.0 = true,
+            Expr::Lambda { .. } => {}
+            _ if !self.0 => walk_expr(self, program, expr),
+            _ => {}
+        }
+    }
+}
+
+/// Source text of a comparison operator, for assertion messages.
+fn comparison_symbol(op: BinaryOp) -> Option<&'static str> {
+    match op {
+        BinaryOp::Eq => Some("=="),
+        BinaryOp::Ne => Some("!="),
+        BinaryOp::Lt => Some("<"),
+        BinaryOp::Gt => Some(">"),
+        BinaryOp::Le => Some("<="),
+        BinaryOp::Ge => Some(">="),
+        _ => None,
+    }
+}
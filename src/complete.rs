@@ -0,0 +1,397 @@
+//! Completions for editors: the names that fit at a position of a source
+//! file, whether or not the file parses yet. Top-level declarations come from
+//! the compiler's first pass, `Compiler::declare`; local variables from the
+//! tokens before the position, as the parser cannot place an unfinished
+//! statement.
+
+use crate::compiler::Compiler;
+use crate::lexer::{Lexer, is_identifier_char};
+use crate::parser::Parser;
+use crate::types::compiler::Value;
+use crate::types::interner::Symbol;
+use crate::types::token::Token;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionKind {
+    Variable,
+    Function,
+    Module,
+    Type,
+    Variant,
+    Field,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    pub label: String,
+    pub kind: CompletionKind,
+    /// How the name is used, e.g. `add(a, b)` or `Shape::Circle(_)`.
+    pub detail: String,
+}
+
+impl Completion {
+    fn new(label: &str, kind: CompletionKind, detail: String) -> Self {
+        Completion {
+            label: label.to_string(),
+            kind,
+            detail,
+        }
+    }
+}
+
+/// Names that complete the word ending at byte `offset` of `source`, sorted
+/// by label:
+///
+/// - after `Enum::`, the enum's variants;
+/// - after `Module.`, the module's functions, or a struct's `impl` functions;
+/// - where a field name goes in `Struct { ... }` or `record <- { ... }`, the
+///   fields not given yet;
+/// - anywhere else, the variables in scope, functions, modules and types.
+pub fn completions(source: &str, offset: usize) -> Vec<Completion> {
+    let mut offset = offset.min(source.len());
+    while !source.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let word_start = source[..offset]
+        .char_indices()
+        .rev()
+        .take_while(|(_, ch)| is_identifier_char(*ch))
+        .last()
+        .map_or(offset, |(i, _)| i);
+    let word = &source[word_start..offset];
+    let before: Vec<Token> = Lexer::new(&source[..word_start]).collect();
+    let compiler = declarations(source, offset);
+    let scope = Scope::scan(&before);
+
+    let mut found = match before.as_slice() {
+        [.., Token::Identifier(name), Token::DoubleColon] => variants(&compiler, name),
+        [.., Token::Identifier(name), Token::Dot] => members(&compiler, name),
+        [.., Token::Dot | Token::DoubleColon] => Vec::new(),
+        _ => match record_type(&before, &scope) {
+            Some((type_name, given)) => fields(&compiler, &type_name, &given),
+            None => names(&compiler, &scope),
+        },
+    };
+    found.retain(|completion| completion.label.starts_with(word));
+    found.sort_by(|a, b| a.label.cmp(&b.label));
+    found.dedup_by(|a, b| a.label == b.label);
+    found
+}
+
+/// A compiler that has seen the program's declarations. An unfinished
+/// program often fails to parse only on the line being typed, so that line
+/// is left out when it does.
+fn declarations(source: &str, offset: usize) -> Compiler {
+    let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[offset..]
+        .find('\n')
+        .map_or(source.len(), |i| offset + i);
+    let without_line = format!("{}{}", &source[..line_start], &source[line_end..]);
+    let program = [source, without_line.as_str()]
+        .iter()
+        .find_map(|source| Parser::new(Lexer::new(source).tokenize()).parse().ok())
+        .unwrap_or_default();
+    let mut compiler = Compiler::new();
+    compiler.declare(&program);
+    compiler
+}
+
+fn names(compiler: &Compiler, scope: &Scope) -> Vec<Completion> {
+    let mut found = Vec::new();
+    for (name, params) in scope.frames.iter().flatten() {
+        if params.is_none() {
+            found.push(Completion::new(
+                name,
+                CompletionKind::Variable,
+                name.to_string(),
+            ));
+        }
+    }
+    for (name, index) in &compiler.functions {
+        if !name.contains('.') {
+            found.push(function(compiler, name, *index));
+        }
+    }
+    // Functions the compiler has not seen, such as those declared inside
+    // other functions
+    for (name, params) in scope.frames.iter().flatten() {
+        if let Some(params) = params {
+            found.push(Completion::new(
+                name,
+                CompletionKind::Function,
+                format!("{}({})", name, params.join(", ")),
+            ));
+        }
+    }
+    for native in compiler.natives.functions() {
+        match native.name.split_once('.') {
+            Some((module, _)) => found.push(Completion::new(
+                module,
+                CompletionKind::Module,
+                format!("module {}", module),
+            )),
+            None => found.push(native_function(&native.name, native.arity)),
+        }
+    }
+    for name in compiler.structs.keys() {
+        found.push(Completion::new(
+            name,
+            CompletionKind::Type,
+            format!("struct {}", name),
+        ));
+    }
+    for name in compiler.enums.keys() {
+        found.push(Completion::new(
+            name,
+            CompletionKind::Type,
+            format!("enum {}", name),
+        ));
+    }
+    found
+}
+
+fn variants(compiler: &Compiler, enum_name: &str) -> Vec<Completion> {
+    let Some(variants) = compiler.enums.get(enum_name) else {
+        return Vec::new();
+    };
+    variants
+        .iter()
+        .map(|(variant, field_count)| {
+            let detail = match field_count {
+                0 => format!("{}::{}", enum_name, variant),
+                n => format!("{}::{}({})", enum_name, variant, vec!["_"; *n].join(", ")),
+            };
+            Completion::new(variant, CompletionKind::Variant, detail)
+        })
+        .collect()
+}
+
+/// Functions of the module or struct `name`, called as `name.function()`.
+fn members(compiler: &Compiler, name: &str) -> Vec<Completion> {
+    let mut found = Vec::new();
+    for native in compiler.natives.functions() {
+        if let Some((module, function)) = native.name.split_once('.')
+            && module == name
+        {
+            let mut completion = native_function(&native.name, native.arity);
+            completion.label = function.to_string();
+            found.push(completion);
+        }
+    }
+    for (qualified, index) in &compiler.functions {
+        if let Some((type_name, function_name)) = qualified.split_once('.')
+            && type_name == name
+        {
+            let mut completion = function(compiler, qualified, *index);
+            completion.label = function_name.to_string();
+            found.push(completion);
+        }
+    }
+    found
+}
+
+fn fields(compiler: &Compiler, type_name: &str, given: &[Symbol]) -> Vec<Completion> {
+    let Some(fields) = compiler.structs.get(type_name) else {
+        return Vec::new();
+    };
+    fields
+        .iter()
+        .filter(|field| !given.contains(field))
+        .map(|field| {
+            Completion::new(
+                field,
+                CompletionKind::Field,
+                format!("{}.{}", type_name, field),
+            )
+        })
+        .collect()
+}
+
+fn function(compiler: &Compiler, name: &str, index: usize) -> Completion {
+    let params = match compiler.function_table.get(index) {
+        Some(Value::Function { params, .. }) => params.join(", "),
+        _ => String::new(),
+    };
+    Completion::new(
+        name,
+        CompletionKind::Function,
+        format!("{}({})", name, params),
+    )
+}
+
+fn native_function(name: &str, arity: Option<usize>) -> Completion {
+    let params = arity.map_or("...".to_string(), |n| vec!["_"; n].join(", "));
+    Completion::new(
+        name,
+        CompletionKind::Function,
+        format!("{}({})", name, params),
+    )
+}
+
+/// The struct of the record literal or update whose field names the end of
+/// `tokens` is at, with the fields it already gives.
+fn record_type(tokens: &[Token], scope: &Scope) -> Option<(Symbol, Vec<Symbol>)> {
+    if !matches!(
+        tokens.last(),
+        Some(Token::LeftBrace | Token::Comma | Token::Newline)
+    ) {
+        return None;
+    }
+    // The innermost `{` still open
+    let mut depth = 0;
+    let open = (0..tokens.len()).rev().find(|&i| {
+        match tokens[i] {
+            Token::RightBrace => depth += 1,
+            Token::LeftBrace if depth == 0 => return true,
+            Token::LeftBrace => depth -= 1,
+            _ => {}
+        }
+        false
+    })?;
+    let type_name = match &tokens[..open] {
+        [.., Token::Identifier(name)] => name.clone(),
+        [.., Token::Identifier(record), Token::Update] => scope.types.get(record)?.clone(),
+        _ => return None,
+    };
+    let given = tokens[open..]
+        .windows(2)
+        .filter_map(|pair| match pair {
+            [Token::Identifier(field), Token::Assign] => Some(field.clone()),
+            _ => None,
+        })
+        .collect();
+    Some((type_name, given))
+}
+
+/// Variables declared before a position, by the blocks still open there.
+struct Scope {
+    /// Names declared in each open block, outermost first, with the
+    /// parameters of those that are functions.
+    frames: Vec<Vec<(Symbol, Option<Vec<Symbol>>)>>,
+    /// The struct of each variable bound to a record literal.
+    types: HashMap<Symbol, Symbol>,
+}
+
+impl Scope {
+    fn scan(tokens: &[Token]) -> Self {
+        let mut scope = Scope {
+            frames: vec![Vec::new()],
+            types: HashMap::new(),
+        };
+        // Parameters and loop variables, declared by the next `{`
+        let mut pending = Vec::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            match &tokens[i] {
+                Token::LeftBrace => {
+                    let names = std::mem::take(&mut pending);
+                    scope.frames.push(Vec::new());
+                    scope.declare(names);
+                }
+                Token::RightBrace if scope.frames.len() > 1 => {
+                    scope.frames.pop();
+                }
+                Token::Let | Token::LetBang | Token::Const => {
+                    let end = assignment(tokens, i + 1);
+                    let names = identifiers(&tokens[i + 1..end]);
+                    if let [name] = names.as_slice()
+                        && let Some(Token::Identifier(type_name)) = tokens.get(end + 1)
+                        && tokens.get(end + 2) == Some(&Token::LeftBrace)
+                    {
+                        scope.types.insert(name.clone(), type_name.clone());
+                    }
+                    scope.declare(names);
+                    i = end;
+                }
+                Token::Func => {
+                    let end = closing_paren(tokens, i + 2);
+                    pending = identifiers(tokens.get(i + 2..end).unwrap_or_default());
+                    if let Some(Token::Identifier(name)) = tokens.get(i + 1)
+                        && let Some(frame) = scope.frames.last_mut()
+                    {
+                        frame.push((name.clone(), Some(pending.clone())));
+                    }
+                    i = end;
+                }
+                Token::Fn => {
+                    // A lambda's body is an expression, so its parameters
+                    // stay until the enclosing block ends
+                    let end = closing_paren(tokens, i + 1);
+                    let params = identifiers(tokens.get(i + 1..end).unwrap_or_default());
+                    scope.declare(params);
+                    i = end;
+                }
+                Token::For => {
+                    if let Some(Token::Identifier(name)) = tokens.get(i + 1) {
+                        pending.push(name.clone());
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        scope
+    }
+
+    fn declare(&mut self, names: Vec<Symbol>) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.extend(names.into_iter().map(|name| (name, None)));
+        }
+    }
+}
+
+/// Position of the `=` ending the pattern that starts at `start`, or of the
+/// last token.
+fn assignment(tokens: &[Token], start: usize) -> usize {
+    let mut depth = 0usize;
+    for (i, token) in tokens.iter().enumerate().skip(start) {
+        match token {
+            Token::LeftParen | Token::LeftBracket | Token::LeftBrace => depth += 1,
+            Token::RightParen | Token::RightBracket | Token::RightBrace => {
+                depth = depth.saturating_sub(1)
+            }
+            Token::Assign if depth == 0 => return i,
+            Token::Newline | Token::Semicolon if depth == 0 => return i,
+            _ => {}
+        }
+    }
+    tokens.len()
+}
+
+/// Position of the `)` closing the parameter list that starts at `start`.
+/// Without one, the position before the `{` or line break that comes
+/// first, or of the last token.
+fn closing_paren(tokens: &[Token], start: usize) -> usize {
+    let end =
+        tokens.iter().enumerate().skip(start).find(|(_, token)| {
+            matches!(token, Token::RightParen | Token::LeftBrace | Token::Newline)
+        });
+    match end {
+        Some((i, Token::RightParen)) => i,
+        Some((i, _)) => i - 1,
+        None => tokens.len(),
+    }
+}
+
+/// The names bound among `tokens`: identifiers except default values,
+/// right after `=`, and the field names of record patterns, `{ field = p }`.
+fn identifiers(tokens: &[Token]) -> Vec<Symbol> {
+    let mut braces = 0usize;
+    let mut names = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::LeftBrace => braces += 1,
+            Token::RightBrace => braces = braces.saturating_sub(1),
+            Token::Identifier(name) => {
+                let default = i > 0 && tokens[i - 1] == Token::Assign;
+                let field = braces > 0 && tokens.get(i + 1) == Some(&Token::Assign);
+                if !default && !field {
+                    names.push(name.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+    names
+}
@@ -0,0 +1,71 @@
+//! Line coverage of scripts, written as an lcov tracefile that tools such as
+//! `genhtml` and most CI services read.
+//!
+//! ```text
+//! SF:src/main.n
+//! DA:1,1
+//! DA:4,0
+//! LF:2
+//! LH:1
+//! end_of_record
+//! ```
+
+use std::collections::BTreeMap;
+
+/// Run counts of lines, by file, added up over any number of runs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    files: BTreeMap<String, BTreeMap<usize, u64>>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the counts of one run of `file`, as `VirtualMachine::line_hits`
+    /// gives them.
+    pub fn add(&mut self, file: &str, hits: &BTreeMap<usize, u64>) {
+        let lines = self.files.entry(file.to_string()).or_default();
+        for (&line, &count) in hits {
+            *lines.entry(line).or_insert(0) += count;
+        }
+    }
+
+    /// Run counts of the lines of `file`, if any run of it was added.
+    pub fn lines(&self, file: &str) -> Option<&BTreeMap<usize, u64>> {
+        self.files.get(file)
+    }
+
+    /// Lines that ran and lines that could have, over every file.
+    pub fn totals(&self) -> (usize, usize) {
+        let lines = self.files.values().flat_map(|lines| lines.values());
+        let (hit, found) = lines.fold((0, 0), |(hit, found), &count| {
+            (hit + usize::from(count > 0), found + 1)
+        });
+        (hit, found)
+    }
+
+    /// One line to print after a run, e.g. `Coverage: 12/15 lines (80.0%)`.
+    pub fn summary(&self) -> String {
+        let (hit, found) = self.totals();
+        let percent = match found {
+            0 => 100.0,
+            _ => hit as f64 * 100.0 / found as f64,
+        };
+        format!("Coverage: {}/{} lines ({:.1}%)", hit, found, percent)
+    }
+
+    pub fn to_lcov(&self) -> String {
+        let mut out = String::new();
+        for (file, lines) in &self.files {
+            out.push_str(&format!("SF:{}\n", file));
+            for (line, count) in lines {
+                out.push_str(&format!("DA:{},{}\n", line, count));
+            }
+            let hit = lines.values().filter(|&&count| count > 0).count();
+            out.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), hit));
+        }
+        out
+    }
+}
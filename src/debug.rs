@@ -1,14 +1,41 @@
+//! Dumps of each stage's output for `n`'s debug mode, written to whatever
+//! sink the caller picks rather than stdout, so they never mix with a
+//! program's own output.
+
+use crate::types::compiler::ByteCode;
 use crate::types::token::Token;
+use std::io::{self, Write};
 
-pub fn print_tokens(tokens: &[Token]) {
-    println!("=== LEXED TOKENS ===");
+pub fn write_tokens(out: &mut dyn Write, tokens: &[Token]) -> io::Result<()> {
+    writeln!(out, "=== LEXED TOKENS ===")?;
     for (i, token) in tokens.iter().enumerate() {
-        println!("{:3}: {:?}", i, token);
+        writeln!(out, "{:3}: {:?}", i, token)?;
+    }
+    writeln!(out, "===================")
+}
+
+pub fn write_bytecode(out: &mut dyn Write, bytecode: &ByteCode) -> io::Result<()> {
+    writeln!(out, "--- Bytecode ---\n")?;
+    if !bytecode.functions.is_empty() {
+        writeln!(out, "--- Functions ---")?;
+        for function in bytecode.functions.iter() {
+            writeln!(out, "{}", function)?;
+        }
+    }
+    if !bytecode.constants.is_empty() {
+        writeln!(out, "--- Constants ---")?;
+        for constant in bytecode.constants.iter() {
+            writeln!(out, "{}", constant)?;
+        }
+    }
+    writeln!(out, "--- Instructions ---")?;
+    for instruction in bytecode.instructions.iter() {
+        writeln!(out, "{}", instruction)?;
     }
-    println!("===================");
+    Ok(())
 }
 
-pub fn print_token_summary(tokens: &[Token]) {
+pub fn write_token_summary(out: &mut dyn Write, tokens: &[Token]) -> io::Result<()> {
     let mut counts = std::collections::HashMap::new();
 
     for token in tokens {
@@ -19,6 +46,7 @@ pub fn print_token_summary(tokens: &[Token]) {
             Token::True => "True",
             Token::False => "False",
             Token::Let => "Let",
+            Token::Const => "Const",
             Token::LetBang => "LetBang",
             Token::Func => "Func",
             Token::Fn => "Fn",
@@ -30,7 +58,15 @@ pub fn print_token_summary(tokens: &[Token]) {
             Token::Return => "Return",
             Token::Async => "Async",
             Token::Await => "Await",
+            Token::For => "For",
+            Token::In => "In",
+            Token::Yield => "Yield",
+            Token::Struct => "Struct",
+            Token::Impl => "Impl",
+            Token::Trait => "Trait",
+            Token::Extern => "Extern",
             Token::Plus => "Plus",
+            Token::PlusPlus => "PlusPlus",
             Token::Minus => "Minus",
             Token::Multiply => "Multiply",
             Token::Divide => "Divide",
@@ -48,6 +84,7 @@ pub fn print_token_summary(tokens: &[Token]) {
             Token::Pipeline => "Pipeline",
             Token::Update => "Update",
             Token::DoubleColon => "DoubleColon",
+            Token::Colon => "Colon",
             Token::LeftParen => "LeftParen",
             Token::RightParen => "RightParen",
             Token::LeftBrace => "LeftBrace",
@@ -56,18 +93,24 @@ pub fn print_token_summary(tokens: &[Token]) {
             Token::RightBracket => "RightBracket",
             Token::Comma => "Comma",
             Token::Dot => "Dot",
+            Token::Spread => "Spread",
             Token::Arrow => "Arrow",
             Token::FatArrow => "FatArrow",
             Token::Hash => "Hash",
+            Token::At => "At",
+            Token::Semicolon => "Semicolon",
+            Token::DocComment(_) => "DocComment",
+            Token::ModuleDoc(_) => "ModuleDoc",
+            Token::Error(_) => "Error",
             Token::Newline => "Newline",
             Token::Eof => "Eof",
         };
         *counts.entry(token_type).or_insert(0) += 1;
     }
 
-    println!("=== TOKEN SUMMARY ===");
+    writeln!(out, "=== TOKEN SUMMARY ===")?;
     for (token_type, count) in counts {
-        println!("{}: {}", token_type, count);
+        writeln!(out, "{}: {}", token_type, count)?;
     }
-    println!("====================");
+    writeln!(out, "====================")
 }
@@ -0,0 +1,291 @@
+//! Errors and warnings as the CLI shows them: a code, the message, and the
+//! source line it is about with the offending name underlined.
+//!
+//! ```text
+//! error[E0002]: Undefined variable 'x'
+//!  --> main.n:3:9
+//!   |
+//! 3 | let y = x + 1
+//!   |         ^
+//! ```
+//!
+//! With `MessageFormat::Json` each diagnostic is instead one line of JSON,
+//! for editors and CI systems:
+//!
+//! ```text
+//! {"code":"E0002","file":"main.n","message":"Undefined variable 'x'","notes":[...],"severity":"error","span":{"column":9,"length":1,"line":3}}
+//! ```
+
+use crate::lexer::is_identifier_char;
+use crate::stdlib::json;
+use crate::types::compiler::{HeapObject, Warning};
+use std::collections::HashMap;
+use std::io::IsTerminal;
+
+/// Every code a diagnostic can have, with what it means.
+pub const CODES: [(&str, &str); 8] = [
+    ("E0001", "the source does not parse"),
+    ("E0002", "a name that nothing declares is read"),
+    ("E0003", "a name is declared twice in the same scope"),
+    ("E0004", "the program does not compile"),
+    ("E0005", "the program stopped with an error while running"),
+    (
+        "E0006",
+        "the program compiled with warnings while they are denied",
+    ),
+    ("W0001", "a `@deprecated` function or enum is used"),
+    ("W0002", "a variable is never read"),
+];
+
+/// How diagnostics are printed, see `n --message-format=json`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum MessageFormat {
+    /// `Diagnostic::render`, in color on a terminal.
+    #[default]
+    Human,
+    /// `Diagnostic::to_json`, one record per line.
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub level: Level,
+    /// One of `CODES`.
+    pub code: &'static str,
+    pub message: String,
+    pub line: Option<usize>,
+}
+
+impl Diagnostic {
+    /// Reads an error as `runtime::run_file` returns it, `"Parse error: ..."`,
+    /// `"Compile error: ..."` or `"Runtime error: ..."`, taking the line from
+    /// its message. Other errors, like a missing file, give `None`.
+    pub fn from_error(error: &str) -> Option<Self> {
+        let (code, message) = if let Some(message) = error.strip_prefix("Parse error: ") {
+            ("E0001", message)
+        } else if let Some(message) = error.strip_prefix("Compile error: ") {
+            let code = if message.starts_with("Warnings are denied") {
+                "E0006"
+            } else if message.contains("Undefined variable") || message.contains("Undefined global")
+            {
+                "E0002"
+            } else if message.contains("already defined") {
+                "E0003"
+            } else {
+                "E0004"
+            };
+            (code, message)
+        } else if let Some(message) = error.strip_prefix("Runtime error: ") {
+            ("E0005", message)
+        } else {
+            return None;
+        };
+        let (message, line) = split_line(message);
+        Some(Diagnostic {
+            level: Level::Error,
+            code,
+            message: message.to_string(),
+            // The line of an error in an imported module is not in this file
+            line: line.filter(|_| !message.starts_with("In module '")),
+        })
+    }
+
+    pub fn from_warning(warning: &Warning) -> Self {
+        Diagnostic {
+            level: Level::Warning,
+            code: match warning.name {
+                "deprecated" => "W0001",
+                _ => "W0002",
+            },
+            message: warning.message.clone(),
+            line: Some(warning.line),
+        }
+    }
+
+    /// The diagnostic with the line of `source` it is about, if any, named
+    /// as `filename`. With `color`, uses terminal colors.
+    pub fn render(&self, source: &str, filename: &str, color: bool) -> String {
+        let paint = |style: &str, text: &str| match color {
+            true => format!("\x1b[{}m{}\x1b[0m", style, text),
+            false => text.to_string(),
+        };
+        let level_style = match self.level {
+            Level::Error => "1;31",
+            Level::Warning => "1;33",
+        };
+        let level = match self.level {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        };
+        let mut out = format!(
+            "{}{}",
+            paint(level_style, &format!("{}[{}]", level, self.code)),
+            paint("1", &format!(": {}", self.message))
+        );
+        let Some((line, text)) = self
+            .line
+            .and_then(|line| Some((line, source.lines().nth(line.checked_sub(1)?)?)))
+        else {
+            if let Some(line) = self.line {
+                out.push_str(&format!(
+                    "\n {} {}:{}",
+                    paint("1;34", "-->"),
+                    filename,
+                    line
+                ));
+            }
+            return out;
+        };
+
+        let (start, len) = self.underline(text);
+        let number = line.to_string();
+        let gutter = " ".repeat(number.len());
+        let bar = paint("1;34", "|");
+        out.push_str(&format!(
+            "\n{}{} {}:{}:{}",
+            gutter,
+            paint("1;34", "-->"),
+            filename,
+            line,
+            text[..start].chars().count() + 1
+        ));
+        out.push_str(&format!("\n{} {}", gutter, bar));
+        out.push_str(&format!("\n{} {} {}", paint("1;34", &number), bar, text));
+        let padding: String = text[..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        out.push_str(&format!(
+            "\n{} {} {}{}",
+            gutter,
+            bar,
+            padding,
+            paint(level_style, &"^".repeat(len.max(1)))
+        ));
+        out
+    }
+
+    /// The diagnostic as a JSON object with its `file`, `severity`, `code`,
+    /// `message`, `notes` and `span`. The span has the 1-based `line`, and
+    /// the `column` and `length` in characters of what `render` underlines
+    /// when the line is in `source`; it is `null` for diagnostics without a
+    /// line.
+    pub fn to_json(&self, source: &str, filename: &str) -> String {
+        let span = self.line.map(|line| {
+            let mut span = HashMap::from([("line".to_string(), HeapObject::from(line as f64))]);
+            if let Some(text) = source.lines().nth(line.saturating_sub(1)) {
+                let (start, len) = self.underline(text);
+                let column = text[..start].chars().count() + 1;
+                span.insert("column".to_string(), (column as f64).into());
+                span.insert("length".to_string(), (len.max(1) as f64).into());
+            }
+            span
+        });
+        let notes = CODES
+            .iter()
+            .filter(|(code, _)| *code == self.code)
+            .map(|(code, meaning)| format!("{} means {}", code, meaning))
+            .collect::<Vec<_>>();
+        record(
+            filename,
+            self.level,
+            Some(self.code),
+            &self.message,
+            notes,
+            span,
+        )
+    }
+
+    /// `render` or `to_json`, as `format` asks.
+    pub fn format(&self, source: &str, filename: &str, format: MessageFormat) -> String {
+        match format {
+            MessageFormat::Human => self.render(source, filename, use_color()),
+            MessageFormat::Json => self.to_json(source, filename),
+        }
+    }
+
+    /// Byte offset and width in characters of what to underline in `text`:
+    /// the first name quoted in the message that the line contains as a
+    /// word, or else the whole line without its indentation.
+    fn underline(&self, text: &str) -> (usize, usize) {
+        let quoted = self.message.split('\'').skip(1).step_by(2);
+        for name in quoted {
+            if name.is_empty() {
+                continue;
+            }
+            let word = text.match_indices(name).find(|&(at, _)| {
+                let before = text[..at].chars().next_back();
+                let after = text[at + name.len()..].chars().next();
+                !before.is_some_and(is_identifier_char) && !after.is_some_and(is_identifier_char)
+            });
+            if let Some((at, _)) = word {
+                return (at, name.chars().count());
+            }
+        }
+        let trimmed = text.trim();
+        let start = text.len() - text.trim_start().len();
+        (start, trimmed.chars().count())
+    }
+}
+
+/// The JSON record of an error that is not about a line of the source, such
+/// as a file that cannot be read, with a `null` code and span.
+pub fn error_json(filename: &str, message: &str) -> String {
+    record(filename, Level::Error, None, message, Vec::new(), None)
+}
+
+fn record(
+    filename: &str,
+    level: Level,
+    code: Option<&str>,
+    message: &str,
+    notes: Vec<String>,
+    span: Option<HashMap<String, HeapObject>>,
+) -> String {
+    let severity = match level {
+        Level::Error => "error",
+        Level::Warning => "warning",
+    };
+    let record = HashMap::from([
+        ("file".to_string(), HeapObject::from(filename)),
+        ("severity".to_string(), severity.into()),
+        ("code".to_string(), code.into()),
+        ("message".to_string(), message.into()),
+        ("notes".to_string(), notes.into()),
+        ("span".to_string(), span.into()),
+    ]);
+    json::stringify(&HeapObject::from(record))
+}
+
+/// Whether diagnostics printed to stderr should use colors: it is a
+/// terminal and `NO_COLOR` is not set.
+pub fn use_color() -> bool {
+    std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
+
+/// `message` without the line number it mentions, `[line N] ...` as runtime
+/// errors start or `... at line N` and `... (line N)` as the parser and
+/// compiler end theirs, and that line.
+fn split_line(message: &str) -> (&str, Option<usize>) {
+    if let Some(rest) = message.strip_prefix("[line ")
+        && let Some((number, rest)) = rest.split_once("] ")
+        && let Ok(line) = number.parse()
+    {
+        return (rest, Some(line));
+    }
+    for (prefix, suffix) in [(" at line ", ""), (" (line ", ")")] {
+        if let Some(rest) = message.strip_suffix(suffix)
+            && let Some((rest, number)) = rest.rsplit_once(prefix)
+            && let Ok(line) = number.parse()
+        {
+            return (rest, Some(line));
+        }
+    }
+    (message, None)
+}
@@ -0,0 +1,147 @@
+//! The form of the bytecode the VM dispatches on. Each `Instruction` is
+//! decoded once, when the program is loaded, into an `Op` that is `Copy`, so
+//! a step reads its operands without cloning the instruction.
+//!
+//! Hot sequences are fused into superinstructions that do the work of
+//! several instructions in one dispatch. A fused op takes the place of the
+//! first instruction of its sequence and the others are decoded as usual,
+//! so positions stay the same and a jump into the middle of a sequence still
+//! runs the rest of it one instruction at a time.
+
+use crate::types::compiler::Instruction;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    StoreVar(usize, usize),
+    LoadVar(usize, usize),
+    LoadArg(usize),
+    Call(usize),
+    Return,
+    LoadConst(usize),
+    CallNative(usize, usize),
+    MakeClosure(usize, usize),
+    CallValue(usize),
+    CallMethod(usize),
+    Apply,
+    Yield,
+    Invoke(usize, usize),
+    DefineMethod(usize),
+    NoMatch,
+    Add,
+    Sub,
+    Div,
+    Mul,
+    Equal,
+    Less,
+    Greater,
+    Not,
+    CreateArray(usize),
+    ConcatArray,
+    Concat,
+    MakeRecord(usize),
+    GetField(usize),
+    MakeVariant(usize, usize),
+    TestVariant(usize),
+    MakeTuple(usize),
+    Jump(usize),
+    JumpIfFalse(usize),
+    JumpIfTrue(usize),
+    Pop,
+    /// The value stays in the `Instruction`, to be cloned onto the stack.
+    Push,
+    Dup,
+    Halt,
+    TestTuple(usize),
+    TestList(usize, bool),
+    HasField(usize),
+    ListFrom(usize),
+    LoadGlobal(usize),
+    ConcatN(usize),
+    /// `LOAD_CONST c; STORE_VAR _ v`, as `let v = c` compiles.
+    LoadConstStore(usize, usize),
+    /// `LOAD_VAR d v; LOAD_CONST c; ADD`, as `v + c` compiles.
+    LoadVarConstAdd(usize, usize, usize),
+}
+
+impl Op {
+    /// How many instructions the op stands for.
+    pub fn width(&self) -> usize {
+        match self {
+            Op::LoadConstStore(..) => 2,
+            Op::LoadVarConstAdd(..) => 3,
+            _ => 1,
+        }
+    }
+}
+
+/// Decodes `instructions`, fusing the sequences that have a superinstruction.
+pub fn decode(instructions: &[Instruction]) -> Vec<Op> {
+    (0..instructions.len())
+        .map(|at| fuse(&instructions[at..]).unwrap_or_else(|| decode_one(&instructions[at])))
+        .collect()
+}
+
+/// The superinstruction `instructions` starts with, if any.
+fn fuse(instructions: &[Instruction]) -> Option<Op> {
+    match instructions {
+        [Instruction::LoadConst(c), Instruction::StoreVar(_, v), ..] => {
+            Some(Op::LoadConstStore(*c, *v))
+        }
+        [
+            Instruction::LoadVar(d, v),
+            Instruction::LoadConst(c),
+            Instruction::Add,
+            ..,
+        ] => Some(Op::LoadVarConstAdd(*d, *v, *c)),
+        _ => None,
+    }
+}
+
+fn decode_one(instruction: &Instruction) -> Op {
+    match *instruction {
+        Instruction::StoreVar(depth, index) => Op::StoreVar(depth, index),
+        Instruction::LoadVar(depth, index) => Op::LoadVar(depth, index),
+        Instruction::LoadArg(count) => Op::LoadArg(count),
+        Instruction::Call(function) => Op::Call(function),
+        Instruction::Return => Op::Return,
+        Instruction::LoadConst(index) => Op::LoadConst(index),
+        Instruction::CallNative(native, count) => Op::CallNative(native, count),
+        Instruction::MakeClosure(function, count) => Op::MakeClosure(function, count),
+        Instruction::CallValue(count) => Op::CallValue(count),
+        Instruction::CallMethod(method) => Op::CallMethod(method),
+        Instruction::Apply => Op::Apply,
+        Instruction::Yield => Op::Yield,
+        Instruction::Invoke(name, count) => Op::Invoke(name, count),
+        Instruction::DefineMethod(name) => Op::DefineMethod(name),
+        Instruction::NoMatch => Op::NoMatch,
+        Instruction::Add => Op::Add,
+        Instruction::Sub => Op::Sub,
+        Instruction::Div => Op::Div,
+        Instruction::Mul => Op::Mul,
+        Instruction::Equal => Op::Equal,
+        Instruction::Less => Op::Less,
+        Instruction::Greater => Op::Greater,
+        Instruction::Not => Op::Not,
+        Instruction::CreateArray(count) => Op::CreateArray(count),
+        Instruction::ConcatArray => Op::ConcatArray,
+        Instruction::Concat => Op::Concat,
+        Instruction::MakeRecord(count) => Op::MakeRecord(count),
+        Instruction::GetField(name) => Op::GetField(name),
+        Instruction::MakeVariant(name, count) => Op::MakeVariant(name, count),
+        Instruction::TestVariant(name) => Op::TestVariant(name),
+        Instruction::MakeTuple(count) => Op::MakeTuple(count),
+        Instruction::Jump(to) => Op::Jump(to),
+        Instruction::JumpIfFalse(to) => Op::JumpIfFalse(to),
+        Instruction::JumpIfTrue(to) => Op::JumpIfTrue(to),
+        Instruction::Pop => Op::Pop,
+        Instruction::Push(_) => Op::Push,
+        Instruction::Dup => Op::Dup,
+        Instruction::Halt => Op::Halt,
+        Instruction::TestTuple(count) => Op::TestTuple(count),
+        Instruction::TestList(count, open) => Op::TestList(count, open),
+        Instruction::HasField(name) => Op::HasField(name),
+        Instruction::ListFrom(from) => Op::ListFrom(from),
+        Instruction::LoadGlobal(index) => Op::LoadGlobal(index),
+        Instruction::ConcatN(count) => Op::ConcatN(count),
+    }
+}
@@ -0,0 +1,260 @@
+use crate::types::ast::{Expr, ExprId, Program, Stmt, UnaryOp};
+use crate::types::interner::Symbol;
+
+/// One documented declaration of a module.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocItem {
+    /// The declaration as it is written, without its body: `area(w, h)`.
+    pub signature: String,
+    pub doc: Option<String>,
+    /// Functions of the type's `impl` blocks, for structs and enums.
+    pub methods: Vec<DocItem>,
+}
+
+/// The public API of a module: its top-level functions, types and consts,
+/// in source order. Names starting with `_` are private and left out.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModuleDoc {
+    pub name: String,
+    pub doc: Option<String>,
+    pub functions: Vec<DocItem>,
+    pub types: Vec<DocItem>,
+    pub consts: Vec<DocItem>,
+}
+
+impl ModuleDoc {
+    pub fn new(name: &str, program: &Program) -> Self {
+        let mut module = Self {
+            name: name.to_string(),
+            doc: program.doc.clone(),
+            ..Self::default()
+        };
+        for stmt in &program.statements {
+            match stmt {
+                Stmt::Func {
+                    name,
+                    params,
+                    defaults,
+                    variadic,
+                    doc,
+                    ..
+                } if is_public(name) => module.functions.push(DocItem {
+                    signature: function_signature(program, name, params, defaults, *variadic),
+                    doc: doc.clone(),
+                    methods: Vec::new(),
+                }),
+                Stmt::Struct {
+                    name, fields, doc, ..
+                } if is_public(name) => module.types.push(DocItem {
+                    signature: format!("struct {} {{ {} }}", name, join(fields)),
+                    doc: doc.clone(),
+                    methods: Vec::new(),
+                }),
+                Stmt::Enum {
+                    name,
+                    variants,
+                    doc,
+                    ..
+                } if is_public(name) => {
+                    let variants: Vec<String> = variants
+                        .iter()
+                        .map(|(variant, fields)| match fields.is_empty() {
+                            true => variant.to_string(),
+                            false => format!("{}({})", variant, join(fields)),
+                        })
+                        .collect();
+                    module.types.push(DocItem {
+                        signature: format!("enum {} {{ {} }}", name, variants.join(", ")),
+                        doc: doc.clone(),
+                        methods: Vec::new(),
+                    });
+                }
+                Stmt::Const { name, doc, .. } if is_public(name) => module.consts.push(DocItem {
+                    signature: format!("const {}", name),
+                    doc: doc.clone(),
+                    methods: Vec::new(),
+                }),
+                _ => {}
+            }
+        }
+        for stmt in &program.statements {
+            let Stmt::Impl {
+                name,
+                methods,
+                constants,
+                ..
+            } = stmt
+            else {
+                continue;
+            };
+            let Some(item) = module.types.iter_mut().find(|item| {
+                item.signature
+                    .split_whitespace()
+                    .nth(1)
+                    .is_some_and(|type_name| type_name == &**name)
+            }) else {
+                continue;
+            };
+            for method in constants.iter().chain(methods) {
+                if let Stmt::Func {
+                    name,
+                    params,
+                    defaults,
+                    variadic,
+                    doc,
+                    ..
+                } = method
+                    && is_public(name.rsplit(['.', ':']).next().unwrap_or(name))
+                {
+                    let signature = match params.is_empty() && name.contains("::") {
+                        true => name.to_string(),
+                        false => function_signature(program, name, params, defaults, *variadic),
+                    };
+                    item.methods.push(DocItem {
+                        signature,
+                        doc: doc.clone(),
+                        methods: Vec::new(),
+                    });
+                }
+            }
+        }
+        module
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut out = format!("# {}\n", self.name);
+        if let Some(doc) = &self.doc {
+            out.push_str(&format!("\n{}\n", doc));
+        }
+        for (title, items) in self.sections() {
+            out.push_str(&format!("\n## {}\n", title));
+            for item in items {
+                out.push_str(&format!("\n### `{}`\n", item.signature));
+                if let Some(doc) = &item.doc {
+                    out.push_str(&format!("\n{}\n", doc));
+                }
+                for method in &item.methods {
+                    out.push_str(&format!("\n#### `{}`\n", method.signature));
+                    if let Some(doc) = &method.doc {
+                        out.push_str(&format!("\n{}\n", doc));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut out = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n</head>\n<body>\n<h1>{0}</h1>\n",
+            escape_html(&self.name)
+        );
+        if let Some(doc) = &self.doc {
+            out.push_str(&paragraphs_html(doc));
+        }
+        for (title, items) in self.sections() {
+            out.push_str(&format!("<h2>{}</h2>\n", title));
+            for item in items {
+                out.push_str(&format!(
+                    "<h3><code>{}</code></h3>\n",
+                    escape_html(&item.signature)
+                ));
+                if let Some(doc) = &item.doc {
+                    out.push_str(&paragraphs_html(doc));
+                }
+                for method in &item.methods {
+                    out.push_str(&format!(
+                        "<h4><code>{}</code></h4>\n",
+                        escape_html(&method.signature)
+                    ));
+                    if let Some(doc) = &method.doc {
+                        out.push_str(&paragraphs_html(doc));
+                    }
+                }
+            }
+        }
+        out.push_str("</body>\n</html>\n");
+        out
+    }
+
+    /// Non-empty sections with their headings.
+    fn sections(&self) -> impl Iterator<Item = (&'static str, &Vec<DocItem>)> {
+        [
+            ("Functions", &self.functions),
+            ("Types", &self.types),
+            ("Constants", &self.consts),
+        ]
+        .into_iter()
+        .filter(|(_, items)| !items.is_empty())
+    }
+}
+
+fn is_public(name: &str) -> bool {
+    !name.starts_with('_')
+}
+
+fn join(names: &[Symbol]) -> String {
+    names
+        .iter()
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// `name(a, b = 1, ...rest)`. Defaults are literals, see `Stmt::Func`.
+fn function_signature(
+    program: &Program,
+    name: &str,
+    params: &[Symbol],
+    defaults: &[ExprId],
+    variadic: bool,
+) -> String {
+    let plain = params.len() - variadic as usize;
+    let first_default = plain - defaults.len().min(plain);
+    let params: Vec<String> = params
+        .iter()
+        .enumerate()
+        .map(|(i, param)| {
+            if i == plain {
+                format!("...{}", param)
+            } else if i >= first_default {
+                format!(
+                    "{} = {}",
+                    param,
+                    literal(program, defaults[i - first_default])
+                )
+            } else {
+                param.to_string()
+            }
+        })
+        .collect();
+    format!("{}({})", name, params.join(", "))
+}
+
+fn literal(program: &Program, expr: ExprId) -> String {
+    match program.expr(expr) {
+        Expr::Number(n) => n.to_string(),
+        Expr::String(s) => format!("\"{}\"", s),
+        Expr::Boolean(b) => b.to_string(),
+        Expr::Unary {
+            op: UnaryOp::Neg,
+            right,
+        } => format!("-{}", literal(program, *right)),
+        _ => "...".to_string(),
+    }
+}
+
+/// Doc text as HTML paragraphs, split at blank lines.
+fn paragraphs_html(doc: &str) -> String {
+    doc.split("\n\n")
+        .filter(|paragraph| !paragraph.trim().is_empty())
+        .map(|paragraph| format!("<p>{}</p>\n", escape_html(paragraph.trim())))
+        .collect()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,380 @@
+use crate::compiler::Compiler;
+use crate::interpreter::{CancellationToken, StopReason, VirtualMachine, VmLimits};
+use crate::lexer::Lexer;
+use crate::natives::NativeContext;
+use crate::parser::Parser;
+use crate::replay::Recording;
+use crate::types::ast::{Program, Stmt};
+use crate::types::compiler::{
+    ByteCode, CompileError, CompileOptions, HeapObject, Opaque, Value, Warning,
+};
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::io::Write;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Parse(String),
+    Compile(CompileError),
+    Runtime(String),
+    /// The script went over one of the engine's `VmLimits` and was stopped.
+    LimitExceeded(String),
+    /// The script was stopped through a `CancellationToken`.
+    Cancelled,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(e) => write!(f, "Parse error: {}", e),
+            Error::Compile(e) => write!(f, "Compile error: {}", e),
+            Error::Runtime(e) => write!(f, "Runtime error: {}", e),
+            Error::LimitExceeded(e) => write!(f, "Limit exceeded: {}", e),
+            Error::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Embedding entry point. An engine keeps one compiler and one VM alive, so
+/// functions, variables and host functions from earlier `eval` calls stay
+/// visible. A failing `eval` leaves the engine as it was before the call.
+///
+/// Values cross into and out of the engine as host objects, copied in and
+/// out of the VM's heap: a heap index held by the host would point at
+/// something else once the collector compacts the heap.
+pub struct Engine {
+    compiler: Compiler,
+    vm: VirtualMachine,
+}
+
+// Engines and compiled programs can move to worker threads
+const _: () = {
+    const fn assert_send<T: Send>() {}
+    assert_send::<Engine>();
+    assert_send::<ByteCode>();
+    assert_send::<Value>();
+};
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self::with_options(CompileOptions::default())
+    }
+
+    /// Engine compiling with `options`, e.g. to add module search paths.
+    /// `keep_last_value` is always on so `eval` can return results.
+    pub fn with_options(options: CompileOptions) -> Self {
+        Self::from_compiler(Compiler::with_options(CompileOptions {
+            keep_last_value: true,
+            ..options
+        }))
+    }
+
+    fn from_compiler(compiler: Compiler) -> Self {
+        let mut vm = VirtualMachine::new(ByteCode::default(), compiler.clone());
+        vm.set_recover_on_error(true);
+        Self { compiler, vm }
+    }
+
+    /// Runs `source` and returns the value of its trailing expression, if any.
+    /// Functions cannot be returned, since they only mean something to the
+    /// engine that made them.
+    pub fn eval(&mut self, source: &str) -> Result<Option<HeapObject>, Error> {
+        let tokens = Lexer::new(source).tokenize();
+        let ast = Parser::new(tokens).parse().map_err(Error::Parse)?;
+        self.eval_program(&ast)
+    }
+
+    /// Like `eval`, for a program built with `Program`'s constructors or
+    /// parsed earlier.
+    pub fn eval_program(&mut self, program: &Program) -> Result<Option<HeapObject>, Error> {
+        // Compile against a copy so a half-compiled input cannot leave stray
+        // variables or instructions behind.
+        let mut compiler = self.compiler.clone();
+        let bytecode = compiler.compile(program).map_err(Error::Compile)?;
+        self.compiler = compiler;
+        self.vm.load(bytecode, self.compiler.clone());
+
+        let value = self.vm.run().map_err(|e| self.runtime_error(e))?;
+        value.map(|value| self.load(&value)).transpose()
+    }
+
+    /// Copies a value the VM just produced out of its heap, before anything
+    /// else can run a collection.
+    fn load(&self, value: &Value) -> Result<HeapObject, Error> {
+        self.vm.heap().load(value).map_err(Error::Runtime)
+    }
+
+    /// Evaluates a single expression with `variables` defined while it runs,
+    /// e.g. `eval_with("x + y * 2", &[("x", 3.0.into()), ("y", 4.0.into())])`
+    /// for config formulas. The variables are gone afterwards, and hide any
+    /// top-level variables of the same name only until then.
+    pub fn eval_with(
+        &mut self,
+        expression: &str,
+        variables: &[(&str, HeapObject)],
+    ) -> Result<HeapObject, Error> {
+        let tokens = Lexer::new(expression).tokenize();
+        let ast = Parser::new(tokens).parse().map_err(Error::Parse)?;
+        if !matches!(ast.statements.as_slice(), [Stmt::Expr(..)]) {
+            return Err(Error::Parse(format!(
+                "Expected one expression, got '{}'",
+                expression.trim()
+            )));
+        }
+        let shadowed: Vec<_> = variables
+            .iter()
+            .map(|(name, value)| {
+                let (index, previous) = self.compiler.shadow_global(name);
+                let value = self.vm.heap_mut().store(value.clone());
+                self.vm.set_global(index, value);
+                (*name, index, previous)
+            })
+            .collect();
+        let result = self.eval_program(&ast);
+        for (name, index, previous) in shadowed.into_iter().rev() {
+            self.compiler.restore_global(name, previous);
+            // Let the collector have what the variable held
+            self.vm.set_global(index, Value::Number(0.0));
+        }
+        result?.ok_or_else(|| Error::Runtime("The expression gave no value".to_string()))
+    }
+
+    fn runtime_error(&self, message: String) -> Error {
+        match self.vm.stop_reason() {
+            Some(StopReason::LimitExceeded) => Error::LimitExceeded(message),
+            Some(StopReason::Cancelled) => Error::Cancelled,
+            None => Error::Runtime(message),
+        }
+    }
+
+    /// Runs `source` on a new thread, in a fresh engine with this engine's
+    /// options, host functions and limits but none of its variables or script
+    /// functions. The result comes back as a host object, since values only
+    /// mean something to the engine that made them.
+    pub fn spawn(&self, source: &str) -> JoinHandle<Result<Option<HeapObject>, Error>> {
+        let mut compiler = Compiler::with_options(self.compiler.options.clone());
+        compiler.natives = self.compiler.natives.clone();
+        compiler.module_cache = self.compiler.module_cache.clone();
+        let limits = self.vm.limits().clone();
+        let source = source.to_string();
+        std::thread::spawn(move || {
+            let mut engine = Engine::from_compiler(compiler);
+            engine.set_limits(limits);
+            engine.eval(&source)
+        })
+    }
+
+    /// Recompiles the functions of an imported file module after its source
+    /// changed, e.g. `reload_module("utils")`. Calls made from then on run the
+    /// new definitions; variables, including the module's own, keep their
+    /// values. On error the old definitions stay in place.
+    pub fn reload_module(&mut self, name: &str) -> Result<(), Error> {
+        let mut compiler = self.compiler.clone();
+        let bytecode = compiler.reload_module(name).map_err(Error::Compile)?;
+        self.compiler = compiler;
+        self.vm.load(bytecode, self.compiler.clone());
+        self.vm.run().map(|_| ()).map_err(|e| self.runtime_error(e))
+    }
+
+    /// Warnings from compiling the last `eval`'s source, e.g. calls to
+    /// `@deprecated` functions.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.compiler.warnings
+    }
+
+    /// Exit code of the last `eval` if the script stopped itself with `OS.exit`.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.vm.exit_code()
+    }
+
+    /// Exposes a host callback to scripts under `name`, which may be a bare
+    /// name (`host_log(x)`) or qualified (`Host.log(x)`). Script functions
+    /// with the same bare name take precedence.
+    pub fn register_fn<F>(&mut self, name: &str, func: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        self.compiler
+            .natives
+            .register(name, None, move |_, args| func(args));
+    }
+
+    /// Like `register_fn`, for callbacks that need the VM's heap, e.g. to
+    /// read lists and strings or to `downcast` opaque values.
+    pub fn register_native<F>(&mut self, name: &str, func: F)
+    where
+        F: Fn(&mut NativeContext, &[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        self.compiler.natives.register(name, None, func);
+    }
+
+    /// Defines or overwrites a top-level variable visible to later `eval`
+    /// calls, from a host value (number, bool, string, `Vec`, `HashMap`,
+    /// `Option`, or one made with `opaque`).
+    pub fn set_global(&mut self, name: &str, value: impl Into<HeapObject>) {
+        let index = self.compiler.declare_global(name);
+        let value = self.vm.heap_mut().store(value);
+        self.vm.set_global(index, value);
+    }
+
+    /// Limits how deeply script functions may nest calls; deeper recursion is
+    /// a runtime error. Defaults to `DEFAULT_MAX_CALL_DEPTH`.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.vm.set_max_call_depth(depth);
+    }
+
+    /// Sets how often a function must be called before it is compiled to
+    /// native code. Defaults to `DEFAULT_JIT_THRESHOLD`.
+    #[cfg(feature = "jit")]
+    pub fn set_jit_threshold(&mut self, threshold: u32) {
+        self.vm.set_jit_threshold(threshold);
+    }
+
+    /// Bounds the instructions, heap and time each `eval` may use. Going over
+    /// stops the script with `Error::LimitExceeded`.
+    pub fn set_limits(&mut self, limits: VmLimits) {
+        self.vm.set_limits(limits);
+    }
+
+    /// With `defer`, `eval` returns without running the tasks a script
+    /// spawned and did not join; the host runs them with `poll` from its own
+    /// event loop instead.
+    pub fn set_defer_tasks(&mut self, defer: bool) {
+        self.vm.set_defer_tasks(defer);
+    }
+
+    /// Runs one queued task. `Ready` once no task is left, `Pending` if
+    /// more are queued.
+    pub fn poll(&mut self) -> Result<Poll<()>, Error> {
+        match self.vm.poll_task() {
+            Ok(true) => Ok(Poll::Pending),
+            Ok(false) => Ok(Poll::Ready(())),
+            Err(e) => Err(self.runtime_error(e)),
+        }
+    }
+
+    /// Wakes `waker` the next time a script spawns a task, so an event loop
+    /// that went idle knows to `poll` again.
+    pub fn register_waker(&mut self, waker: Waker) {
+        self.vm.add_waker(waker);
+    }
+
+    /// Queues a call of the script function `name` with `args` and returns
+    /// a future of its result. Each poll of the future runs one queued task,
+    /// so it makes progress whichever executor drives it.
+    pub fn call_async(
+        &mut self,
+        name: &str,
+        args: Vec<HeapObject>,
+    ) -> Result<TaskFuture<'_>, Error> {
+        let &function = self
+            .compiler
+            .functions
+            .get(name)
+            .ok_or_else(|| Error::Runtime(format!("Undefined function '{}'", name)))?;
+        let bound = args
+            .into_iter()
+            .map(|arg| self.vm.heap_mut().store(arg))
+            .collect();
+        let task = self
+            .vm
+            .queue_call(Value::Closure { function, bound })
+            .map_err(Error::Runtime)?;
+        Ok(TaskFuture { engine: self, task })
+    }
+
+    /// Records what the clock, the environment, files, processes and the
+    /// network return to scripts from here on, for `take_recording`.
+    pub fn record(&mut self) {
+        self.vm.record();
+    }
+
+    /// What was recorded since `record`, e.g. to save with
+    /// `Recording::to_text` when a script fails.
+    pub fn take_recording(&mut self) -> Option<Recording> {
+        self.vm.take_recording()
+    }
+
+    /// Makes later `eval` calls see what `recording` saw instead of the real
+    /// clock, environment, files, processes and network, so a recorded run
+    /// repeats exactly.
+    pub fn replay(&mut self, recording: Recording) {
+        self.vm.replay(recording);
+    }
+
+    /// A handle for stopping a running `eval` from another thread; the call
+    /// then returns `Error::Cancelled`.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.vm.cancellation_token()
+    }
+
+    /// Redirects what scripts print.
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.vm.set_output(output);
+    }
+
+    /// Wraps a host value for scripts to hold and pass back to host
+    /// functions, which get it back with `context.heap.downcast` (see
+    /// `register_native`). Scripts cannot look inside it; it prints as
+    /// `<type name>`.
+    pub fn opaque<T: Any + Send + Sync>(&self, value: T) -> HeapObject {
+        HeapObject::Opaque(Opaque::new(value))
+    }
+
+    /// The host value `value` wraps, if it was made by `opaque` from a `T`.
+    pub fn downcast<T: Any + Send + Sync>(&self, value: &HeapObject) -> Result<Arc<T>, String> {
+        Opaque::try_from(value.clone())?.downcast()
+    }
+
+    /// Converts a value returned by `eval` into a Rust type.
+    pub fn convert<T>(&self, value: &HeapObject) -> Result<T, String>
+    where
+        T: TryFrom<HeapObject, Error = String>,
+    {
+        T::try_from(value.clone())
+    }
+
+    /// Renders a value returned by `eval`.
+    pub fn display(&self, value: &HeapObject) -> String {
+        value.to_string()
+    }
+}
+
+/// The result of a script function queued with `Engine::call_async`.
+pub struct TaskFuture<'a> {
+    engine: &'a mut Engine,
+    task: usize,
+}
+
+impl Future for TaskFuture<'_> {
+    type Output = Result<HeapObject, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Some(result) = this.engine.vm.task_result(this.task) {
+            return Poll::Ready(
+                result
+                    .map_err(Error::Runtime)
+                    .and_then(|value| this.engine.load(&value)),
+            );
+        }
+        if let Err(e) = this.engine.vm.poll_task() {
+            return Poll::Ready(Err(this.engine.runtime_error(e)));
+        }
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
@@ -0,0 +1,58 @@
+//! Central registry of the interpreter version and the language features that
+//! are compiled in, so scripts can adapt at runtime through the `Lang` natives.
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Language features of every build. Add an entry here when a feature lands.
+pub const FEATURES: &[&str] = &[
+    "arrays",
+    "async", // Task.spawn, join and all
+    "attributes",
+    "blocks",
+    "channels",
+    "closures",
+    "concat-operator",
+    "const",
+    "currying",
+    "default-parameters",
+    "destructuring",
+    "doc-comments",
+    "enums",
+    "for-loops",
+    "functions",
+    "generational-gc",
+    "generators",
+    "if-expressions",
+    "imports",
+    "interpolation",
+    "io",
+    "iterators",
+    "lambdas",
+    "match",
+    "named-arguments",
+    "natives",
+    "pipeline",
+    "rest-parameters",
+    "spread",
+    "structs",
+    "tasks",
+    "traits",
+    "tuples",
+    "unicode-identifiers",
+];
+
+/// Cargo features, which only some builds have.
+pub const BUILD_FEATURES: &[(&str, bool)] = &[
+    ("ffi", cfg!(feature = "ffi")),
+    ("fs", cfg!(feature = "fs")),
+    ("jit", cfg!(feature = "jit")),
+    ("registers", cfg!(feature = "registers")),
+    ("tui", cfg!(feature = "tui")),
+];
+
+pub fn has_feature(name: &str) -> bool {
+    FEATURES.contains(&name)
+        || BUILD_FEATURES
+            .iter()
+            .any(|&(feature, enabled)| feature == name && enabled)
+}
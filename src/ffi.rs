@@ -0,0 +1,165 @@
+//! `extern` blocks: functions of native shared libraries, opened with
+//! `libloading` and registered as natives.
+//!
+//! Without a library like libffi, each call needs a Rust function pointer
+//! type matching the C signature exactly. `call_as!` spells one out for every
+//! combination of parameter and return types up to `MAX_PARAMS` parameters,
+//! and a call picks the one its declaration names. Variadic functions such as
+//! `printf` are not supported.
+
+use crate::natives::{NativeContext, NativeRegistry};
+use crate::types::ast::{ForeignFunction, ForeignType};
+use crate::types::compiler::Value;
+use libloading::{Library, Symbol};
+use std::ffi::{CStr, CString, c_char, c_int};
+use std::sync::Arc;
+
+/// Parameters an `extern` function may take.
+pub const MAX_PARAMS: usize = 4;
+
+/// An argument converted to the C type its parameter declares.
+#[derive(Clone, Copy)]
+enum Arg {
+    Number(f64),
+    Int(c_int),
+    Long(i64),
+    String(*const c_char),
+}
+
+/// Opens `library` and registers each of `functions` under its name. The
+/// library stays loaded while any of them is registered.
+pub fn bind(
+    registry: &mut NativeRegistry,
+    library: &str,
+    functions: &[ForeignFunction],
+) -> Result<(), String> {
+    // SAFETY: loading runs the library's initialisers, which the script
+    // trusts by naming it
+    let handle = unsafe { Library::new(library) }
+        .map_err(|e| format!("Cannot load library '{}': {}", library, e))?;
+    let handle = Arc::new(handle);
+    for function in functions {
+        if function.params.len() > MAX_PARAMS {
+            return Err(format!(
+                "'{}' takes more than {} parameters",
+                function.name, MAX_PARAMS
+            ));
+        }
+        // SAFETY: only checks that the symbol exists, it is not called
+        unsafe { handle.get::<unsafe extern "C" fn()>(function.name.as_bytes()) }
+            .map_err(|_| format!("Library '{}' has no function '{}'", library, function.name))?;
+        let handle = handle.clone();
+        let function = function.clone();
+        registry.register(
+            &function.name.clone(),
+            Some(function.params.len()),
+            move |context, args| call(context, &handle, &function, args),
+        );
+    }
+    Ok(())
+}
+
+/// Calls `$name` in `$library` through the pointer type its arguments and
+/// `$ret` spell out, one `Arg` at a time.
+macro_rules! call_as {
+    ($library:expr, $name:expr, $ret:ty, [], [$($arg:ident: $ty:ty),*]) => {{
+        let f: Symbol<unsafe extern "C" fn($($ty),*) -> $ret> = $library
+            .get($name)
+            .map_err(|e| e.to_string())?;
+        f($($arg),*)
+    }};
+    ($library:expr, $name:expr, $ret:ty, [$next:expr $(, $rest:expr)*], [$($arg:ident: $ty:ty),*]) => {
+        match $next {
+            Arg::Number(a) => call_as!($library, $name, $ret, [$($rest),*], [$($arg: $ty,)* a: f64]),
+            Arg::Int(a) => call_as!($library, $name, $ret, [$($rest),*], [$($arg: $ty,)* a: c_int]),
+            Arg::Long(a) => call_as!($library, $name, $ret, [$($rest),*], [$($arg: $ty,)* a: i64]),
+            Arg::String(a) => {
+                call_as!($library, $name, $ret, [$($rest),*], [$($arg: $ty,)* a: *const c_char])
+            }
+        }
+    };
+}
+
+/// `call_as!` for the number of arguments in `$args`.
+macro_rules! call_with_arity {
+    ($library:expr, $name:expr, $ret:ty, $args:expr) => {
+        match $args {
+            [] => call_as!($library, $name, $ret, [], []),
+            [a] => call_as!($library, $name, $ret, [*a], []),
+            [a, b] => call_as!($library, $name, $ret, [*a, *b], []),
+            [a, b, c] => call_as!($library, $name, $ret, [*a, *b, *c], []),
+            [a, b, c, d] => call_as!($library, $name, $ret, [*a, *b, *c, *d], []),
+            _ => unreachable!("bind checks the parameter count"),
+        }
+    };
+}
+
+/// Converts `args` to `function`'s C types, calls it and converts its
+/// result back.
+fn call(
+    context: &mut NativeContext,
+    library: &Library,
+    function: &ForeignFunction,
+    args: &[Value],
+) -> Result<Value, String> {
+    let mut converted = Vec::with_capacity(args.len());
+    // Owns the strings passed until the call returns
+    let mut strings = Vec::new();
+    for ((param, param_type), arg) in function.params.iter().zip(args) {
+        let arg = match (param_type, arg) {
+            (ForeignType::Number, Value::Number(n)) => Arg::Number(*n),
+            (ForeignType::Int, Value::Number(n)) if n.fract() == 0.0 => Arg::Int(*n as c_int),
+            (ForeignType::Long, Value::Number(n)) if n.fract() == 0.0 => Arg::Long(*n as i64),
+            (ForeignType::String, arg) => {
+                let text: String = context
+                    .heap
+                    .load_as(arg)
+                    .map_err(|_| format!("'{}' must be a string", param))?;
+                let text =
+                    CString::new(text).map_err(|_| format!("'{}' contains a NUL byte", param))?;
+                let pointer = text.as_ptr();
+                strings.push(text);
+                Arg::String(pointer)
+            }
+            (param_type, _) => {
+                return Err(format!(
+                    "'{}' of '{}' must be {}",
+                    param,
+                    function.name,
+                    match param_type {
+                        ForeignType::Number => "a number",
+                        _ => "a whole number",
+                    }
+                ));
+            }
+        };
+        converted.push(arg);
+    }
+
+    let name = function.name.as_bytes();
+    // SAFETY: the symbol is called with the parameter and return types the
+    // script declared for it
+    let value = unsafe {
+        match function.returns {
+            ForeignType::Number => {
+                Value::Number(call_with_arity!(library, name, f64, converted.as_slice()))
+            }
+            ForeignType::Int => {
+                Value::Number(call_with_arity!(library, name, c_int, converted.as_slice()) as f64)
+            }
+            ForeignType::Long => {
+                Value::Number(call_with_arity!(library, name, i64, converted.as_slice()) as f64)
+            }
+            ForeignType::String => {
+                let text = call_with_arity!(library, name, *const c_char, converted.as_slice());
+                if text.is_null() {
+                    return Err(format!("'{}' returned a null string", function.name));
+                }
+                let text = CStr::from_ptr(text).to_string_lossy().into_owned();
+                context.heap.store(text)
+            }
+        }
+    };
+    drop(strings);
+    Ok(value)
+}
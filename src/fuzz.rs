@@ -0,0 +1,76 @@
+//! Entry points for fuzzers such as `cargo fuzz`. Each takes arbitrary bytes
+//! and must return, without panicking, hanging or running out of stack,
+//! whatever they are; errors are the expected outcome for most inputs.
+//!
+//! ```ignore
+//! fuzz_target!(|data: &[u8]| n::fuzz::fuzz_compile(data));
+//! ```
+
+use crate::compiler::Compiler;
+use crate::interpreter::{VirtualMachine, VmLimits};
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::types::compiler::{ByteCode, CompileOptions};
+use std::time::Duration;
+
+/// Limits for `fuzz_run`, so programs that loop or allocate forever stop.
+const FUZZ_LIMITS: VmLimits = VmLimits {
+    max_instructions: Some(100_000),
+    max_heap_bytes: Some(16 << 20),
+    wall_clock_timeout: Some(Duration::from_secs(1)),
+};
+
+pub fn fuzz_lex(input: &[u8]) {
+    let source = String::from_utf8_lossy(input);
+    let _ = Lexer::new(&source).tokenize();
+}
+
+pub fn fuzz_parse(input: &[u8]) {
+    let source = String::from_utf8_lossy(input);
+    let _ = Parser::new(Lexer::new(&source).tokenize()).parse();
+}
+
+/// Lexes, parses and compiles the input as source. The prelude is left out
+/// so each input only exercises its own code.
+pub fn fuzz_compile(input: &[u8]) {
+    let _ = compile(input);
+}
+
+/// Compiles the input and runs it under instruction, heap and time limits.
+/// Output is discarded, and programs that import a module reaching outside
+/// the process (files, the network, the environment) or that can sleep,
+/// which would spend the whole time limit waiting, are only compiled.
+pub fn fuzz_run(input: &[u8]) {
+    let Some((bytecode, compiler)) = compile(input) else {
+        return;
+    };
+    let outside = ["FS.", "Http.", "OS.", "Time.sleep"];
+    if compiler
+        .natives
+        .functions()
+        .iter()
+        .any(|native| outside.iter().any(|module| native.name.starts_with(module)))
+    {
+        return;
+    }
+    let mut vm = VirtualMachine::new(bytecode, compiler);
+    vm.set_output(Box::new(std::io::sink()));
+    vm.set_limits(FUZZ_LIMITS);
+    let _ = vm.run();
+}
+
+/// Decodes and verifies the input as an encoded bytecode file.
+pub fn fuzz_decode(input: &[u8]) {
+    let _ = crate::bytecode::decode(input);
+}
+
+fn compile(input: &[u8]) -> Option<(ByteCode, Compiler)> {
+    let source = String::from_utf8_lossy(input);
+    let program = Parser::new(Lexer::new(&source).tokenize()).parse().ok()?;
+    let mut compiler = Compiler::with_options(CompileOptions {
+        prelude: Some(String::new()),
+        ..Default::default()
+    });
+    let bytecode = compiler.compile(&program).ok()?;
+    Some((bytecode, compiler))
+}
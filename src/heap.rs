@@ -0,0 +1,221 @@
+use crate::types::compiler::{HeapObject, Opaque, Value};
+use crate::types::constants::{
+    GC_NURSERY_THRESHOLD, GC_THRESHOLD, HEAP_SCORE_ARRAY_BASE, HEAP_SCORE_ARRAY_PER_ELEMENT,
+    HEAP_SCORE_MAP_BASE, HEAP_SCORE_MAP_PER_ELEMENT, HEAP_SCORE_OTHER_OBJECT,
+    HEAP_SCORE_STRING_BASE, INVALID_HEAP_POINTER_ERROR,
+};
+use std::any::Any;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GcStats {
+    pub minor_collections: usize,
+    pub major_collections: usize,
+    pub promoted_objects: usize,
+    pub freed_objects: usize,
+}
+
+/// Generational heap.
+///
+/// Objects live in a single vector split in two regions: `[0, tenured_len)` is the
+/// tenured generation and everything after it is the nursery. New objects are always
+/// pushed onto the nursery. A minor collection only marks and compacts the nursery and
+/// promotes survivors, so its cost is proportional to the nursery rather than the whole
+/// heap. A major collection compacts everything and is only triggered once the tenured
+/// generation outgrows its (adaptive) threshold.
+///
+/// Heap objects never hold heap pointers themselves (arrays store `HeapObject`s by value),
+/// so the only roots are the values held by the VM and no write barrier is required.
+#[derive(Debug)]
+pub struct Heap {
+    objects: Vec<HeapObject>,
+    tenured_len: usize,
+    nursery_score: usize,
+    tenured_score: usize,
+    major_threshold: usize,
+    stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            tenured_len: 0,
+            nursery_score: 0,
+            tenured_score: 0,
+            major_threshold: GC_THRESHOLD,
+            stats: GcStats::default(),
+        }
+    }
+
+    pub fn allocate(&mut self, object: HeapObject) -> usize {
+        self.nursery_score += Self::object_score(&object);
+        self.objects.push(object);
+        self.objects.len() - 1
+    }
+
+    pub fn get(&self, index: usize) -> Option<&HeapObject> {
+        self.objects.get(index)
+    }
+
+    pub fn objects(&self) -> &[HeapObject] {
+        &self.objects
+    }
+
+    /// Turns a host object into a value: scalars stay inline, everything else is
+    /// allocated.
+    pub fn store(&mut self, object: impl Into<HeapObject>) -> Value {
+        match object.into() {
+            HeapObject::Number(n) => Value::Number(n),
+            HeapObject::Boolean(b) => Value::Boolean(b),
+            HeapObject::String(s) => Value::String(s),
+            HeapObject::Task(id) => Value::Task(id),
+            HeapObject::Channel(id) => Value::Channel(id),
+            HeapObject::Generator(id) => Value::Generator(id),
+            object => Value::HeapPointer(self.allocate(object)),
+        }
+    }
+
+    /// Copies a value out of the VM as a host object.
+    pub fn load(&self, value: &Value) -> Result<HeapObject, String> {
+        match value {
+            Value::Number(n) => Ok(HeapObject::Number(*n)),
+            Value::Boolean(b) => Ok(HeapObject::Boolean(*b)),
+            Value::String(s) => Ok(HeapObject::String(s.clone())),
+            Value::HeapPointer(idx) => self
+                .get(*idx)
+                .cloned()
+                .ok_or_else(|| INVALID_HEAP_POINTER_ERROR.to_string()),
+            Value::Function { .. } | Value::Closure { .. } => {
+                Err("Cannot convert a function".to_string())
+            }
+            Value::Task(id) => Ok(HeapObject::Task(*id)),
+            Value::Channel(id) => Ok(HeapObject::Channel(*id)),
+            Value::Generator(id) => Ok(HeapObject::Generator(*id)),
+        }
+    }
+
+    /// `load` followed by a conversion into a Rust type, e.g. `Vec<f64>`.
+    pub fn load_as<T>(&self, value: &Value) -> Result<T, String>
+    where
+        T: TryFrom<HeapObject, Error = String>,
+    {
+        T::try_from(self.load(value)?)
+    }
+
+    /// The host value an opaque value wraps, if it is a `T`. For natives
+    /// receiving values made with `Opaque::new`.
+    pub fn downcast<T: Any + Send + Sync>(&self, value: &Value) -> Result<Arc<T>, String> {
+        self.load_as::<Opaque>(value)?.downcast()
+    }
+
+    pub fn stats(&self) -> &GcStats {
+        &self.stats
+    }
+
+    pub fn score(&self) -> usize {
+        self.nursery_score + self.tenured_score
+    }
+
+    /// Whether `maybe_collect` would collect anything, so the VM can skip
+    /// gathering roots when it would not.
+    pub fn needs_collection(&self) -> bool {
+        self.nursery_score >= GC_NURSERY_THRESHOLD || self.tenured_score >= self.major_threshold
+    }
+
+    /// Runs a minor collection when the nursery is full, followed by a major one
+    /// if promotion pushed the tenured generation over its threshold.
+    pub fn maybe_collect(&mut self, roots: &mut [&mut Value]) {
+        if self.nursery_score >= GC_NURSERY_THRESHOLD {
+            self.collect_minor(roots);
+        }
+        if self.tenured_score >= self.major_threshold {
+            self.collect_major(roots);
+        }
+    }
+
+    pub fn collect_minor(&mut self, roots: &mut [&mut Value]) {
+        let start = self.tenured_len;
+        let survivors = self.collect_from(start, roots);
+
+        self.stats.minor_collections += 1;
+        self.stats.promoted_objects += survivors;
+        self.tenured_score += self.nursery_score_of(start);
+        self.tenured_len = self.objects.len();
+        self.nursery_score = 0;
+    }
+
+    pub fn collect_major(&mut self, roots: &mut [&mut Value]) {
+        self.collect_from(0, roots);
+
+        self.stats.major_collections += 1;
+        self.tenured_len = self.objects.len();
+        self.tenured_score = self.nursery_score_of(0);
+        self.nursery_score = 0;
+        // Grow the threshold with the live set so a large steady-state heap
+        // does not trigger a full collection on every check.
+        self.major_threshold = GC_THRESHOLD.max(self.tenured_score * 2);
+    }
+
+    /// Mark-compacts `objects[start..]`, leaving everything below `start` untouched.
+    /// Returns the number of surviving objects in the collected region.
+    fn collect_from(&mut self, start: usize, roots: &mut [&mut Value]) -> usize {
+        // Mark phase: Find all live objects in the region by tracing from the roots
+        let region_len = self.objects.len() - start;
+        let mut marked = vec![false; region_len];
+        for value in roots.iter() {
+            if let Value::HeapPointer(idx) = **value
+                && idx >= start
+                && idx < self.objects.len()
+            {
+                marked[idx - start] = true;
+            }
+        }
+
+        // Sweep phase: Compact the region in place and create index mapping
+        let mut remap = vec![None; region_len];
+        let mut next = start;
+        for (offset, is_marked) in marked.iter().enumerate() {
+            if *is_marked {
+                self.objects.swap(next, start + offset);
+                remap[offset] = Some(next);
+                next += 1;
+            }
+        }
+        self.stats.freed_objects += self.objects.len() - next;
+        self.objects.truncate(next);
+
+        // Update phase: Fix all heap pointer references to use new indices
+        for value in roots.iter_mut() {
+            if let Value::HeapPointer(idx) = **value
+                && idx >= start
+                && let Some(Some(new_idx)) = remap.get(idx - start)
+            {
+                **value = Value::HeapPointer(*new_idx);
+            }
+        }
+
+        next - start
+    }
+
+    fn nursery_score_of(&self, start: usize) -> usize {
+        self.objects[start..].iter().map(Self::object_score).sum()
+    }
+
+    fn object_score(object: &HeapObject) -> usize {
+        match object {
+            HeapObject::Array(arr) => {
+                HEAP_SCORE_ARRAY_BASE + arr.len() * HEAP_SCORE_ARRAY_PER_ELEMENT
+            }
+            HeapObject::String(s) => HEAP_SCORE_STRING_BASE + s.len(),
+            HeapObject::Object(map) => HEAP_SCORE_MAP_BASE + map.len() * HEAP_SCORE_MAP_PER_ELEMENT,
+            _ => HEAP_SCORE_OTHER_OBJECT,
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! Re-parsing after an edit, for editors. A `Document` remembers which lines
+//! each top-level statement spans and, after an edit, parses again only the
+//! statements whose lines it touched. Statements below keep their trees and
+//! move by the number of lines the edit added or removed.
+
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::types::ast::{Expr, ExprId, Program, Stmt, Visitor, walk_expr};
+use crate::types::token::Token;
+use std::ops::Range;
+
+/// Replaces the bytes `range` of the source with `text`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextEdit {
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+pub struct Document {
+    source: String,
+    program: Program,
+    /// First and last line of each top-level statement.
+    items: Vec<(usize, usize)>,
+    /// Expressions right after the last full parse. Replaced statements leave
+    /// theirs behind, so the arena is rebuilt once it has doubled.
+    parsed_exprs: usize,
+    last_reparsed: Option<usize>,
+}
+
+impl Document {
+    pub fn new(source: &str) -> Result<Self, String> {
+        let mut document = Document {
+            source: source.to_string(),
+            program: Program::default(),
+            items: Vec::new(),
+            parsed_exprs: 0,
+            last_reparsed: None,
+        };
+        document.parse_all()?;
+        Ok(document)
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    /// How many top-level statements the last `edit` parsed again, or `None`
+    /// when it parsed the whole source.
+    pub fn last_reparsed(&self) -> Option<usize> {
+        self.last_reparsed
+    }
+
+    /// Applies `edit` and brings the program up to date. On a parse error the
+    /// source keeps the edit, so later edits can fix it, and the program
+    /// stays as it was.
+    pub fn edit(&mut self, edit: TextEdit) -> Result<&Program, String> {
+        let TextEdit { range, text } = edit;
+        if range.start > range.end
+            || !self.source.is_char_boundary(range.start)
+            || !self.source.is_char_boundary(range.end)
+        {
+            return Err(format!(
+                "Edit range {}..{} is not inside the source ({} bytes)",
+                range.start,
+                range.end,
+                self.source.len()
+            ));
+        }
+        let first_line = line_at(&self.source, range.start);
+        let last_line = line_at(&self.source, range.end);
+        let removed = &self.source[range.clone()];
+        // `//!` lines anywhere make up the program's doc
+        let module_doc = removed.contains("//!");
+        let removed = removed.matches('\n').count();
+        let delta = text.matches('\n').count() as isize - removed as isize;
+        self.source.replace_range(range, &text);
+
+        match self.dirty_items(first_line, last_line) {
+            Some(dirty)
+                if !module_doc && self.program.exprs.len() < self.parsed_exprs * 2 + 1024 =>
+            {
+                if self.reparse(dirty, last_line, delta).is_err() {
+                    self.parse_all()?;
+                }
+            }
+            _ => self.parse_all()?,
+        }
+        Ok(&self.program)
+    }
+
+    fn parse_all(&mut self) -> Result<(), String> {
+        self.last_reparsed = None;
+        let tokens = Lexer::new(&self.source).tokenize();
+        let by_line = newlines_match(&self.source, &tokens);
+        let mut parser = Parser::new(tokens);
+        match parser.parse() {
+            Ok(program) => {
+                self.program = program;
+                self.items = parser.item_lines().to_vec();
+                if !by_line {
+                    // The parser's lines are off from the source's, so
+                    // every edit parses everything
+                    self.items.clear();
+                }
+                self.parsed_exprs = self.program.exprs.len();
+                Ok(())
+            }
+            Err(e) => {
+                // The lines no longer match the source, so the next edit
+                // parses everything
+                self.items.clear();
+                Err(e)
+            }
+        }
+    }
+
+    /// The statements whose lines, or the blank and comment lines above
+    /// them, overlap `first_line..=last_line`. Statements sharing a line are
+    /// taken together, since the source is only cut between lines.
+    fn dirty_items(&self, first_line: usize, last_line: usize) -> Option<Range<usize>> {
+        let items = &self.items;
+        let mut start = items.iter().position(|&(_, last)| last >= first_line)?;
+        let mut end = items
+            .iter()
+            .position(|&(first, _)| first > last_line)
+            .unwrap_or(items.len())
+            .max(start + 1);
+        while start > 0 && items[start - 1].1 >= items[start].0 {
+            start -= 1;
+        }
+        while end < items.len() && items[end - 1].1 >= items[end].0 {
+            end += 1;
+        }
+        Some(start..end)
+    }
+
+    /// Parses the lines of the statements `dirty` again, from the line after
+    /// the statement above them to the end of the last one or of the edit,
+    /// whichever is further, and splices the result into the program.
+    fn reparse(
+        &mut self,
+        dirty: Range<usize>,
+        edit_end: usize,
+        delta: isize,
+    ) -> Result<(), String> {
+        let first_line = match dirty.start {
+            0 => 1,
+            start => self.items[start - 1].1 + 1,
+        };
+        let start = line_start(&self.source, first_line);
+        let end = match self.items.get(dirty.end) {
+            Some(_) => {
+                let last_line = self.items[dirty.end - 1].1.max(edit_end);
+                let last_line = last_line.checked_add_signed(delta);
+                let last_line = last_line.ok_or("Edit removed the statement's lines")?;
+                line_start(&self.source, last_line + 1)
+            }
+            None => self.source.len(),
+        };
+        let chunk = self.source.get(start..end.max(start)).unwrap_or("");
+        let tokens = Lexer::new(chunk).tokenize();
+        if !newlines_match(chunk, &tokens) {
+            // A string or comment now runs past the end of its line, maybe
+            // into statements below the chunk
+            return Err("Edit made a token span lines".to_string());
+        }
+        let last = tokens
+            .iter()
+            .rfind(|t| !matches!(t, Token::Newline | Token::Semicolon | Token::Eof));
+        if matches!(last, Some(Token::DocComment(_))) {
+            // Joins the doc comment of the statement below
+            return Err("Edit left a doc comment at the end".to_string());
+        }
+
+        let exprs = std::mem::take(&mut self.program.exprs);
+        let kept = exprs.len();
+        let mut parser = Parser::continuing(tokens, first_line, exprs);
+        let parsed = match parser.parse() {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                let mut exprs = parser.into_exprs();
+                exprs.truncate(kept);
+                self.program.exprs = exprs;
+                return Err(e);
+            }
+        };
+        if parsed.doc.is_some() {
+            return Err("Edit may change the module doc".to_string());
+        }
+        self.program.exprs = parsed.exprs;
+
+        let mut after = self.program.statements.split_off(dirty.end);
+        shift_lines(&mut self.program, &mut after, delta);
+        self.program.statements.truncate(dirty.start);
+        self.program.statements.extend(parsed.statements);
+        self.program.statements.extend(after);
+
+        let after: Vec<(usize, usize)> = self.items[dirty.end..]
+            .iter()
+            .map(|&(first, last)| {
+                (
+                    first.saturating_add_signed(delta),
+                    last.saturating_add_signed(delta),
+                )
+            })
+            .collect();
+        self.items.truncate(dirty.start);
+        self.items.extend_from_slice(parser.item_lines());
+        self.items.extend(after);
+        self.last_reparsed = Some(parser.item_lines().len());
+        Ok(())
+    }
+}
+
+/// Whether every line break in `source` is a `Newline` token. The parser
+/// counts lines by those tokens, so otherwise its lines are not the source's.
+fn newlines_match(source: &str, tokens: &[Token]) -> bool {
+    let breaks = tokens
+        .iter()
+        .filter(|t| matches!(t, Token::Newline))
+        .count();
+    breaks == source.matches('\n').count()
+}
+
+/// 1-based line of the byte at `offset`.
+fn line_at(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count() + 1
+}
+
+/// Byte offset where 1-based `line` starts, or the end of the source.
+fn line_start(source: &str, line: usize) -> usize {
+    match line {
+        0 | 1 => 0,
+        line => source
+            .match_indices('\n')
+            .nth(line - 2)
+            .map_or(source.len(), |(i, _)| i + 1),
+    }
+}
+
+/// Moves `statements` and everything nested in them `delta` lines.
+/// Statements inside `if` and block expressions live in `program`'s arena.
+fn shift_lines(program: &mut Program, statements: &mut [Stmt], delta: isize) {
+    if delta == 0 {
+        return;
+    }
+    let mut nested = NestedBlocks(Vec::new());
+    for stmt in statements.iter() {
+        nested.visit_stmt(program, stmt);
+    }
+    for stmt in statements.iter_mut() {
+        shift_stmt(stmt, delta);
+    }
+    for id in nested.0 {
+        match &mut program.exprs[id.0] {
+            Expr::If {
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                then_branch
+                    .iter_mut()
+                    .chain(else_branch.iter_mut().flatten())
+                    .for_each(|stmt| shift_stmt(stmt, delta));
+            }
+            Expr::Block(statements) => statements
+                .iter_mut()
+                .for_each(|stmt| shift_stmt(stmt, delta)),
+            _ => {}
+        }
+    }
+}
+
+/// `if` and block expressions, which hold statements of their own.
+struct NestedBlocks(Vec<ExprId>);
+
+impl Visitor for NestedBlocks {
+    fn visit_expr(&mut self, program: &Program, expr: ExprId) {
+        if matches!(program.expr(expr), Expr::If { .. } | Expr::Block(_)) {
+            self.0.push(expr);
+        }
+        walk_expr(self, program, expr);
+    }
+}
+
+/// Moves `stmt` and the statements it holds directly, but not those inside
+/// its expressions, `delta` lines.
+fn shift_stmt(stmt: &mut Stmt, delta: isize) {
+    match stmt {
+        Stmt::Let { line, .. }
+        | Stmt::Const { line, .. }
+        | Stmt::LetPattern { line, .. }
+        | Stmt::Import { line, .. }
+        | Stmt::Extern { line, .. }
+        | Stmt::Struct { line, .. }
+        | Stmt::Trait { line, .. }
+        | Stmt::Enum { line, .. }
+        | Stmt::Expr(_, line) => *line = line.saturating_add_signed(delta),
+        Stmt::Func { body, line, .. } | Stmt::For { body, line, .. } => {
+            *line = line.saturating_add_signed(delta);
+            body.iter_mut().for_each(|stmt| shift_stmt(stmt, delta));
+        }
+        Stmt::Impl {
+            methods,
+            constants,
+            line,
+            ..
+        } => {
+            *line = line.saturating_add_signed(delta);
+            methods
+                .iter_mut()
+                .chain(constants.iter_mut())
+                .for_each(|stmt| shift_stmt(stmt, delta));
+        }
+    }
+}
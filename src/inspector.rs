@@ -0,0 +1,380 @@
+//! Browser over an encoded program for `n inspect -i file.nb`: the
+//! constant, function and enum tables, each function's instructions with the
+//! constants and functions they name, and which instructions refer to a
+//! given constant, function or enum variant. `tui` draws its answers in
+//! panes; without a terminal, `Inspector::run` takes them as commands.
+//!
+//! ```text
+//! inspect> function 4
+//! function 4 fn(n) @24, instructions 24..29
+//!   0024 LOAD_ARG 1                   ; line 2  in function 4
+//!   0025 LOAD_VAR 1 0                 ; line 2  in function 4
+//!   0026 LOAD_CONST 3                 ; line 2  = 2  in function 4
+//! ...
+//! referenced by 1 instruction(s)
+//!   0040 CALL 4                       ; line 5  -> fn(n) @24  in top level
+//! ```
+
+use crate::bytecode::decode_with_sizes;
+use crate::types::compiler::{ByteCode, Instruction, Value};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+
+const HELP: &str = "\
+commands:
+  summary                 table sizes
+  constants               every constant with how often it is used
+  constant N              constant N and what refers to it
+  functions               every function with its instructions
+  function N | top        the instructions of function N or of the top level
+  enums                   every enum with its variants
+  enum N                  enum N and the instructions using its variants
+  help                    this list
+  quit";
+
+/// A decoded program and where each function's instructions are. Like
+/// `bytecode::inspect`, it does not verify the program, so it can show a
+/// malformed file.
+pub struct Inspector {
+    bytecode: ByteCode,
+    /// Instructions of each function in the table. Everything outside them
+    /// is the top level.
+    ranges: Vec<Range<usize>>,
+}
+
+impl Inspector {
+    pub fn new(bytes: &[u8]) -> Result<Self, String> {
+        let (bytecode, _) = decode_with_sizes(bytes)?;
+        Ok(Self::from_bytecode(bytecode))
+    }
+
+    pub fn from_bytecode(bytecode: ByteCode) -> Self {
+        let len = bytecode.instructions.len();
+        // The compiler puts each body where the function is defined, behind
+        // a jump over it
+        let ranges = bytecode
+            .functions
+            .iter()
+            .map(|function| match function {
+                Value::Function { offset, .. } if *offset > 0 && *offset <= len => {
+                    match bytecode.instructions[offset - 1] {
+                        Instruction::Jump(end) if end >= *offset && end <= len => *offset..end,
+                        _ => *offset..len,
+                    }
+                }
+                _ => len..len,
+            })
+            .collect();
+        Inspector { bytecode, ranges }
+    }
+
+    pub fn bytecode(&self) -> &ByteCode {
+        &self.bytecode
+    }
+
+    /// The answer to one command line, see `HELP`.
+    pub fn command(&self, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let index = |word: &str, count: usize, what: &str| match word.parse::<usize>() {
+            Ok(index) if index < count => Ok(index),
+            _ => Err(format!("No {} '{}', there are {}", what, word, count)),
+        };
+        let result = match words.as_slice() {
+            ["summary"] => Ok(self.summary()),
+            ["constants"] => Ok(self.constants()),
+            ["constant", n] => {
+                index(n, self.bytecode.constants.len(), "constant").map(|n| self.constant(n))
+            }
+            ["functions"] => Ok(self.functions()),
+            ["function", "top"] => Ok(self.listing(None)),
+            ["function", n] => {
+                index(n, self.bytecode.functions.len(), "function").map(|n| self.listing(Some(n)))
+            }
+            ["enums"] => Ok(self.enums()),
+            ["enum", n] => index(n, self.bytecode.enums.len(), "enum").map(|n| self.enum_uses(n)),
+            ["help"] => Ok(HELP.to_string()),
+            _ => Err(format!("Unknown command '{}', try 'help'", line.trim())),
+        };
+        result.unwrap_or_else(|err| err)
+    }
+
+    /// Reads commands from `input` until it ends or one is `quit`, writing
+    /// each answer to `output`.
+    pub fn run(&self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        writeln!(output, "{}\n\ntype 'help' for commands", self.summary())?;
+        write!(output, "inspect> ")?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            match line.trim() {
+                "quit" => return Ok(()),
+                "" => {}
+                command => writeln!(output, "{}", self.command(command))?,
+            }
+            write!(output, "inspect> ")?;
+            output.flush()?;
+        }
+        writeln!(output)
+    }
+
+    fn summary(&self) -> String {
+        let bytecode = &self.bytecode;
+        format!(
+            "{} constants, {} functions, {} enums, {} instructions",
+            bytecode.constants.len(),
+            bytecode.functions.len(),
+            bytecode.enums.len(),
+            bytecode.instructions.len()
+        )
+    }
+
+    fn constants(&self) -> String {
+        let mut out = String::new();
+        for (i, constant) in self.bytecode.constants.iter().enumerate() {
+            let uses = self.constant_uses(i).len();
+            let _ = writeln!(
+                out,
+                "  [{}] {:<32} {} use(s)",
+                i,
+                constant.to_string(),
+                uses
+            );
+        }
+        out.trim_end().to_string()
+    }
+
+    fn constant(&self, index: usize) -> String {
+        let mut out = format!("constant {} = {}", index, self.bytecode.constants[index]);
+        for (i, def) in self.bytecode.enums.iter().enumerate() {
+            if def.name == index {
+                let _ = write!(out, "\n  names enum {}", i);
+            }
+            for variant in def.variants.iter().filter(|v| v.name == index) {
+                let _ = write!(
+                    out,
+                    "\n  names a variant of enum {} with {} field(s)",
+                    i, variant.field_count
+                );
+            }
+        }
+        let uses = self.constant_uses(index);
+        if uses.is_empty() {
+            out.push_str("\n  no instruction uses it");
+        }
+        for pc in uses {
+            out.push('\n');
+            out.push_str(&self.line(pc));
+        }
+        out
+    }
+
+    fn functions(&self) -> String {
+        let mut out = String::new();
+        let top = self.top_level().len();
+        let _ = writeln!(out, "  [top] {} instruction(s)", top);
+        for (i, function) in self.bytecode.functions.iter().enumerate() {
+            let range = &self.ranges[i];
+            let callers = self.function_uses(i).len();
+            let _ = writeln!(
+                out,
+                "  [{}] {:<24} instructions {}..{}, {} reference(s)",
+                i,
+                function.to_string(),
+                range.start,
+                range.end,
+                callers
+            );
+        }
+        out.trim_end().to_string()
+    }
+
+    /// The instructions of function `index`, or of the top level, then the
+    /// instructions that call it or make a closure of it.
+    fn listing(&self, index: Option<usize>) -> String {
+        let (pcs, mut out) = match index {
+            Some(i) => {
+                let range = self.ranges[i].clone();
+                let title = format!(
+                    "function {} {}, instructions {}..{}",
+                    i, self.bytecode.functions[i], range.start, range.end
+                );
+                (range.collect(), title)
+            }
+            None => {
+                let pcs = self.top_level();
+                let title = format!("top level, {} instruction(s)", pcs.len());
+                (pcs, title)
+            }
+        };
+        for pc in pcs {
+            out.push('\n');
+            out.push_str(&self.line(pc));
+        }
+        if let Some(i) = index {
+            let uses = self.function_uses(i);
+            let _ = write!(out, "\nreferenced by {} instruction(s)", uses.len());
+            for pc in uses {
+                out.push('\n');
+                out.push_str(&self.line(pc));
+            }
+        }
+        out
+    }
+
+    fn enums(&self) -> String {
+        let name = |index| self.constant_name(index);
+        let mut out = String::new();
+        for (i, def) in self.bytecode.enums.iter().enumerate() {
+            let variants: Vec<String> = def
+                .variants
+                .iter()
+                .map(|variant| format!("{}/{}", name(variant.name), variant.field_count))
+                .collect();
+            let _ = writeln!(
+                out,
+                "  [{}] {} {{ {} }}",
+                i,
+                name(def.name),
+                variants.join(", ")
+            );
+        }
+        match out.is_empty() {
+            true => "no enums".to_string(),
+            false => out.trim_end().to_string(),
+        }
+    }
+
+    fn enum_uses(&self, index: usize) -> String {
+        let def = &self.bytecode.enums[index];
+        let enum_name = self.constant_name(def.name);
+        let mut out = format!("enum {} {}", index, enum_name);
+        for variant in &def.variants {
+            // Instructions name the variant with a constant of their own,
+            // qualified or not
+            let name = self.constant_name(variant.name);
+            let qualified = format!("{}::{}", enum_name, name);
+            let uses = self.uses(|instruction| match instruction {
+                Instruction::MakeVariant(index, _) | Instruction::TestVariant(index) => matches!(
+                    self.bytecode.constants.get(*index),
+                    Some(Value::String(text)) if *text == name || *text == qualified
+                ),
+                _ => false,
+            });
+            let _ = write!(
+                out,
+                "\n{} with {} field(s), used by {} instruction(s)",
+                name,
+                variant.field_count,
+                uses.len()
+            );
+            for pc in uses {
+                out.push('\n');
+                out.push_str(&self.line(pc));
+            }
+        }
+        out
+    }
+
+    /// The string constant `index`, as enums name themselves and their
+    /// variants.
+    fn constant_name(&self, index: usize) -> String {
+        match self.bytecode.constants.get(index) {
+            Some(Value::String(name)) => name.clone(),
+            _ => format!("#{}", index),
+        }
+    }
+
+    /// Instruction `pc` with its line, what it refers to, and the function
+    /// it is in.
+    fn line(&self, pc: usize) -> String {
+        let instruction = &self.bytecode.instructions[pc];
+        let line = self
+            .bytecode
+            .instruction_lines
+            .get(pc)
+            .copied()
+            .unwrap_or(0);
+        let mut out = format!(
+            "  {:04} {:<28} ; line {}",
+            pc,
+            instruction.to_string(),
+            line
+        );
+        if let Some(constant) = constant_operand(instruction) {
+            match self.bytecode.constants.get(constant) {
+                Some(value) => {
+                    let _ = write!(out, "  = {}", value);
+                }
+                None => out.push_str("  = <missing>"),
+            }
+        }
+        if let Some(function) = function_operand(instruction) {
+            match self.bytecode.functions.get(function) {
+                Some(value) => {
+                    let _ = write!(out, "  -> {}", value);
+                }
+                None => out.push_str("  -> <missing>"),
+            }
+        }
+        match self.owner(pc) {
+            Some(i) => {
+                let _ = write!(out, "  in function {}", i);
+            }
+            None => out.push_str("  in top level"),
+        }
+        out
+    }
+
+    /// The function `pc` is in: the innermost, for a lambda's body inside
+    /// another function's.
+    fn owner(&self, pc: usize) -> Option<usize> {
+        let containing = self.ranges.iter().enumerate();
+        containing
+            .filter(|(_, range)| range.contains(&pc))
+            .min_by_key(|(_, range)| range.len())
+            .map(|(i, _)| i)
+    }
+
+    fn top_level(&self) -> Vec<usize> {
+        let pcs = 0..self.bytecode.instructions.len();
+        pcs.filter(|&pc| self.owner(pc).is_none()).collect()
+    }
+
+    fn constant_uses(&self, index: usize) -> Vec<usize> {
+        self.uses(|instruction| constant_operand(instruction) == Some(index))
+    }
+
+    fn function_uses(&self, index: usize) -> Vec<usize> {
+        self.uses(|instruction| function_operand(instruction) == Some(index))
+    }
+
+    fn uses(&self, refers: impl Fn(&Instruction) -> bool) -> Vec<usize> {
+        let instructions = self.bytecode.instructions.iter().enumerate();
+        instructions
+            .filter(|(_, instruction)| refers(instruction))
+            .map(|(pc, _)| pc)
+            .collect()
+    }
+}
+
+/// The constant an instruction reads or names, as `verify` checks them.
+fn constant_operand(instruction: &Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::LoadConst(index)
+        | Instruction::GetField(index)
+        | Instruction::Invoke(index, _)
+        | Instruction::DefineMethod(index)
+        | Instruction::MakeVariant(index, _)
+        | Instruction::TestVariant(index)
+        | Instruction::HasField(index) => Some(*index),
+        _ => None,
+    }
+}
+
+fn function_operand(instruction: &Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::Call(index) | Instruction::MakeClosure(index, _) => Some(*index),
+        _ => None,
+    }
+}
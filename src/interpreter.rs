@@ -1,19 +1,44 @@
 use crate::compiler::Compiler;
-use crate::types::compiler::{ByteCode, HeapObject, Instruction, Value};
+use crate::dispatch::{self, Op};
+use crate::heap::{GcStats, Heap};
+#[cfg(feature = "jit")]
+use crate::jit::Jit;
+use crate::methods::{self, METHODS};
+#[cfg(feature = "registers")]
+use crate::nanbox::NanBox;
+use crate::natives::{NativeContext, NativeFunction, NativeRegistry};
+#[cfg(feature = "registers")]
+use crate::register::{self, Budget, Translations};
+use crate::replay::{Event, NONDETERMINISTIC, Recording, ReplayMode};
+use crate::types::compiler::{ByteCode, FLAG_STRICT_CONCAT, HeapObject, Instruction, Value, arity};
+#[cfg(feature = "jit")]
+use crate::types::constants::DEFAULT_JIT_THRESHOLD;
 use crate::types::constants::{
-    GC_CHECK_INTERVAL, GC_HISTORY_BUFFER_SIZE, GC_THRESHOLD, HEAP_SCORE_ARRAY_BASE,
-    HEAP_SCORE_ARRAY_PER_ELEMENT, HEAP_SCORE_MAP_BASE, HEAP_SCORE_MAP_PER_ELEMENT,
-    HEAP_SCORE_OTHER_OBJECT, HEAP_SCORE_STRING_BASE, INVALID_HEAP_POINTER_ERROR, MAX_STRING_LENGTH,
+    CANCELLED_ERROR, DEFAULT_MAX_CALL_DEPTH, DEFAULT_MAX_STACK_SIZE, GC_CHECK_INTERVAL,
+    INVALID_HEAP_POINTER_ERROR, LIMIT_CHECK_INTERVAL, MAX_CALLBACK_DEPTH, MAX_STRING_LENGTH,
     UNDERFLOW_ERROR,
 };
 use crate::types::traits::IntoResult;
-use std::collections::VecDeque;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::task::Waker;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone)]
 pub struct StackFrame {
     variables: Vec<Value>,
 }
 
+impl Default for StackFrame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl StackFrame {
     pub fn new() -> Self {
         Self {
@@ -33,131 +58,638 @@ impl StackFrame {
     }
 }
 
+/// Resource limits for running untrusted scripts. Each `run` gets the full
+/// budget; `None` leaves a resource unlimited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VmLimits {
+    pub max_instructions: Option<u64>,
+    /// Estimated size of the live heap, checked after garbage collection.
+    pub max_heap_bytes: Option<usize>,
+    pub wall_clock_timeout: Option<Duration>,
+}
+
+/// Why a run ended in an error that the program itself did not cause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    LimitExceeded,
+    Cancelled,
+}
+
+/// The error a run stops with once it has used up its `wall_clock_timeout`.
+pub(crate) fn timed_out(timeout: Duration) -> String {
+    format!("Timed out after {:?}", timeout)
+}
+
+/// Asks a running VM to stop at its next instruction, from any thread. The
+/// run then fails with "Cancelled". A request is used up by the run it stops,
+/// and one made while nothing runs stops the next run straight away.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, AtomicOrdering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Clears the request, returning whether there was one. Checked before
+    /// every instruction, so the common case is a plain load.
+    pub(crate) fn take(&self) -> bool {
+        self.is_cancelled() && self.0.swap(false, AtomicOrdering::Relaxed)
+    }
+}
+
+/// A task made by `Task.spawn`. Tasks run one at a time, in spawn order,
+/// when something waits for them or when the program ends.
+#[derive(Debug, Clone)]
+struct Task {
+    state: TaskState,
+    /// Whether anything waited for it, so a failure has been reported.
+    joined: bool,
+}
+
+#[derive(Debug, Clone)]
+enum TaskState {
+    Pending(Value), // The closure to call
+    Running,
+    Done(Value),
+    Failed(String),
+}
+
+/// A queue of values between tasks, made by `Channel.new`. It has no bound,
+/// so sending never waits.
+#[derive(Debug, Clone, Default)]
+struct Channel {
+    buffer: VecDeque<Value>,
+    closed: bool,
+}
+
+/// A lazy sequence: the body of a generator function, run by `next` up to
+/// each `yield`, the items of a list a loop goes through, or the steps of an
+/// `unfold`.
+#[derive(Debug, Clone)]
+struct Generator {
+    state: GeneratorState,
+    /// A value taken early by `done`, handed out by the next `next`.
+    peeked: Option<Value>,
+}
+
+#[derive(Debug, Clone)]
+enum GeneratorState {
+    Start(Value), // The body closure, not run yet
+    Suspended(Suspended),
+    Items(VecDeque<Value>),
+    /// `step(state)` gives `[value, next_state]`, or `[]` at the end.
+    Unfold {
+        state: Value,
+        step: Value,
+    },
+    Running,
+    Done,
+}
+
+/// What a generator's body had on the VM's stacks when it yielded.
+#[derive(Debug, Clone)]
+struct Suspended {
+    stack: Vec<Value>,
+    frames: Vec<StackFrame>,
+    return_addresses: Vec<usize>,
+    pc: usize,
+}
+
+/// Where the VM's stacks started when a generator was resumed; a `yield`
+/// takes everything above.
+#[derive(Debug, Clone, Copy)]
+struct Resumed {
+    generator: usize,
+    stack: usize,
+    frames: usize,
+    return_addresses: usize,
+}
+
 pub struct VirtualMachine {
     stack: Vec<Value>,
     stack_frames: Vec<StackFrame>,
     return_addresses: Vec<usize>,
     pc: usize,
+    flags: u16,
     constants: Vec<Value>,
     functions: Vec<Value>,
+    natives: NativeRegistry,
     instructions: Vec<Instruction>,
+    /// `instructions` decoded for dispatch, see `dispatch`.
+    code: Vec<Op>,
+    /// Functions translated to register code, by index, see `register`.
+    #[cfg(feature = "registers")]
+    register_code: Translations,
+    #[cfg(feature = "registers")]
+    registers: Vec<NanBox>,
+    /// Native code of hot register functions, see `jit`.
+    #[cfg(feature = "jit")]
+    jit: Jit,
     instruction_lines: Vec<usize>,
-    heap: Vec<HeapObject>,
-    last_heap_score: VecDeque<usize>,
+    heap: Heap,
+    output: Box<dyn Write + Send>,
+    /// Where `debug_stack` writes, stderr unless the host changes it.
+    debug_output: Box<dyn Write + Send>,
+    exit_code: Option<i32>,
+    recover_on_error: bool,
+    max_call_depth: usize,
+    max_stack_size: usize,
+    callback_depth: usize,
+    /// Callbacks an instruction calls more than once, such as the function
+    /// `map` applies. They are roots while the instruction runs, since a
+    /// collection inside a call moves what they point to.
+    pinned: Vec<Value>,
+    tasks: Vec<Task>,
+    /// Whether `run` leaves queued tasks for the host to `poll_task`.
+    defer_tasks: bool,
+    /// Woken when a task is queued, so a host event loop polls again.
+    wakers: Vec<Waker>,
+    channels: Vec<Channel>,
+    generators: Vec<Generator>,
+    resumed: Vec<Resumed>,
+    /// Closures of `impl` methods, by `Type.method` name.
+    methods: HashMap<String, Value>,
+    limits: VmLimits,
+    executed: u64,
+    started: Option<Instant>,
+    stopped: Option<StopReason>,
+    cancellation: CancellationToken,
+    replay: ReplayMode,
+    /// With coverage on, how many times each instruction ran.
+    coverage: Option<Vec<u64>>,
     raw_compiler: Compiler,
 }
 
 impl VirtualMachine {
     pub fn new(bytecode: ByteCode, compiler: Compiler) -> Self {
-        let vm = Self {
+        Self {
             stack: Vec::new(),
             stack_frames: vec![StackFrame::new()],
             return_addresses: Vec::new(),
             pc: 0,
+            flags: bytecode.flags,
+            natives: compiler.natives.clone(),
             raw_compiler: compiler,
+            code: dispatch::decode(&bytecode.instructions),
+            #[cfg(feature = "registers")]
+            register_code: Translations::new(&bytecode.instructions, &bytecode.functions),
+            #[cfg(feature = "registers")]
+            registers: Vec::new(),
+            #[cfg(feature = "jit")]
+            jit: Jit::new(bytecode.functions.len(), DEFAULT_JIT_THRESHOLD),
             constants: bytecode.constants,
             functions: bytecode.functions,
             instructions: bytecode.instructions,
             instruction_lines: bytecode.instruction_lines,
-            heap: Vec::new(),
-            last_heap_score: VecDeque::new(),
+            heap: Heap::new(),
+            output: Box::new(io::stdout()),
+            debug_output: Box::new(io::stderr()),
+            exit_code: None,
+            recover_on_error: false,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_stack_size: DEFAULT_MAX_STACK_SIZE,
+            callback_depth: 0,
+            pinned: Vec::new(),
+            tasks: Vec::new(),
+            defer_tasks: false,
+            wakers: Vec::new(),
+            channels: Vec::new(),
+            generators: Vec::new(),
+            resumed: Vec::new(),
+            methods: HashMap::new(),
+            limits: VmLimits::default(),
+            executed: 0,
+            started: None,
+            stopped: None,
+            cancellation: CancellationToken::new(),
+            replay: ReplayMode::Off,
+            coverage: None,
+        }
+    }
+
+    /// Redirects everything scripts print, e.g. into a buffer owned by the host.
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.output = output;
+    }
+
+    /// Redirects `debug_stack`, e.g. into the host's log, away from stderr.
+    pub fn set_debug_output(&mut self, output: Box<dyn Write + Send>) {
+        self.debug_output = output;
+    }
+
+    /// Takes back the writer given to `set_output`, leaving stdout.
+    pub fn take_output(&mut self) -> Box<dyn Write + Send> {
+        std::mem::replace(&mut self.output, Box::new(io::stdout()))
+    }
+
+    /// Takes back the writer given to `set_debug_output`, leaving stderr.
+    pub fn take_debug_output(&mut self) -> Box<dyn Write + Send> {
+        std::mem::replace(&mut self.debug_output, Box::new(io::stderr()))
+    }
+
+    /// Sets a top-level variable before (or between) runs. `index` comes from
+    /// `Compiler::declare_global`.
+    pub fn set_global(&mut self, index: usize, value: Value) {
+        let value = self.heap_push(value.clone()).unwrap_or(value);
+        self.stack_frames[0].set_variable(index, value);
+    }
+
+    pub fn global(&self, index: usize) -> Option<&Value> {
+        self.stack_frames[0].get_variable(index)
+    }
+
+    /// In recovery mode a runtime error unwinds the VM to the top level instead of
+    /// leaving it mid-call, so globals and the heap survive for the next `load`.
+    pub fn set_recover_on_error(&mut self, recover: bool) {
+        self.recover_on_error = recover;
+    }
+
+    /// Caps how deeply script functions may call each other, so runaway
+    /// recursion fails with a runtime error instead of exhausting memory.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Caps the number of values on the operand stack.
+    pub fn set_max_stack_size(&mut self, size: usize) {
+        self.max_stack_size = size;
+    }
+
+    /// Sets how many times a function the register code runs is called
+    /// before it is compiled to native code. Defaults to
+    /// `DEFAULT_JIT_THRESHOLD`; functions compiled already stay so.
+    #[cfg(feature = "jit")]
+    pub fn set_jit_threshold(&mut self, threshold: u32) {
+        self.jit.set_threshold(threshold);
+    }
+
+    /// Whether function `index` has been compiled to native code.
+    #[cfg(feature = "jit")]
+    pub fn jit_compiled(&self, index: usize) -> bool {
+        self.jit.is_compiled(index)
+    }
+
+    /// With `defer`, `run` returns with spawned tasks still queued instead
+    /// of finishing them, for a host event loop to drive with `poll_task`.
+    pub fn set_defer_tasks(&mut self, defer: bool) {
+        self.defer_tasks = defer;
+    }
+
+    /// Wakes `waker` the next time a task is queued.
+    pub fn add_waker(&mut self, waker: Waker) {
+        self.wakers.push(waker);
+    }
+
+    /// Queues a call of closure `callee`, as `Task.spawn` does, and returns
+    /// the task's id.
+    pub fn queue_call(&mut self, callee: Value) -> Result<usize, String> {
+        match self.spawn_task(callee)? {
+            Value::Task(id) => Ok(id),
+            _ => unreachable!("spawn_task returns a task"),
+        }
+    }
+
+    /// Runs the oldest queued task, if any, and returns whether tasks are
+    /// still queued. Errors only when the VM was stopped by its limits or
+    /// cancelled; a failing task is recorded like any other result.
+    pub fn poll_task(&mut self) -> Result<bool, String> {
+        self.executed = 0;
+        self.started = self.limits.wall_clock_timeout.map(|_| Instant::now());
+        self.stopped = None;
+        self.run_next_task()?;
+        Ok(self
+            .tasks
+            .iter()
+            .any(|task| matches!(task.state, TaskState::Pending(_))))
+    }
+
+    /// The result of task `id` once it has finished, which counts as waiting
+    /// for it. `None` while it is queued or running.
+    pub fn task_result(&mut self, id: usize) -> Option<Result<Value, String>> {
+        let task = self.tasks.get_mut(id)?;
+        let result = match &task.state {
+            TaskState::Done(value) => Ok(value.clone()),
+            TaskState::Failed(e) => Err(format!("Task {} failed: {}", id, e)),
+            TaskState::Pending(_) | TaskState::Running => return None,
         };
-        vm
+        task.joined = true;
+        Some(result)
     }
 
-    fn gc(&mut self) {
-        // Mark phase: Find all live objects by tracing from stack variables
-        let mut marked = vec![false; self.heap.len()];
-        for frame in &self.stack_frames {
-            for value in &frame.variables {
-                if let Value::HeapPointer(idx) = value {
-                    if *idx < marked.len() {
-                        marked[*idx] = true;
-                    }
-                }
+    /// Starts recording the results of the natives in `NONDETERMINISTIC`.
+    pub fn record(&mut self) {
+        self.replay = ReplayMode::Record(Recording::default());
+    }
+
+    /// What was recorded since `record`, ending the recording.
+    pub fn take_recording(&mut self) -> Option<Recording> {
+        match std::mem::take(&mut self.replay) {
+            ReplayMode::Record(recording) => Some(recording),
+            other => {
+                self.replay = other;
+                None
             }
         }
+    }
 
-        // Sweep phase: Build new compacted heap and create index mapping
-        let mut new_heap = Vec::with_capacity(self.heap.len());
-        let mut remap = vec![None; self.heap.len()];
-        for (i, (obj, is_marked)) in self.heap.iter().zip(marked.iter()).enumerate() {
-            if *is_marked {
-                remap[i] = Some(new_heap.len());
-                new_heap.push(obj.clone());
+    /// Answers calls of the natives in `NONDETERMINISTIC` from `recording`,
+    /// in order, instead of calling them. A call the recording does not have
+    /// next is a runtime error, since the run has left the recorded path.
+    pub fn replay(&mut self, recording: Recording) {
+        self.replay = ReplayMode::Replay(recording);
+    }
+
+    /// Starts counting how often each line of the program runs, for
+    /// `line_hits`.
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(vec![0; self.instructions.len()]);
+    }
+
+    /// How many times each line of the program's own file ran, for every
+    /// line that compiled to instructions, or `None` without
+    /// `enable_coverage`. The prelude and imported modules are left out.
+    pub fn line_hits(&self) -> Option<BTreeMap<usize, u64>> {
+        let hits = self.coverage.as_ref()?;
+        let modules = &self.raw_compiler.module_instructions;
+        let mut lines = BTreeMap::new();
+        for (at, (&line, &count)) in self.instruction_lines.iter().zip(hits).enumerate() {
+            // `Halt` never runs, and ends the program on whatever line came last
+            if line == 0
+                || matches!(self.instructions[at], Instruction::Halt)
+                || modules.iter().any(|range| range.contains(&at))
+            {
+                continue;
             }
+            let entry = lines.entry(line).or_insert(0);
+            *entry = count.max(*entry);
         }
+        Some(lines)
+    }
 
-        // Update phase: Fix all heap pointer references to use new indices
-        for frame in &mut self.stack_frames {
-            for value in &mut frame.variables {
-                if let Value::HeapPointer(idx) = value {
-                    if *idx < remap.len() {
-                        if let Some(new_idx) = remap[*idx] {
-                            *value = Value::HeapPointer(new_idx);
-                        }
-                    }
-                }
-            }
+    pub fn set_limits(&mut self, limits: VmLimits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> &VmLimits {
+        &self.limits
+    }
+
+    /// Why the last `run` was stopped from outside the program, if it was.
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stopped
+    }
+
+    /// A token other threads can use to stop this VM, see `CancellationToken`.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Replaces the program with `bytecode`, which must extend the previously
+    /// loaded one (as produced by compiling more input with the same compiler).
+    /// Execution resumes at the first new instruction; top-level variables and
+    /// the heap are kept.
+    pub fn load(&mut self, bytecode: ByteCode, compiler: Compiler) {
+        self.pc = self.instructions.len();
+        self.exit_code = None;
+        self.flags = bytecode.flags;
+        self.natives = compiler.natives.clone();
+        self.raw_compiler = compiler;
+        #[cfg(feature = "registers")]
+        {
+            self.register_code = Translations::new(&bytecode.instructions, &bytecode.functions);
+        }
+        #[cfg(feature = "jit")]
+        {
+            self.jit = Jit::new(bytecode.functions.len(), self.jit.threshold());
+        }
+        self.constants = bytecode.constants;
+        self.functions = bytecode.functions;
+        self.code = dispatch::decode(&bytecode.instructions);
+        self.instructions = bytecode.instructions;
+        self.instruction_lines = bytecode.instruction_lines;
+        if let Some(hits) = &mut self.coverage {
+            hits.resize(self.instructions.len(), 0);
         }
+    }
 
-        // Replace old heap with compacted heap
-        self.heap = new_heap;
+    /// Drops every call frame and temporary above the top level.
+    pub fn unwind(&mut self) {
+        self.stack.clear();
+        self.stack_frames.truncate(1);
+        self.return_addresses.clear();
+        self.callback_depth = 0;
+        self.pinned.clear();
+        self.resumed.clear();
+        self.pc = self.instructions.len();
     }
 
-    fn heap_score(&mut self) -> usize {
-        let mut heap_score: usize = 0;
-        for obj in &self.heap {
-            match obj {
-                HeapObject::Array(arr) => {
-                    heap_score += HEAP_SCORE_ARRAY_BASE + arr.len() * HEAP_SCORE_ARRAY_PER_ELEMENT;
-                }
-                HeapObject::String(s) => {
-                    heap_score += HEAP_SCORE_STRING_BASE + s.len();
-                }
-                HeapObject::Object(map) => {
-                    heap_score += HEAP_SCORE_MAP_BASE + map.len() * HEAP_SCORE_MAP_PER_ELEMENT;
-                }
-                _ => {
-                    heap_score += HEAP_SCORE_OTHER_OBJECT;
+    /// Renders a value for display, following heap pointers.
+    pub fn format_value(&self, value: &Value) -> String {
+        match value {
+            Value::HeapPointer(idx) => match self.heap.get(*idx) {
+                Some(object) => object.to_string(),
+                None => INVALID_HEAP_POINTER_ERROR.to_string(),
+            },
+            _ => value.to_string(),
+        }
+    }
+
+    fn gc(&mut self) {
+        if self.heap.needs_collection() {
+            self.with_roots(Heap::maybe_collect);
+        }
+    }
+
+    /// Calls `collect` with the GC roots: every value the program can still
+    /// reach, on the operand stack, in the variables of each live frame and
+    /// in tasks, channels and generators.
+    fn with_roots(&mut self, collect: impl FnOnce(&mut Heap, &mut [&mut Value])) {
+        let mut roots: Vec<&mut Value> = Vec::new();
+        let values = self
+            .stack
+            .iter_mut()
+            .chain(
+                self.stack_frames
+                    .iter_mut()
+                    .flat_map(|frame| frame.variables.iter_mut()),
+            )
+            .chain(self.pinned.iter_mut());
+        let task_values = self
+            .tasks
+            .iter_mut()
+            .filter_map(|task| match &mut task.state {
+                TaskState::Pending(value) | TaskState::Done(value) => Some(value),
+                TaskState::Running | TaskState::Failed(_) => None,
+            });
+        let channel_values = self
+            .channels
+            .iter_mut()
+            .flat_map(|channel| channel.buffer.iter_mut());
+        let generator_values = self.generators.iter_mut().flat_map(|generator| {
+            let state: Box<dyn Iterator<Item = &mut Value>> = match &mut generator.state {
+                GeneratorState::Start(value) => Box::new(std::iter::once(value)),
+                GeneratorState::Suspended(suspended) => Box::new(
+                    suspended.stack.iter_mut().chain(
+                        suspended
+                            .frames
+                            .iter_mut()
+                            .flat_map(|frame| frame.variables.iter_mut()),
+                    ),
+                ),
+                GeneratorState::Items(items) => Box::new(items.iter_mut()),
+                GeneratorState::Unfold { state, step } => {
+                    Box::new(std::iter::once(state).chain(std::iter::once(step)))
                 }
-            }
+                GeneratorState::Running | GeneratorState::Done => Box::new(std::iter::empty()),
+            };
+            state.chain(generator.peeked.iter_mut())
+        });
+        for value in values
+            .chain(task_values)
+            .chain(channel_values)
+            .chain(generator_values)
+        {
+            push_roots(value, &mut roots);
+        }
+        collect(&mut self.heap, &mut roots);
+    }
+
+    /// Fails once the heap outgrows `max_heap_bytes` even after a full
+    /// collection. Only safe between instructions, or inside a callback
+    /// whose caller keeps the values it still needs in `pinned`.
+    fn check_heap_limit(&mut self) -> Result<(), String> {
+        let Some(max) = self.limits.max_heap_bytes else {
+            return Ok(());
+        };
+        if self.heap.score() > max {
+            self.with_roots(Heap::collect_major);
         }
-        self.last_heap_score.push_back(heap_score);
-        if self.last_heap_score.len() > GC_HISTORY_BUFFER_SIZE {
-            self.last_heap_score.pop_front();
+        if self.heap.score() > max {
+            self.stopped = Some(StopReason::LimitExceeded);
+            return Err(format!("Heap limit exceeded ({} bytes)", max));
         }
-        heap_score
+        Ok(())
+    }
+
+    /// Exit code requested by the program (e.g. via `OS.exit`), if it asked to stop.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
     }
 
-    pub fn run(&mut self) -> Result<(), String> {
+    pub fn gc_stats(&self) -> &GcStats {
+        self.heap.stats()
+    }
+
+    /// Runs from the current position to the end of the program. Returns the
+    /// value left on top of the stack, which is the result of `main` or of a
+    /// trailing expression kept with `CompileOptions::keep_last_value`. A
+    /// program stopped by `OS.exit` returns `None`.
+    pub fn run(&mut self) -> Result<Option<Value>, String> {
+        self.executed = 0;
+        self.started = self.limits.wall_clock_timeout.map(|_| Instant::now());
+        self.stopped = None;
         while self.pc < self.instructions.len() {
-            if (self.pc + 1) % GC_CHECK_INTERVAL == 0 {
-                let heap_score = self.heap_score();
-                if heap_score >= GC_THRESHOLD {
-                    self.gc();
-                }
-            }
             match &self.instructions[self.pc] {
                 Instruction::Halt => break,
                 _ => {
-                    if let Err(e) = self.execute_instruction() {
+                    if let Err(e) = self.step() {
                         let line = self.instruction_lines.get(self.pc).cloned().unwrap_or(0);
+                        if self.recover_on_error {
+                            self.unwind();
+                        }
                         return Err(format!("[line {}] {}", line, e));
                     }
+                    if self.exit_code.is_some() {
+                        if self.recover_on_error {
+                            self.unwind();
+                        }
+                        return Ok(None);
+                    }
                 }
             }
         }
+        if self.defer_tasks {
+            return Ok(self.stack.pop());
+        }
+        if let Err(e) = self.finish_tasks() {
+            if self.recover_on_error {
+                self.unwind();
+            }
+            return Err(e);
+        }
+        Ok(self.stack.pop())
+    }
+
+    /// Executes one instruction, enforcing the stack size limit, the
+    /// instruction, heap and time budgets and cancellation. Callbacks and
+    /// generators run through here too, so they are held to the same limits
+    /// and collect garbage like the top level.
+    fn step(&mut self) -> Result<(), String> {
+        if self.cancellation.take() {
+            self.stopped = Some(StopReason::Cancelled);
+            return Err(CANCELLED_ERROR.to_string());
+        }
+        // Counted in steps, not positions, since a superinstruction skips
+        // the positions it covers
+        if (self.executed + 1).is_multiple_of(GC_CHECK_INTERVAL) {
+            self.gc();
+            self.check_heap_limit()?;
+        }
+        self.executed += 1;
+        if let Some(max) = self.limits.max_instructions
+            && self.executed > max
+        {
+            self.stopped = Some(StopReason::LimitExceeded);
+            return Err(format!("Instruction limit exceeded ({})", max));
+        }
+        if let (Some(timeout), Some(started)) = (self.limits.wall_clock_timeout, self.started)
+            && self.executed.is_multiple_of(LIMIT_CHECK_INTERVAL)
+            && started.elapsed() > timeout
+        {
+            self.stopped = Some(StopReason::LimitExceeded);
+            return Err(timed_out(timeout));
+        }
+        if let Some(hits) = &mut self.coverage {
+            hits[self.pc] += 1;
+        }
+        self.execute_instruction()?;
+        if self.stack.len() > self.max_stack_size {
+            return Err(format!(
+                "Stack overflow: more than {} values on the stack",
+                self.max_stack_size
+            ));
+        }
         Ok(())
     }
 
     fn execute_instruction(&mut self) -> Result<(), String> {
-        match &self.instructions[self.pc].clone() {
-            Instruction::Push(value) => {
+        let op = self.code[self.pc];
+        match &op {
+            Op::Push => {
+                let Instruction::Push(value) = &self.instructions[self.pc] else {
+                    unreachable!("decoded from a push");
+                };
                 self.stack.push(value.clone());
             }
 
-            Instruction::LoadConst(index) => {
+            Op::LoadConst(index) => {
                 let value = self
                     .constants
                     .get(*index)
@@ -166,62 +698,96 @@ impl VirtualMachine {
                 self.stack.push(value);
             }
 
-            Instruction::StoreVar(_, var_index) => {
+            Op::StoreVar(_, var_index) => {
                 let value = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
 
                 self.set_variable(*var_index, value)?;
             }
 
-            Instruction::LoadVar(depth, var_index) => {
+            Op::LoadVar(depth, var_index) => {
                 let value = self.resolve_variable(*depth, *var_index)?;
                 self.stack.push(value);
             }
 
-            Instruction::LoadArg(arg_count) => {
-                let mut args = Vec::new();
-                for _ in 0..*arg_count {
-                    args.push(self.stack.pop().ok_or("Not enough arguments")?);
-                }
-                for (param_index, arg_value) in args.iter().rev().enumerate() {
-                    self.set_variable(param_index, arg_value.clone())?;
+            Op::LoadGlobal(var_index) => {
+                let value = self.resolve_global(*var_index)?;
+                self.stack.push(value);
+            }
+
+            Op::LoadArg(arg_count) => {
+                // Arguments are pushed last-to-first, so the first pop is parameter 0
+                for param_index in 0..*arg_count {
+                    let arg_value = self.stack.pop().ok_or("Not enough arguments")?;
+                    self.set_variable(param_index, arg_value)?;
                 }
             }
 
-            Instruction::Add => {
+            Op::LoadConstStore(const_index, var_index) => {
+                let value = self
+                    .constants
+                    .get(*const_index)
+                    .ok_or("Invalid constant index")?
+                    .clone();
+                self.set_variable(*var_index, value)?;
+            }
+
+            Op::LoadVarConstAdd(depth, var_index, const_index) => {
+                let a = self.resolve_variable(*depth, *var_index)?;
+                let b = self
+                    .constants
+                    .get(*const_index)
+                    .ok_or("Invalid constant index")?
+                    .clone();
+                let sum = self.add(a, b)?;
+                self.stack.push(sum);
+            }
+
+            Op::Add => {
+                let b = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let a = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let sum = self.add(a, b)?;
+                self.stack.push(sum);
+            }
+
+            Op::Concat => {
                 let b = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
                 let a = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let result = format!("{}{}", self.concat_operand(&a)?, self.concat_operand(&b)?);
+                self.stack.push(Value::String(result));
+            }
 
-                match (&a, &b) {
-                    (Value::Number(a_num), Value::Number(b_num)) => {
-                        self.stack.push(Value::Number(a_num + b_num));
-                    }
-                    (Value::String(a_str), Value::String(b_str)) => {
-                        let result = format!("{}{}", a_str, b_str);
-                        self.stack.push(Value::String(result));
-                    }
-                    _ => {
-                        return Err(format!(
-                            "Cannot add {} and {} - both operands must be the same type",
-                            a.type_name(&self.heap),
-                            b.type_name(&self.heap)
-                        ));
-                    }
+            Op::ConcatN(count) => {
+                let start = self
+                    .stack
+                    .len()
+                    .checked_sub(*count)
+                    .ok_or(UNDERFLOW_ERROR)?;
+                let values = self.stack.split_off(start);
+                let parts = values
+                    .iter()
+                    .map(|value| self.concat_operand(value))
+                    .collect::<Result<Vec<_>, _>>()?;
+                // Sized up front, so the parts are copied once
+                let mut result = String::with_capacity(parts.iter().map(|part| part.len()).sum());
+                for part in &parts {
+                    result.push_str(part);
                 }
+                self.stack.push(Value::String(result));
             }
 
-            Instruction::Sub => {
+            Op::Sub => {
                 let b: f64 = self.pop_value()?;
                 let a: f64 = self.pop_value()?;
                 self.stack.push(Value::Number(a - b));
             }
 
-            Instruction::Mul => {
+            Op::Mul => {
                 let b: f64 = self.pop_value()?;
                 let a: f64 = self.pop_value()?;
                 self.stack.push(Value::Number(a * b));
             }
 
-            Instruction::Div => {
+            Op::Div => {
                 let b: f64 = self.pop_value()?;
                 let a: f64 = self.pop_value()?;
                 if b == 0.0 {
@@ -230,30 +796,31 @@ impl VirtualMachine {
                 self.stack.push(Value::Number(a / b));
             }
 
-            Instruction::Equal => {
+            Op::Equal => {
                 const STACK_UNDERFLOW: &str = UNDERFLOW_ERROR;
                 let b: Value = self.stack.pop().ok_or(STACK_UNDERFLOW)?;
                 let a: Value = self.stack.pop().ok_or(STACK_UNDERFLOW)?;
                 let result = self.values_equal(&a, &b);
-                self.stack
-                    .push(Value::Boolean(if result { true } else { false }));
+                self.stack.push(Value::Boolean(result));
             }
 
-            Instruction::Less => {
-                let b: f64 = self.pop_value()?;
-                let a: f64 = self.pop_value()?;
+            Op::Less => {
+                let b = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let a = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let ordering = self.compare_values(&a, &b)?;
                 self.stack
-                    .push(Value::Boolean(if a < b { true } else { false }));
+                    .push(Value::Boolean(ordering == Some(Ordering::Less)));
             }
 
-            Instruction::Greater => {
-                let b: f64 = self.pop_value()?;
-                let a: f64 = self.pop_value()?;
+            Op::Greater => {
+                let b = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let a = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let ordering = self.compare_values(&a, &b)?;
                 self.stack
-                    .push(Value::Boolean(if a > b { true } else { false }));
+                    .push(Value::Boolean(ordering == Some(Ordering::Greater)));
             }
 
-            Instruction::Not => {
+            Op::Not => {
                 let value = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
                 match value {
                     Value::Boolean(b) => {
@@ -268,7 +835,7 @@ impl VirtualMachine {
                 }
             }
 
-            Instruction::CreateArray(size) => {
+            Op::CreateArray(size) => {
                 let mut elements = Vec::new();
                 for _ in 0..*size {
                     let element = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
@@ -276,13 +843,11 @@ impl VirtualMachine {
                 }
                 elements.reverse();
 
-                let heap_array = HeapObject::Array(elements);
-                self.heap.push(heap_array);
-                let heap_index = self.heap.len() - 1;
+                let heap_index = self.heap.allocate(HeapObject::Array(elements.into()));
                 self.stack.push(Value::HeapPointer(heap_index));
             }
 
-            Instruction::ConcatArray => {
+            Op::ConcatArray => {
                 let right = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
                 let left = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
 
@@ -290,9 +855,9 @@ impl VirtualMachine {
                     (Value::HeapPointer(li), Value::HeapPointer(ri)) => (li, ri),
                     (l, r) => {
                         return Err(format!(
-                            "Update expects arrays, got {} and {}",
-                            l.type_name(&self.heap),
-                            r.type_name(&self.heap)
+                            "Expected lists, got {} and {}",
+                            l.type_name(self.heap.objects()),
+                            r.type_name(self.heap.objects())
                         ));
                     }
                 };
@@ -300,62 +865,335 @@ impl VirtualMachine {
                 let left_arr = self.heap.get(left_idx).ok_or(INVALID_HEAP_POINTER_ERROR)?;
                 let right_arr = self.heap.get(right_idx).ok_or(INVALID_HEAP_POINTER_ERROR)?;
 
-                match (left_arr, right_arr) {
+                let updated = match (left_arr, right_arr) {
                     (HeapObject::Array(left_vec), HeapObject::Array(right_vec)) => {
-                        let mut new_vec = Vec::with_capacity(left_vec.len() + right_vec.len());
-                        new_vec.extend_from_slice(left_vec);
-                        new_vec.extend_from_slice(right_vec);
-                        self.heap.push(HeapObject::Array(new_vec));
-                        let idx = self.heap.len() - 1;
-                        self.stack.push(Value::HeapPointer(idx));
+                        let mut new_vec = left_vec.clone();
+                        new_vec.extend(right_vec.iter().cloned());
+                        HeapObject::Array(new_vec)
                     }
-                    _ => {
-                        return Err("Update expects arrays".to_string());
+                    // `record <- { field = value }` replaces fields
+                    (HeapObject::Record { type_name, fields }, HeapObject::Object(changes)) => {
+                        let mut changes: Vec<_> = changes.iter().collect();
+                        changes.sort_by_key(|(name, _)| *name);
+                        let mut fields = fields.clone();
+                        for (name, value) in changes {
+                            let Some(position) = fields.iter().position(|(field, _)| field == name)
+                            else {
+                                return Err(format!("{} has no field '{}'", type_name, name));
+                            };
+                            fields.set(position, (name.clone(), value.clone()));
+                        }
+                        HeapObject::Record {
+                            type_name: type_name.clone(),
+                            fields,
+                        }
                     }
-                }
+                    (HeapObject::Object(map), HeapObject::Object(changes)) => {
+                        let mut map = map.clone();
+                        map.extend(changes.iter().map(|(k, v)| (k.clone(), v.clone())));
+                        HeapObject::Object(map)
+                    }
+                    (l, r) => {
+                        return Err(format!(
+                            "Expected lists, got {} and {}",
+                            l.type_name(),
+                            r.type_name()
+                        ));
+                    }
+                };
+                let idx = self.heap.allocate(updated);
+                self.stack.push(Value::HeapPointer(idx));
             }
 
-            Instruction::Jump(addr) => {
+            Op::Jump(addr) => {
                 self.pc = *addr;
                 return Ok(());
             }
 
-            Instruction::JumpIfFalse(addr) => {
-                let value: bool = self.pop_value()?;
-                if value == false {
+            Op::JumpIfFalse(addr) => {
+                let value = self.pop_condition()?;
+                if !value {
                     self.pc = *addr;
                     return Ok(());
                 }
             }
 
-            Instruction::JumpIfTrue(addr) => {
-                let value: bool = self.pop_value()?;
-                if value == true {
+            Op::JumpIfTrue(addr) => {
+                let value = self.pop_condition()?;
+                if value {
                     self.pc = *addr;
                     return Ok(());
                 }
             }
 
-            Instruction::Call(func_index) => {
-                let function = self
-                    .functions
-                    .get(*func_index)
-                    .ok_or("Invalid function index")?;
+            Op::Call(func_index) => {
+                #[cfg(feature = "registers")]
+                if self.call_registers(*func_index) {
+                    return Ok(());
+                }
+                return self.enter_function(*func_index);
+            }
+
+            Op::MakeClosure(function, arg_count) => {
+                let bound = self.pop_args(*arg_count)?;
+                self.stack.push(Value::Closure {
+                    function: *function,
+                    bound,
+                });
+            }
 
-                if let Value::Function { offset, .. } = function {
-                    self.return_addresses.push(self.pc + 1);
+            Op::CallValue(arg_count) => {
+                let callee = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let args = self.pop_args(*arg_count)?;
+                return self.call_value(callee, args);
+            }
 
-                    let new_frame = StackFrame::new();
-                    self.stack_frames.push(new_frame);
+            Op::Apply => {
+                let callee = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let list = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let HeapObject::Array(items) = self.heap.load(&list)? else {
+                    return Err(format!(
+                        "Spread arguments must be a list, got {}",
+                        list.type_name(self.heap.objects())
+                    ));
+                };
+                let args = items
+                    .into_iter()
+                    .map(|item| self.heap.store(item))
+                    .collect();
+                return self.call_value(callee, args);
+            }
 
-                    self.pc = *offset;
-                    return Ok(());
-                } else {
-                    return Err("Invalid function value".to_string());
+            Op::Invoke(name, arg_count) => {
+                let receiver = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let mut args = self.pop_args(*arg_count)?;
+                let name = self.constant_string(*name)?;
+                let type_name = self.type_of(&receiver);
+                let method = self.method_of(&receiver, &name);
+                args.insert(0, receiver);
+                if let Some(method) = method {
+                    return self.call_value(method, args);
+                }
+                // Built-in methods apply to values without one of their own
+                match methods::resolve(&name) {
+                    Some(method) if METHODS[method].arity == args.len() => {
+                        let result = self.call_method(method, args)?;
+                        self.stack.push(result);
+                    }
+                    _ => {
+                        return Err(match type_name {
+                            Some(type_name) => format!("'{}' has no method '{}'", type_name, name),
+                            None => format!(
+                                "A {} has no method '{}'",
+                                args[0].type_name(self.heap.objects()),
+                                name
+                            ),
+                        });
+                    }
+                }
+            }
+
+            Op::DefineMethod(name) => {
+                let method = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let name = self.constant_string(*name)?;
+                self.methods.insert(name, method);
+            }
+
+            Op::MakeRecord(field_count) => {
+                let Some(Value::String(type_name)) = self.stack.pop() else {
+                    return Err("Expected a record type name".to_string());
+                };
+                if self.stack.len() < field_count * 2 {
+                    return Err(UNDERFLOW_ERROR.to_string());
+                }
+                let values = self.stack.split_off(self.stack.len() - field_count * 2);
+                let mut fields = Vec::with_capacity(*field_count);
+                for pair in values.chunks(2) {
+                    let Value::String(name) = &pair[0] else {
+                        return Err("Expected a field name".to_string());
+                    };
+                    fields.push((name.clone(), self.heap.load(&pair[1])?));
+                }
+                let record = match type_name.is_empty() {
+                    true => HeapObject::Object(fields.into_iter().collect()),
+                    false => HeapObject::Record {
+                        type_name,
+                        fields: fields.into(),
+                    },
+                };
+                let record = self.heap.store(record);
+                self.stack.push(record);
+            }
+
+            Op::MakeVariant(name, field_count) => {
+                let name = self.constant_string(*name)?;
+                if self.stack.len() < *field_count {
+                    return Err(UNDERFLOW_ERROR.to_string());
+                }
+                let values = self
+                    .stack
+                    .split_off(self.stack.len() - field_count)
+                    .iter()
+                    .map(|value| self.heap.load(value))
+                    .collect::<Result<_, _>>()?;
+                let variant = self.heap.store(HeapObject::Variant { name, values });
+                self.stack.push(variant);
+            }
+
+            Op::MakeTuple(count) => {
+                if self.stack.len() < *count {
+                    return Err(UNDERFLOW_ERROR.to_string());
+                }
+                let elements = self
+                    .stack
+                    .split_off(self.stack.len() - count)
+                    .iter()
+                    .map(|value| self.heap.load(value))
+                    .collect::<Result<_, _>>()?;
+                let tuple = self.heap.store(HeapObject::Tuple(elements));
+                self.stack.push(tuple);
+            }
+
+            Op::TestTuple(count) => {
+                let value = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let is_tuple = matches!(
+                    self.as_object(&value).as_deref(),
+                    Some(HeapObject::Tuple(elements)) if elements.len() == *count
+                );
+                self.stack.push(Value::Boolean(is_tuple));
+            }
+
+            Op::TestList(count, at_least) => {
+                let value = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let is_list = matches!(
+                    self.as_object(&value).as_deref(),
+                    Some(HeapObject::Array(elements))
+                        if elements.len() == *count || (*at_least && elements.len() > *count)
+                );
+                self.stack.push(Value::Boolean(is_list));
+            }
+
+            Op::HasField(name) => {
+                let value = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let name = self.constant_string(*name)?;
+                let has_field = match self.as_object(&value).as_deref() {
+                    Some(HeapObject::Record { fields, .. }) => {
+                        fields.iter().any(|(field, _)| *field == name)
+                    }
+                    Some(HeapObject::Object(map)) => map.contains_key(&name),
+                    _ => false,
+                };
+                self.stack.push(Value::Boolean(has_field));
+            }
+
+            Op::ListFrom(start) => {
+                let value = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let Ok(HeapObject::Array(elements)) = self.heap.load(&value) else {
+                    return Err(format!(
+                        "Expected a list, got {}",
+                        value.type_name(self.heap.objects())
+                    ));
+                };
+                let rest = elements.skip(*start);
+                let rest = self.heap.store(HeapObject::Array(rest));
+                self.stack.push(rest);
+            }
+
+            Op::TestVariant(name) => {
+                let value = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let name = self.constant_string(*name)?;
+                let is_variant = match &value {
+                    Value::HeapPointer(idx) => matches!(
+                        self.heap.get(*idx),
+                        Some(HeapObject::Variant { name: variant, .. }) if *variant == name
+                    ),
+                    _ => false,
+                };
+                self.stack.push(Value::Boolean(is_variant));
+            }
+
+            Op::NoMatch => {
+                let value = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                return Err(format!("No pattern matched {}", self.format_value(&value)));
+            }
+
+            // A number constant reads a variant's field by position
+            Op::GetField(position)
+                if let Some(Value::Number(position)) = self.constants.get(*position) =>
+            {
+                let position = *position as usize;
+                let value = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let field =
+                    match self.heap.load(&value) {
+                        Ok(
+                            HeapObject::Variant { mut values, .. } | HeapObject::Tuple(mut values),
+                        ) if position < values.len() => values.swap_remove(position),
+                        Ok(HeapObject::Array(values)) if position < values.len() => {
+                            values[position].clone()
+                        }
+                        _ => {
+                            return Err(format!(
+                                "Cannot read field {} of a {}",
+                                position,
+                                value.type_name(self.heap.objects())
+                            ));
+                        }
+                    };
+                let field = self.heap.store(field);
+                self.stack.push(field);
+            }
+
+            Op::GetField(name) => {
+                let record = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let name = self.constant_string(*name)?;
+                let field = match self.heap.load(&record) {
+                    Ok(HeapObject::Record { fields, .. }) => fields
+                        .into_iter()
+                        .find(|(field, _)| *field == name)
+                        .map(|(_, value)| value),
+                    Ok(HeapObject::Object(mut map)) => map.remove(&name),
+                    _ => {
+                        return Err(format!(
+                            "Cannot read field '{}' of a {}",
+                            name,
+                            record.type_name(self.heap.objects())
+                        ));
+                    }
+                };
+                let field = field.ok_or_else(|| format!("No field '{}'", name))?;
+                let field = self.heap.store(field);
+                self.stack.push(field);
+            }
+
+            Op::CallMethod(method) => {
+                let args = self.pop_args(METHODS[*method].arity)?;
+                let result = self.call_method(*method, args)?;
+                self.stack.push(result);
+            }
+
+            Op::CallNative(native_index, arg_count) => {
+                let native = self
+                    .natives
+                    .get(*native_index)
+                    .ok_or("Invalid native function index")?
+                    .clone();
+
+                // Arguments are pushed last-to-first, so popping yields them in call order
+                let mut args = Vec::with_capacity(*arg_count);
+                for _ in 0..*arg_count {
+                    args.push(self.stack.pop().ok_or(UNDERFLOW_ERROR)?);
                 }
+
+                let result = match self.call_native(&native, &args) {
+                    Ok(result) => result,
+                    // Stopped like the instruction loop stops a run
+                    Err(e) if self.stopped.is_some() => return Err(e),
+                    Err(e) => return Err(format!("{}: {}", native.name, e)),
+                };
+                self.stack.push(result);
             }
 
-            Instruction::Return => {
+            Op::Return => {
                 if self.stack_frames.len() > 1 {
                     self.stack_frames.pop();
                 }
@@ -368,24 +1206,84 @@ impl VirtualMachine {
                 }
             }
 
-            Instruction::Pop => {
+            Op::Pop => {
                 self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
             }
 
-            Instruction::Dup => {
+            Op::Dup => {
                 let value = self.stack.last().ok_or(UNDERFLOW_ERROR)?.clone();
                 self.stack.push(value);
             }
 
-            Instruction::Halt => {
+            Op::Yield => {
+                let value = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let Some(resumed) = self.resumed.last().copied() else {
+                    return Err("'yield' outside of a generator".to_string());
+                };
+                let mut stack = self.stack.split_off(resumed.stack);
+                // What the `yield` expression gives once resumed
+                stack.push(Value::Boolean(true));
+                self.generators[resumed.generator].state = GeneratorState::Suspended(Suspended {
+                    stack,
+                    frames: self.stack_frames.split_off(resumed.frames),
+                    return_addresses: self.return_addresses.split_off(resumed.return_addresses),
+                    pc: self.pc + 1,
+                });
+                self.stack.push(value);
+                return Ok(());
+            }
+
+            Op::Halt => {
                 return Ok(());
             }
         }
 
-        self.pc += 1;
+        self.pc += op.width();
         Ok(())
     }
 
+    /// `a + b`: numbers add up and, unless concatenation is strict, strings
+    /// are joined, whether they are inline or, being long, on the heap.
+    fn add(&self, a: Value, b: Value) -> Result<Value, String> {
+        match (a, b) {
+            (Value::Number(a_num), Value::Number(b_num)) => Ok(Value::Number(a_num + b_num)),
+            (a, b) if self.string_text(&a).is_some() && self.string_text(&b).is_some() => {
+                if self.flags & FLAG_STRICT_CONCAT != 0 {
+                    return Err(
+                        "Cannot add strings in strict mode - use '++' to concatenate".to_string(),
+                    );
+                }
+                // Appending to the left string, which grows by doubling, keeps
+                // `a + b + c + ...` linear. A stored left operand is copied
+                // first, so `s = s + x` in a loop is still quadratic
+                let mut a_str = match a {
+                    Value::String(a_str) => a_str,
+                    a => self.string_text(&a).unwrap_or_default().to_string(),
+                };
+                a_str.push_str(self.string_text(&b).unwrap_or_default());
+                Ok(Value::String(a_str))
+            }
+            (a, b) => Err(format!(
+                "Cannot add {} and {} - both operands must be the same type",
+                a.type_name(self.heap.objects()),
+                b.type_name(self.heap.objects())
+            )),
+        }
+    }
+
+    /// The text of a string value, which is on the heap when it is longer
+    /// than `MAX_STRING_LENGTH`.
+    fn string_text<'v>(&'v self, value: &'v Value) -> Option<&'v str> {
+        match value {
+            Value::String(s) => Some(s),
+            Value::HeapPointer(idx) => match self.heap.get(*idx) {
+                Some(HeapObject::String(s)) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
     fn resolve_variable(&self, depth: usize, var_index: usize) -> Result<Value, String> {
         for frame in self.stack_frames.iter().rev() {
             if let Some(value) = frame.get_variable(var_index) {
@@ -405,17 +1303,31 @@ impl VirtualMachine {
         Err(format!("Variable with index {} not found", var_index))
     }
 
+    /// A top-level variable read from inside a function, which fails when the
+    /// function runs before the variable's `let`.
+    fn resolve_global(&self, var_index: usize) -> Result<Value, String> {
+        if let Some(value) = self.stack_frames[0].get_variable(var_index) {
+            return Ok(value.clone());
+        }
+        let name = self
+            .raw_compiler
+            .variables
+            .first()
+            .and_then(|scope| scope.iter().find(|(_, idx)| **idx == var_index))
+            .map(|(name, _)| name.to_string())
+            .unwrap_or_else(|| format!("#{}", var_index));
+        Err(format!("Undefined global '{}'", name))
+    }
+
     fn heap_push(&mut self, value: Value) -> Option<Value> {
         let heap_index = match &value {
             Value::String(s) if s.len() > MAX_STRING_LENGTH => {
-                let heap_obj = HeapObject::String(s.clone());
-                self.heap.push(heap_obj);
-                Some(self.heap.len() - 1)
+                Some(self.heap.allocate(HeapObject::String(s.clone())))
             }
             _ => None,
         };
 
-        heap_index.map(|index| Value::HeapPointer(index))
+        heap_index.map(Value::HeapPointer)
     }
 
     fn set_variable(&mut self, var_index: usize, value: Value) -> Result<(), String> {
@@ -443,25 +1355,846 @@ impl VirtualMachine {
         }
     }
 
+    /// Jumps into script function `index`; its arguments are already on the
+    /// stack, first argument on top.
+    fn enter_function(&mut self, index: usize) -> Result<(), String> {
+        let Some(Value::Function { offset, .. }) = self.functions.get(index) else {
+            return Err("Invalid function index".to_string());
+        };
+        let offset = *offset;
+        if self.return_addresses.len() >= self.max_call_depth {
+            return Err(format!(
+                "Maximum recursion depth exceeded ({})",
+                self.max_call_depth
+            ));
+        }
+        self.return_addresses.push(self.pc + 1);
+        self.stack_frames.push(StackFrame::new());
+        self.pc = offset;
+        Ok(())
+    }
+
+    /// Runs a call of function `index` on its register code, if it has any,
+    /// replacing the arguments on the stack with the result. Returns false,
+    /// having changed nothing, when the stack code must run the call instead.
+    #[cfg(feature = "registers")]
+    fn call_registers(&mut self, index: usize) -> bool {
+        // Coverage counts the stack code's instructions
+        if self.coverage.is_some() {
+            return false;
+        }
+        let Some(function) =
+            self.register_code
+                .translate(index, &self.instructions, &self.constants)
+        else {
+            return false;
+        };
+        let Some(below) = self.stack.len().checked_sub(function.arity) else {
+            return false;
+        };
+        let args = &self.stack[below..];
+        let budget = Budget {
+            instructions: self
+                .limits
+                .max_instructions
+                .map_or(u64::MAX, |max| max.saturating_sub(self.executed)),
+            calls: self
+                .max_call_depth
+                .saturating_sub(self.return_addresses.len()),
+            values: self.max_stack_size.saturating_sub(below),
+        };
+        let (cancellation, limits, started) = (&self.cancellation, &self.limits, self.started);
+        let interrupted = || {
+            cancellation.is_cancelled()
+                || matches!(
+                    (limits.wall_clock_timeout, started),
+                    (Some(timeout), Some(started)) if started.elapsed() > timeout
+                )
+        };
+        // Native code counts calls rather than instructions, and checks for
+        // cancellation only as it enters a function
+        #[cfg(feature = "jit")]
+        let (jit, register_code) = (&mut self.jit, &self.register_code);
+        #[cfg(feature = "jit")]
+        let native = |function: usize, args: &[NanBox], budget: Budget| match (
+            limits.max_instructions,
+            limits.wall_clock_timeout,
+        ) {
+            (None, None) => jit.call(function, register_code, args, budget, &cancellation.0),
+            _ => None,
+        };
+        #[cfg(not(feature = "jit"))]
+        let native = |_: usize, _: &[NanBox], _: Budget| None;
+        let Some((result, steps)) = register::run(
+            &self.register_code,
+            index,
+            args,
+            &mut self.registers,
+            budget,
+            interrupted,
+            native,
+        ) else {
+            return false;
+        };
+        self.executed += steps;
+        self.stack.truncate(below);
+        self.stack.push(result);
+        self.pc += 1;
+        true
+    }
+
+    /// Calls a closure with `args` after its bound ones, moving `pc` on to the
+    /// function or, when too few arguments make a new closure, to the next
+    /// instruction.
+    fn call_value(&mut self, callee: Value, args: Vec<Value>) -> Result<(), String> {
+        let Value::Closure { function, bound } = callee else {
+            return Err(format!(
+                "Cannot call a {}",
+                callee.type_name(self.heap.objects())
+            ));
+        };
+        let args: Vec<Value> = bound.into_iter().chain(args).collect();
+        match self.bind_args(function, args)? {
+            Ok(args) => {
+                // First argument on top, as a direct call leaves them
+                self.stack.extend(args.into_iter().rev());
+                self.enter_function(function)
+            }
+            Err(bound) => {
+                self.stack.push(Value::Closure { function, bound });
+                self.pc += 1;
+                Ok(())
+            }
+        }
+    }
+
+    /// Matches a function's complete argument list to its parameters,
+    /// gathering surplus arguments into a list for a rest parameter. Too few
+    /// arguments are handed back as `Err`, to be bound into a closure.
+    fn bind_args(
+        &mut self,
+        function: usize,
+        mut args: Vec<Value>,
+    ) -> Result<Result<Vec<Value>, Vec<Value>>, String> {
+        let (fixed, variadic) = match self.functions.get(function) {
+            Some(Value::Function { params, .. }) => arity(params),
+            _ => return Err("Invalid function index".to_string()),
+        };
+        if args.len() < fixed {
+            return Ok(Err(args));
+        }
+        if variadic {
+            let rest = args
+                .split_off(fixed)
+                .into_iter()
+                .map(|value| self.value_to_heap_object(value))
+                .collect();
+            args.push(Value::HeapPointer(
+                self.heap.allocate(HeapObject::Array(rest)),
+            ));
+        } else if args.len() > fixed {
+            return Err(format!(
+                "Function expects {} argument(s), got {}",
+                fixed,
+                args.len()
+            ));
+        }
+        Ok(Ok(args))
+    }
+
+    fn call_method(&mut self, method: usize, mut args: Vec<Value>) -> Result<Value, String> {
+        match method {
+            methods::TASK_SPAWN => self.spawn_task(args.remove(0)),
+            methods::TASK_JOIN => self.join_task(&args[0]),
+            methods::TASK_ALL => self.join_all(&args[0]),
+            methods::CHANNEL_NEW => {
+                self.channels.push(Channel::default());
+                Ok(Value::Channel(self.channels.len() - 1))
+            }
+            methods::SEND => {
+                let value = args.remove(1);
+                let channel = self.channel(&args[0], "send")?;
+                if channel.closed {
+                    return Err("Cannot send on a closed channel".to_string());
+                }
+                channel.buffer.push_back(value);
+                Ok(Value::Boolean(true))
+            }
+            methods::RECV => self.receive(&args[0]),
+            methods::CLOSE => {
+                self.channel(&args[0], "close")?.closed = true;
+                Ok(Value::Boolean(true))
+            }
+            methods::GENERATOR_NEW => Ok(self.new_generator(GeneratorState::Start(args.remove(0)))),
+            methods::ITER => self.iter(args.remove(0)),
+            methods::UNFOLD => {
+                let step = args.remove(1);
+                if !matches!(step, Value::Closure { .. }) {
+                    return Err(format!(
+                        "unfold expects a step function, got {}",
+                        step.type_name(self.heap.objects())
+                    ));
+                }
+                let state = args.remove(0);
+                Ok(self.new_generator(GeneratorState::Unfold { state, step }))
+            }
+            methods::NEXT => {
+                let id = self.generator(&args[0], "next")?;
+                self.advance(id)?
+                    .ok_or_else(|| "The generator has no more values".to_string())
+            }
+            methods::DONE => {
+                let id = self.generator(&args[0], "done")?;
+                if self.generators[id].peeked.is_none() {
+                    let value = self.advance(id)?;
+                    self.generators[id].peeked = value;
+                }
+                Ok(Value::Boolean(self.generators[id].peeked.is_none()))
+            }
+            _ => self.call_list_method(method, args),
+        }
+    }
+
+    /// `Task.spawn(f)`: queues a call of closure `f` without arguments.
+    fn spawn_task(&mut self, callee: Value) -> Result<Value, String> {
+        if !matches!(callee, Value::Closure { .. }) {
+            return Err(format!(
+                "Task.spawn expects a function, got {}",
+                callee.type_name(self.heap.objects())
+            ));
+        }
+        self.tasks.push(Task {
+            state: TaskState::Pending(callee),
+            joined: false,
+        });
+        self.wakers.drain(..).for_each(Waker::wake);
+        Ok(Value::Task(self.tasks.len() - 1))
+    }
+
+    /// `Task.join(task)`: the task's result, running queued tasks in spawn
+    /// order until it has finished. A failed task's error is raised here.
+    fn join_task(&mut self, task: &Value) -> Result<Value, String> {
+        let Value::Task(id) = *task else {
+            return Err(format!(
+                "Task.join expects a task, got {}",
+                task.type_name(self.heap.objects())
+            ));
+        };
+        self.tasks.get_mut(id).ok_or("Invalid task")?.joined = true;
+        loop {
+            match &self.tasks[id].state {
+                TaskState::Done(value) => return Ok(value.clone()),
+                TaskState::Failed(e) => return Err(format!("Task {} failed: {}", id, e)),
+                TaskState::Running => return Err(format!("Task {} is waiting for itself", id)),
+                TaskState::Pending(_) => self.run_next_task()?,
+            }
+        }
+    }
+
+    /// `Task.all(tasks)`: waits for every task in the list and returns their
+    /// results in order. All of them finish first, then the error of the first
+    /// failed task in the list, if any, is raised.
+    fn join_all(&mut self, tasks: &Value) -> Result<Value, String> {
+        let HeapObject::Array(items) = self.heap.load(tasks)? else {
+            return Err(format!(
+                "Task.all expects a list, got {}",
+                tasks.type_name(self.heap.objects())
+            ));
+        };
+        let mut results = Vec::with_capacity(items.len());
+        let mut failure = None;
+        for item in items {
+            let HeapObject::Task(id) = item else {
+                return Err(format!(
+                    "Task.all expects a list of tasks, found {}",
+                    item.type_name()
+                ));
+            };
+            match self.join_task(&Value::Task(id)) {
+                Ok(value) => results.push(self.value_to_heap_object(value)),
+                // Limits and cancellation stop everything at once
+                Err(e) if self.stopped.is_some() => return Err(e),
+                Err(e) => {
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(self.heap.store(HeapObject::Array(results.into()))),
+        }
+    }
+
+    fn channel(&mut self, value: &Value, method: &str) -> Result<&mut Channel, String> {
+        match *value {
+            Value::Channel(id) => self
+                .channels
+                .get_mut(id)
+                .ok_or("Invalid channel".to_string()),
+            _ => Err(format!(
+                "{} expects a channel, got {}",
+                method,
+                value.type_name(self.heap.objects())
+            )),
+        }
+    }
+
+    /// `recv(channel)`: the oldest value sent on the channel. While it is
+    /// empty, queued tasks run in spawn order until one sends something.
+    fn receive(&mut self, channel: &Value) -> Result<Value, String> {
+        loop {
+            let channel = self.channel(channel, "recv")?;
+            if let Some(value) = channel.buffer.pop_front() {
+                return Ok(value);
+            }
+            if channel.closed {
+                return Err("Cannot receive from a closed, empty channel".to_string());
+            }
+            if !self
+                .tasks
+                .iter()
+                .any(|task| matches!(task.state, TaskState::Pending(_)))
+            {
+                return Err(
+                    "Deadlock: receiving from an empty channel with no task left to send"
+                        .to_string(),
+                );
+            }
+            self.run_next_task()?;
+        }
+    }
+
+    fn constant_string(&self, index: usize) -> Result<String, String> {
+        match self.constants.get(index) {
+            Some(Value::String(s)) => Ok(s.clone()),
+            _ => Err("Invalid name constant".to_string()),
+        }
+    }
+
+    fn new_generator(&mut self, state: GeneratorState) -> Value {
+        self.generators.push(Generator {
+            state,
+            peeked: None,
+        });
+        Value::Generator(self.generators.len() - 1)
+    }
+
+    fn generator(&self, value: &Value, method: &str) -> Result<usize, String> {
+        match *value {
+            Value::Generator(id) if id < self.generators.len() => Ok(id),
+            _ => Err(format!(
+                "{} expects a generator, got {}",
+                method,
+                value.type_name(self.heap.objects())
+            )),
+        }
+    }
+
+    /// The struct or enum `value` belongs to, whose `impl` methods it has.
+    fn type_of(&self, value: &Value) -> Option<String> {
+        match value {
+            Value::HeapPointer(idx) => match self.heap.get(*idx) {
+                Some(HeapObject::Record { type_name, .. }) => Some(type_name.clone()),
+                Some(HeapObject::Variant { name, .. }) => name
+                    .split_once("::")
+                    .map(|(enum_name, _)| enum_name.to_string()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Method `name` of `value`'s type, from an `impl` block.
+    fn method_of(&self, value: &Value, name: &str) -> Option<Value> {
+        let type_name = self.type_of(value)?;
+        self.methods
+            .get(&format!("{}.{}", type_name, name))
+            .cloned()
+    }
+
+    /// `iter(iterable)`: a generator going through a list's items, or the
+    /// generator itself. A struct or enum value with a `next` method steps
+    /// like `unfold`, with `next` as the step and the value as the first
+    /// state.
+    fn iter(&mut self, iterable: Value) -> Result<Value, String> {
+        if let Value::Generator(_) = iterable {
+            return Ok(iterable);
+        }
+        if let Some(step) = self.method_of(&iterable, "next") {
+            let state = iterable;
+            return Ok(self.new_generator(GeneratorState::Unfold { state, step }));
+        }
+        match self.heap.load(&iterable) {
+            Ok(HeapObject::Array(items)) => {
+                let items = items
+                    .into_iter()
+                    .map(|item| self.heap.store(item))
+                    .collect();
+                Ok(self.new_generator(GeneratorState::Items(items)))
+            }
+            _ => Err(format!(
+                "Cannot iterate over a {}",
+                iterable.type_name(self.heap.objects())
+            )),
+        }
+    }
+
+    /// The generator's next value, or `None` once it is exhausted. A generator
+    /// function's body runs until its next `yield` or its end.
+    fn advance(&mut self, id: usize) -> Result<Option<Value>, String> {
+        if let Some(value) = self.generators[id].peeked.take() {
+            return Ok(Some(value));
+        }
+        let resume = self.pc;
+        let resumed = Resumed {
+            generator: id,
+            stack: self.stack.len(),
+            frames: self.stack_frames.len(),
+            return_addresses: self.return_addresses.len(),
+        };
+        match std::mem::replace(&mut self.generators[id].state, GeneratorState::Running) {
+            GeneratorState::Items(mut items) => {
+                let value = items.pop_front();
+                if value.is_some() {
+                    self.generators[id].state = GeneratorState::Items(items);
+                } else {
+                    self.generators[id].state = GeneratorState::Done;
+                }
+                return Ok(value);
+            }
+            GeneratorState::Done => {
+                self.generators[id].state = GeneratorState::Done;
+                return Ok(None);
+            }
+            GeneratorState::Unfold { state, step } => {
+                let slot = self.pinned.len();
+                self.pinned.push(step);
+                let next = self.call_pinned(slot, vec![state]);
+                let step = self.pinned.pop().expect("pinned above");
+                let next = match next {
+                    Ok(next) => next,
+                    Err(e) => {
+                        self.generators[id].state = GeneratorState::Done;
+                        return Err(e);
+                    }
+                };
+                return match self.heap.load(&next) {
+                    Ok(HeapObject::Array(pair)) if pair.is_empty() => {
+                        self.generators[id].state = GeneratorState::Done;
+                        Ok(None)
+                    }
+                    Ok(HeapObject::Array(pair)) if pair.len() == 2 => {
+                        let mut pair = pair.into_iter().map(|item| self.heap.store(item));
+                        let (value, state) = (pair.next(), pair.next());
+                        self.generators[id].state = GeneratorState::Unfold {
+                            state: state.expect("pairs have two items"),
+                            step,
+                        };
+                        Ok(value)
+                    }
+                    _ => {
+                        self.generators[id].state = GeneratorState::Done;
+                        Err(format!(
+                            "unfold's step or a next method must return [value, next_state] or [], got {}",
+                            self.format_value(&next)
+                        ))
+                    }
+                };
+            }
+            GeneratorState::Running => {
+                return Err("A generator cannot resume itself".to_string());
+            }
+            // Each resumption runs on the host stack, like a callback
+            _ if self.callback_depth >= MAX_CALLBACK_DEPTH => {
+                self.generators[id].state = GeneratorState::Done;
+                return Err(format!(
+                    "Maximum recursion depth exceeded in callbacks ({})",
+                    MAX_CALLBACK_DEPTH
+                ));
+            }
+            GeneratorState::Start(Value::Closure { function, bound }) => {
+                let args = self
+                    .bind_args(function, bound)?
+                    .map_err(|_| "A generator's body takes no arguments".to_string())?;
+                self.stack.extend(args.into_iter().rev());
+                if let Err(e) = self.enter_function(function) {
+                    self.generators[id].state = GeneratorState::Done;
+                    return Err(e);
+                }
+            }
+            GeneratorState::Start(other) => {
+                return Err(format!(
+                    "Generator.new expects a function, got {}",
+                    other.type_name(self.heap.objects())
+                ));
+            }
+            GeneratorState::Suspended(suspended) => {
+                self.stack.extend(suspended.stack);
+                self.stack_frames.extend(suspended.frames);
+                self.return_addresses.extend(suspended.return_addresses);
+                self.pc = suspended.pc;
+            }
+        }
+
+        self.resumed.push(resumed);
+        self.callback_depth += 1;
+        let mut result = Ok(());
+        while self.return_addresses.len() > resumed.return_addresses
+            && self.exit_code.is_none()
+            && result.is_ok()
+        {
+            result = self.step();
+        }
+        self.callback_depth -= 1;
+        self.resumed.pop();
+        if let Err(e) = result {
+            self.generators[id].state = GeneratorState::Done;
+            return Err(e);
+        }
+        self.pc = resume;
+        let value = self.stack.pop().unwrap_or(Value::Boolean(false));
+        match self.generators[id].state {
+            GeneratorState::Suspended(_) => Ok(Some(value)),
+            // The body returned; its result is not one of the values
+            _ => {
+                self.generators[id].state = GeneratorState::Done;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Calls `native`, or with replay on and a nondeterministic native,
+    /// takes its result from the recording.
+    fn call_native(&mut self, native: &NativeFunction, args: &[Value]) -> Result<Value, String> {
+        let recorded = NONDETERMINISTIC.contains(&native.name.as_str());
+        if recorded && let ReplayMode::Replay(recording) = &mut self.replay {
+            let event = match recording.events.pop_front() {
+                Some(event) if event.native == native.name => event,
+                Some(event) => {
+                    return Err(format!(
+                        "Replay diverged: the recording has a call of {} here",
+                        event.native
+                    ));
+                }
+                None => return Err("Replay diverged: the recording has ended".to_string()),
+            };
+            return event.result.map(|object| self.heap.store(object));
+        }
+        let mut context = NativeContext {
+            heap: &mut self.heap,
+            output: &mut self.output,
+            exit_code: &mut self.exit_code,
+            deadline: self.started.zip(self.limits.wall_clock_timeout),
+            cancellation: &self.cancellation,
+            stopped: &mut self.stopped,
+        };
+        let result = (native.func)(&mut context, args);
+        if recorded && let ReplayMode::Record(recording) = &mut self.replay {
+            recording.events.push_back(Event {
+                native: native.name.clone(),
+                result: match &result {
+                    Ok(value) => self.heap.load(value),
+                    Err(e) => Err(e.clone()),
+                },
+            });
+        }
+        result
+    }
+
+    /// Runs the oldest queued task to completion and records its result. A
+    /// failure only ends the task, leaving the VM as it was before the call.
+    fn run_next_task(&mut self) -> Result<(), String> {
+        let Some(id) = self
+            .tasks
+            .iter()
+            .position(|task| matches!(task.state, TaskState::Pending(_)))
+        else {
+            return Ok(());
+        };
+        let TaskState::Pending(callee) =
+            std::mem::replace(&mut self.tasks[id].state, TaskState::Running)
+        else {
+            unreachable!("found as pending");
+        };
+        let (stack, frames, returns, pc) = (
+            self.stack.len(),
+            self.stack_frames.len(),
+            self.return_addresses.len(),
+            self.pc,
+        );
+        let result = self.call_sync(&callee, Vec::new());
+        self.tasks[id].state = match result {
+            Ok(value) => TaskState::Done(value),
+            Err(e) => {
+                let line = self.instruction_lines.get(self.pc).cloned().unwrap_or(0);
+                let e = format!("[line {}] {}", line, e);
+                self.stack.truncate(stack);
+                self.stack_frames.truncate(frames);
+                self.return_addresses.truncate(returns);
+                self.pc = pc;
+                if self.stopped.is_some() {
+                    self.tasks[id].state = TaskState::Failed(e.clone());
+                    return Err(e);
+                }
+                TaskState::Failed(e)
+            }
+        };
+        Ok(())
+    }
+
+    /// Runs the tasks nobody waited for once the program is done. The first
+    /// failure among tasks that were never joined fails the program.
+    fn finish_tasks(&mut self) -> Result<(), String> {
+        while self
+            .tasks
+            .iter()
+            .any(|task| matches!(task.state, TaskState::Pending(_)))
+        {
+            self.run_next_task()?;
+        }
+        for (id, task) in self.tasks.iter_mut().enumerate() {
+            if let TaskState::Failed(e) = &task.state
+                && !task.joined
+            {
+                task.joined = true;
+                return Err(format!("Task {} failed: {}", id, e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs list method `method`; `args[0]` is the receiver.
+    fn call_list_method(&mut self, method: usize, args: Vec<Value>) -> Result<Value, String> {
+        let slot = self.pinned.len();
+        self.pinned.extend(args);
+        let result = self.apply_list_method(method, slot);
+        self.pinned.truncate(slot);
+        result
+    }
+
+    /// The result of list method `method` on the arguments pinned from
+    /// `slot` on: the receiver, then the callback and `reduce`'s starting
+    /// value. They are read from there after anything that may collect.
+    fn apply_list_method(&mut self, method: usize, slot: usize) -> Result<Value, String> {
+        let name = METHODS[method].name;
+        if self.method_of(&self.pinned[slot], "next").is_some() {
+            let receiver = self.pinned[slot].clone();
+            self.pinned[slot] = self.iter(receiver)?;
+        }
+        let items = match self.pinned[slot] {
+            // Generators are run to the end, so any iterable works
+            Value::Generator(id) => {
+                let mut items = Vec::new();
+                while let Some(value) = self.advance(id)? {
+                    items.push(self.heap.load(&value)?);
+                }
+                HeapObject::Array(items.into())
+            }
+            ref receiver => self.heap.load(receiver)?,
+        };
+        let HeapObject::Array(items) = items else {
+            return Err(format!(
+                "'{}' expects a list or generator, got {}",
+                name,
+                self.pinned[slot].type_name(self.heap.objects())
+            ));
+        };
+        let result = match method {
+            methods::MAP => {
+                let mut mapped = Vec::with_capacity(items.len());
+                for item in items {
+                    let item = self.heap.store(item);
+                    let value = self.call_pinned(slot + 1, vec![item])?;
+                    mapped.push(self.heap.load(&value)?);
+                }
+                HeapObject::Array(mapped.into())
+            }
+            methods::FILTER => {
+                let mut kept = Vec::new();
+                for item in items {
+                    let value = self.heap.store(item.clone());
+                    match self.call_pinned(slot + 1, vec![value])? {
+                        Value::Boolean(true) => kept.push(item),
+                        Value::Boolean(false) => {}
+                        other => {
+                            return Err(format!(
+                                "filter predicate must return a boolean, got {}",
+                                other.type_name(self.heap.objects())
+                            ));
+                        }
+                    }
+                }
+                HeapObject::Array(kept.into())
+            }
+            methods::REDUCE => {
+                let mut acc = self.pinned[slot + 2].clone();
+                for item in items {
+                    let item = self.heap.store(item);
+                    acc = self.call_pinned(slot + 1, vec![acc, item])?;
+                }
+                return Ok(acc);
+            }
+            methods::LENGTH => HeapObject::Number(items.len() as f64),
+            _ => return Err("Invalid method index".to_string()),
+        };
+        Ok(self.heap.store(result))
+    }
+
+    /// `call_sync` of the callback pinned at `slot`, read from there since
+    /// earlier calls may have moved what it points to.
+    fn call_pinned(&mut self, slot: usize, args: Vec<Value>) -> Result<Value, String> {
+        let callee = self.pinned[slot].clone();
+        self.call_sync(&callee, args)
+    }
+
+    /// Calls a closure from inside an instruction and runs it to completion,
+    /// returning its result. Once the program has asked to exit the call is
+    /// skipped and the result is meaningless; `run` stops after the instruction.
+    fn call_sync(&mut self, callee: &Value, args: Vec<Value>) -> Result<Value, String> {
+        if self.exit_code.is_some() {
+            return Ok(Value::Boolean(false));
+        }
+        let Value::Closure { function, bound } = callee else {
+            return Err(format!(
+                "Cannot call a {}",
+                callee.type_name(self.heap.objects())
+            ));
+        };
+        let args = bound.iter().cloned().chain(args).collect();
+        let args = self
+            .bind_args(*function, args)?
+            .map_err(|args| format!("Function expects more than {} argument(s)", args.len()))?;
+        // Each nested callback runs on the host stack, so they get a tighter limit
+        if self.callback_depth >= MAX_CALLBACK_DEPTH {
+            return Err(format!(
+                "Maximum recursion depth exceeded in callbacks ({})",
+                MAX_CALLBACK_DEPTH
+            ));
+        }
+        let resume = self.pc;
+        let depth = self.return_addresses.len();
+        self.stack.extend(args.into_iter().rev());
+        self.enter_function(*function)?;
+        self.callback_depth += 1;
+        let mut result = Ok(());
+        while self.return_addresses.len() > depth && self.exit_code.is_none() && result.is_ok() {
+            result = self.step();
+        }
+        self.callback_depth -= 1;
+        result?;
+        self.pc = resume;
+        Ok(self.stack.pop().unwrap_or(Value::Boolean(false)))
+    }
+
+    /// Pops `count` call arguments, returning them first to last.
+    fn pop_args(&mut self, count: usize) -> Result<Vec<Value>, String> {
+        if self.stack.len() < count {
+            return Err(UNDERFLOW_ERROR.to_string());
+        }
+        Ok(self.stack.drain(self.stack.len() - count..).rev().collect())
+    }
+
+    /// Pops the value a conditional jump tests. There is no truthiness:
+    /// conditions must be booleans.
+    fn pop_condition(&mut self) -> Result<bool, String> {
+        match self.stack.pop().ok_or(UNDERFLOW_ERROR)? {
+            Value::Boolean(b) => Ok(b),
+            other => Err(format!(
+                "Condition must be a boolean, got {}",
+                other.type_name(self.heap.objects())
+            )),
+        }
+    }
+
+    /// Text used for a `++` operand. Numbers and booleans are stringified so
+    /// `"total: " ++ 5` works without an explicit conversion.
+    fn concat_operand<'v>(&'v self, value: &'v Value) -> Result<Cow<'v, str>, String> {
+        match value {
+            Value::String(s) => Ok(Cow::Borrowed(s)),
+            Value::Number(n) => Ok(Cow::Owned(n.to_string())),
+            Value::Boolean(b) => Ok(Cow::Owned(b.to_string())),
+            Value::HeapPointer(idx) => match self.heap.get(*idx) {
+                Some(HeapObject::String(s)) => Ok(Cow::Borrowed(s)),
+                _ => Err(format!(
+                    "Cannot concatenate {}",
+                    value.type_name(self.heap.objects())
+                )),
+            },
+            Value::Function { .. }
+            | Value::Closure { .. }
+            | Value::Task(_)
+            | Value::Channel(_)
+            | Value::Generator(_) => Err(format!(
+                "Cannot concatenate {}",
+                value.type_name(self.heap.objects())
+            )),
+        }
+    }
+
+    /// Structural equality: heap values are equal when their contents are,
+    /// and a string is equal to a heap string with the same text. Heap objects
+    /// hold their children by value, so they cannot form cycles.
     fn values_equal(&self, a: &Value, b: &Value) -> bool {
         match (a, b) {
-            (Value::Number(x), Value::Number(y)) => x == y,
-            (Value::String(x), Value::String(y)) => x == y,
-            _ => false,
+            (Value::Function { offset: x, .. }, Value::Function { offset: y, .. }) => x == y,
+            (Value::Closure { .. }, Value::Closure { .. }) => a == b,
+            _ => match (self.as_object(a), self.as_object(b)) {
+                (Some(x), Some(y)) => x == y,
+                _ => false,
+            },
+        }
+    }
+
+    /// Ordering for `<` and `>`: numbers numerically, strings by text and lists
+    /// element by element. `None` when the values are unordered, e.g. NaN or
+    /// lists holding mismatched elements.
+    fn compare_values(&self, a: &Value, b: &Value) -> Result<Option<Ordering>, String> {
+        match (self.as_object(a), self.as_object(b)) {
+            (Some(x), Some(y))
+                if x.type_name() == y.type_name()
+                    && matches!(
+                        *x,
+                        HeapObject::Number(_) | HeapObject::String(_) | HeapObject::Array(_)
+                    ) =>
+            {
+                Ok(x.partial_cmp(&y))
+            }
+            _ => Err(format!(
+                "Cannot compare {} with {}",
+                a.type_name(self.heap.objects()),
+                b.type_name(self.heap.objects())
+            )),
+        }
+    }
+
+    /// A value as the object it denotes, borrowing heap contents. `None` for
+    /// functions and dangling pointers.
+    fn as_object<'a>(&'a self, value: &Value) -> Option<Cow<'a, HeapObject>> {
+        match value {
+            Value::Number(n) => Some(Cow::Owned(HeapObject::Number(*n))),
+            Value::String(s) => Some(Cow::Owned(HeapObject::String(s.clone()))),
+            Value::Boolean(b) => Some(Cow::Owned(HeapObject::Boolean(*b))),
+            Value::HeapPointer(idx) => self.heap.get(*idx).map(Cow::Borrowed),
+            Value::Task(id) => Some(Cow::Owned(HeapObject::Task(*id))),
+            Value::Channel(id) => Some(Cow::Owned(HeapObject::Channel(*id))),
+            Value::Generator(id) => Some(Cow::Owned(HeapObject::Generator(*id))),
+            Value::Function { .. } | Value::Closure { .. } => None,
         }
     }
 
-    pub fn debug_stack(&self) {
-        println!("=== VM DEBUG ===");
-        println!("PC: {}", self.pc);
-        println!("Stack: {:?}", self.stack);
-        println!("Stack Frames: {}", self.stack_frames.len());
-        println!("Heap: {:?}", self.heap);
+    /// Writes the VM's registers, stack and heap to the debug output.
+    pub fn debug_stack(&mut self) -> io::Result<()> {
+        let out = &mut self.debug_output;
+        writeln!(out, "=== VM DEBUG ===")?;
+        writeln!(out, "PC: {}", self.pc)?;
+        writeln!(out, "Stack: {:?}", self.stack)?;
+        writeln!(out, "Stack Frames: {}", self.stack_frames.len())?;
+        writeln!(out, "Heap: {:?}", self.heap.objects())?;
 
         if let Some(current_instruction) = self.instructions.get(self.pc) {
-            println!("Next Instruction: {:?}", current_instruction);
+            writeln!(out, "Next Instruction: {:?}", current_instruction)?;
         }
-        println!("================");
+        writeln!(out, "================")
     }
 
     fn value_to_heap_object(&self, value: Value) -> HeapObject {
@@ -469,8 +2202,24 @@ impl VirtualMachine {
             Value::Number(n) => HeapObject::Number(n),
             Value::String(s) => HeapObject::String(s),
             Value::Boolean(b) => HeapObject::Boolean(b),
-            Value::HeapPointer(_) => HeapObject::Null, // Could preserve references, but simplify for now
-            Value::Function { .. } => HeapObject::Null, // Functions can't go in arrays yet
+            // Values are immutable, so a copy is as good as a reference
+            Value::HeapPointer(idx) => self.heap.get(idx).cloned().unwrap_or(HeapObject::Null),
+            Value::Task(id) => HeapObject::Task(id),
+            Value::Channel(id) => HeapObject::Channel(id),
+            Value::Generator(id) => HeapObject::Generator(id),
+            Value::Function { .. } | Value::Closure { .. } => HeapObject::Null, // Functions can't go in arrays yet
+        }
+    }
+}
+
+/// Adds `value` to the GC roots, or for a closure the arguments it holds.
+fn push_roots<'a>(value: &'a mut Value, roots: &mut Vec<&'a mut Value>) {
+    match value {
+        Value::Closure { bound, .. } => {
+            for value in bound {
+                push_roots(value, roots);
+            }
         }
+        _ => roots.push(value),
     }
 }
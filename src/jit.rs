@@ -0,0 +1,666 @@
+//! Native code for hot functions, compiled with Cranelift from their register
+//! code when the `jit` feature is on.
+//!
+//! A function is compiled once it has been called `threshold` times, together
+//! with every function it calls. Compiled code assumes its arguments are
+//! numbers: from that, the type of each register at each op is inferred, and
+//! a function whose registers could hold either a number or a boolean where
+//! an op needs one of them is left to the register code. Numbers and
+//! booleans are both kept as `f64`, booleans as `0.0` and `1.0`.
+//!
+//! Like the register code, compiled code never changes behaviour. Dividing
+//! by zero, a cancellation or running out of calls or values sets `failed`
+//! in the `Context` and unwinds, and the VM runs the call again on the
+//! register code, which the functions' lack of side effects makes safe.
+
+use crate::nanbox::NanBox;
+use crate::register::{BinaryOp, Budget, Operand, RegFunction, RegOp, Translations};
+use crate::types::constants::MAX_JIT_CALL_DEPTH;
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::{
+    AbiParam, Block, InstBuilder, MemFlags, Signature, UserFuncName, types,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{FuncId, Linkage, Module, default_libcall_names};
+use std::collections::HashMap;
+use std::mem::offset_of;
+use std::sync::atomic::AtomicBool;
+
+/// What compiled code reads and updates while it runs.
+#[repr(C)]
+struct Context {
+    /// Calls that may still be entered.
+    calls: i64,
+    /// Registers that may still be used.
+    values: i64,
+    /// Functions entered, counted as the VM's executed instructions.
+    entered: u64,
+    cancelled: *const AtomicBool,
+    failed: u8,
+}
+
+type Entry = unsafe extern "C" fn(*mut Context, *const f64) -> f64;
+
+/// What a register may hold at an op.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    /// Nothing yet, e.g. the result of a call that has not been seen to return.
+    Unset,
+    Number,
+    Boolean,
+    Either,
+}
+
+impl Type {
+    fn join(self, other: Type) -> Type {
+        match (self, other) {
+            (Type::Unset, t) | (t, Type::Unset) => t,
+            (a, b) if a == b => a,
+            _ => Type::Either,
+        }
+    }
+
+    fn is(self, wanted: Type) -> bool {
+        self == wanted || self == Type::Unset
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Compiled {
+    /// Called this many times so far.
+    Counting(u32),
+    Native(Entry, Type),
+    /// Could not be compiled, so it stays on the register code.
+    Unsupported,
+}
+
+/// Compiled functions of a program, by index.
+pub struct Jit {
+    threshold: u32,
+    functions: Vec<Compiled>,
+    /// Modules own the code of their functions, so they live as long as the
+    /// functions may be called.
+    modules: Vec<JITModule>,
+}
+
+impl Jit {
+    pub fn new(functions: usize, threshold: u32) -> Self {
+        Self {
+            threshold,
+            functions: vec![Compiled::Counting(0); functions],
+            modules: Vec::new(),
+        }
+    }
+
+    /// Runs a call of function `index` with `args`, in order, once it has
+    /// been called often enough to be compiled. Returns the
+    /// result and how many functions were entered, or `None` when the
+    /// register code must run it: it is not compiled (yet), an argument is
+    /// not a number or the call failed.
+    pub fn call(
+        &mut self,
+        index: usize,
+        translations: &Translations,
+        args: &[NanBox],
+        budget: Budget,
+        cancelled: &AtomicBool,
+    ) -> Option<(NanBox, u64)> {
+        let (entry, returns) = match *self.functions.get(index)? {
+            Compiled::Counting(count) if count + 1 >= self.threshold => {
+                self.compile(index, translations);
+                match self.functions[index] {
+                    Compiled::Native(entry, returns) => (entry, returns),
+                    _ => return None,
+                }
+            }
+            Compiled::Counting(count) => {
+                self.functions[index] = Compiled::Counting(count + 1);
+                return None;
+            }
+            Compiled::Native(entry, returns) => (entry, returns),
+            Compiled::Unsupported => return None,
+        };
+        let params = args
+            .iter()
+            .map(|arg| arg.as_number())
+            .collect::<Option<Vec<f64>>>()?;
+        let mut context = Context {
+            calls: budget.calls.min(MAX_JIT_CALL_DEPTH) as i64,
+            values: budget.values.min(i64::MAX as usize) as i64,
+            entered: 0,
+            cancelled,
+            failed: 0,
+        };
+        let result = unsafe { entry(&mut context, params.as_ptr()) };
+        if context.failed != 0 {
+            return None;
+        }
+        let value = match returns {
+            Type::Boolean => NanBox::boolean(result != 0.0),
+            _ => NanBox::number(result),
+        };
+        Some((value, context.entered))
+    }
+
+    pub fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    pub fn set_threshold(&mut self, threshold: u32) {
+        self.threshold = threshold;
+    }
+
+    /// Whether function `index` runs on native code.
+    pub fn is_compiled(&self, index: usize) -> bool {
+        matches!(self.functions.get(index), Some(Compiled::Native(..)))
+    }
+
+    /// Compiles function `index` and the functions it calls, or marks it
+    /// unsupported.
+    fn compile(&mut self, index: usize, translations: &Translations) {
+        let compiled = group(index, translations)
+            .and_then(|group| {
+                let returns = infer(&group)?;
+                Some((group, returns))
+            })
+            .and_then(|(group, returns)| {
+                let (module, entries) = build(&group, &returns).ok()?;
+                self.modules.push(module);
+                Some((entries, returns))
+            });
+        let Some((entries, returns)) = compiled else {
+            self.functions[index] = Compiled::Unsupported;
+            return;
+        };
+        for (function, entry) in entries {
+            // A function compiled before keeps its code
+            if !matches!(self.functions[function], Compiled::Native(..)) {
+                self.functions[function] = Compiled::Native(entry, returns[&function]);
+            }
+        }
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        for module in self.modules.drain(..) {
+            // Their entries go with `functions`, so none is called again
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+/// Function `index` and every function it calls, directly or not, with their
+/// register code. `None` if one of them has no register code, or jumps
+/// backwards, which compiled code does not count towards the limits.
+fn group(index: usize, translations: &Translations) -> Option<HashMap<usize, &RegFunction>> {
+    let mut group = HashMap::new();
+    let mut pending = vec![index];
+    while let Some(index) = pending.pop() {
+        if group.contains_key(&index) {
+            continue;
+        }
+        let function = translations.get(index)?;
+        for (pc, op) in function.code.iter().enumerate() {
+            match *op {
+                RegOp::Call { function, .. } => pending.push(function),
+                RegOp::Jump(target) | RegOp::JumpIf { target, .. } if target <= pc => {
+                    return None;
+                }
+                _ => {}
+            }
+        }
+        match function.code.last() {
+            Some(RegOp::Return(_) | RegOp::Jump(_)) => {}
+            _ => return None,
+        }
+        group.insert(index, function);
+    }
+    Some(group)
+}
+
+fn operand_type(types: &[Type], operand: Operand) -> Type {
+    match operand {
+        Operand::Reg(r) => types[r],
+        Operand::Number(_) => Type::Number,
+        Operand::Boolean(_) => Type::Boolean,
+    }
+}
+
+/// The types of each function's registers before each of its ops, given
+/// what the functions return.
+fn register_types(function: &RegFunction, returns: &HashMap<usize, Type>) -> Vec<Vec<Type>> {
+    let mut before = vec![vec![Type::Unset; function.registers]; function.code.len()];
+    before[0][..function.arity].fill(Type::Number);
+    // Ops only jump forwards, so one pass in order reaches a fixed point
+    for pc in 0..function.code.len() {
+        let mut after = before[pc].clone();
+        let op = function.code[pc];
+        match op {
+            RegOp::Move { dst, src } => after[dst] = operand_type(&before[pc], src),
+            RegOp::Binary { op, dst, .. } => {
+                after[dst] = match op {
+                    BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div => Type::Number,
+                    BinaryOp::Equal | BinaryOp::Less | BinaryOp::Greater => Type::Boolean,
+                }
+            }
+            RegOp::Not { dst, .. } => after[dst] = Type::Boolean,
+            RegOp::Call { dst, function, .. } => after[dst] = returns[&function],
+            RegOp::Jump(_) | RegOp::JumpIf { .. } | RegOp::Return(_) => {}
+        }
+        let mut flow = |to: usize, types: &[Type]| {
+            for (known, new) in before[to].iter_mut().zip(types) {
+                *known = known.join(*new);
+            }
+        };
+        match op {
+            RegOp::Jump(target) => flow(target, &after),
+            RegOp::JumpIf { target, .. } => {
+                flow(target, &after);
+                flow(pc + 1, &after);
+            }
+            RegOp::Return(_) => {}
+            _ => flow(pc + 1, &after),
+        }
+    }
+    before
+}
+
+/// What each function of `group` returns, or `None` if an op of one of them
+/// may get a value of a type it cannot take.
+fn infer(group: &HashMap<usize, &RegFunction>) -> Option<HashMap<usize, Type>> {
+    let mut returns: HashMap<usize, Type> =
+        group.keys().map(|&index| (index, Type::Unset)).collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (&index, function) in group {
+            let types = register_types(function, &returns);
+            let mut returned = returns[&index];
+            for (pc, op) in function.code.iter().enumerate() {
+                if let RegOp::Return(value) = *op {
+                    returned = returned.join(operand_type(&types[pc], value));
+                }
+            }
+            if returned != returns[&index] {
+                returns.insert(index, returned);
+                changed = true;
+            }
+        }
+    }
+    for (&index, function) in group {
+        let types = register_types(function, &returns);
+        for (pc, op) in function.code.iter().enumerate() {
+            let ty = |operand| operand_type(&types[pc], operand);
+            let fits = match *op {
+                RegOp::Binary {
+                    op: BinaryOp::Equal,
+                    a,
+                    b,
+                    ..
+                } => ty(a) != Type::Either && ty(b) != Type::Either,
+                RegOp::Binary { a, b, .. } => ty(a).is(Type::Number) && ty(b).is(Type::Number),
+                RegOp::Not { a, .. } => ty(a).is(Type::Boolean),
+                RegOp::JumpIf { cond, .. } => ty(cond).is(Type::Boolean),
+                RegOp::Call { args, count, .. } => {
+                    (args..args + count).all(|r| types[pc][r].is(Type::Number))
+                }
+                RegOp::Return(value) => ty(value) != Type::Either,
+                RegOp::Move { .. } | RegOp::Jump(_) => true,
+            };
+            if !fits {
+                return None;
+            }
+        }
+        if returns[&index] == Type::Either {
+            return None;
+        }
+    }
+    Some(returns)
+}
+
+/// Compiles `group` into a new module, returning it and the entry of each
+/// function.
+fn build(
+    group: &HashMap<usize, &RegFunction>,
+    returns: &HashMap<usize, Type>,
+) -> Result<(JITModule, Vec<(usize, Entry)>), String> {
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").map_err(|e| e.to_string())?;
+    let isa = cranelift_native::builder()?
+        .finish(settings::Flags::new(flags))
+        .map_err(|e| e.to_string())?;
+    let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+    let pointer = module.target_config().pointer_type();
+
+    let mut bodies = HashMap::new();
+    let mut entries = Vec::new();
+    for (&index, function) in group {
+        let mut body = module.make_signature();
+        body.params.push(AbiParam::new(pointer));
+        for _ in 0..function.arity {
+            body.params.push(AbiParam::new(types::F64));
+        }
+        body.returns.push(AbiParam::new(types::F64));
+        let id = module
+            .declare_function(&format!("f{}", index), Linkage::Local, &body)
+            .map_err(|e| e.to_string())?;
+        bodies.insert(index, (id, body));
+    }
+
+    let mut context = module.make_context();
+    let mut builder_context = FunctionBuilderContext::new();
+    for (&index, function) in group {
+        let (id, signature) = bodies[&index].clone();
+        context.func.signature = signature;
+        context.func.name = UserFuncName::user(0, id.as_u32());
+        let builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+        Lowering {
+            builder,
+            types: register_types(function, returns),
+            module: &mut module,
+            bodies: &bodies,
+            pointer,
+        }
+        .body(function);
+        module
+            .define_function(id, &mut context)
+            .map_err(|e| e.to_string())?;
+        module.clear_context(&mut context);
+
+        // The entry Rust calls, taking the arguments from an array
+        let mut signature = module.make_signature();
+        signature.params.push(AbiParam::new(pointer));
+        signature.params.push(AbiParam::new(pointer));
+        signature.returns.push(AbiParam::new(types::F64));
+        let entry = module
+            .declare_function(&format!("entry{}", index), Linkage::Local, &signature)
+            .map_err(|e| e.to_string())?;
+        context.func.signature = signature;
+        context.func.name = UserFuncName::user(0, entry.as_u32());
+        let mut builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+        let block = builder.create_block();
+        builder.append_block_params_for_function_params(block);
+        builder.switch_to_block(block);
+        let (ctx, args) = (
+            builder.block_params(block)[0],
+            builder.block_params(block)[1],
+        );
+        let mut call_args = vec![ctx];
+        for param in 0..function.arity {
+            let offset = (param * size_of::<f64>()) as i32;
+            call_args.push(
+                builder
+                    .ins()
+                    .load(types::F64, MemFlags::trusted(), args, offset),
+            );
+        }
+        let callee = module.declare_func_in_func(id, builder.func);
+        let call = builder.ins().call(callee, &call_args);
+        let result = builder.inst_results(call)[0];
+        builder.ins().return_(&[result]);
+        builder.seal_all_blocks();
+        builder.finalize();
+        module
+            .define_function(entry, &mut context)
+            .map_err(|e| e.to_string())?;
+        module.clear_context(&mut context);
+        entries.push((index, entry));
+    }
+
+    module.finalize_definitions().map_err(|e| e.to_string())?;
+    let entries = entries
+        .into_iter()
+        .map(|(index, id)| {
+            let code = module.get_finalized_function(id);
+            let entry = unsafe { std::mem::transmute::<*const u8, Entry>(code) };
+            (index, entry)
+        })
+        .collect();
+    Ok((module, entries))
+}
+
+fn var(register: usize) -> Variable {
+    Variable::from_u32(register as u32)
+}
+
+/// Lowers one function's register code to Cranelift IR.
+struct Lowering<'a, 'b> {
+    builder: FunctionBuilder<'a>,
+    /// Types of the registers before each op.
+    types: Vec<Vec<Type>>,
+    module: &'b mut JITModule,
+    bodies: &'b HashMap<usize, (FuncId, Signature)>,
+    pointer: types::Type,
+}
+
+impl Lowering<'_, '_> {
+    fn body(mut self, function: &RegFunction) {
+        let b = &mut self.builder;
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        let blocks: Vec<Block> = function.code.iter().map(|_| b.create_block()).collect();
+        // Unwinds after a failure, setting `failed` when it is this call's
+        let fail = b.create_block();
+        let unwind = b.create_block();
+
+        b.switch_to_block(entry);
+        let ctx = b.block_params(entry)[0];
+        let params: Vec<_> = b.block_params(entry)[1..].to_vec();
+        for r in 0..function.registers {
+            let variable = var(r);
+            b.declare_var(variable, types::F64);
+            let value = match params.get(r) {
+                Some(&param) => param,
+                None => b.ins().f64const(0.0),
+            };
+            b.def_var(variable, value);
+        }
+        let trusted = MemFlags::trusted();
+        let cancelled = b.ins().load(
+            self.pointer,
+            trusted,
+            ctx,
+            offset_of!(Context, cancelled) as i32,
+        );
+        let cancelled = b.ins().load(types::I8, trusted, cancelled, 0);
+        let counted = b.create_block();
+        b.ins().brif(cancelled, fail, &[], counted, &[]);
+        b.switch_to_block(counted);
+        let mut exhausted = None;
+        for (field, amount) in [
+            (offset_of!(Context, calls), 1),
+            (offset_of!(Context, values), function.registers as i64),
+        ] {
+            let left = b.ins().load(types::I64, trusted, ctx, field as i32);
+            let left = b.ins().iadd_imm(left, -amount);
+            b.ins().store(trusted, left, ctx, field as i32);
+            let below = b.ins().icmp_imm(IntCC::SignedLessThan, left, 0);
+            exhausted = Some(match exhausted {
+                Some(other) => b.ins().bor(other, below),
+                None => below,
+            });
+        }
+        let entered = b.ins().load(
+            types::I64,
+            trusted,
+            ctx,
+            offset_of!(Context, entered) as i32,
+        );
+        let entered = b.ins().iadd_imm(entered, 1);
+        b.ins()
+            .store(trusted, entered, ctx, offset_of!(Context, entered) as i32);
+        b.ins().brif(exhausted.unwrap(), fail, &[], blocks[0], &[]);
+
+        for (pc, op) in function.code.iter().enumerate() {
+            self.builder.switch_to_block(blocks[pc]);
+            self.op(*op, ctx, function, &blocks, pc, fail, unwind);
+        }
+
+        let b = &mut self.builder;
+        b.switch_to_block(fail);
+        let one = b.ins().iconst(types::I8, 1);
+        b.ins()
+            .store(trusted, one, ctx, offset_of!(Context, failed) as i32);
+        b.ins().jump(unwind, &[]);
+        b.switch_to_block(unwind);
+        let zero = b.ins().f64const(0.0);
+        b.ins().return_(&[zero]);
+
+        b.seal_all_blocks();
+        self.builder.finalize();
+    }
+
+    fn operand(&mut self, operand: Operand) -> cranelift_codegen::ir::Value {
+        match operand {
+            Operand::Reg(r) => self.builder.use_var(var(r)),
+            Operand::Number(n) => self.builder.ins().f64const(n),
+            Operand::Boolean(b) => self.builder.ins().f64const(if b { 1.0 } else { 0.0 }),
+        }
+    }
+
+    /// `1.0` if `cond` holds, else `0.0`.
+    fn boolean(&mut self, cond: cranelift_codegen::ir::Value) -> cranelift_codegen::ir::Value {
+        let one = self.builder.ins().f64const(1.0);
+        let zero = self.builder.ins().f64const(0.0);
+        self.builder.ins().select(cond, one, zero)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn op(
+        &mut self,
+        op: RegOp,
+        ctx: cranelift_codegen::ir::Value,
+        function: &RegFunction,
+        blocks: &[Block],
+        pc: usize,
+        fail: Block,
+        unwind: Block,
+    ) {
+        let next = blocks.get(pc + 1).copied();
+        match op {
+            RegOp::Move { dst, src } => {
+                let value = self.operand(src);
+                self.builder.def_var(var(dst), value);
+            }
+            RegOp::Binary {
+                op: BinaryOp::Equal,
+                dst,
+                a,
+                b,
+            } if {
+                let (a, b) = (
+                    operand_type(&self.types[pc], a),
+                    operand_type(&self.types[pc], b),
+                );
+                a != b && a != Type::Unset && b != Type::Unset
+            } =>
+            {
+                // A number never equals a boolean
+                let value = self.builder.ins().f64const(0.0);
+                self.builder.def_var(var(dst), value);
+            }
+            RegOp::Binary { op, dst, a, b } => {
+                let (a, b) = (self.operand(a), self.operand(b));
+                let ins = self.builder.ins();
+                let value = match op {
+                    BinaryOp::Add => ins.fadd(a, b),
+                    BinaryOp::Sub => ins.fsub(a, b),
+                    BinaryOp::Mul => ins.fmul(a, b),
+                    BinaryOp::Div => {
+                        let zero = ins.f64const(0.0);
+                        let by_zero = self.builder.ins().fcmp(FloatCC::Equal, b, zero);
+                        let divide = self.builder.create_block();
+                        self.builder.ins().brif(by_zero, fail, &[], divide, &[]);
+                        self.builder.switch_to_block(divide);
+                        self.builder.ins().fdiv(a, b)
+                    }
+                    BinaryOp::Equal => {
+                        let cond = ins.fcmp(FloatCC::Equal, a, b);
+                        self.boolean(cond)
+                    }
+                    BinaryOp::Less => {
+                        let cond = ins.fcmp(FloatCC::LessThan, a, b);
+                        self.boolean(cond)
+                    }
+                    BinaryOp::Greater => {
+                        let cond = ins.fcmp(FloatCC::GreaterThan, a, b);
+                        self.boolean(cond)
+                    }
+                };
+                self.builder.def_var(var(dst), value);
+            }
+            RegOp::Not { dst, a } => {
+                let a = self.operand(a);
+                let one = self.builder.ins().f64const(1.0);
+                let value = self.builder.ins().fsub(one, a);
+                self.builder.def_var(var(dst), value);
+            }
+            RegOp::Jump(target) => {
+                self.builder.ins().jump(blocks[target], &[]);
+                return;
+            }
+            RegOp::JumpIf { cond, when, target } => {
+                let cond = self.operand(cond);
+                let zero = self.builder.ins().f64const(0.0);
+                let cc = if when {
+                    FloatCC::NotEqual
+                } else {
+                    FloatCC::Equal
+                };
+                let taken = self.builder.ins().fcmp(cc, cond, zero);
+                let next = next.expect("a jump is not last");
+                self.builder
+                    .ins()
+                    .brif(taken, blocks[target], &[], next, &[]);
+                return;
+            }
+            RegOp::Call {
+                dst,
+                function: callee,
+                args,
+                count,
+            } => {
+                let mut call_args = vec![ctx];
+                for param in 0..count {
+                    call_args.push(self.builder.use_var(var(args + count - 1 - param)));
+                }
+                let (id, _) = self.bodies[&callee];
+                let callee = self.module.declare_func_in_func(id, self.builder.func);
+                let call = self.builder.ins().call(callee, &call_args);
+                let result = self.builder.inst_results(call)[0];
+                self.builder.def_var(var(dst), result);
+                let failed = self.builder.ins().load(
+                    types::I8,
+                    MemFlags::trusted(),
+                    ctx,
+                    offset_of!(Context, failed) as i32,
+                );
+                let returned = self.builder.create_block();
+                self.builder.ins().brif(failed, unwind, &[], returned, &[]);
+                self.builder.switch_to_block(returned);
+            }
+            RegOp::Return(value) => {
+                let value = self.operand(value);
+                let trusted = MemFlags::trusted();
+                for (field, amount) in [
+                    (offset_of!(Context, calls), 1),
+                    (offset_of!(Context, values), function.registers as i64),
+                ] {
+                    let left = self
+                        .builder
+                        .ins()
+                        .load(types::I64, trusted, ctx, field as i32);
+                    let left = self.builder.ins().iadd_imm(left, amount);
+                    self.builder.ins().store(trusted, left, ctx, field as i32);
+                }
+                self.builder.ins().return_(&[value]);
+                return;
+            }
+        }
+        let next = next.expect("register code ends in a return or jump");
+        self.builder.ins().jump(next, &[]);
+    }
+}
@@ -1,29 +1,71 @@
+use crate::types::interner::Interner;
 use crate::types::token::Token;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::iter::FusedIterator;
 
-pub struct Lexer {
-    input: String,
-    position: usize,
+/// Whether `ch` can start an identifier: `_` or a Unicode `XID_Start`
+/// character, so `café`, `名前` and `π` are names, as they are in Rust.
+pub fn is_identifier_start(ch: char) -> bool {
+    ch == '_' || unicode_ident::is_xid_start(ch)
+}
+
+/// Whether `ch` can follow the first character of an identifier: a Unicode
+/// `XID_Continue` character, which includes digits and `_`.
+pub fn is_identifier_char(ch: char) -> bool {
+    unicode_ident::is_xid_continue(ch)
+}
+
+/// Scans a borrowed source by byte offset. Identifiers, strings and numbers are
+/// sliced straight out of the source, so the only allocation per token is the
+/// first time the interner sees a new symbol.
+pub struct Lexer<'a> {
+    input: &'a str,
+    position: usize, // Byte offset of `current_char`
     current_char: Option<char>,
+    interner: Interner,
+    /// The rest of the tokens an interpolated string was lexed into.
+    pending: VecDeque<Token>,
+    /// Line breaks inside string literals since the last `Token::Newline`,
+    /// which it is followed by so the parser's line numbers stay right.
+    string_newlines: usize,
 }
 
-impl Lexer {
-    pub fn new(input: String) -> Self {
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self::with_interner(input, Interner::new())
+    }
+
+    /// Creates a lexer that keeps adding to an existing symbol table, so
+    /// symbols stay shared across several sources.
+    pub fn with_interner(input: &'a str, interner: Interner) -> Self {
         let mut lexer = Lexer {
             input,
             position: 0,
-            current_char: None,
+            current_char: input.chars().next(),
+            interner,
+            pending: VecDeque::new(),
+            string_newlines: 0,
         };
-        lexer.current_char = lexer.input.chars().nth(0);
+        // A `#!` line, as in `#!/usr/bin/env n`, is for the shell; its
+        // newline is kept so line numbers stay the same
+        if input.starts_with("#!") {
+            Lexer::take_while(&mut lexer, |ch| ch != '\n');
+        }
         lexer
     }
 
     fn advance(&mut self) {
-        self.position += 1;
-        self.current_char = self.input.chars().nth(self.position);
+        if let Some(ch) = self.current_char {
+            self.position += ch.len_utf8();
+        }
+        self.current_char = self.input[self.position..].chars().next();
     }
 
     fn peek(&self) -> Option<char> {
-        self.input.chars().nth(self.position + 1)
+        let mut chars = self.input[self.position..].chars();
+        chars.next();
+        chars.next()
     }
 
     fn skip_whitespace(&mut self) {
@@ -36,87 +78,170 @@ impl Lexer {
         }
     }
 
-    fn read_string(&mut self) -> String {
-        let mut value = String::new();
-        self.advance(); // skip opening quote
-
+    /// Advances while `predicate` holds and returns the consumed slice.
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> &'a str {
+        let start = self.position;
         while let Some(ch) = self.current_char {
-            if ch == '"' {
-                self.advance(); // skip closing quote
+            if predicate(ch) {
+                self.advance();
+            } else {
                 break;
             }
-            value.push(ch);
-            self.advance();
         }
+        &self.input[start..self.position]
+    }
 
+    fn read_string(&mut self) -> &'a str {
+        self.advance(); // skip opening quote
+        let value = self.take_while(|ch| ch != '"');
+        self.advance(); // skip closing quote
         value
     }
 
-    fn read_number(&mut self) -> f64 {
-        let mut value = String::new();
+    /// The token for the text of a string literal, or an error token when
+    /// one of its escapes is malformed.
+    fn string_token(&mut self, raw: &str) -> Token {
+        match unescape(raw) {
+            Ok(text) => Token::String(self.interner.intern(&text)),
+            Err(message) => Token::Error(self.interner.intern(&message)),
+        }
+    }
 
-        while let Some(ch) = self.current_char {
-            if ch.is_ascii_digit() || ch == '.' {
-                value.push(ch);
+    /// Lexes `$"Hello {name}!"` as `("Hello " ++ (name) ++ "!")`, which
+    /// compiles to a single `CONCAT_N`. `close` is the quote the string ends
+    /// with: `"`, `'` for `$'...'`, or `"""` for a multi-line `$"""..."""`,
+    /// whose first line break is dropped when it directly follows the quotes.
+    /// Returns the opening parenthesis and queues the rest.
+    fn read_interpolated_string(&mut self, close: &str) -> Token {
+        let start = self.position;
+        self.advance(); // skip $
+        self.skip(close); // skip opening quote
+        if close == "\"\"\"" && !self.skip("\r\n") {
+            self.skip("\n");
+        }
+        let mut literal_start = self.position;
+        let mut first = true;
+        loop {
+            let rest = &self.input[self.position..];
+            // The brace of a `\u{...}` escape does not start an expression
+            if rest.starts_with("\\u{") {
+                self.take_while(|ch| ch != '}' && !close.starts_with(ch));
+                if self.current_char == Some('}') {
+                    self.advance();
+                }
+                continue;
+            }
+            if !rest.is_empty() && !rest.starts_with('{') && !rest.starts_with(close) {
                 self.advance();
-            } else {
+                continue;
+            }
+            let literal = &self.input[literal_start..self.position];
+            // The first literal is kept even when empty, so the result is a string
+            if first || !literal.is_empty() {
+                if !first {
+                    self.pending.push_back(Token::PlusPlus);
+                }
+                let literal = self.string_token(literal);
+                self.pending.push_back(literal);
+                first = false;
+            }
+            if self.current_char != Some('{') {
+                self.skip(close); // skip closing quote
                 break;
             }
+            self.advance(); // skip {
+            let expression = self.read_interpolated_expression();
+            let mut lexer = Lexer::with_interner(expression, std::mem::take(&mut self.interner));
+            self.pending.push_back(Token::PlusPlus);
+            self.pending.push_back(Token::LeftParen);
+            self.pending.extend(
+                lexer
+                    .by_ref()
+                    .filter(|token| !matches!(token, Token::Newline)),
+            );
+            self.pending.push_back(Token::RightParen);
+            self.interner = lexer.into_interner();
+            literal_start = self.position;
         }
-
-        value.parse::<f64>().unwrap_or(0.0)
+        self.pending.push_back(Token::RightParen);
+        self.string_newlines += self.input[start..self.position].matches('\n').count();
+        Token::LeftParen
     }
 
-    fn read_identifier(&mut self) -> String {
-        let mut value = String::new();
+    /// Advances past `text` if the input continues with it.
+    fn skip(&mut self, text: &str) -> bool {
+        if !self.input[self.position..].starts_with(text) {
+            return false;
+        }
+        self.position += text.len();
+        self.current_char = self.input[self.position..].chars().next();
+        true
+    }
 
+    /// The source of an interpolated `{...}`, up to its closing brace, which
+    /// is skipped. Braces and strings inside it are passed over whole.
+    fn read_interpolated_expression(&mut self) -> &'a str {
+        let start = self.position;
+        let mut depth = 0;
         while let Some(ch) = self.current_char {
-            if ch.is_alphanumeric() || ch == '_' {
-                value.push(ch);
-                self.advance();
-            } else {
-                break;
+            match ch {
+                '}' if depth == 0 => break,
+                '}' => depth -= 1,
+                '{' => depth += 1,
+                '"' => {
+                    self.read_string();
+                    continue;
+                }
+                _ => {}
             }
+            self.advance();
         }
+        let expression = &self.input[start..self.position];
+        self.advance(); // skip closing brace
+        expression
+    }
 
-        value
+    fn read_number(&mut self) -> f64 {
+        self.take_while(|ch| ch.is_ascii_digit() || ch == '.')
+            .parse::<f64>()
+            .unwrap_or(0.0)
     }
 
-    fn read_comment(&mut self) -> String {
-        let mut comment = String::new();
+    fn read_identifier(&mut self) -> &'a str {
+        self.take_while(is_identifier_char)
+    }
 
+    fn read_comment(&mut self) -> &'a str {
         if self.current_char == Some('/') && self.peek() == Some('/') {
             // Single line comment
             self.advance(); // skip first /
             self.advance(); // skip second /
-
-            while let Some(ch) = self.current_char {
-                if ch == '\n' {
-                    break;
-                }
-                comment.push(ch);
-                self.advance();
-            }
+            self.take_while(|ch| ch != '\n')
         } else if self.current_char == Some('/') && self.peek() == Some('*') {
             // Multi-line comment
             self.advance(); // skip /
             self.advance(); // skip *
 
+            let start = self.position;
             while let Some(ch) = self.current_char {
                 if ch == '*' && self.peek() == Some('/') {
+                    let comment = &self.input[start..self.position];
                     self.advance(); // skip *
                     self.advance(); // skip /
-                    break;
+                    return comment;
                 }
-                comment.push(ch);
                 self.advance();
             }
+            &self.input[start..]
+        } else {
+            ""
         }
-
-        comment
     }
 
     pub fn next_token(&mut self) -> Token {
+        if let Some(token) = self.pending.pop_front() {
+            return token;
+        }
         loop {
             match self.current_char {
                 None => return Token::Eof,
@@ -128,12 +253,25 @@ impl Lexer {
 
                 Some('\n') => {
                     self.advance();
+                    let newlines = std::mem::take(&mut self.string_newlines);
+                    self.pending
+                        .extend(std::iter::repeat_n(Token::Newline, newlines));
                     return Token::Newline;
                 }
 
                 Some('"') => {
-                    let string_value = self.read_string();
-                    return Token::String(string_value);
+                    let raw = self.read_string();
+                    self.string_newlines += raw.matches('\n').count();
+                    return self.string_token(raw);
+                }
+
+                Some('$') if self.input[self.position..].starts_with("$\"\"\"") => {
+                    return self.read_interpolated_string("\"\"\"");
+                }
+
+                Some('$') if matches!(self.peek(), Some('"' | '\'')) => {
+                    let close = if self.peek() == Some('"') { "\"" } else { "'" };
+                    return self.read_interpolated_string(close);
                 }
 
                 Some(ch) if ch.is_ascii_digit() => {
@@ -141,9 +279,9 @@ impl Lexer {
                     return Token::Number(number);
                 }
 
-                Some(ch) if ch.is_alphabetic() || ch == '_' => {
+                Some(ch) if is_identifier_start(ch) => {
                     let identifier = self.read_identifier();
-                    return match identifier.as_str() {
+                    return match identifier {
                         "let" => {
                             if self.current_char == Some('!') {
                                 self.advance();
@@ -157,26 +295,52 @@ impl Lexer {
                         "match" => Token::Match,
                         "import" => Token::Import,
                         "enum" => Token::Enum,
+                        "const" => Token::Const,
                         "if" => Token::If,
                         "else" => Token::Else,
                         "return" => Token::Return,
                         "async" => Token::Async,
                         "await" => Token::Await,
+                        "for" => Token::For,
+                        "in" => Token::In,
+                        "yield" => Token::Yield,
+                        "struct" => Token::Struct,
+                        "impl" => Token::Impl,
+                        "trait" => Token::Trait,
+                        "extern" => Token::Extern,
                         "true" => Token::True,
                         "false" => Token::False,
-                        _ => Token::Identifier(identifier),
+                        _ => Token::Identifier(self.interner.intern(identifier)),
                     };
                 }
 
                 Some('/') if self.peek() == Some('/') || self.peek() == Some('*') => {
-                    self.read_comment();
-                    continue; // Skip comments entirely
+                    let line_comment = self.peek() == Some('/');
+                    let comment = self.read_comment();
+                    // `///` and `//!` are documentation, other comments are skipped
+                    let doc = |text: &'a str| text.strip_prefix(' ').unwrap_or(text);
+                    match comment.chars().next() {
+                        Some('/') if line_comment && !comment.starts_with("//") => {
+                            return Token::DocComment(self.interner.intern(doc(&comment[1..])));
+                        }
+                        Some('!') if line_comment => {
+                            return Token::ModuleDoc(self.interner.intern(doc(&comment[1..])));
+                        }
+                        _ => continue,
+                    }
                 }
 
                 Some(ch) => {
                     self.advance();
                     match ch {
-                        '+' => return Token::Plus,
+                        '+' => {
+                            if self.current_char == Some('+') {
+                                self.advance();
+                                return Token::PlusPlus;
+                            } else {
+                                return Token::Plus;
+                            }
+                        }
                         '-' => {
                             if self.current_char == Some('>') {
                                 self.advance();
@@ -250,7 +414,7 @@ impl Lexer {
                                 self.advance();
                                 return Token::DoubleColon;
                             } else {
-                                continue; // Skip single :
+                                return Token::Colon;
                             }
                         }
                         '(' => return Token::LeftParen,
@@ -260,8 +424,18 @@ impl Lexer {
                         '[' => return Token::LeftBracket,
                         ']' => return Token::RightBracket,
                         ',' => return Token::Comma,
-                        '.' => return Token::Dot,
+                        '.' => {
+                            if self.current_char == Some('.') && self.peek() == Some('.') {
+                                self.advance();
+                                self.advance();
+                                return Token::Spread;
+                            } else {
+                                return Token::Dot;
+                            }
+                        }
                         '#' => return Token::Hash,
+                        '@' => return Token::At,
+                        ';' => return Token::Semicolon,
                         _ => continue, // Skip unknown characters
                     }
                 }
@@ -269,19 +443,74 @@ impl Lexer {
         }
     }
 
-    pub fn tokenize(&mut self) -> Vec<Token> {
-        let mut tokens = Vec::new();
+    pub fn interner(&self) -> &Interner {
+        &self.interner
+    }
 
-        loop {
-            let token = self.next_token();
-            let is_eof = matches!(token, Token::Eof);
-            tokens.push(token);
+    pub fn into_interner(self) -> Interner {
+        self.interner
+    }
 
-            if is_eof {
-                break;
+    /// Lexes the rest of the input. Unlike the iterator, the result always ends
+    /// with a single `Token::Eof`, which is what the parser expects.
+    pub fn tokenize(&mut self) -> Vec<Token> {
+        let mut tokens: Vec<Token> = self.by_ref().collect();
+        tokens.push(Token::Eof);
+        tokens
+    }
+}
+
+/// Replaces each `\u{...}` escape in the text of a string literal, 1 to 6
+/// hex digits naming a Unicode code point, with its character. Other
+/// backslashes are kept as written.
+fn unescape(raw: &str) -> Result<Cow<'_, str>, String> {
+    if !raw.contains("\\u{") {
+        return Ok(Cow::Borrowed(raw));
+    }
+    let mut text = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(start) = rest.find("\\u{") {
+        text.push_str(&rest[..start]);
+        let escape = &rest[start + 3..];
+        let Some(end) = escape.find('}') else {
+            return Err("Unterminated escape '\\u{': expected hex digits and '}'".to_string());
+        };
+        let digits = &escape[..end];
+        let code = match digits.len() {
+            1..=6 if digits.chars().all(|ch| ch.is_ascii_hexdigit()) => {
+                u32::from_str_radix(digits, 16).ok()
             }
-        }
+            _ => {
+                return Err(format!(
+                    "Malformed escape '\\u{{{}}}': expected 1 to 6 hex digits",
+                    digits
+                ));
+            }
+        };
+        let Some(ch) = code.and_then(char::from_u32) else {
+            return Err(format!(
+                "Invalid escape '\\u{{{}}}': not a Unicode character",
+                digits
+            ));
+        };
+        text.push(ch);
+        rest = &escape[end + 1..];
+    }
+    text.push_str(rest);
+    Ok(Cow::Owned(text))
+}
 
-        tokens
+/// Yields tokens up to, but not including, `Token::Eof`; after the end of the
+/// input it keeps returning `None`.
+impl Iterator for Lexer<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        match self.next_token() {
+            Token::Eof => None,
+            token => Some(token),
+        }
     }
 }
+
+impl FusedIterator for Lexer<'_> {}
@@ -0,0 +1,549 @@
+pub mod ast_json;
+pub mod bench;
+pub mod bytecode;
+pub mod cache;
+pub mod compiler;
+pub mod complete;
+pub mod coverage;
+pub mod debug;
+pub mod diagnostic;
+pub mod dispatch;
+pub mod doc;
+pub mod engine;
+pub mod features;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fuzz;
+pub mod heap;
+pub mod incremental;
+pub mod inspector;
+pub mod interpreter;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod lexer;
+pub mod lint;
+#[cfg(feature = "fs")]
+pub mod manifest;
+pub mod methods;
+pub mod nanbox;
+pub mod natives;
+pub mod parser;
+pub mod persistent;
+#[cfg(feature = "registers")]
+pub mod register;
+pub mod repl;
+pub mod replay;
+pub mod stdlib;
+#[cfg(feature = "fs")]
+pub mod testing;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod types;
+pub mod verify;
+pub mod wasm;
+
+pub use engine::{Engine, Error, TaskFuture};
+pub use interpreter::{CancellationToken, VmLimits};
+
+#[cfg(all(test, feature = "fs"))]
+mod tests;
+
+pub mod runtime {
+    use crate::compiler::Compiler;
+    #[cfg(feature = "fs")]
+    use crate::coverage::Coverage;
+    #[cfg(feature = "fs")]
+    use crate::diagnostic::{self, Diagnostic, MessageFormat};
+    #[cfg(feature = "fs")]
+    use crate::interpreter::VirtualMachine;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    #[cfg(feature = "fs")]
+    use crate::replay::Recording;
+    #[cfg(feature = "fs")]
+    use crate::types::compiler::HeapObject;
+    use crate::types::compiler::{ByteCode, CompileOptions, Value};
+    #[cfg(feature = "fs")]
+    use std::io::{self, BufRead, Write};
+    use std::sync::{Arc, Mutex};
+
+    /// Lexes, parses and compiles `source` without any debug output.
+    pub fn compile_source(source: &str) -> Result<(ByteCode, Compiler), String> {
+        compile_source_with(source, CompileOptions::default())
+    }
+
+    pub fn compile_source_with(
+        source: &str,
+        options: CompileOptions,
+    ) -> Result<(ByteCode, Compiler), String> {
+        let mut compiler = Compiler::with_options(options);
+        let bytecode = compile_with(&mut compiler, source)?;
+        Ok((bytecode, compiler))
+    }
+
+    /// Compiles `source` with a caller-prepared compiler, so natives and
+    /// globals registered on it are visible to the script.
+    pub fn compile_with(compiler: &mut Compiler, source: &str) -> Result<ByteCode, String> {
+        let tokens = Lexer::new(source).tokenize();
+        let ast = Parser::new(tokens)
+            .parse()
+            .map_err(|e| format!("Parse error: {}", e))?;
+        compiler
+            .compile(&ast)
+            .map_err(|e| format!("Compile error: {}", e))
+    }
+
+    /// Options for compiling the file `filename`: modules next to it can be
+    /// imported by name, and its `main` function is called.
+    pub fn options_for_file(filename: &str) -> CompileOptions {
+        let dir = std::path::Path::new(filename)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty());
+        CompileOptions {
+            module_paths: dir.map(|dir| dir.to_path_buf()).into_iter().collect(),
+            call_main: true,
+            ..CompileOptions::default()
+        }
+    }
+
+    /// Compiles a `.n` file and writes its binary encoding to `output`.
+    #[cfg(feature = "fs")]
+    pub fn build_file(filename: &str, output: &str) -> Result<(), String> {
+        let source = std::fs::read_to_string(filename)
+            .map_err(|err| format!("Error reading file '{}': {}", filename, err))?;
+        let (bytecode, _) = compile_source_with(&source, options_for_file(filename))?;
+        let bytes = crate::bytecode::encode(&bytecode)?;
+        std::fs::write(output, bytes)
+            .map_err(|err| format!("Error writing file '{}': {}", output, err))
+    }
+
+    /// Compiles the project whose `n.toml` is in `root` into one bytecode
+    /// file. Returns the path written, `<root>/<name>.nb` unless `output` is
+    /// given.
+    #[cfg(feature = "fs")]
+    pub fn build_project(root: &str, output: Option<&str>) -> Result<String, String> {
+        let manifest = crate::manifest::Manifest::load(std::path::Path::new(root))?;
+        let source = std::fs::read_to_string(&manifest.entry)
+            .map_err(|err| format!("Error reading file '{}': {}", manifest.entry.display(), err))?;
+        let options = CompileOptions {
+            module_paths: manifest.module_paths()?,
+            call_main: true,
+            ..CompileOptions::default()
+        };
+        let (bytecode, _) = compile_source_with(&source, options)?;
+        let bytes = crate::bytecode::encode(&bytecode)?;
+        let output = match output {
+            Some(output) => std::path::PathBuf::from(output),
+            None => manifest.root.join(format!("{}.nb", manifest.name)),
+        };
+        std::fs::write(&output, bytes)
+            .map_err(|err| format!("Error writing file '{}': {}", output.display(), err))?;
+        Ok(output.display().to_string())
+    }
+
+    /// Markdown, or with `html` an HTML page, documenting the declarations of
+    /// a `.n` file and their `///` comments.
+    #[cfg(feature = "fs")]
+    pub fn doc_file(filename: &str, html: bool) -> Result<String, String> {
+        let source = std::fs::read_to_string(filename)
+            .map_err(|err| format!("Error reading file '{}': {}", filename, err))?;
+        let program = Parser::new(Lexer::new(&source).tokenize())
+            .parse()
+            .map_err(|e| format!("Parse error: {}", e))?;
+        let name = std::path::Path::new(filename)
+            .file_stem()
+            .map_or(filename.into(), |stem| stem.to_string_lossy());
+        let module = crate::doc::ModuleDoc::new(&name, &program);
+        Ok(match html {
+            true => module.to_html(),
+            false => module.to_markdown(),
+        })
+    }
+
+    /// The parse tree of a `.n` file as JSON, see `ast_json`.
+    #[cfg(feature = "fs")]
+    pub fn ast_json_file(filename: &str) -> Result<String, String> {
+        let source = std::fs::read_to_string(filename)
+            .map_err(|err| format!("Error reading file '{}': {}", filename, err))?;
+        let program = Parser::new(Lexer::new(&source).tokenize())
+            .parse()
+            .map_err(|e| format!("Parse error: {}", e))?;
+        Ok(crate::ast_json::to_json(&program))
+    }
+
+    /// Lints a `.n` file and lists the findings as JSON, see `lint`. Fails,
+    /// with the same text, if any finding has the `Error` severity.
+    #[cfg(feature = "fs")]
+    pub fn lint_file(filename: &str, config: &crate::lint::LintConfig) -> Result<String, String> {
+        let source = std::fs::read_to_string(filename)
+            .map_err(|err| format!("Error reading file '{}': {}", filename, err))?;
+        let program = Parser::new(Lexer::new(&source).tokenize())
+            .parse()
+            .map_err(|e| format!("Parse error: {}", e))?;
+        let findings = crate::lint::lint(&program, config);
+        let json = crate::lint::to_json(&findings);
+        match findings
+            .iter()
+            .any(|finding| finding.severity == crate::lint::Severity::Error)
+        {
+            true => Err(json),
+            false => Ok(json),
+        }
+    }
+
+    /// Writes `coverage` to `path` as lcov and returns its summary.
+    #[cfg(feature = "fs")]
+    fn write_coverage(path: &str, coverage: &Coverage) -> Result<String, String> {
+        std::fs::write(path, coverage.to_lcov())
+            .map_err(|err| format!("Error writing coverage '{}': {}", path, err))?;
+        Ok(coverage.summary())
+    }
+
+    /// Runs the `@test` functions below `path` and lists each outcome with a
+    /// summary line. Fails, with the same text, if any test failed. With
+    /// `coverage`, also writes the lines the tests ran there as lcov and
+    /// ends the text with the coverage summary.
+    #[cfg(feature = "fs")]
+    pub fn test_path(path: &str, coverage: Option<&str>) -> Result<String, String> {
+        let options = crate::testing::TestOptions {
+            coverage: coverage.is_some(),
+        };
+        let report = crate::testing::run_tests_with(path, &options)
+            .map_err(|err| format!("Error reading '{}': {}", path, err))?;
+        let mut lines = Vec::new();
+        for result in &report.results {
+            match &result.error {
+                None => lines.push(format!("PASS {}: {}", result.path.display(), result.name)),
+                Some(error) => {
+                    lines.push(format!("FAIL {}: {}", result.path.display(), result.name));
+                    lines.push(format!("    {}", error));
+                }
+            }
+        }
+        for (path, error) in &report.errors {
+            lines.push(format!("ERROR {}: {}", path.display(), error));
+        }
+        let failed = report.results.len() - report.passed();
+        lines.push(format!("{} passed, {} failed", report.passed(), failed));
+        if let (Some(path), Some(coverage)) = (coverage, &report.coverage) {
+            lines.push(write_coverage(path, coverage)?);
+        }
+        match report.is_success() {
+            true => Ok(lines.join("\n")),
+            false => Err(lines.join("\n")),
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    pub fn inspect_file(filename: &str) -> Result<String, String> {
+        let bytes = std::fs::read(filename)
+            .map_err(|err| format!("Error reading file '{}': {}", filename, err))?;
+        crate::bytecode::inspect(&bytes)
+    }
+
+    /// Browses an encoded file with `inspector::Inspector`, reading commands
+    /// from `input` and writing the answers to `output`.
+    #[cfg(feature = "fs")]
+    pub fn inspect_file_interactive(
+        filename: &str,
+        input: impl BufRead,
+        output: impl Write,
+    ) -> Result<String, String> {
+        let bytes = std::fs::read(filename)
+            .map_err(|err| format!("Error reading file '{}': {}", filename, err))?;
+        let inspector = crate::inspector::Inspector::new(&bytes)?;
+        inspector
+            .run(input, output)
+            .map_err(|err| format!("Error in inspector: {}", err))?;
+        Ok(String::new())
+    }
+
+    /// Browses an encoded file in the terminal UI of `tui`.
+    #[cfg(all(feature = "fs", feature = "tui"))]
+    pub fn inspect_file_tui(filename: &str) -> Result<String, String> {
+        let bytes = std::fs::read(filename)
+            .map_err(|err| format!("Error reading file '{}': {}", filename, err))?;
+        let inspector = crate::inspector::Inspector::new(&bytes)?;
+        crate::tui::run(&inspector).map_err(|err| format!("Error in inspector: {}", err))?;
+        Ok(String::new())
+    }
+
+    #[cfg(feature = "fs")]
+    pub fn compile_and_run(filename: &str) -> Result<String, String> {
+        compile_and_run_with_debug(filename, false)
+    }
+
+    #[cfg(feature = "fs")]
+    pub fn compile_and_run_with_debug(filename: &str, debug: bool) -> Result<String, String> {
+        let mut options = RunOptions {
+            debug,
+            ..RunOptions::default()
+        };
+        match run_file(filename, &mut options)? {
+            Some(code) => Ok(format!("Program exited with code {}", code)),
+            None => Ok("Successfully executed program".to_string()),
+        }
+    }
+
+    /// `error`, as `run_file` returned it for `filename`, with the source
+    /// line it is about, or unchanged if it is not about one.
+    #[cfg(feature = "fs")]
+    pub fn render_error(filename: &str, error: &str) -> String {
+        format_file_error(filename, error, MessageFormat::Human)
+    }
+
+    /// `error`, as `run_source` returned it for `source`, with the source
+    /// line it is about, or unchanged if it is not about one.
+    #[cfg(feature = "fs")]
+    pub fn render_source_error(source: &str, filename: &str, error: &str) -> String {
+        format_error(source, filename, error, MessageFormat::Human)
+    }
+
+    /// `render_error` in either `MessageFormat`.
+    #[cfg(feature = "fs")]
+    pub fn format_file_error(filename: &str, error: &str, format: MessageFormat) -> String {
+        let source = std::fs::read_to_string(filename).unwrap_or_default();
+        format_error(&source, filename, error, format)
+    }
+
+    /// `render_source_error` in either `MessageFormat`. As JSON, errors
+    /// that are not about the source still become a record, see
+    /// `diagnostic::error_json`.
+    #[cfg(feature = "fs")]
+    pub fn format_error(
+        source: &str,
+        filename: &str,
+        error: &str,
+        format: MessageFormat,
+    ) -> String {
+        match (Diagnostic::from_error(error), format) {
+            (Some(diagnostic), format) => diagnostic.format(source, filename, format),
+            (None, MessageFormat::Human) => error.to_string(),
+            (None, MessageFormat::Json) => diagnostic::error_json(filename, error),
+        }
+    }
+
+    /// An output sink that keeps what is written, so a host can read it
+    /// while a VM or `RunOptions` still owns a clone.
+    #[derive(Debug, Clone, Default)]
+    pub struct Capture(pub Arc<Mutex<Vec<u8>>>);
+
+    impl Capture {
+        /// Everything written so far, as text.
+        pub fn text(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    impl std::io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Exit code for a program's result: whole numbers returned from `main`
+    /// become the process exit code, anything else leaves it to the runner.
+    pub fn exit_status(result: &Value) -> Option<i32> {
+        match result {
+            Value::Number(n) if n.fract() == 0.0 => Some(*n as i32),
+            _ => None,
+        }
+    }
+
+    /// How `run_file` runs a file.
+    #[cfg(feature = "fs")]
+    pub struct RunOptions {
+        /// Print the source, tokens, tree, bytecode and final stack.
+        pub debug: bool,
+        /// Print the value of a trailing top-level expression, unless the
+        /// program has a `main`.
+        pub echo: bool,
+        /// Fail on compiler warnings.
+        pub deny_warnings: bool,
+        /// Write what the program read from its surroundings to this file,
+        /// whether it succeeds or not.
+        pub record: Option<String>,
+        /// Feed the program what this recording saw, see `replay`.
+        pub replay: Option<String>,
+        /// Write the line coverage of the run to this file as lcov.
+        pub coverage: Option<String>,
+        /// Run files without the `.n` extension too, such as scripts made
+        /// executable with a `#!` line.
+        pub any_extension: bool,
+        /// How compiler warnings are printed to `err`.
+        pub message_format: MessageFormat,
+        /// Where the program's output and the echoed value go, stdout by
+        /// default.
+        pub out: Box<dyn Write + Send>,
+        /// Where debug dumps, warnings and the coverage summary go, stderr
+        /// by default.
+        pub err: Box<dyn Write + Send>,
+    }
+
+    #[cfg(feature = "fs")]
+    impl Default for RunOptions {
+        fn default() -> Self {
+            RunOptions {
+                debug: false,
+                echo: false,
+                deny_warnings: false,
+                record: None,
+                replay: None,
+                coverage: None,
+                any_extension: false,
+                message_format: MessageFormat::default(),
+                out: Box::new(io::stdout()),
+                err: Box::new(io::stderr()),
+            }
+        }
+    }
+
+    /// Compiles and runs a `.n` file, or any file with `any_extension`.
+    /// Returns the exit code the program asked for with `OS.exit` or by
+    /// returning a number from `main`, if any.
+    #[cfg(feature = "fs")]
+    pub fn run_file(filename: &str, options: &mut RunOptions) -> Result<Option<i32>, String> {
+        // Check if file ends with .n extension
+        if !filename.ends_with(".n") && !options.any_extension {
+            return Err(
+                "Error: File must have .n extension (use --any-extension to run it anyway)"
+                    .to_string(),
+            );
+        }
+
+        // Read the file
+        let source_code = match std::fs::read_to_string(filename) {
+            Ok(content) => content,
+            Err(err) => {
+                return Err(format!("Error reading file '{}': {}", filename, err));
+            }
+        };
+        run_source(&source_code, filename, options)
+    }
+
+    /// Compiles and runs `source` as `run_file` runs a file, e.g. for
+    /// `n -e` and `n -`. `filename` names it in diagnostics and coverage, and
+    /// its directory, if it has one, is searched by `import`.
+    #[cfg(feature = "fs")]
+    pub fn run_source(
+        source_code: &str,
+        filename: &str,
+        options: &mut RunOptions,
+    ) -> Result<Option<i32>, String> {
+        let (debug, echo, deny_warnings) = (options.debug, options.echo, options.deny_warnings);
+        // Debug dumps go to `err`, apart from the program's output
+        let dump = |result: io::Result<()>| {
+            result.map_err(|err| format!("Error writing debug output: {}", err))
+        };
+        let debug_out = &mut options.err;
+        if debug {
+            dump(writeln!(debug_out, "--- Source Code ---\n{}", source_code))?;
+        }
+
+        let mut lexer = Lexer::new(source_code);
+        let tokens = lexer.tokenize();
+
+        if debug {
+            dump(crate::debug::write_tokens(debug_out, &tokens))?;
+        }
+
+        let mut parser = Parser::new(tokens);
+        let ast = match parser.parse() {
+            Ok(ast) => ast,
+            Err(e) => return Err(format!("Parse error: {}", e)),
+        };
+
+        if debug {
+            dump(writeln!(debug_out, "--- AST ---\n{:#?}", ast))?;
+        }
+
+        let mut compiler = Compiler::with_options(CompileOptions {
+            keep_last_value: echo,
+            deny_warnings,
+            ..options_for_file(filename)
+        });
+        let bytecode = match compiler.compile(&ast) {
+            Ok(bc) => bc,
+            // Name the line for `Diagnostic::from_error`, if the message does not
+            Err(e) => match e.line() {
+                Some(line) if !e.to_string().contains("line ") => {
+                    return Err(format!("Compile error: {} (line {})", e, line));
+                }
+                _ => return Err(format!("Compile error: {}", e)),
+            },
+        };
+        for warning in &compiler.warnings {
+            let warning = Diagnostic::from_warning(warning);
+            let warning = warning.format(source_code, filename, options.message_format);
+            writeln!(debug_out, "{}", warning)
+                .map_err(|err| format!("Error writing warnings: {}", err))?;
+        }
+
+        if debug {
+            dump(crate::debug::write_bytecode(debug_out, &bytecode))?;
+        }
+
+        let compiler_has_main = compiler.functions.contains_key("main");
+        let mut vm = VirtualMachine::new(bytecode, compiler);
+        if let Some(path) = &options.replay {
+            let text = std::fs::read_to_string(path)
+                .map_err(|err| format!("Error reading recording '{}': {}", path, err))?;
+            vm.replay(Recording::parse(&text)?);
+        }
+        if options.record.is_some() {
+            vm.record();
+        }
+        if options.coverage.is_some() {
+            vm.enable_coverage();
+        }
+
+        if debug {
+            dump(writeln!(debug_out, "--- Runtime ---"))?;
+        }
+
+        vm.set_output(std::mem::replace(&mut options.out, Box::new(io::sink())));
+        vm.set_debug_output(std::mem::replace(&mut options.err, Box::new(io::sink())));
+        let result = vm.run();
+        let stack = match debug {
+            true => dump(vm.debug_stack()),
+            false => Ok(()),
+        };
+        options.out = vm.take_output();
+        options.err = vm.take_debug_output();
+        stack?;
+        if let (Some(path), Some(recording)) = (&options.record, vm.take_recording()) {
+            std::fs::write(path, recording.to_text())
+                .map_err(|err| format!("Error writing recording '{}': {}", path, err))?;
+        }
+        if let (Some(path), Some(hits)) = (&options.coverage, vm.line_hits()) {
+            let mut coverage = Coverage::new();
+            coverage.add(filename, &hits);
+            let summary = write_coverage(path, &coverage)?;
+            writeln!(options.err, "{}", summary)
+                .map_err(|err| format!("Error writing coverage summary: {}", err))?;
+        }
+        match result {
+            Ok(result) => {
+                if vm.exit_code().is_some() {
+                    return Ok(vm.exit_code());
+                }
+                if compiler_has_main {
+                    return Ok(result.as_ref().and_then(exit_status));
+                }
+                // `null`, e.g. from `print`, has nothing to show
+                let shown = |value: &Value| vm.heap().load(value) != Ok(HeapObject::Null);
+                if let Some(value) = result.filter(|value| echo && shown(value)) {
+                    writeln!(options.out, "{}", vm.format_value(&value))
+                        .map_err(|err| format!("Error writing output: {}", err))?;
+                }
+                Ok(None)
+            }
+            Err(e) => Err(format!("Runtime error: {}", e)),
+        }
+    }
+}
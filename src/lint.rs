@@ -0,0 +1,448 @@
+//! Checks for code that compiles but is likely a mistake, run over the parse
+//! tree. Each rule has a severity that `LintConfig` can change; `n lint`
+//! prints the findings as JSON:
+//!
+//! ```text
+//! [{"rule":"unused-variable","severity":"warning","line":3,
+//!   "message":"'total' is never read"}]
+//! ```
+
+use crate::stdlib;
+use crate::stdlib::json;
+use crate::types::ast::{Expr, ExprId, Pattern, Program, Stmt, Visitor, walk_expr, walk_pattern};
+use crate::types::compiler::HeapObject;
+use crate::types::interner::Symbol;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The rule is off.
+    Allow,
+    Warning,
+    /// `n lint` fails when a finding has this severity.
+    Error,
+}
+
+impl Severity {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Severity::Allow => "allow",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+pub struct Rule {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub default: Severity,
+}
+
+pub const RULES: &[Rule] = &[
+    Rule {
+        name: "unused-variable",
+        description: "A local variable or pattern binding is never read",
+        default: Severity::Warning,
+    },
+    Rule {
+        name: "unused-import",
+        description: "A standard module is imported but none of its functions are called",
+        default: Severity::Warning,
+    },
+    Rule {
+        name: "shadowed-binding",
+        description: "A binding hides a variable of an enclosing scope",
+        default: Severity::Warning,
+    },
+    Rule {
+        name: "unreachable-match-arm",
+        description: "An earlier arm of the `match` accepts every value this one would",
+        default: Severity::Warning,
+    },
+    Rule {
+        name: "constant-condition",
+        description: "An `if` condition is made of literals only",
+        default: Severity::Warning,
+    },
+];
+
+/// Severities that differ from the rules' defaults.
+#[derive(Debug, Clone, Default)]
+pub struct LintConfig {
+    severities: HashMap<&'static str, Severity>,
+}
+
+impl LintConfig {
+    pub fn set(&mut self, rule: &str, severity: Severity) -> Result<(), String> {
+        let rule = RULES
+            .iter()
+            .find(|known| known.name == rule)
+            .ok_or_else(|| format!("Unknown lint rule '{}'", rule))?;
+        self.severities.insert(rule.name, severity);
+        Ok(())
+    }
+
+    pub fn severity(&self, rule: &str) -> Severity {
+        match self.severities.get(rule) {
+            Some(severity) => *severity,
+            None => RULES
+                .iter()
+                .find(|known| known.name == rule)
+                .map_or(Severity::Allow, |known| known.default),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub line: usize,
+    pub message: String,
+}
+
+/// Findings of the rules not set to `Allow`, by line.
+pub fn lint(program: &Program, config: &LintConfig) -> Vec<Finding> {
+    let mut linter = Linter {
+        config,
+        findings: Vec::new(),
+        scopes: vec![Vec::new()],
+        line: 0,
+        modules_used: HashSet::new(),
+    };
+    for stmt in &program.statements {
+        linter.visit_stmt(program, stmt);
+    }
+    for stmt in &program.statements {
+        if let Stmt::Import { module, line } = stmt
+            && stdlib::MODULES.contains(&&**module)
+            && !linter.modules_used.contains(module)
+        {
+            linter.line = *line;
+            linter.report(
+                "unused-import",
+                format!("Module '{}' is imported but not used", module),
+            );
+        }
+    }
+    let mut findings = linter.findings;
+    findings.sort_by_key(|finding| finding.line);
+    findings
+}
+
+pub fn to_json(findings: &[Finding]) -> String {
+    let findings = findings
+        .iter()
+        .map(|finding| HeapObject::Record {
+            type_name: String::new(),
+            fields: vec![
+                ("rule".to_string(), text(finding.rule)),
+                ("severity".to_string(), text(finding.severity.name())),
+                ("line".to_string(), HeapObject::Number(finding.line as f64)),
+                ("message".to_string(), text(&finding.message)),
+            ]
+            .into(),
+        })
+        .collect();
+    json::stringify(&HeapObject::Array(findings))
+}
+
+fn text(s: &str) -> HeapObject {
+    HeapObject::String(s.to_string())
+}
+
+struct Binding {
+    name: Symbol,
+    line: usize,
+    used: bool,
+}
+
+struct Linter<'a> {
+    config: &'a LintConfig,
+    findings: Vec<Finding>,
+    /// Bindings of each enclosing scope, the top level first.
+    scopes: Vec<Vec<Binding>>,
+    /// Line of the statement being visited.
+    line: usize,
+    /// Names read as `Name.member` that no variable binds.
+    modules_used: HashSet<Symbol>,
+}
+
+impl Linter<'_> {
+    fn report(&mut self, rule: &'static str, message: String) {
+        let severity = self.config.severity(rule);
+        if severity != Severity::Allow {
+            self.findings.push(Finding {
+                rule,
+                severity,
+                line: self.line,
+                message,
+            });
+        }
+    }
+
+    fn lookup(&mut self, name: &str) -> Option<&mut Binding> {
+        self.scopes
+            .iter_mut()
+            .rev()
+            .flat_map(|scope| scope.iter_mut().rev())
+            .find(|binding| &*binding.name == name)
+    }
+
+    /// Adds `name` to the innermost scope. Parameters count as used, since
+    /// a caller must pass them whether or not the body reads them.
+    fn declare(&mut self, name: &Symbol, used: bool) {
+        let shadowed = self.scopes[..self.scopes.len() - 1]
+            .iter()
+            .flatten()
+            .rfind(|binding| binding.name == *name)
+            .map(|binding| binding.line);
+        if let Some(line) = shadowed {
+            self.report(
+                "shadowed-binding",
+                format!("'{}' shadows the variable from line {}", name, line),
+            );
+        }
+        let line = self.line;
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.push(Binding {
+                name: name.clone(),
+                line,
+                used,
+            });
+        }
+    }
+
+    /// Runs `f` in a new scope, then reports its bindings nothing read.
+    /// Top-level variables are globals that other files may read, so only
+    /// nested scopes are checked.
+    fn scoped(&mut self, f: impl FnOnce(&mut Self)) {
+        self.scopes.push(Vec::new());
+        f(self);
+        let line = self.line;
+        for binding in self.scopes.pop().unwrap_or_default() {
+            if !binding.used && !binding.name.starts_with('_') {
+                self.line = binding.line;
+                self.report(
+                    "unused-variable",
+                    format!("'{}' is never read", binding.name),
+                );
+            }
+        }
+        self.line = line;
+    }
+
+    fn statements(&mut self, program: &Program, statements: &[Stmt]) {
+        let line = self.line;
+        self.scoped(|this| {
+            for stmt in statements {
+                this.visit_stmt(program, stmt);
+            }
+        });
+        self.line = line;
+    }
+
+    fn check_arms(&mut self, program: &Program, arms: &[(Pattern, ExprId)]) {
+        for (i, (pattern, _)) in arms.iter().enumerate() {
+            let covering = arms[..i]
+                .iter()
+                .position(|(earlier, _)| covers(program, earlier, pattern));
+            if let Some(earlier) = covering {
+                self.report(
+                    "unreachable-match-arm",
+                    format!(
+                        "Arm {} of the match is never reached: arm {} accepts all its values",
+                        i + 1,
+                        earlier + 1
+                    ),
+                );
+            }
+        }
+    }
+}
+
+impl Visitor for Linter<'_> {
+    fn visit_stmt(&mut self, program: &Program, stmt: &Stmt) {
+        self.line = stmt.line();
+        match stmt {
+            Stmt::Let { name, value, .. } | Stmt::Const { name, value, .. } => {
+                self.visit_expr(program, *value);
+                self.declare(name, false);
+            }
+            Stmt::LetPattern { pattern, value, .. } => {
+                self.visit_expr(program, *value);
+                self.visit_pattern(program, pattern);
+            }
+            Stmt::Func {
+                params,
+                defaults,
+                body,
+                ..
+            } => {
+                for default in defaults {
+                    self.visit_expr(program, *default);
+                }
+                self.scoped(|this| {
+                    for param in params {
+                        this.declare(param, true);
+                    }
+                    for stmt in body {
+                        this.visit_stmt(program, stmt);
+                    }
+                });
+            }
+            Stmt::For {
+                name,
+                iterable,
+                body,
+                ..
+            } => {
+                self.visit_expr(program, *iterable);
+                self.scoped(|this| {
+                    this.declare(name, false);
+                    for stmt in body {
+                        this.visit_stmt(program, stmt);
+                    }
+                });
+            }
+            Stmt::Impl {
+                methods, constants, ..
+            } => {
+                for stmt in methods.iter().chain(constants) {
+                    self.visit_stmt(program, stmt);
+                }
+            }
+            Stmt::Expr(value, _) => self.visit_expr(program, *value),
+            Stmt::Import { .. }
+            | Stmt::Extern { .. }
+            | Stmt::Struct { .. }
+            | Stmt::Trait { .. }
+            | Stmt::Enum { .. } => {}
+        }
+    }
+
+    fn visit_expr(&mut self, program: &Program, expr: ExprId) {
+        match program.expr(expr) {
+            Expr::Identifier(name) => {
+                if let Some(binding) = self.lookup(name) {
+                    binding.used = true;
+                }
+            }
+            Expr::Member { object, .. } => {
+                if let Expr::Identifier(name) = program.expr(*object)
+                    && self.lookup(name).is_none()
+                {
+                    self.modules_used.insert(name.clone());
+                }
+                walk_expr(self, program, expr);
+            }
+            Expr::Lambda { params, body } => self.scoped(|this| {
+                for param in params {
+                    this.declare(param, true);
+                }
+                this.visit_expr(program, *body);
+            }),
+            Expr::Block(statements) => self.statements(program, statements),
+            Expr::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                if is_constant(program, *condition) {
+                    self.report(
+                        "constant-condition",
+                        "The condition of this 'if' is a constant".to_string(),
+                    );
+                }
+                self.visit_expr(program, *condition);
+                self.statements(program, then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statements(program, else_branch);
+                }
+            }
+            Expr::Match { subject, arms } => {
+                self.visit_expr(program, *subject);
+                self.check_arms(program, arms);
+                for (pattern, value) in arms {
+                    self.scoped(|this| {
+                        this.visit_pattern(program, pattern);
+                        this.visit_expr(program, *value);
+                    });
+                }
+            }
+            _ => walk_expr(self, program, expr),
+        }
+    }
+
+    fn visit_pattern(&mut self, program: &Program, pattern: &Pattern) {
+        match pattern {
+            Pattern::Binding(name) => self.declare(name, false),
+            Pattern::List {
+                rest: Some(rest), ..
+            } => {
+                walk_pattern(self, program, pattern);
+                self.declare(rest, false);
+            }
+            Pattern::Record(fields) => {
+                for (field, pattern) in fields {
+                    match pattern {
+                        // `{ name }` binds the field's own name
+                        Pattern::Binding(name) if name == field => self.declare(name, false),
+                        pattern => self.visit_pattern(program, pattern),
+                    }
+                }
+            }
+            _ => walk_pattern(self, program, pattern),
+        }
+    }
+}
+
+/// Whether the literals and operators of `expr` alone decide its value.
+fn is_constant(program: &Program, expr: ExprId) -> bool {
+    match program.expr(expr) {
+        Expr::Number(_) | Expr::String(_) | Expr::Boolean(_) => true,
+        Expr::Unary { right, .. } => is_constant(program, *right),
+        Expr::Binary { left, right, .. } => {
+            is_constant(program, *left) && is_constant(program, *right)
+        }
+        _ => false,
+    }
+}
+
+/// Whether `earlier` accepts every value that `later` does.
+fn covers(program: &Program, earlier: &Pattern, later: &Pattern) -> bool {
+    match (earlier, later) {
+        (Pattern::Wildcard | Pattern::Binding(_), _) => true,
+        (Pattern::Literal(a), Pattern::Literal(b)) => match (program.expr(*a), program.expr(*b)) {
+            (Expr::Number(a), Expr::Number(b)) => a == b,
+            (Expr::String(a), Expr::String(b)) => a == b,
+            (Expr::Boolean(a), Expr::Boolean(b)) => a == b,
+            _ => false,
+        },
+        (
+            Pattern::Variant {
+                enum_name: a_enum,
+                variant: a_variant,
+                fields: a_fields,
+            },
+            Pattern::Variant {
+                enum_name: b_enum,
+                variant: b_variant,
+                fields: b_fields,
+            },
+        ) => {
+            a_enum == b_enum
+                && a_variant == b_variant
+                && a_fields.len() == b_fields.len()
+                && a_fields
+                    .iter()
+                    .zip(b_fields)
+                    .all(|(a, b)| covers(program, a, b))
+        }
+        (Pattern::Tuple(a), Pattern::Tuple(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| covers(program, a, b))
+        }
+        _ => false,
+    }
+}
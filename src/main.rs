@@ -1,126 +1,204 @@
-mod compiler;
-mod debug;
-mod interpreter;
-mod lexer;
-mod parser;
-mod types;
-
-#[cfg(test)]
-mod tests;
-
-pub mod runtime {
-    use crate::compiler::Compiler;
-    use crate::interpreter::VirtualMachine;
-    use crate::lexer::Lexer;
-    use crate::parser::Parser;
+use n::diagnostic::MessageFormat;
+use n::lint::{LintConfig, Severity};
+use n::repl::Repl;
+use n::runtime::{self, RunOptions};
+use n::stdlib;
+use std::env;
+use std::io::Read;
+use std::path::Path;
+use std::process;
 
-    pub fn compile_and_run(filename: &str) -> Result<String, String> {
-        compile_and_run_with_debug(filename, false)
+/// The terminal UI when both ends are a terminal, otherwise the command
+/// prompt, which also takes commands piped in.
+fn inspect_interactive(filename: &str) -> Result<String, String> {
+    #[cfg(feature = "tui")]
+    {
+        use std::io::IsTerminal;
+        if std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+            return runtime::inspect_file_tui(filename);
+        }
     }
+    runtime::inspect_file_interactive(filename, std::io::stdin().lock(), std::io::stdout())
+}
 
-    pub fn compile_and_run_with_debug(filename: &str, debug: bool) -> Result<String, String> {
-        // Check if file ends with .n extension
-        if !filename.ends_with(".n") {
-            return Err("Error: File must have .n extension".to_string());
-        }
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {}", program);
+    eprintln!(
+        "       {} [--debug] [--no-echo] [--deny-warnings] [--any-extension] [--message-format=json] [--record=FILE | --replay=FILE] [--coverage=FILE] <file.n> [args...]",
+        program
+    );
+    eprintln!(
+        "       {} [--no-echo] [--deny-warnings] [--message-format=json] -e <code> [args...]",
+        program
+    );
+    eprintln!(
+        "       {} [--no-echo] [--deny-warnings] [--message-format=json] - [args...]  (program read from stdin)",
+        program
+    );
+    eprintln!("       {} build <file.n> [out.nb]", program);
+    eprintln!("       {} build <project dir> [out.nb]", program);
+    eprintln!("       {} inspect [-i] <file.nb>", program);
+    eprintln!("       {} doc <file.n> [--html]", program);
+    eprintln!("       {} test [--coverage=FILE] [file.n | dir]", program);
+    eprintln!("       {} --emit=ast-json <file.n>", program);
+    eprintln!(
+        "       {} lint [--allow=RULE | --warn=RULE | --deny=RULE]... <file.n>",
+        program
+    );
+    process::exit(1);
+}
 
-        // Read the file
-        let source_code = match std::fs::read_to_string(filename) {
-            Ok(content) => content,
-            Err(err) => {
-                return Err(format!("Error reading file '{}': {}", filename, err));
+/// Runs a script after any `--debug`, `--no-echo`, `--deny-warnings`, `--any-extension`,
+/// `--message-format=human|json`, `--record=FILE`, `--replay=FILE` and `--coverage=FILE` flags
+/// in `args`. Unless `--no-echo` is given, the trailing expression's value is printed after
+/// the program's output, and `--debug` writes what each stage made of a file to stderr.
+/// `-e <code>` runs `code` and `-` runs stdin instead of a file, for shell pipelines.
+fn run_script(program: &str, args: &[String]) -> Result<String, String> {
+    let mut options = RunOptions {
+        echo: true,
+        ..RunOptions::default()
+    };
+    let mut rest = args;
+    while let Some((flag, tail)) = rest.split_first() {
+        match flag.split_once('=') {
+            Some(("--record", path)) => options.record = Some(path.to_string()),
+            Some(("--replay", path)) => options.replay = Some(path.to_string()),
+            Some(("--coverage", path)) => options.coverage = Some(path.to_string()),
+            Some(("--message-format", format)) => {
+                options.message_format = match format {
+                    "human" => MessageFormat::Human,
+                    "json" => MessageFormat::Json,
+                    _ => return Err(format!("Unknown message format '{}'", format)),
+                }
             }
-        };
-
-        if debug {
-            println!("--- Source Code ---\n{}", source_code);
+            _ => match flag.as_str() {
+                "--debug" => options.debug = true,
+                "--no-echo" => options.echo = false,
+                "--deny-warnings" => options.deny_warnings = true,
+                "--any-extension" => options.any_extension = true,
+                _ => break,
+            },
         }
-
-        let mut lexer = Lexer::new(source_code);
-        let tokens = lexer.tokenize();
-
-        if debug {
-            println!("--- Tokens ---");
-            for token in &tokens {
-                println!("{:?}", token);
-            }
+        rest = tail;
+    }
+    // Keep stderr to the JSON records
+    if options.message_format == MessageFormat::Json {
+        options.debug = false;
+    }
+    let source = match rest {
+        [flag, code, script_args @ ..] if flag == "-e" => {
+            Some((code.clone(), "<eval>", script_args))
         }
-
-        let mut parser = Parser::new(tokens);
-        let ast = match parser.parse() {
-            Ok(ast) => ast,
-            Err(e) => return Err(format!("Parse error: {}", e)),
-        };
-
-        if debug {
-            println!("--- AST ---");
-            // Assuming AST implements Debug
-            println!("{:#?}", ast);
+        [flag, script_args @ ..] if flag == "-" => {
+            let mut code = String::new();
+            std::io::stdin()
+                .read_to_string(&mut code)
+                .map_err(|err| format!("Error reading stdin: {}", err))?;
+            Some((code, "<stdin>", script_args))
         }
-
-        let mut compiler = Compiler::new();
-        let bytecode = match compiler.compile(&ast) {
-            Ok(bc) => bc,
-            Err(e) => return Err(format!("Compile error: {}", e)),
+        _ => None,
+    };
+    if let Some((code, name, script_args)) = source {
+        stdlib::os::set_args(script_args.to_vec());
+        options.debug = false;
+        return match runtime::run_source(&code, name, &mut options) {
+            Ok(Some(code)) if code != 0 => process::exit(code),
+            Ok(_) => Ok(String::new()),
+            Err(e) => Err(runtime::format_error(
+                &code,
+                name,
+                &e,
+                options.message_format,
+            )),
         };
+    }
+    let Some((filename, script_args)) = rest.split_first() else {
+        usage(program)
+    };
+    stdlib::os::set_args(script_args.to_vec());
+    match runtime::run_file(filename, &mut options) {
+        Ok(Some(code)) if code != 0 => process::exit(code),
+        Ok(_) => Ok(String::new()),
+        Err(e) => Err(runtime::format_file_error(
+            filename,
+            &e,
+            options.message_format,
+        )),
+    }
+}
 
-        if debug {
-            println!("--- Bytecode ---\n");
-            if bytecode.functions.len() > 0 {
-                println!("--- Functions ---");
-                for function in bytecode.functions.iter() {
-                    println!("{}", function);
-                }
-            }
-            if bytecode.constants.len() > 0 {
-                println!("--- Constants ---");
-                for constant in bytecode.constants.iter() {
-                    println!("{}", constant);
-                }
-            }
-            println!("--- Instructions ---");
-            for instruction in bytecode.instructions.iter() {
-                println!("{}", instruction);
-            }
-        }
-
-        let mut vm = VirtualMachine::new(bytecode, compiler);
-
-        if debug {
-            println!("--- Runtime ---");
-        }
-
-        match vm.run() {
-            Ok(()) => {
-                vm.debug_stack();
-                Ok("Successfully executed program".to_string())
-            }
-            Err(e) => {
-                vm.debug_stack();
-                Err(format!("Runtime error: {}", e))
-            }
+/// `n test [--coverage=FILE] [path]`.
+fn test(args: &[String]) -> Result<String, String> {
+    let (coverage, rest) = match args.split_first() {
+        Some((flag, rest)) if flag.starts_with("--coverage=") => {
+            (Some(&flag["--coverage=".len()..]), rest)
         }
+        _ => (None, args),
+    };
+    match rest {
+        [] => runtime::test_path(".", coverage),
+        [path] => runtime::test_path(path, coverage),
+        _ => Err("Usage: n test [--coverage=FILE] [file.n | dir]".to_string()),
     }
 }
 
-use std::env;
-use std::process;
+/// `n lint`: the options change the severity of a rule, see `lint::RULES`.
+fn lint(args: &[String]) -> Result<String, String> {
+    let (file, options) = args.split_last().ok_or("Missing file to lint")?;
+    let mut config = LintConfig::default();
+    for option in options {
+        let (severity, rule) = match option.split_once('=') {
+            Some(("--allow", rule)) => (Severity::Allow, rule),
+            Some(("--warn", rule)) => (Severity::Warning, rule),
+            Some(("--deny", rule)) => (Severity::Error, rule),
+            _ => return Err(format!("Unknown lint option '{}'", option)),
+        };
+        config.set(rule, severity)?;
+    }
+    runtime::lint_file(file, &config)
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        eprintln!("Usage: {} <file.n>", args[0]);
-        process::exit(1);
+    if args.len() == 1 {
+        let stdin = std::io::stdin();
+        if let Err(e) = Repl::new().run(stdin.lock(), std::io::stdout()) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
     }
 
-    let filename = &args[1];
-
-    match runtime::compile_and_run_with_debug(filename, true) {
-        Ok(result) => {
-            println!("=== EXECUTION ===");
-            println!("{}", result);
+    let result = match args.get(1).map(String::as_str) {
+        Some("build") if (args.len() == 3 || args.len() == 4) && Path::new(&args[2]).is_dir() => {
+            runtime::build_project(&args[2], args.get(3).map(String::as_str))
+                .map(|output| format!("Wrote {}", output))
+        }
+        Some("build") if args.len() == 3 || args.len() == 4 => {
+            let input = &args[2];
+            let output = args
+                .get(3)
+                .cloned()
+                .unwrap_or_else(|| format!("{}b", input));
+            runtime::build_file(input, &output).map(|()| format!("Wrote {}", output))
         }
+        Some("inspect") if args.len() == 3 => runtime::inspect_file(&args[2]),
+        Some("inspect") if args.len() == 4 && args[2] == "-i" => inspect_interactive(&args[3]),
+        Some("doc") if args.len() == 3 => runtime::doc_file(&args[2], false),
+        Some("doc") if args.len() == 4 && args[3] == "--html" => runtime::doc_file(&args[2], true),
+        Some("test") if args.len() <= 4 => test(&args[2..]),
+        Some("--emit=ast-json") if args.len() == 3 => runtime::ast_json_file(&args[2]),
+        Some("lint") if args.len() >= 3 => lint(&args[2..]),
+        Some("build" | "inspect" | "doc" | "test" | "lint" | "--emit=ast-json") | None => {
+            usage(&args[0])
+        }
+        Some(_) => run_script(&args[0], &args[1..]),
+    };
+
+    match result {
+        Ok(output) if output.is_empty() => {}
+        Ok(output) => println!("{}", output),
         Err(e) => {
             eprintln!("{}", e);
             process::exit(1);
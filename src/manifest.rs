@@ -0,0 +1,190 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// File name of a project manifest.
+pub const MANIFEST_FILE: &str = "n.toml";
+
+/// A project described by an `n.toml` file:
+///
+/// ```toml
+/// [package]
+/// name = "app"
+/// entry = "src/main.n"
+/// module_dirs = ["src", "lib"]
+///
+/// [dependencies]
+/// utils = { path = "../utils" }
+/// ```
+///
+/// Paths are relative to the directory holding the manifest. Only this subset
+/// of TOML is understood: sections, comments, and string, string array and
+/// `{ path = "..." }` values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub root: PathBuf,
+    pub name: String,
+    pub entry: PathBuf,
+    pub module_dirs: Vec<PathBuf>,
+    pub dependencies: Vec<Dependency>,
+}
+
+/// A local package whose module directories become importable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dependency {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TomlValue {
+    String(String),
+    Array(Vec<String>),
+    Table(Vec<(String, String)>),
+}
+
+impl Manifest {
+    /// Reads `n.toml` from the project directory `root`.
+    pub fn load(root: &Path) -> Result<Self, String> {
+        let path = root.join(MANIFEST_FILE);
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Error reading manifest '{}': {}", path.display(), e))?;
+        Self::parse(&text, root).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str, root: &Path) -> Result<Self, String> {
+        let mut name = None;
+        let mut entry = None;
+        let mut module_dirs = None;
+        let mut dependencies = Vec::new();
+        let mut section = String::new();
+
+        for (number, raw) in text.lines().enumerate() {
+            let line = strip_comment(raw).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let at = |e: String| format!("{} at line {}", e, number + 1);
+            if let Some(header) = line.strip_prefix('[') {
+                section = header
+                    .strip_suffix(']')
+                    .ok_or_else(|| at("Unclosed section header".to_string()))?
+                    .trim()
+                    .to_string();
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| at(format!("Expected 'key = value', got '{}'", line)))?;
+            let key = key.trim();
+            let value = parse_value(value.trim()).map_err(at)?;
+
+            match (section.as_str(), key, value) {
+                ("package", "name", TomlValue::String(s)) => name = Some(s),
+                ("package", "entry", TomlValue::String(s)) => entry = Some(root.join(s)),
+                ("package", "module_dirs", TomlValue::Array(dirs)) => {
+                    module_dirs = Some(dirs.iter().map(|dir| root.join(dir)).collect())
+                }
+                ("dependencies", dep, TomlValue::Table(fields)) => {
+                    let path = fields
+                        .into_iter()
+                        .find(|(field, _)| field == "path")
+                        .map(|(_, path)| root.join(path))
+                        .ok_or_else(|| at(format!("Dependency '{}' needs a path", dep)))?;
+                    dependencies.push(Dependency {
+                        name: dep.to_string(),
+                        path,
+                    });
+                }
+                (section, key, _) => {
+                    return Err(at(format!("Unexpected key '{}' in [{}]", key, section)));
+                }
+            }
+        }
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            name: name.ok_or("Missing 'name' in [package]")?,
+            entry: entry.ok_or("Missing 'entry' in [package]")?,
+            module_dirs: module_dirs.unwrap_or_else(|| vec![root.to_path_buf()]),
+            dependencies,
+        })
+    }
+
+    /// Module directories of this package followed by those of its
+    /// dependencies, transitively. Each package is visited once.
+    pub fn module_paths(&self) -> Result<Vec<PathBuf>, String> {
+        let mut paths = Vec::new();
+        let mut seen = HashSet::new();
+        self.collect_module_paths(&mut paths, &mut seen)?;
+        Ok(paths)
+    }
+
+    fn collect_module_paths(
+        &self,
+        paths: &mut Vec<PathBuf>,
+        seen: &mut HashSet<PathBuf>,
+    ) -> Result<(), String> {
+        let key = self
+            .root
+            .canonicalize()
+            .unwrap_or_else(|_| self.root.clone());
+        if !seen.insert(key) {
+            return Ok(());
+        }
+        paths.extend(self.module_dirs.iter().cloned());
+        for dependency in &self.dependencies {
+            Manifest::load(&dependency.path)
+                .map_err(|e| format!("In dependency '{}': {}", dependency.name, e))?
+                .collect_module_paths(paths, seen)?;
+        }
+        Ok(())
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(text: &str) -> Result<TomlValue, String> {
+    if let Some(inner) = text.strip_prefix('[') {
+        let inner = inner.strip_suffix(']').ok_or("Unclosed array")?;
+        return split_items(inner)
+            .map(parse_string)
+            .collect::<Result<_, _>>()
+            .map(TomlValue::Array);
+    }
+    if let Some(inner) = text.strip_prefix('{') {
+        let inner = inner.strip_suffix('}').ok_or("Unclosed inline table")?;
+        return split_items(inner)
+            .map(|item| {
+                let (key, value) = item
+                    .split_once('=')
+                    .ok_or_else(|| format!("Expected 'key = value', got '{}'", item))?;
+                Ok((key.trim().to_string(), parse_string(value.trim())?))
+            })
+            .collect::<Result<_, String>>()
+            .map(TomlValue::Table);
+    }
+    parse_string(text).map(TomlValue::String)
+}
+
+fn split_items(text: &str) -> impl Iterator<Item = &str> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+fn parse_string(text: &str) -> Result<String, String> {
+    text.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .map(str::to_string)
+        .ok_or_else(|| format!("Expected a quoted string, got '{}'", text))
+}
@@ -0,0 +1,99 @@
+/// An operation the VM runs itself because it calls back into script code or
+/// runs tasks, which natives cannot do. List and channel methods are called as `list.name(args)` or,
+/// when no function of that name is in scope, as `name(list, args)`; the
+/// others by their qualified name, e.g. `Task.join(task)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Method {
+    pub name: &'static str,
+    /// Arguments, counting the receiver of a list or channel method.
+    pub arity: usize,
+}
+
+/// Method table; `CALL_METHOD` operands index into it.
+pub const METHODS: &[Method] = &[
+    Method {
+        name: "map",
+        arity: 2,
+    },
+    Method {
+        name: "filter",
+        arity: 2,
+    },
+    Method {
+        name: "reduce",
+        arity: 3,
+    },
+    Method {
+        name: "length",
+        arity: 1,
+    },
+    Method {
+        name: "Task.spawn",
+        arity: 1,
+    },
+    Method {
+        name: "Task.join",
+        arity: 1,
+    },
+    Method {
+        name: "Task.all",
+        arity: 1,
+    },
+    Method {
+        name: "Channel.new",
+        arity: 0,
+    },
+    Method {
+        name: "send",
+        arity: 2,
+    },
+    Method {
+        name: "recv",
+        arity: 1,
+    },
+    Method {
+        name: "close",
+        arity: 1,
+    },
+    Method {
+        name: "Generator.new",
+        arity: 1,
+    },
+    Method {
+        name: "iter",
+        arity: 1,
+    },
+    Method {
+        name: "next",
+        arity: 1,
+    },
+    Method {
+        name: "done",
+        arity: 1,
+    },
+    Method {
+        name: "unfold",
+        arity: 2,
+    },
+];
+
+pub const MAP: usize = 0;
+pub const FILTER: usize = 1;
+pub const REDUCE: usize = 2;
+pub const LENGTH: usize = 3;
+pub const TASK_SPAWN: usize = 4;
+pub const TASK_JOIN: usize = 5;
+pub const TASK_ALL: usize = 6;
+pub const CHANNEL_NEW: usize = 7;
+pub const SEND: usize = 8;
+pub const RECV: usize = 9;
+pub const CLOSE: usize = 10;
+pub const GENERATOR_NEW: usize = 11;
+pub const ITER: usize = 12;
+pub const NEXT: usize = 13;
+pub const DONE: usize = 14;
+pub const UNFOLD: usize = 15;
+
+pub fn resolve(name: &str) -> Option<usize> {
+    METHODS.iter().position(|method| method.name == name)
+}
@@ -0,0 +1,114 @@
+//! A `Value` packed into 8 bytes by NaN-boxing, for the register file of
+//! the `registers` feature.
+//!
+//! A number is stored as its own bits. Every other value is stored as a
+//! negative quiet NaN, whose 51 spare bits hold a tag and a 48-bit payload:
+//!
+//! ```text
+//! 1 11111111111 1 ttt pppp...pppp
+//! ```
+//!
+//! Numbers that are NaN are stored as the one positive quiet NaN, so no
+//! number is mistaken for a tagged value. Booleans and handles (heap
+//! pointers, tasks, channels, generators) fit; strings and functions, which
+//! own their contents, do not, and stay `Value`s.
+//!
+//! Only the register file is packed this way. The stack VM still holds full
+//! `Value`s, strings and parameter lists included, so its stack is no smaller
+//! and its arithmetic no faster.
+
+use crate::types::compiler::Value;
+use std::fmt;
+
+const TAGGED: u64 = 0xFFF8_0000_0000_0000;
+const CANONICAL_NAN: u64 = 0x7FF8_0000_0000_0000;
+const TAG_SHIFT: u32 = 48;
+const TAG_MASK: u64 = 0x7 << TAG_SHIFT;
+const PAYLOAD_MASK: u64 = (1 << TAG_SHIFT) - 1;
+
+const TAG_BOOLEAN: u64 = 1;
+const TAG_HEAP_POINTER: u64 = 2;
+const TAG_TASK: u64 = 3;
+const TAG_CHANNEL: u64 = 4;
+const TAG_GENERATOR: u64 = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct NanBox(u64);
+
+impl NanBox {
+    pub fn number(n: f64) -> Self {
+        if n.is_nan() {
+            NanBox(CANONICAL_NAN)
+        } else {
+            NanBox(n.to_bits())
+        }
+    }
+
+    pub fn boolean(b: bool) -> Self {
+        Self::tagged(TAG_BOOLEAN, b as u64)
+    }
+
+    fn tagged(tag: u64, payload: u64) -> Self {
+        NanBox(TAGGED | (tag << TAG_SHIFT) | payload)
+    }
+
+    /// A handle as `tag`, if its index fits the payload.
+    fn handle(tag: u64, index: usize) -> Option<Self> {
+        let index = index as u64;
+        (index <= PAYLOAD_MASK).then(|| Self::tagged(tag, index))
+    }
+
+    fn tag(self) -> Option<u64> {
+        (self.0 & TAGGED == TAGGED).then_some((self.0 & TAG_MASK) >> TAG_SHIFT)
+    }
+
+    fn payload(self) -> u64 {
+        self.0 & PAYLOAD_MASK
+    }
+
+    pub fn as_number(self) -> Option<f64> {
+        match self.tag() {
+            None => Some(f64::from_bits(self.0)),
+            Some(_) => None,
+        }
+    }
+
+    pub fn as_boolean(self) -> Option<bool> {
+        match self.tag() {
+            Some(TAG_BOOLEAN) => Some(self.payload() != 0),
+            _ => None,
+        }
+    }
+
+    /// Packs `value`, or `None` for strings and functions.
+    pub fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(n) => Some(Self::number(*n)),
+            Value::Boolean(b) => Some(Self::boolean(*b)),
+            Value::HeapPointer(index) => Self::handle(TAG_HEAP_POINTER, *index),
+            Value::Task(index) => Self::handle(TAG_TASK, *index),
+            Value::Channel(index) => Self::handle(TAG_CHANNEL, *index),
+            Value::Generator(index) => Self::handle(TAG_GENERATOR, *index),
+            Value::String(_) | Value::Function { .. } | Value::Closure { .. } => None,
+        }
+    }
+
+    pub fn to_value(self) -> Value {
+        let index = self.payload() as usize;
+        match self.tag() {
+            None => Value::Number(f64::from_bits(self.0)),
+            Some(TAG_BOOLEAN) => Value::Boolean(index != 0),
+            Some(TAG_HEAP_POINTER) => Value::HeapPointer(index),
+            Some(TAG_TASK) => Value::Task(index),
+            Some(TAG_CHANNEL) => Value::Channel(index),
+            Some(TAG_GENERATOR) => Value::Generator(index),
+            Some(tag) => unreachable!("NaN box with tag {}", tag),
+        }
+    }
+}
+
+impl fmt::Debug for NanBox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.to_value())
+    }
+}
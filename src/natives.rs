@@ -0,0 +1,254 @@
+use crate::features;
+use crate::heap::Heap;
+use crate::interpreter::{CancellationToken, StopReason, timed_out};
+use crate::types::compiler::{HeapObject, Value};
+use crate::types::constants::CANCELLED_ERROR;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// What a native function can reach of the running VM.
+pub struct NativeContext<'a> {
+    pub heap: &'a mut Heap,
+    /// Where script output goes; stdout unless the host installed a sink.
+    pub output: &'a mut dyn Write,
+    /// Set by a native to stop the program with this exit code.
+    pub exit_code: &'a mut Option<i32>,
+    /// When the run started and how long it may take, if it has a
+    /// `wall_clock_timeout`.
+    pub deadline: Option<(Instant, Duration)>,
+    pub cancellation: &'a CancellationToken,
+    /// Set by `check_stop` when it stops the run.
+    pub stopped: &'a mut Option<StopReason>,
+}
+
+impl NativeContext<'_> {
+    /// Time left before the run's `wall_clock_timeout`, if it has one.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|(started, timeout)| timeout.saturating_sub(started.elapsed()))
+    }
+
+    /// Fails with the error the instruction loop stops the run with once it
+    /// is out of time or cancelled. Natives that wait, such as `Time.sleep`,
+    /// call it between short waits so they cannot outlast either.
+    pub fn check_stop(&mut self) -> Result<(), String> {
+        if self.cancellation.take() {
+            *self.stopped = Some(StopReason::Cancelled);
+            return Err(CANCELLED_ERROR.to_string());
+        }
+        if let Some((started, timeout)) = self.deadline
+            && started.elapsed() > timeout
+        {
+            *self.stopped = Some(StopReason::LimitExceeded);
+            return Err(timed_out(timeout));
+        }
+        Ok(())
+    }
+
+    /// Text of a value as a script would print it: strings unquoted, heap
+    /// values resolved.
+    pub fn display(&self, value: &Value) -> String {
+        match value {
+            Value::String(s) => s.clone(),
+            Value::HeapPointer(idx) => match self.heap.get(*idx) {
+                Some(HeapObject::String(s)) => s.clone(),
+                Some(object) => object.to_string(),
+                None => "null".to_string(),
+            },
+            _ => value.to_string(),
+        }
+    }
+
+    /// Text of a value with strings quoted, as in a list: `"a"`, `[1, 2]`.
+    pub fn describe(&self, value: &Value) -> String {
+        match self.heap.load(value) {
+            Ok(object) => object.to_string(),
+            Err(_) => value.to_string(),
+        }
+    }
+}
+
+pub type NativeFn = dyn Fn(&mut NativeContext, &[Value]) -> Result<Value, String> + Send + Sync;
+
+#[derive(Clone)]
+pub struct NativeFunction {
+    /// Fully qualified name as written in source, e.g. `Lang.version`.
+    pub name: String,
+    /// Exact number of arguments, or `None` for variadic natives.
+    pub arity: Option<usize>,
+    pub func: Arc<NativeFn>,
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "native {}", self.name)
+    }
+}
+
+/// Table of host functions callable from scripts. The compiler resolves names to
+/// indices and the VM dispatches `CALL_NATIVE` through the same table.
+#[derive(Debug, Clone, Default)]
+pub struct NativeRegistry {
+    functions: Vec<NativeFunction>,
+    index: HashMap<String, usize>,
+}
+
+impl NativeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registry pre-populated with the builtin modules.
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::new();
+        register_lang(&mut registry);
+        register_io(&mut registry);
+        register_assert(&mut registry);
+        registry
+    }
+
+    pub fn register<F>(&mut self, name: &str, arity: Option<usize>, func: F) -> usize
+    where
+        F: Fn(&mut NativeContext, &[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        let function = NativeFunction {
+            name: name.to_string(),
+            arity,
+            func: Arc::new(func),
+        };
+        // Re-registering a name replaces the previous host function
+        if let Some(index) = self.index.get(name) {
+            self.functions[*index] = function;
+            return *index;
+        }
+        self.functions.push(function);
+        let index = self.functions.len() - 1;
+        self.index.insert(name.to_string(), index);
+        index
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<usize> {
+        self.index.get(name).cloned()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&NativeFunction> {
+        self.functions.get(index)
+    }
+
+    pub fn functions(&self) -> &[NativeFunction] {
+        &self.functions
+    }
+}
+
+fn register_lang(registry: &mut NativeRegistry) {
+    registry.register("Lang.version", Some(0), |_, _| {
+        Ok(Value::String(features::VERSION.to_string()))
+    });
+    registry.register("Lang.has_feature", Some(1), |_, args| match &args[0] {
+        Value::String(name) => Ok(Value::Boolean(features::has_feature(name))),
+        other => Err(format!(
+            "Lang.has_feature expects a string, got {}",
+            other.type_name_stack()
+        )),
+    });
+}
+
+fn register_io(registry: &mut NativeRegistry) {
+    registry.register("IO.print", Some(1), print);
+    registry.register("print", Some(1), print);
+}
+
+/// `assert(cond)`, `assert(cond, message)`, `assert_eq(a, b)` and
+/// `assert_eq(a, b, message)`, failing the program with a runtime error.
+fn register_assert(registry: &mut NativeRegistry) {
+    registry.register("assert", None, |context, args| {
+        let (condition, message) = match args {
+            [condition] => (condition, None),
+            [condition, message] => (condition, Some(message)),
+            [_, _, _, _] | [_, _, _, _, _] => return assert_compare(context, args),
+            _ => {
+                return Err(format!(
+                    "assert expects 1 or 2 arguments, got {}",
+                    args.len()
+                ));
+            }
+        };
+        match condition {
+            Value::Boolean(true) => Ok(Value::Boolean(true)),
+            Value::Boolean(false) => Err(match message {
+                Some(message) => format!("Assertion failed: {}", context.display(message)),
+                None => "Assertion failed".to_string(),
+            }),
+            other => Err(format!(
+                "assert expects a boolean, got {}",
+                other.type_name(context.heap.objects())
+            )),
+        }
+    });
+    registry.register("assert_eq", None, |context, args| {
+        let (left, right, message) = match args {
+            [left, right] => (left, right, None),
+            [left, right, message] => (left, right, Some(message)),
+            _ => {
+                return Err(format!(
+                    "assert_eq expects 2 or 3 arguments, got {}",
+                    args.len()
+                ));
+            }
+        };
+        let equal = match (context.heap.load(left), context.heap.load(right)) {
+            (Ok(left), Ok(right)) => left == right,
+            _ => left == right,
+        };
+        if equal {
+            return Ok(Value::Boolean(true));
+        }
+        let mut error = format!(
+            "left {} is not equal to right {}",
+            context.describe(left),
+            context.describe(right)
+        );
+        if let Some(message) = message {
+            error.push_str(&format!(": {}", context.display(message)));
+        }
+        Err(error)
+    });
+}
+
+/// `assert(result, op, left, right)`, with an optional message last, which
+/// the compiler calls for `assert(left op right)` so a failure can show the
+/// operands.
+fn assert_compare(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let (result, op, left, right, message) = match args {
+        [result, op, left, right] => (result, op, left, right, None),
+        [result, op, left, right, message] => (result, op, left, right, Some(message)),
+        _ => unreachable!("called with 4 or 5 arguments"),
+    };
+    if *result == Value::Boolean(true) {
+        return Ok(Value::Boolean(true));
+    }
+    let comparison = format!(
+        "{} {} {}",
+        context.describe(left),
+        context.display(op),
+        context.describe(right)
+    );
+    Err(match message {
+        Some(message) => format!(
+            "Assertion failed: {} ({})",
+            context.display(message),
+            comparison
+        ),
+        None => format!("Assertion failed: {}", comparison),
+    })
+}
+
+/// Returns `null`, which runners never echo, so a printed value is not shown twice.
+fn print(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let text = context.display(&args[0]);
+    writeln!(context.output, "{}", text).map_err(|e| e.to_string())?;
+    Ok(context.heap.store(HeapObject::Null))
+}
@@ -1,37 +1,200 @@
+use crate::types::constants::MAX_NESTING_DEPTH;
+use crate::types::interner::Symbol;
 use crate::types::{ast::*, token::Token};
 
+/// Binding power of infix operators, lowest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    None,
+    Pipeline,
+    Or,
+    And,
+    Comparison,
+    Term,
+    Factor,
+    Call,
+}
+
+impl Precedence {
+    fn next(self) -> Self {
+        match self {
+            Precedence::None => Precedence::Pipeline,
+            Precedence::Pipeline => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor | Precedence::Call => Precedence::Call,
+        }
+    }
+}
+
+/// How a token behaves in infix position.
+#[derive(Debug, Clone, Copy)]
+struct InfixRule {
+    precedence: Precedence,
+    op: Option<BinaryOp>,
+}
+
+impl InfixRule {
+    const fn new(precedence: Precedence, op: Option<BinaryOp>) -> Self {
+        Self { precedence, op }
+    }
+}
+
+/// Static infix rule table. Tokens that cannot continue an expression get
+/// `Precedence::None`, which ends the expression loop.
+fn infix_rule(token: &Token) -> InfixRule {
+    use Precedence as P;
+    match token {
+        Token::Pipeline | Token::Update => InfixRule::new(P::Pipeline, None),
+        Token::Or => InfixRule::new(P::Or, Some(BinaryOp::Or)),
+        Token::And => InfixRule::new(P::And, Some(BinaryOp::And)),
+        Token::Equal => InfixRule::new(P::Comparison, Some(BinaryOp::Eq)),
+        Token::NotEqual => InfixRule::new(P::Comparison, Some(BinaryOp::Ne)),
+        Token::Less => InfixRule::new(P::Comparison, Some(BinaryOp::Lt)),
+        Token::Greater => InfixRule::new(P::Comparison, Some(BinaryOp::Gt)),
+        Token::LessEqual => InfixRule::new(P::Comparison, Some(BinaryOp::Le)),
+        Token::GreaterEqual => InfixRule::new(P::Comparison, Some(BinaryOp::Ge)),
+        Token::Plus => InfixRule::new(P::Term, Some(BinaryOp::Add)),
+        Token::PlusPlus => InfixRule::new(P::Term, Some(BinaryOp::Concat)),
+        Token::Minus => InfixRule::new(P::Term, Some(BinaryOp::Sub)),
+        Token::Multiply => InfixRule::new(P::Factor, Some(BinaryOp::Mul)),
+        Token::Divide => InfixRule::new(P::Factor, Some(BinaryOp::Div)),
+        Token::LeftParen | Token::Dot => InfixRule::new(P::Call, None),
+        _ => InfixRule::new(P::None, None),
+    }
+}
+
 pub struct Parser {
     tokens: Vec<Token>,
+    lines: Vec<usize>, // Source line of each token
     pos: usize,
+    exprs: Vec<Expr>,
+    in_condition: bool, // `{` may follow the expression, opening a block
+    doc: Vec<Symbol>,   // `///` lines read since the last declaration
+    doc_end: usize,     // Position after the last of them
+    module_doc: Vec<Symbol>,
+    nesting: usize, // Depth of the tree being built, see `MAX_NESTING_DEPTH`
+    item_lines: Vec<(usize, usize)>, // First and last line of each top-level statement
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, pos: 0 }
+        Self::continuing(tokens, 1, Vec::new())
+    }
+
+    /// Parses source that starts at `first_line` of a file, adding its
+    /// expressions after `exprs`, so statements of an already parsed program
+    /// can be parsed again in place. See `incremental::Document`.
+    pub fn continuing(tokens: Vec<Token>, first_line: usize, exprs: Vec<Expr>) -> Self {
+        let mut line = first_line;
+        let lines = tokens
+            .iter()
+            .map(|token| {
+                let current = line;
+                if matches!(token, Token::Newline) {
+                    line += 1;
+                }
+                current
+            })
+            .collect();
+        Self {
+            tokens,
+            lines,
+            pos: 0,
+            exprs,
+            in_condition: false,
+            doc: Vec::new(),
+            doc_end: 0,
+            module_doc: Vec::new(),
+            nesting: 0,
+            item_lines: Vec::new(),
+        }
     }
 
     pub fn parse(&mut self) -> Result<Program, String> {
+        if let Some((i, Token::Error(message))) = self
+            .tokens
+            .iter()
+            .enumerate()
+            .find(|(_, token)| matches!(token, Token::Error(_)))
+        {
+            return Err(format!("{} at line {}", message, self.lines[i]));
+        }
         let mut statements = Vec::new();
-        while !self.is_at_end() {
-            self.skip_newlines();
-            if !self.is_at_end() {
-                statements.push(self.statement()?);
+        loop {
+            let start = self.pos;
+            if self.is_at_end() {
+                break;
             }
+            // A statement starts at its doc comment, if it has one
+            let first = self.tokens[start..self.pos]
+                .iter()
+                .position(|token| matches!(token, Token::DocComment(_)))
+                .map_or(self.current_line(), |i| self.lines[start + i]);
+            statements.push(self.statement()?);
+            let last = self.lines[self.pos.saturating_sub(1)];
+            self.item_lines.push((first, last.max(first)));
         }
-        Ok(Program { statements })
+        Ok(Program {
+            statements,
+            exprs: std::mem::take(&mut self.exprs),
+            doc: (!self.module_doc.is_empty()).then(|| self.module_doc.join("\n")),
+        })
+    }
+
+    /// First and last line of each top-level statement `parse` read.
+    pub fn item_lines(&self) -> &[(usize, usize)] {
+        &self.item_lines
+    }
+
+    /// The expression arena, e.g. to get back the one given to `continuing`
+    /// after `parse` failed.
+    pub fn into_exprs(self) -> Vec<Expr> {
+        self.exprs
     }
 
     fn statement(&mut self) -> Result<Stmt, String> {
+        let outer = self.nesting;
+        let result = self.nest().and_then(|()| self.statement_body());
+        self.nesting = outer;
+        result
+    }
+
+    fn statement_body(&mut self) -> Result<Stmt, String> {
         let line = self.current_line();
         match self.current() {
             Token::Let | Token::LetBang => self.let_statement(line),
+            Token::Const => self.const_statement(line),
             Token::Func => self.func_statement(line),
-            _ => Ok(Stmt::Expr(self.expression(1)?, line)),
+            Token::Import => self.import_statement(line),
+            Token::For => self.for_statement(line),
+            Token::Struct => self.struct_statement(line),
+            Token::Impl => self.impl_statement(line),
+            Token::Trait => self.trait_statement(line),
+            Token::Extern => self.extern_statement(line),
+            Token::Enum => self.enum_statement(line),
+            Token::At => self.attributed_statement(line),
+            _ => Ok(Stmt::Expr(self.expression(Precedence::Pipeline)?, line)),
         }
     }
 
     fn let_statement(&mut self, line: usize) -> Result<Stmt, String> {
         self.advance();
+        if matches!(
+            self.current(),
+            Token::LeftParen | Token::LeftBracket | Token::LeftBrace
+        ) {
+            let pattern = self.pattern()?;
+            self.expect(Token::Assign)?;
+            let value = self.expression(Precedence::Pipeline)?;
+            return Ok(Stmt::LetPattern {
+                pattern,
+                value,
+                line,
+            });
+        }
         let name = match self.advance() {
             Token::Identifier(n) => n,
             _ => {
@@ -42,11 +205,372 @@ impl Parser {
             }
         };
         self.expect(Token::Assign)?;
-        let value = self.expression(1)?;
+        let value = self.expression(Precedence::Pipeline)?;
         Ok(Stmt::Let { name, value, line })
     }
 
+    fn const_statement(&mut self, line: usize) -> Result<Stmt, String> {
+        let doc = self.take_doc();
+        self.bump();
+        let name = match self.advance() {
+            Token::Identifier(n) => n,
+            _ => {
+                return Err(format!(
+                    "Expected name after 'const' at line {}",
+                    self.current_line()
+                ));
+            }
+        };
+        self.expect(Token::Assign)?;
+        let value = self.expression(Precedence::Pipeline)?;
+        Ok(Stmt::Const {
+            name,
+            value,
+            doc,
+            line,
+        })
+    }
+
+    fn import_statement(&mut self, line: usize) -> Result<Stmt, String> {
+        self.bump();
+        match self.advance() {
+            Token::String(module) => Ok(Stmt::Import { module, line }),
+            t => Err(format!(
+                "Expected module name string after 'import', found {:?} at line {}",
+                t,
+                self.current_line()
+            )),
+        }
+    }
+
+    fn struct_statement(&mut self, line: usize) -> Result<Stmt, String> {
+        let doc = self.take_doc();
+        self.bump();
+        let name = self.type_name("struct")?;
+        self.expect(Token::LeftBrace)?;
+        let mut fields = Vec::new();
+        loop {
+            self.skip_newlines();
+            match self.advance() {
+                Token::RightBrace => break,
+                Token::Identifier(field) => fields.push(field),
+                t => {
+                    return Err(format!(
+                        "Expected field name in struct '{}', found {:?} at line {}",
+                        name,
+                        t,
+                        self.current_line()
+                    ));
+                }
+            }
+            if matches!(self.current(), Token::Comma) {
+                self.bump();
+            }
+        }
+        Ok(Stmt::Struct {
+            name,
+            fields,
+            doc,
+            line,
+        })
+    }
+
+    fn impl_statement(&mut self, line: usize) -> Result<Stmt, String> {
+        self.bump();
+        let mut name = self.type_name("impl")?;
+        let mut trait_name = None;
+        if matches!(self.current(), Token::For) {
+            self.bump();
+            trait_name = Some(std::mem::replace(&mut name, self.type_name("for")?));
+        }
+        self.expect(Token::LeftBrace)?;
+        let mut methods = Vec::new();
+        let mut constants = Vec::new();
+        loop {
+            self.skip_newlines();
+            let line = self.current_line();
+            match self.current() {
+                Token::RightBrace => {
+                    self.bump();
+                    break;
+                }
+                Token::Func => {
+                    let mut method = self.func_statement(line)?;
+                    if let Stmt::Func { name: method, .. } = &mut method {
+                        *method = Symbol::from(format!("{}.{}", name, method).as_str());
+                    }
+                    methods.push(method);
+                }
+                Token::Let => {
+                    let doc = self.take_doc();
+                    let Stmt::Let {
+                        name: constant,
+                        value,
+                        ..
+                    } = self.let_statement(line)?
+                    else {
+                        unreachable!("let_statement parses a let");
+                    };
+                    constants.push(Stmt::Func {
+                        name: Symbol::from(format!("{}::{}", name, constant).as_str()),
+                        params: Vec::new(),
+                        defaults: Vec::new(),
+                        variadic: false,
+                        body: vec![Stmt::Expr(value, line)],
+                        doc,
+                        attributes: Vec::new(),
+                        line,
+                    });
+                }
+                t => {
+                    return Err(format!(
+                        "Expected 'func' or 'let' in impl '{}', found {:?} at line {}",
+                        name, t, line
+                    ));
+                }
+            }
+        }
+        Ok(Stmt::Impl {
+            name,
+            trait_name,
+            methods,
+            constants,
+            line,
+        })
+    }
+
+    fn trait_statement(&mut self, line: usize) -> Result<Stmt, String> {
+        self.bump();
+        let name = self.type_name("trait")?;
+        self.expect(Token::LeftBrace)?;
+        let mut methods = Vec::new();
+        loop {
+            self.skip_newlines();
+            if matches!(self.current(), Token::RightBrace) {
+                self.bump();
+                break;
+            }
+            self.expect(Token::Func)?;
+            let Token::Identifier(method) = self.advance() else {
+                return Err(format!(
+                    "Expected method name in trait '{}' at line {}",
+                    name,
+                    self.current_line()
+                ));
+            };
+            self.expect(Token::LeftParen)?;
+            let mut params = Vec::new();
+            while let Token::Identifier(param) = self.current().clone() {
+                self.bump();
+                params.push(param);
+                if matches!(self.current(), Token::Comma) {
+                    self.bump();
+                }
+            }
+            self.expect(Token::RightParen)?;
+            methods.push((method, params));
+        }
+        Ok(Stmt::Trait {
+            name,
+            methods,
+            line,
+        })
+    }
+
+    fn extern_statement(&mut self, line: usize) -> Result<Stmt, String> {
+        self.bump();
+        let Token::String(library) = self.advance() else {
+            return Err(format!(
+                "Expected library name string after 'extern' at line {}",
+                self.current_line()
+            ));
+        };
+        self.expect(Token::LeftBrace)?;
+        let mut functions = Vec::new();
+        loop {
+            self.skip_newlines();
+            if matches!(self.current(), Token::RightBrace) {
+                self.bump();
+                break;
+            }
+            self.expect(Token::Func)?;
+            let Token::Identifier(name) = self.advance() else {
+                return Err(format!(
+                    "Expected function name in extern \"{}\" at line {}",
+                    library,
+                    self.current_line()
+                ));
+            };
+            self.expect(Token::LeftParen)?;
+            let mut params = Vec::new();
+            while let Token::Identifier(param) = self.current().clone() {
+                self.bump();
+                let mut param_type = ForeignType::Number;
+                if matches!(self.current(), Token::Colon) {
+                    self.bump();
+                    param_type = self.foreign_type()?;
+                }
+                params.push((param, param_type));
+                if matches!(self.current(), Token::Comma) {
+                    self.bump();
+                }
+            }
+            self.expect(Token::RightParen)?;
+            let mut returns = ForeignType::Number;
+            if matches!(self.current(), Token::Arrow) {
+                self.bump();
+                returns = self.foreign_type()?;
+            }
+            functions.push(ForeignFunction {
+                name,
+                params,
+                returns,
+            });
+        }
+        Ok(Stmt::Extern {
+            library,
+            functions,
+            line,
+        })
+    }
+
+    /// `number`, `int`, `long` or `string` in an `extern` declaration.
+    fn foreign_type(&mut self) -> Result<ForeignType, String> {
+        let line = self.current_line();
+        match self.advance() {
+            Token::Identifier(name) => ForeignType::from_name(&name).ok_or_else(|| {
+                format!(
+                    "Unknown type '{}' at line {}, expected number, int, long or string",
+                    name, line
+                )
+            }),
+            t => Err(format!("Expected a type, found {:?} at line {}", t, line)),
+        }
+    }
+
+    fn enum_statement(&mut self, line: usize) -> Result<Stmt, String> {
+        let doc = self.take_doc();
+        self.bump();
+        let name = self.type_name("enum")?;
+        self.expect(Token::LeftBrace)?;
+        let mut variants = Vec::new();
+        loop {
+            self.skip_newlines();
+            let variant = match self.advance() {
+                Token::RightBrace => break,
+                Token::Identifier(variant) => variant,
+                t => {
+                    return Err(format!(
+                        "Expected variant name in enum '{}', found {:?} at line {}",
+                        name,
+                        t,
+                        self.current_line()
+                    ));
+                }
+            };
+            let mut fields = Vec::new();
+            if matches!(self.current(), Token::LeftParen) {
+                self.bump();
+                while let Token::Identifier(field) = self.current().clone() {
+                    self.bump();
+                    fields.push(field);
+                    if matches!(self.current(), Token::Comma) {
+                        self.bump();
+                    }
+                }
+                self.expect(Token::RightParen)?;
+            }
+            variants.push((variant, fields));
+            if matches!(self.current(), Token::Comma) {
+                self.bump();
+            }
+        }
+        Ok(Stmt::Enum {
+            name,
+            variants,
+            doc,
+            attributes: Vec::new(),
+            line,
+        })
+    }
+
+    fn type_name(&mut self, keyword: &str) -> Result<Symbol, String> {
+        match self.advance() {
+            Token::Identifier(name) => Ok(name),
+            _ => Err(format!(
+                "Expected type name after '{}' at line {}",
+                keyword,
+                self.current_line()
+            )),
+        }
+    }
+
+    /// Whether the `{` just read opens a record literal rather than a block:
+    /// it is empty, or its first entry is `name = value`.
+    fn starts_record(&self) -> bool {
+        let mut pos = self.pos;
+        while matches!(self.tokens.get(pos), Some(Token::Newline)) {
+            pos += 1;
+        }
+        match self.tokens.get(pos) {
+            Some(Token::RightBrace) => true,
+            Some(Token::Identifier(_)) => matches!(self.tokens.get(pos + 1), Some(Token::Assign)),
+            _ => false,
+        }
+    }
+
+    /// Parses the fields of a record literal, after its `{`.
+    fn record(&mut self, name: Option<Symbol>) -> Result<ExprId, String> {
+        let mut fields = Vec::new();
+        loop {
+            self.skip_newlines();
+            let field = match self.advance() {
+                Token::RightBrace => break,
+                Token::Identifier(field) => field,
+                t => {
+                    return Err(format!(
+                        "Expected field name in record, found {:?} at line {}",
+                        t,
+                        self.current_line()
+                    ));
+                }
+            };
+            self.expect(Token::Assign)?;
+            fields.push((field, self.expression(Precedence::Pipeline)?));
+            if matches!(self.current(), Token::Comma) {
+                self.bump();
+            }
+        }
+        Ok(self.alloc(Expr::Record { name, fields }))
+    }
+
+    fn for_statement(&mut self, line: usize) -> Result<Stmt, String> {
+        self.bump();
+        let name = match self.advance() {
+            Token::Identifier(n) => n,
+            _ => {
+                return Err(format!(
+                    "Expected loop variable after 'for' at line {}",
+                    self.current_line()
+                ));
+            }
+        };
+        self.expect(Token::In)?;
+        let in_condition = std::mem::replace(&mut self.in_condition, true);
+        let iterable = self.expression(Precedence::Pipeline);
+        self.in_condition = in_condition;
+        let iterable = iterable?;
+        let body = self.block()?;
+        Ok(Stmt::For {
+            name,
+            iterable,
+            body,
+            line,
+        })
+    }
+
     fn func_statement(&mut self, line: usize) -> Result<Stmt, String> {
+        let doc = self.take_doc();
         self.advance();
         let name = match self.advance() {
             Token::Identifier(n) => n,
@@ -59,63 +583,312 @@ impl Parser {
         };
         self.expect(Token::LeftParen)?;
         let mut params = Vec::new();
+        let mut defaults = Vec::new();
+        let mut variadic = false;
         while !matches!(self.current(), Token::RightParen) {
-            if let Token::Identifier(p) = self.advance() {
-                params.push(p);
+            if variadic {
+                return Err(format!(
+                    "The rest parameter must be the last parameter, at line {}",
+                    self.current_line()
+                ));
             }
+            if matches!(self.current(), Token::Spread) {
+                self.bump();
+                variadic = true;
+                if !matches!(self.current(), Token::Identifier(_)) {
+                    return Err(format!(
+                        "Expected parameter name after '...' at line {}",
+                        self.current_line()
+                    ));
+                }
+            }
+            let p = match self.advance() {
+                Token::Identifier(p) => p,
+                t => {
+                    return Err(format!(
+                        "Expected parameter name, found {:?} at line {}",
+                        t,
+                        self.current_line()
+                    ));
+                }
+            };
+            if matches!(self.current(), Token::Assign) && !variadic {
+                self.bump();
+                defaults.push(self.default_value(&p)?);
+            } else if !defaults.is_empty() && !variadic {
+                return Err(format!(
+                    "Parameter '{}' needs a default, as it follows one with a default, at line {}",
+                    p,
+                    self.current_line()
+                ));
+            }
+            params.push(p);
             if matches!(self.current(), Token::Comma) {
                 self.advance();
             }
         }
         self.expect(Token::RightParen)?;
-        self.expect(Token::LeftBrace)?;
-        let mut body = Vec::new();
-        while !matches!(self.current(), Token::RightBrace) {
-            self.skip_newlines();
-            if !matches!(self.current(), Token::RightBrace) {
-                body.push(self.statement()?);
-            }
-        }
-        self.expect(Token::RightBrace)?;
+        let body = self.block()?;
         Ok(Stmt::Func {
             name,
             params,
+            defaults,
+            variadic,
             body,
+            doc,
+            attributes: Vec::new(),
             line,
         })
     }
 
-    fn expression(&mut self, min_prec: u8) -> Result<Expr, String> {
+    /// `@name` and `@name("text")` lines, then the `func` or `enum` they
+    /// describe.
+    fn attributed_statement(&mut self, line: usize) -> Result<Stmt, String> {
+        let doc = self.take_doc();
+        let mut parsed = Vec::new();
+        while matches!(self.current(), Token::At) {
+            let line = self.current_line();
+            self.bump();
+            let Token::Identifier(name) = self.advance() else {
+                return Err(format!(
+                    "Expected attribute name after '@' at line {}",
+                    line
+                ));
+            };
+            if !ATTRIBUTES.contains(&&*name) {
+                return Err(format!("Unknown attribute '@{}' at line {}", name, line));
+            }
+            let mut argument = None;
+            if matches!(self.current(), Token::LeftParen) {
+                self.bump();
+                let Token::String(text) = self.advance() else {
+                    return Err(format!(
+                        "Expected a string in '@{}(...)' at line {}",
+                        name, line
+                    ));
+                };
+                argument = Some(text);
+                self.expect(Token::RightParen)?;
+            }
+            if &*name == "allow" {
+                match argument.as_deref() {
+                    Some(warning) if WARNINGS.contains(&warning) => {}
+                    Some(warning) => {
+                        return Err(format!(
+                            "Unknown warning '{}' in '@allow' at line {}",
+                            warning, line
+                        ));
+                    }
+                    None => {
+                        return Err(format!(
+                            "'@allow' needs the name of a warning at line {}",
+                            line
+                        ));
+                    }
+                }
+            }
+            parsed.push(Attribute { name, argument });
+            self.skip_newlines();
+        }
+        let mut stmt = match self.current() {
+            Token::Func => self.func_statement(line)?,
+            Token::Enum => self.enum_statement(line)?,
+            _ => {
+                return Err(format!(
+                    "Attributes can only be placed on 'func' and 'enum' declarations, at line {}",
+                    line
+                ));
+            }
+        };
+        match &mut stmt {
+            Stmt::Func {
+                name,
+                params,
+                doc: own_doc,
+                attributes,
+                ..
+            } => {
+                if find_attribute(&parsed, "test").is_some() && !params.is_empty() {
+                    return Err(format!(
+                        "Test function '{}' must not take parameters, at line {}",
+                        name, line
+                    ));
+                }
+                *attributes = parsed;
+                *own_doc = own_doc.take().or(doc);
+            }
+            Stmt::Enum {
+                name,
+                doc: own_doc,
+                attributes,
+                ..
+            } => {
+                if let Some(attribute) = parsed.iter().find(|a| &*a.name != "deprecated") {
+                    return Err(format!(
+                        "'@{}' does not apply to enum '{}', at line {}",
+                        attribute.name, name, line
+                    ));
+                }
+                *attributes = parsed;
+                *own_doc = own_doc.take().or(doc);
+            }
+            _ => unreachable!("only functions and enums are parsed here"),
+        }
+        Ok(stmt)
+    }
+
+    /// A parameter default: a number, string or boolean literal.
+    fn default_value(&mut self, param: &str) -> Result<ExprId, String> {
+        let value = self.expression(Precedence::Pipeline)?;
+        match &self.exprs[value.0] {
+            Expr::Number(_) | Expr::String(_) | Expr::Boolean(_) => Ok(value),
+            Expr::Unary {
+                op: UnaryOp::Neg,
+                right,
+            } if matches!(self.exprs[right.0], Expr::Number(_)) => Ok(value),
+            _ => Err(format!(
+                "Default value of '{}' must be a number, string or boolean literal at line {}",
+                param,
+                self.current_line()
+            )),
+        }
+    }
+
+    fn expression(&mut self, min_prec: Precedence) -> Result<ExprId, String> {
+        let outer = self.nesting;
+        let result = self.expression_chain(min_prec);
+        self.nesting = outer;
+        result
+    }
+
+    fn expression_chain(&mut self, min_prec: Precedence) -> Result<ExprId, String> {
+        self.nest()?;
         let mut left = self.nud()?;
-        while self.precedence(false)? >= min_prec {
-            left = self.led(left)?;
+        loop {
+            let rule = infix_rule(self.current());
+            if rule.precedence == Precedence::None {
+                self.check_hanging_literal()?;
+                break;
+            }
+            if rule.precedence < min_prec {
+                break;
+            }
+            // Each operator wraps the expression so far one level deeper
+            self.nest()?;
+            left = self.led(left, rule)?;
         }
         Ok(left)
     }
 
-    fn nud(&mut self) -> Result<Expr, String> {
+    /// Goes one level deeper into the tree, failing past `MAX_NESTING_DEPTH`
+    /// so that the parser and compiler do not run out of stack.
+    fn nest(&mut self) -> Result<(), String> {
+        self.nesting += 1;
+        if self.nesting > MAX_NESTING_DEPTH {
+            return Err(format!(
+                "Code is nested more than {} levels deep at line {}",
+                MAX_NESTING_DEPTH,
+                self.current_line()
+            ));
+        }
+        Ok(())
+    }
+
+    /// A literal directly after a complete expression (`1 2`) is always a mistake.
+    fn check_hanging_literal(&self) -> Result<(), String> {
+        match self.current() {
+            Token::LeftBrace if self.in_condition => Ok(()),
+            Token::String(_)
+            | Token::Number(_)
+            | Token::Identifier(_)
+            | Token::True
+            | Token::False
+            | Token::LeftBracket
+            | Token::LeftBrace => Err(format!(
+                "Invalid hanging literal: {:?} at line {}",
+                self.current(),
+                self.current_line()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn nud(&mut self) -> Result<ExprId, String> {
         match self.advance() {
-            Token::Identifier(s) => Ok(Expr::Identifier(s)),
-            Token::Number(n) => Ok(Expr::Number(n)),
-            Token::String(s) => Ok(Expr::String(s)),
+            Token::Identifier(type_name) if matches!(self.current(), Token::DoubleColon) => {
+                self.bump();
+                let member = self.type_name("::")?;
+                let mut args = None;
+                if matches!(self.current(), Token::LeftParen) {
+                    self.bump();
+                    let mut values = Vec::new();
+                    while !matches!(self.current(), Token::RightParen) {
+                        values.push(self.expression(Precedence::Pipeline)?);
+                        if matches!(self.current(), Token::Comma) {
+                            self.bump();
+                        }
+                    }
+                    self.expect(Token::RightParen)?;
+                    args = Some(values);
+                }
+                Ok(self.alloc(Expr::Path {
+                    type_name,
+                    member,
+                    args,
+                }))
+            }
+            // `{` after a condition opens its block instead
+            Token::Identifier(s)
+                if matches!(self.current(), Token::LeftBrace) && !self.in_condition =>
+            {
+                self.bump();
+                self.record(Some(s))
+            }
+            Token::Identifier(s) => Ok(self.alloc(Expr::Identifier(s))),
+            Token::LeftBrace if self.starts_record() => self.record(None),
+            Token::LeftBrace => {
+                let in_condition = std::mem::replace(&mut self.in_condition, false);
+                let statements = self.block_body();
+                self.in_condition = in_condition;
+                Ok(self.alloc(Expr::Block(statements?)))
+            }
+            Token::Number(n) => Ok(self.alloc(Expr::Number(n))),
+            Token::String(s) => Ok(self.alloc(Expr::String(s))),
+            // A comma makes a tuple of what would otherwise be grouping
             Token::LeftParen => {
-                let expr = self.expression(1)?;
+                if matches!(self.current(), Token::RightParen) {
+                    self.bump();
+                    return Ok(self.alloc(Expr::Tuple(Vec::new())));
+                }
+                let expr = self.expression(Precedence::Pipeline)?;
+                if !matches!(self.current(), Token::Comma) {
+                    self.expect(Token::RightParen)?;
+                    return Ok(expr);
+                }
+                let mut elements = vec![expr];
+                while matches!(self.current(), Token::Comma) {
+                    self.bump();
+                    if matches!(self.current(), Token::RightParen) {
+                        break;
+                    }
+                    elements.push(self.expression(Precedence::Pipeline)?);
+                }
                 self.expect(Token::RightParen)?;
-                Ok(expr)
+                Ok(self.alloc(Expr::Tuple(elements)))
             }
             Token::Minus => {
-                let right = self.expression(5)?;
-                Ok(Expr::Unary {
+                let right = self.expression(Precedence::Call)?;
+                Ok(self.alloc(Expr::Unary {
                     op: UnaryOp::Neg,
-                    right: Box::new(right),
-                })
+                    right,
+                }))
             }
             Token::Not => {
-                let right = self.expression(5)?;
-                Ok(Expr::Unary {
+                let right = self.expression(Precedence::Call)?;
+                Ok(self.alloc(Expr::Unary {
                     op: UnaryOp::Not,
-                    right: Box::new(right),
-                })
+                    right,
+                }))
             }
             Token::LeftBracket => {
                 let mut elements = Vec::new();
@@ -123,12 +896,12 @@ impl Parser {
                 // Handle empty array
                 if matches!(self.current(), Token::RightBracket) {
                     self.advance();
-                    return Ok(Expr::Array { elements });
+                    return Ok(self.alloc(Expr::Array { elements }));
                 }
 
                 // Parse array elements [expr, expr, ...]
                 loop {
-                    elements.push(self.expression(1)?);
+                    elements.push(self.expression(Precedence::Pipeline)?);
 
                     match self.current() {
                         Token::Comma => {
@@ -149,10 +922,21 @@ impl Parser {
                 }
 
                 self.expect(Token::RightBracket)?;
-                Ok(Expr::Array { elements })
+                Ok(self.alloc(Expr::Array { elements }))
+            }
+            Token::True => Ok(self.alloc(Expr::Boolean(true))),
+            Token::False => Ok(self.alloc(Expr::Boolean(false))),
+            Token::If => self.if_expression(),
+            Token::Match => self.match_expression(),
+            Token::Fn => self.lambda(),
+            Token::Spread => {
+                let list = self.expression(Precedence::Pipeline)?;
+                Ok(self.alloc(Expr::Spread(list)))
+            }
+            Token::Yield => {
+                let value = self.expression(Precedence::Pipeline)?;
+                Ok(self.alloc(Expr::Yield(value)))
             }
-            Token::True => Ok(Expr::Boolean(true)),
-            Token::False => Ok(Expr::Boolean(false)),
             t => Err(format!(
                 "Unexpected token in nud: {:?} at line {}",
                 t,
@@ -161,132 +945,319 @@ impl Parser {
         }
     }
 
-    fn led(&mut self, left: Expr) -> Result<Expr, String> {
+    fn led(&mut self, left: ExprId, rule: InfixRule) -> Result<ExprId, String> {
+        if let Some(op) = rule.op {
+            self.bump();
+            let right = self.expression(rule.precedence.next())?;
+            return Ok(self.alloc(Expr::Binary { left, op, right }));
+        }
+
         match self.current() {
-            Token::Plus
-            | Token::Minus
-            | Token::Multiply
-            | Token::Divide
-            | Token::Equal
-            | Token::NotEqual
-            | Token::Less
-            | Token::Greater
-            | Token::LessEqual
-            | Token::GreaterEqual => {
-                let op = self.binary_op()?;
-                self.advance();
-                let right = self.expression(self.precedence(true)? + 1)?;
-                Ok(Expr::Binary {
-                    left: Box::new(left),
-                    op,
-                    right: Box::new(right),
-                })
-            }
             Token::LeftParen => {
-                self.advance();
+                self.bump();
                 let mut args = Vec::new();
                 while !matches!(self.current(), Token::RightParen) {
-                    args.push(self.expression(1)?);
+                    let arg = match (self.current().clone(), self.tokens.get(self.pos + 1)) {
+                        (Token::Identifier(name), Some(Token::Assign)) => {
+                            self.bump();
+                            self.bump();
+                            let value = self.expression(Precedence::Pipeline)?;
+                            self.alloc(Expr::NamedArg { name, value })
+                        }
+                        _ => self.expression(Precedence::Pipeline)?,
+                    };
+                    args.push(arg);
                     if matches!(self.current(), Token::Comma) {
-                        self.advance();
+                        self.bump();
                     }
                 }
                 self.expect(Token::RightParen)?;
-                Ok(Expr::Call {
-                    func: Box::new(left),
-                    args,
-                })
+                Ok(self.alloc(Expr::Call { func: left, args }))
+            }
+            Token::Dot => {
+                self.bump();
+                match self.advance() {
+                    Token::Identifier(property) => Ok(self.alloc(Expr::Member {
+                        object: left,
+                        property,
+                    })),
+                    // `pair.0`; `pair.0.1` lexes its indices as one number
+                    Token::Number(n) if n.fract() == 0.0 && n >= 0.0 => {
+                        Ok(self.alloc(Expr::Member {
+                            object: left,
+                            property: Symbol::from(n.to_string().as_str()),
+                        }))
+                    }
+                    Token::Number(_) => Err(format!(
+                        "Write '(tuple.a).b' to read an element of a nested tuple, at line {}",
+                        self.current_line()
+                    )),
+                    t => Err(format!(
+                        "Expected property name after '.', found {:?} at line {}",
+                        t,
+                        self.current_line()
+                    )),
+                }
             }
             Token::Pipeline => {
-                self.advance();
-                let right = self.expression(self.precedence(true)? + 1)?;
-                Ok(Expr::Pipeline {
-                    left: Box::new(left),
-                    right: Box::new(right),
-                })
+                self.bump();
+                let right = self.expression(rule.precedence.next())?;
+                Ok(self.alloc(Expr::Pipeline { left, right }))
             }
             Token::Update => {
-                self.advance();
-                // Make update right-associative: parse RHS with same precedence
-                println!("{:?}", self.current());
-                let right = self.expression(self.precedence(true)?)?;
-
-                Ok(Expr::Update {
-                    left: Box::new(left),
-                    right: Box::new(right),
-                })
+                self.bump();
+                // Update is right-associative: parse the RHS at the same precedence
+                let right = self.expression(rule.precedence)?;
+                Ok(self.alloc(Expr::Update { left, right }))
             }
             _ => Ok(left),
         }
     }
 
-    fn binary_op(&self) -> Result<BinaryOp, String> {
+    /// Parses what follows `fn`: `(params) => body`.
+    fn lambda(&mut self) -> Result<ExprId, String> {
+        self.expect(Token::LeftParen)?;
+        let mut params = Vec::new();
+        while !matches!(self.current(), Token::RightParen) {
+            match self.advance() {
+                Token::Identifier(param) => params.push(param),
+                t => {
+                    return Err(format!(
+                        "Expected parameter name, found {:?} at line {}",
+                        t,
+                        self.current_line()
+                    ));
+                }
+            }
+            if matches!(self.current(), Token::Comma) {
+                self.bump();
+            }
+        }
+        self.expect(Token::RightParen)?;
         match self.current() {
-            Token::Plus => Ok(BinaryOp::Add),
-            Token::Minus => Ok(BinaryOp::Sub),
-            Token::Multiply => Ok(BinaryOp::Mul),
-            Token::Divide => Ok(BinaryOp::Div),
-            Token::Equal => Ok(BinaryOp::Eq),
-            Token::NotEqual => Ok(BinaryOp::Ne),
-            Token::Less => Ok(BinaryOp::Lt),
-            Token::Greater => Ok(BinaryOp::Gt),
-            Token::LessEqual => Ok(BinaryOp::Le),
-            Token::GreaterEqual => Ok(BinaryOp::Ge),
-            _ => Err(format!(
-                "Not a binary operator: {:?} at line {}",
-                self.current(),
-                self.current_line()
-            )),
+            Token::FatArrow | Token::Arrow => self.bump(),
+            t => {
+                return Err(format!(
+                    "Expected '=>' after lambda parameters, found {:?} at line {}",
+                    t,
+                    self.current_line()
+                ));
+            }
         }
+        let body = self.expression(Precedence::Pipeline)?;
+        Ok(self.alloc(Expr::Lambda { params, body }))
     }
 
-    fn precedence(&self, right_parse: bool) -> Result<u8, String> {
-        match self.current() {
-            Token::Pipeline | Token::Update => Ok(1),
-            Token::Equal
-            | Token::NotEqual
-            | Token::Less
-            | Token::Greater
-            | Token::LessEqual
-            | Token::GreaterEqual => Ok(2),
-            Token::Plus | Token::Minus => Ok(3),
-            Token::Multiply | Token::Divide => Ok(4),
-            Token::LeftParen => Ok(5),
-            Token::String(_)
-            | Token::Number(_)
-            | Token::Identifier(_)
-            | Token::True
-            | Token::False
-            | Token::LeftBracket
-            | Token::LeftBrace => {
-                if right_parse {
-                    return Ok(1);
-                } else {
-                    return Err(format!(
-                        "Invalid hanging literal: {:?} at line {}",
-                        self.current(),
-                        self.current_line()
-                    ));
+    /// `{ statement* }`
+    fn block(&mut self) -> Result<Vec<Stmt>, String> {
+        self.expect(Token::LeftBrace)?;
+        self.block_body()
+    }
+
+    /// The statements of a block and its closing `}`, after its `{`.
+    fn block_body(&mut self) -> Result<Vec<Stmt>, String> {
+        let mut statements = Vec::new();
+        while !matches!(self.current(), Token::RightBrace) {
+            self.skip_newlines();
+            if !matches!(self.current(), Token::RightBrace) {
+                statements.push(self.statement()?);
+            }
+        }
+        self.expect(Token::RightBrace)?;
+        Ok(statements)
+    }
+
+    /// Parses what follows `if`: condition, then-block and optional else part.
+    fn if_expression(&mut self) -> Result<ExprId, String> {
+        let in_condition = std::mem::replace(&mut self.in_condition, true);
+        let condition = self.expression(Precedence::Pipeline);
+        self.in_condition = in_condition;
+        let condition = condition?;
+        let then_branch = self.block()?;
+
+        // `else` may start the next line
+        let after_block = self.pos;
+        self.skip_newlines();
+        if !matches!(self.current(), Token::Else) {
+            self.pos = after_block;
+            return Ok(self.alloc(Expr::If {
+                condition,
+                then_branch,
+                else_branch: None,
+            }));
+        }
+        self.bump();
+        let else_branch = match self.current() {
+            Token::If => {
+                let line = self.current_line();
+                self.bump();
+                vec![Stmt::Expr(self.if_expression()?, line)]
+            }
+            _ => self.block()?,
+        };
+        Ok(self.alloc(Expr::If {
+            condition,
+            then_branch,
+            else_branch: Some(else_branch),
+        }))
+    }
+
+    /// Parses what follows `match`: the subject, then `pattern -> value` arms
+    /// separated by newlines or commas.
+    fn match_expression(&mut self) -> Result<ExprId, String> {
+        let in_condition = std::mem::replace(&mut self.in_condition, true);
+        let subject = self.expression(Precedence::Pipeline);
+        self.in_condition = in_condition;
+        let subject = subject?;
+        self.expect(Token::LeftBrace)?;
+        let mut arms = Vec::new();
+        loop {
+            self.skip_newlines();
+            if matches!(self.current(), Token::RightBrace) {
+                self.bump();
+                break;
+            }
+            let pattern = self.pattern()?;
+            self.expect(Token::Arrow)?;
+            arms.push((pattern, self.expression(Precedence::Pipeline)?));
+            if matches!(self.current(), Token::Comma) {
+                self.bump();
+            }
+        }
+        Ok(self.alloc(Expr::Match { subject, arms }))
+    }
+
+    fn pattern(&mut self) -> Result<Pattern, String> {
+        match self.advance() {
+            Token::Identifier(enum_name) if matches!(self.current(), Token::DoubleColon) => {
+                self.bump();
+                let variant = self.type_name("::")?;
+                let mut fields = Vec::new();
+                if matches!(self.current(), Token::LeftParen) {
+                    self.bump();
+                    while !matches!(self.current(), Token::RightParen) {
+                        fields.push(self.pattern()?);
+                        if matches!(self.current(), Token::Comma) {
+                            self.bump();
+                        }
+                    }
+                    self.expect(Token::RightParen)?;
+                }
+                Ok(Pattern::Variant {
+                    enum_name,
+                    variant,
+                    fields,
+                })
+            }
+            Token::LeftParen => {
+                let mut elements = Vec::new();
+                let mut tuple = false;
+                while !matches!(self.current(), Token::RightParen) {
+                    elements.push(self.pattern()?);
+                    if matches!(self.current(), Token::Comma) {
+                        self.bump();
+                        tuple = true;
+                    }
+                }
+                self.expect(Token::RightParen)?;
+                // Without a comma the parentheses only group
+                match (tuple, elements.len()) {
+                    (false, 1) => Ok(elements.remove(0)),
+                    _ => Ok(Pattern::Tuple(elements)),
+                }
+            }
+            Token::LeftBracket => {
+                let mut elements = Vec::new();
+                let mut rest = None;
+                while !matches!(self.current(), Token::RightBracket) {
+                    if rest.is_some() {
+                        return Err(format!(
+                            "The rest pattern must come last, at line {}",
+                            self.current_line()
+                        ));
+                    }
+                    if matches!(self.current(), Token::Spread) {
+                        self.bump();
+                        rest = Some(self.type_name("...")?);
+                    } else {
+                        elements.push(self.pattern()?);
+                    }
+                    if matches!(self.current(), Token::Comma) {
+                        self.bump();
+                    }
+                }
+                self.expect(Token::RightBracket)?;
+                Ok(Pattern::List { elements, rest })
+            }
+            Token::LeftBrace => {
+                let mut fields = Vec::new();
+                loop {
+                    self.skip_newlines();
+                    let field = match self.advance() {
+                        Token::RightBrace => break,
+                        Token::Identifier(field) => field,
+                        t => {
+                            return Err(format!(
+                                "Expected field name in pattern, found {:?} at line {}",
+                                t,
+                                self.current_line()
+                            ));
+                        }
+                    };
+                    let pattern = match self.current() {
+                        Token::Assign => {
+                            self.bump();
+                            self.pattern()?
+                        }
+                        _ => Pattern::Binding(field.clone()),
+                    };
+                    fields.push((field, pattern));
+                    if matches!(self.current(), Token::Comma) {
+                        self.bump();
+                    }
                 }
+                Ok(Pattern::Record(fields))
+            }
+            Token::Identifier(name) if &*name == "_" => Ok(Pattern::Wildcard),
+            Token::Identifier(name) => Ok(Pattern::Binding(name)),
+            Token::Number(n) => Ok(Pattern::Literal(self.alloc(Expr::Number(n)))),
+            Token::Minus if matches!(self.current(), Token::Number(_)) => {
+                let Token::Number(n) = self.advance() else {
+                    unreachable!("checked above");
+                };
+                Ok(Pattern::Literal(self.alloc(Expr::Number(-n))))
             }
-            _ => Ok(0),
+            Token::String(s) => Ok(Pattern::Literal(self.alloc(Expr::String(s)))),
+            Token::True => Ok(Pattern::Literal(self.alloc(Expr::Boolean(true)))),
+            Token::False => Ok(Pattern::Literal(self.alloc(Expr::Boolean(false)))),
+            t => Err(format!(
+                "Expected a pattern, found {:?} at line {}",
+                t,
+                self.current_line()
+            )),
         }
     }
 
-    fn current(&self) -> &Token {
-        self.tokens.get(self.pos).unwrap_or(&Token::Eof)
+    fn alloc(&mut self, expr: Expr) -> ExprId {
+        self.exprs.push(expr);
+        ExprId(self.exprs.len() - 1)
     }
 
-    fn peek(&self) -> Option<&Token> {
-        self.tokens.get(self.pos + 1)
+    fn current(&self) -> &Token {
+        self.tokens.get(self.pos).unwrap_or(&Token::Eof)
     }
 
     fn advance(&mut self) -> Token {
         let token = self.current().clone();
-        if self.pos < self.tokens.len() - 1 {
+        self.bump();
+        token
+    }
+
+    /// Moves past the current token without cloning it.
+    fn bump(&mut self) {
+        if self.pos + 1 < self.tokens.len() {
             self.pos += 1;
         }
-        token
     }
 
     fn expect(&mut self, expected: Token) -> Result<(), String> {
@@ -302,24 +1273,47 @@ impl Parser {
         Ok(())
     }
 
+    /// Skips statement separators: newlines and `;`. Doc comments between
+    /// them are kept for the declaration that follows, see `take_doc`.
     fn skip_newlines(&mut self) {
-        while matches!(self.current(), Token::Newline) {
+        loop {
+            match self.current().clone() {
+                Token::Newline | Token::Semicolon => {}
+                // Already read before the parser backtracked, e.g. after
+                // looking for an `else`
+                Token::DocComment(_) if self.pos < self.doc_end => {}
+                Token::DocComment(line) => {
+                    if !self.only_separators_since(self.doc_end) {
+                        self.doc.clear();
+                    }
+                    self.doc.push(line);
+                    self.doc_end = self.pos + 1;
+                }
+                Token::ModuleDoc(line) => self.module_doc.push(line),
+                _ => break,
+            }
             self.advance();
         }
     }
 
+    /// The `///` lines directly above the declaration at the current token.
+    fn take_doc(&mut self) -> Option<String> {
+        let lines = std::mem::take(&mut self.doc);
+        (!lines.is_empty() && self.only_separators_since(self.doc_end)).then(|| lines.join("\n"))
+    }
+
+    fn only_separators_since(&self, start: usize) -> bool {
+        self.tokens[start.min(self.pos)..self.pos]
+            .iter()
+            .all(|token| matches!(token, Token::Newline | Token::Semicolon))
+    }
+
     fn is_at_end(&mut self) -> bool {
         self.skip_newlines();
         matches!(self.current(), Token::Eof)
     }
 
     fn current_line(&self) -> usize {
-        let mut line = 1;
-        for t in self.tokens.iter().take(self.pos) {
-            if matches!(t, Token::Newline) {
-                line += 1;
-            }
-        }
-        line
+        self.lines.get(self.pos).copied().unwrap_or(1)
     }
 }
@@ -0,0 +1,302 @@
+//! A persistent vector backing lists and record fields on the heap.
+//!
+//! Elements sit in leaves of 32 under a tree of branches of 32, as in
+//! Clojure's vectors, with the last leaf kept aside as the tail. Nodes are
+//! shared through `Arc`s, so cloning a vector is O(1) and `push` and `set`
+//! copy only the O(log32 n) nodes on the way to their element, leaving the
+//! original as it was. That keeps functional updates such as
+//! `list <- [item]` and `record <- { field = value }` from copying the whole
+//! value.
+
+use std::fmt;
+use std::sync::Arc;
+
+const BITS: u32 = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+#[derive(Clone)]
+enum Node<T> {
+    Branch(Arc<Vec<Node<T>>>),
+    Leaf(Arc<Vec<T>>),
+}
+
+impl<T: Clone> Node<T> {
+    fn branch(&self) -> &[Node<T>] {
+        match self {
+            Node::Branch(children) => children,
+            Node::Leaf(_) => unreachable!("a leaf above the bottom level"),
+        }
+    }
+
+    fn branch_mut(&mut self) -> &mut Vec<Node<T>> {
+        match self {
+            Node::Branch(children) => Arc::make_mut(children),
+            Node::Leaf(_) => unreachable!("a leaf above the bottom level"),
+        }
+    }
+
+    fn leaf(&self) -> &[T] {
+        match self {
+            Node::Leaf(items) => items,
+            Node::Branch(_) => unreachable!("a branch at the bottom level"),
+        }
+    }
+
+    fn leaf_mut(&mut self) -> &mut Vec<T> {
+        match self {
+            Node::Leaf(items) => Arc::make_mut(items),
+            Node::Branch(_) => unreachable!("a branch at the bottom level"),
+        }
+    }
+
+    /// `leaf` under `level - BITS` levels of single-child branches.
+    fn path(level: u32, leaf: Node<T>) -> Node<T> {
+        match level {
+            0 => leaf,
+            _ => Node::Branch(Arc::new(vec![Node::path(level - BITS, leaf)])),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Vector<T> {
+    len: usize,
+    /// Bits of an index the root's children are chosen by.
+    shift: u32,
+    root: Node<T>,
+    tail: Arc<Vec<T>>,
+}
+
+impl<T: Clone> Vector<T> {
+    pub fn new() -> Self {
+        Vector {
+            len: 0,
+            shift: BITS,
+            root: Node::Branch(Arc::new(Vec::new())),
+            tail: Arc::new(Vec::new()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Index of the tail's first element.
+    fn tail_offset(&self) -> usize {
+        self.len - self.tail.len()
+    }
+
+    /// The leaf holding element `index`, which must be in the tree.
+    fn leaf(&self, index: usize) -> &[T] {
+        let mut node = &self.root;
+        let mut level = self.shift;
+        while level > 0 {
+            node = &node.branch()[(index >> level) & MASK];
+            level -= BITS;
+        }
+        node.leaf()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len {
+            None
+        } else if index >= self.tail_offset() {
+            self.tail.get(index - self.tail_offset())
+        } else {
+            Some(&self.leaf(index)[index & MASK])
+        }
+    }
+
+    pub fn first(&self) -> Option<&T> {
+        self.get(0)
+    }
+
+    pub fn last(&self) -> Option<&T> {
+        self.len.checked_sub(1).and_then(|index| self.get(index))
+    }
+
+    pub fn push(&mut self, item: T) {
+        if self.tail.len() < WIDTH {
+            Arc::make_mut(&mut self.tail).push(item);
+            self.len += 1;
+            return;
+        }
+        let leaf = Node::Leaf(std::mem::replace(&mut self.tail, Arc::new(vec![item])));
+        // The tree is full when its leaves fill every slot of the root
+        if ((self.len - WIDTH) >> BITS) >= (1 << self.shift) {
+            let root = std::mem::replace(&mut self.root, Node::Branch(Arc::new(Vec::new())));
+            let path = Node::path(self.shift, leaf);
+            self.root = Node::Branch(Arc::new(vec![root, path]));
+            self.shift += BITS;
+        } else {
+            Self::push_leaf(&mut self.root, self.shift, self.len - WIDTH, leaf);
+        }
+        self.len += 1;
+    }
+
+    /// Puts `leaf`, holding elements from `offset` on, under `node`.
+    fn push_leaf(node: &mut Node<T>, level: u32, offset: usize, leaf: Node<T>) {
+        let children = node.branch_mut();
+        let slot = (offset >> level) & MASK;
+        if level == BITS {
+            children.push(leaf);
+        } else if slot < children.len() {
+            Self::push_leaf(&mut children[slot], level - BITS, offset, leaf);
+        } else {
+            children.push(Node::path(level - BITS, leaf));
+        }
+    }
+
+    /// Replaces element `index`, returning false if there is none.
+    pub fn set(&mut self, index: usize, item: T) -> bool {
+        if index >= self.len {
+            return false;
+        }
+        if index >= self.tail_offset() {
+            let offset = self.tail_offset();
+            Arc::make_mut(&mut self.tail)[index - offset] = item;
+            return true;
+        }
+        let mut node = &mut self.root;
+        let mut level = self.shift;
+        while level > 0 {
+            node = &mut node.branch_mut()[(index >> level) & MASK];
+            level -= BITS;
+        }
+        node.leaf_mut()[index & MASK] = item;
+        true
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            vector: self,
+            index: 0,
+            chunk: &[],
+        }
+    }
+
+    /// The elements from `start` on.
+    pub fn skip(&self, start: usize) -> Self {
+        self.iter().skip(start).cloned().collect()
+    }
+
+    pub fn to_vec(&self) -> Vec<T> {
+        self.iter().cloned().collect()
+    }
+}
+
+impl<T: Clone> std::ops::Index<usize> for Vector<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        self.get(index)
+            .unwrap_or_else(|| panic!("index {} out of range for a vector of {}", index, self.len))
+    }
+}
+
+impl<T: Clone> Default for Vector<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Iter<'a, T> {
+    vector: &'a Vector<T>,
+    index: usize,
+    /// The rest of the current leaf.
+    chunk: &'a [T],
+}
+
+impl<'a, T: Clone> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        if self.chunk.is_empty() {
+            if self.index >= self.vector.len {
+                return None;
+            }
+            self.chunk = if self.index >= self.vector.tail_offset() {
+                &self.vector.tail[self.index - self.vector.tail_offset()..]
+            } else {
+                &self.vector.leaf(self.index)[self.index & MASK..]
+            };
+        }
+        let (item, rest) = self.chunk.split_first()?;
+        self.chunk = rest;
+        self.index += 1;
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.vector.len - self.index;
+        (left, Some(left))
+    }
+}
+
+impl<T: Clone> ExactSizeIterator for Iter<'_, T> {}
+
+impl<'a, T: Clone> IntoIterator for &'a Vector<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+/// Iterates over clones of the elements, which share the nodes of their
+/// containers.
+impl<T: Clone> IntoIterator for Vector<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.to_vec().into_iter()
+    }
+}
+
+impl<T: Clone> FromIterator<T> for Vector<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(items: I) -> Self {
+        let mut vector = Vector::new();
+        vector.extend(items);
+        vector
+    }
+}
+
+impl<T: Clone> Extend<T> for Vector<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, items: I) {
+        for item in items {
+            self.push(item);
+        }
+    }
+}
+
+impl<T: Clone> From<Vec<T>> for Vector<T> {
+    fn from(items: Vec<T>) -> Self {
+        items.into_iter().collect()
+    }
+}
+
+impl<T: Clone + PartialEq> PartialEq for Vector<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+/// Element by element, as for slices.
+impl<T: Clone + PartialOrd> PartialOrd for Vector<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        self.iter().partial_cmp(other.iter())
+    }
+}
+
+impl<T: Clone + fmt::Debug> fmt::Debug for Vector<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
@@ -0,0 +1,731 @@
+//! Register-based form of script functions, run by the VM in place of the
+//! stack code when the `registers` feature is on.
+//!
+//! A function is translated when its body only moves numbers and booleans
+//! between its variables: arithmetic, comparisons, `!`, `if`, `let` and
+//! direct calls of other translated functions. Each operand names a register
+//! or holds its constant, so
+//!
+//! ```text
+//! LOAD_VAR 1 0; LOAD_CONST 0; SUB; STORE_VAR 1 2
+//! ```
+//!
+//! becomes the single `r2 = r0 - 1`. Registers `0..locals` hold the
+//! function's variables; above them, register `locals + i` holds the `i`th
+//! value of the stack the instructions would have built. Values only move to
+//! those when a jump or call needs them in a known place. The register file
+//! holds `NanBox`es, eight bytes each, so moving a value is a copy.
+//!
+//! Translation never changes behaviour: anything the register code cannot do
+//! exactly like the stack code, such as adding strings or dividing by zero,
+//! makes the VM run the call again on the stack code, which then reports
+//! errors as usual.
+
+use crate::nanbox::NanBox;
+use crate::types::compiler::{Instruction, Value};
+use crate::types::constants::LIMIT_CHECK_INTERVAL;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Reg(usize),
+    Number(f64),
+    Boolean(bool),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Reg(r) => write!(f, "r{}", r),
+            Operand::Number(n) => write!(f, "{}", n),
+            Operand::Boolean(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Equal,
+    Less,
+    Greater,
+}
+
+impl BinaryOp {
+    fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Equal => "==",
+            BinaryOp::Less => "<",
+            BinaryOp::Greater => ">",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegOp {
+    Move {
+        dst: usize,
+        src: Operand,
+    },
+    Binary {
+        op: BinaryOp,
+        dst: usize,
+        a: Operand,
+        b: Operand,
+    },
+    Not {
+        dst: usize,
+        a: Operand,
+    },
+    Jump(usize),
+    /// Jumps when `cond` is `when`.
+    JumpIf {
+        cond: Operand,
+        when: bool,
+        target: usize,
+    },
+    /// Calls function `function` with the `count` arguments in registers
+    /// `args..args + count`, the last of them being the first argument, as
+    /// they were on the stack.
+    Call {
+        dst: usize,
+        function: usize,
+        args: usize,
+        count: usize,
+    },
+    Return(Operand),
+}
+
+impl fmt::Display for RegOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegOp::Move { dst, src } => write!(f, "r{} = {}", dst, src),
+            RegOp::Binary { op, dst, a, b } => write!(f, "r{} = {} {} {}", dst, a, op.symbol(), b),
+            RegOp::Not { dst, a } => write!(f, "r{} = !{}", dst, a),
+            RegOp::Jump(target) => write!(f, "jump {}", target),
+            RegOp::JumpIf { cond, when, target } => {
+                write!(f, "jump {} if {} is {}", target, cond, when)
+            }
+            RegOp::Call {
+                dst,
+                function,
+                args,
+                count,
+            } => write!(
+                f,
+                "r{} = call {} r{}..r{}",
+                dst,
+                function,
+                args,
+                args + count
+            ),
+            RegOp::Return(value) => write!(f, "return {}", value),
+        }
+    }
+}
+
+/// A translated function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegFunction {
+    pub arity: usize,
+    pub locals: usize,
+    /// Registers a call needs: the variables, then the deepest stack.
+    pub registers: usize,
+    pub code: Vec<RegOp>,
+}
+
+/// Where the translation of a function stands.
+#[derive(Debug, Clone, PartialEq)]
+enum Translation {
+    Pending,
+    /// Being translated, with its callees; a recursive call finds it so.
+    InProgress,
+    Done(Option<RegFunction>),
+}
+
+/// Register code of a program's functions, translated the first time each is
+/// called, so a program pays only for the functions it runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Translations {
+    /// Offset and arity of each function, `None` for ones that are not
+    /// plain functions starting with `LOAD_ARG`.
+    entries: Vec<Option<(usize, usize)>>,
+    arities: Vec<Option<usize>>,
+    functions: Vec<Translation>,
+}
+
+impl Translations {
+    pub fn new(instructions: &[Instruction], functions: &[Value]) -> Self {
+        let entries: Vec<Option<(usize, usize)>> = functions
+            .iter()
+            .map(|function| match function {
+                Value::Function { offset, .. } => match instructions.get(*offset) {
+                    Some(Instruction::LoadArg(count)) => Some((*offset, *count)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        Self {
+            arities: entries
+                .iter()
+                .map(|entry| entry.map(|(_, arity)| arity))
+                .collect(),
+            functions: vec![Translation::Pending; entries.len()],
+            entries,
+        }
+    }
+
+    /// The register code of function `index`, translating it and the
+    /// functions it calls if they have not been yet. `None` when its body,
+    /// or that of a function it calls, can only run on the stack code.
+    pub fn translate(
+        &mut self,
+        index: usize,
+        instructions: &[Instruction],
+        constants: &[Value],
+    ) -> Option<&RegFunction> {
+        if self.functions.get(index)? == &Translation::Pending {
+            self.functions[index] = Translation::InProgress;
+            let translated = self.entries[index].and_then(|(offset, arity)| {
+                Translator::new(instructions, constants, &self.arities, offset, arity).run()
+            });
+            let function = translated.filter(|(_, callees)| {
+                callees.iter().all(|&callee| {
+                    self.functions[callee] == Translation::InProgress
+                        || self.translate(callee, instructions, constants).is_some()
+                })
+            });
+            self.functions[index] = Translation::Done(function.map(|(function, _)| function));
+        }
+        self.get(index)
+    }
+
+    /// The register code of function `index`, if it has been translated.
+    /// A call of one that has not makes the caller fall back.
+    pub fn get(&self, index: usize) -> Option<&RegFunction> {
+        match self.functions.get(index)? {
+            Translation::Done(function) => function.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// What the translator knows on reaching an instruction: the stack depth
+/// and which variables have been assigned on every way there.
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    depth: usize,
+    assigned: Vec<bool>,
+}
+
+struct Translator<'a> {
+    instructions: &'a [Instruction],
+    constants: &'a [Value],
+    arities: &'a [Option<usize>],
+    offset: usize,
+    arity: usize,
+    locals: usize,
+}
+
+impl<'a> Translator<'a> {
+    fn new(
+        instructions: &'a [Instruction],
+        constants: &'a [Value],
+        arities: &'a [Option<usize>],
+        offset: usize,
+        arity: usize,
+    ) -> Self {
+        Self {
+            instructions,
+            constants,
+            arities,
+            offset,
+            arity,
+            locals: 0,
+        }
+    }
+
+    fn run(mut self) -> Option<(RegFunction, HashSet<usize>)> {
+        let (states, jump_targets) = self.analyze()?;
+        self.generate(&states, &jump_targets)
+    }
+
+    /// The constant `LOAD_CONST index` or `PUSH` puts on the stack, if it
+    /// is one registers can hold.
+    fn constant(&self, instruction: &Instruction) -> Option<Operand> {
+        let value = match instruction {
+            Instruction::LoadConst(index) => self.constants.get(*index)?,
+            Instruction::Push(value) => value,
+            _ => return None,
+        };
+        match value {
+            Value::Number(n) => Some(Operand::Number(*n)),
+            Value::Boolean(b) => Some(Operand::Boolean(*b)),
+            _ => None,
+        }
+    }
+
+    /// Finds the instructions the body reaches and the state at each, or
+    /// `None` if it does anything registers cannot, reads a variable that
+    /// may not be assigned yet or reaches a point with two stack depths.
+    fn analyze(&mut self) -> Option<(BTreeMap<usize, State>, HashSet<usize>)> {
+        self.locals = self.arity;
+        let mut states: BTreeMap<usize, State> = BTreeMap::new();
+        let mut jump_targets = HashSet::new();
+        let mut pending = vec![(
+            self.offset + 1,
+            State {
+                depth: 0,
+                assigned: vec![true; self.arity],
+            },
+        )];
+        while let Some((at, state)) = pending.pop() {
+            self.instructions.get(at)?;
+            let merged = match states.get(&at) {
+                None => state,
+                Some(known) if known.depth != state.depth => return None,
+                Some(known) => {
+                    let assigned: Vec<bool> = known
+                        .assigned
+                        .iter()
+                        .zip(&state.assigned)
+                        .map(|(a, b)| *a && *b)
+                        .collect();
+                    let assigned = trim(assigned);
+                    if assigned == known.assigned {
+                        continue;
+                    }
+                    State {
+                        depth: state.depth,
+                        assigned,
+                    }
+                }
+            };
+            states.insert(at, merged.clone());
+            let State {
+                depth,
+                mut assigned,
+            } = merged;
+            let instruction = &self.instructions[at];
+            let (pops, pushes) = match instruction {
+                Instruction::LoadVar(_, index) if assigned.get(*index) == Some(&true) => (0, 1),
+                Instruction::StoreVar(_, index) => {
+                    if *index >= assigned.len() {
+                        assigned.resize(index + 1, false);
+                    }
+                    assigned[*index] = true;
+                    self.locals = self.locals.max(index + 1);
+                    (1, 0)
+                }
+                Instruction::LoadConst(_) | Instruction::Push(_) => {
+                    self.constant(instruction)?;
+                    (0, 1)
+                }
+                Instruction::Add
+                | Instruction::Sub
+                | Instruction::Mul
+                | Instruction::Div
+                | Instruction::Equal
+                | Instruction::Less
+                | Instruction::Greater => (2, 1),
+                Instruction::Not => (1, 1),
+                Instruction::Pop => (1, 0),
+                Instruction::Dup => (1, 2),
+                Instruction::Call(function) => ((*self.arities.get(*function)?)?, 1),
+                Instruction::Jump(_) => (0, 0),
+                Instruction::JumpIfFalse(_) | Instruction::JumpIfTrue(_) => (1, 0),
+                Instruction::Return if depth == 1 => continue,
+                _ => return None,
+            };
+            let depth = depth.checked_sub(pops)? + pushes;
+            let next = State { depth, assigned };
+            match instruction {
+                Instruction::Jump(target) => {
+                    jump_targets.insert(*target);
+                    pending.push((*target, next));
+                }
+                Instruction::JumpIfFalse(target) | Instruction::JumpIfTrue(target) => {
+                    jump_targets.insert(*target);
+                    pending.push((*target, next.clone()));
+                    pending.push((at + 1, next));
+                }
+                _ => pending.push((at + 1, next)),
+            }
+        }
+        Some((states, jump_targets))
+    }
+
+    fn generate(
+        &self,
+        states: &BTreeMap<usize, State>,
+        jump_targets: &HashSet<usize>,
+    ) -> Option<(RegFunction, HashSet<usize>)> {
+        let mut code = Vec::new();
+        let mut callees = HashSet::new();
+        // Where each instruction's code starts, and jumps to patch with it
+        let mut starts: HashMap<usize, usize> = HashMap::new();
+        let mut jumps: Vec<(usize, usize)> = Vec::new();
+        let mut stack: Vec<Operand> = Vec::new();
+        let mut max_depth = 0;
+        let mut falls_through = false;
+        let temp = |slot: usize| self.locals + slot;
+
+        let mut previous = None;
+        for (&at, state) in states {
+            if previous != at.checked_sub(1) {
+                falls_through = false;
+            }
+            previous = Some(at);
+            let target = jump_targets.contains(&at);
+            if target && falls_through {
+                materialize(&mut code, &mut stack, temp, 0);
+            }
+            if target || !falls_through {
+                stack = (0..state.depth)
+                    .map(|slot| Operand::Reg(temp(slot)))
+                    .collect();
+            }
+            starts.insert(at, code.len());
+            falls_through = true;
+            let instruction = &self.instructions[at];
+            match instruction {
+                Instruction::LoadVar(_, index) => stack.push(Operand::Reg(*index)),
+                Instruction::LoadConst(_) | Instruction::Push(_) => {
+                    stack.push(self.constant(instruction)?);
+                }
+                Instruction::StoreVar(_, index) => {
+                    let value = stack.pop()?;
+                    // Values read from the variable before must keep the old value
+                    for (slot, operand) in stack.iter_mut().enumerate() {
+                        if *operand == Operand::Reg(*index) {
+                            code.push(RegOp::Move {
+                                dst: temp(slot),
+                                src: *operand,
+                            });
+                            *operand = Operand::Reg(temp(slot));
+                        }
+                    }
+                    // Write the result of the last operation straight to the variable
+                    let retarget = match (value, code.last_mut()) {
+                        (
+                            Operand::Reg(r),
+                            Some(
+                                RegOp::Move { dst, .. }
+                                | RegOp::Binary { dst, .. }
+                                | RegOp::Not { dst, .. }
+                                | RegOp::Call { dst, .. },
+                            ),
+                        ) if r == temp(stack.len()) && *dst == r && !target => {
+                            *dst = *index;
+                            true
+                        }
+                        _ => false,
+                    };
+                    if !retarget {
+                        code.push(RegOp::Move {
+                            dst: *index,
+                            src: value,
+                        });
+                    }
+                }
+                Instruction::Add
+                | Instruction::Sub
+                | Instruction::Mul
+                | Instruction::Div
+                | Instruction::Equal
+                | Instruction::Less
+                | Instruction::Greater => {
+                    let b = stack.pop()?;
+                    let a = stack.pop()?;
+                    let op = match instruction {
+                        Instruction::Add => BinaryOp::Add,
+                        Instruction::Sub => BinaryOp::Sub,
+                        Instruction::Mul => BinaryOp::Mul,
+                        Instruction::Div => BinaryOp::Div,
+                        Instruction::Equal => BinaryOp::Equal,
+                        Instruction::Less => BinaryOp::Less,
+                        _ => BinaryOp::Greater,
+                    };
+                    let dst = temp(stack.len());
+                    code.push(RegOp::Binary { op, dst, a, b });
+                    stack.push(Operand::Reg(dst));
+                }
+                Instruction::Not => {
+                    let a = stack.pop()?;
+                    let dst = temp(stack.len());
+                    code.push(RegOp::Not { dst, a });
+                    stack.push(Operand::Reg(dst));
+                }
+                Instruction::Pop => {
+                    stack.pop()?;
+                }
+                Instruction::Dup => stack.push(*stack.last()?),
+                Instruction::Call(function) => {
+                    let count = self.arities[*function]?;
+                    let args = stack.len() - count;
+                    materialize(&mut code, &mut stack, temp, args);
+                    stack.truncate(args);
+                    code.push(RegOp::Call {
+                        dst: temp(args),
+                        function: *function,
+                        args: temp(args),
+                        count,
+                    });
+                    callees.insert(*function);
+                    stack.push(Operand::Reg(temp(args)));
+                }
+                Instruction::Jump(to) => {
+                    materialize(&mut code, &mut stack, temp, 0);
+                    jumps.push((code.len(), *to));
+                    code.push(RegOp::Jump(0));
+                    falls_through = false;
+                }
+                Instruction::JumpIfFalse(to) | Instruction::JumpIfTrue(to) => {
+                    let cond = stack.pop()?;
+                    materialize(&mut code, &mut stack, temp, 0);
+                    jumps.push((code.len(), *to));
+                    code.push(RegOp::JumpIf {
+                        cond,
+                        when: matches!(instruction, Instruction::JumpIfTrue(_)),
+                        target: 0,
+                    });
+                }
+                Instruction::Return => {
+                    code.push(RegOp::Return(stack.pop()?));
+                    falls_through = false;
+                }
+                _ => return None,
+            }
+            max_depth = max_depth.max(stack.len()).max(state.depth);
+        }
+        for (at, to) in jumps {
+            let start = *starts.get(&to)?;
+            match &mut code[at] {
+                RegOp::Jump(target) | RegOp::JumpIf { target, .. } => *target = start,
+                _ => unreachable!("patching a jump"),
+            }
+        }
+        let function = RegFunction {
+            arity: self.arity,
+            locals: self.locals,
+            registers: self.locals + max_depth,
+            code,
+        };
+        Some((function, callees))
+    }
+}
+
+/// Drops trailing unassigned variables, so states reached by different
+/// ways compare equal when they agree on the assigned ones.
+fn trim(mut assigned: Vec<bool>) -> Vec<bool> {
+    while assigned.last() == Some(&false) {
+        assigned.pop();
+    }
+    assigned
+}
+
+/// Moves the values of stack slots `from..` that are not yet in their own
+/// register there, so code reached from elsewhere finds them in place.
+fn materialize(
+    code: &mut Vec<RegOp>,
+    stack: &mut [Operand],
+    temp: impl Fn(usize) -> usize,
+    from: usize,
+) {
+    for (slot, operand) in stack.iter_mut().enumerate().skip(from) {
+        if *operand != Operand::Reg(temp(slot)) {
+            code.push(RegOp::Move {
+                dst: temp(slot),
+                src: *operand,
+            });
+            *operand = Operand::Reg(temp(slot));
+        }
+    }
+}
+
+/// How far a register call may go before it is left to the stack code,
+/// which reports the limit it reaches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Budget {
+    pub instructions: u64,
+    /// Calls, counting the first.
+    pub calls: usize,
+    /// Values the stack code could still hold at the deepest call.
+    pub values: usize,
+}
+
+/// A call waiting for the one it made to return.
+struct Frame {
+    function: usize,
+    pc: usize,
+    base: usize,
+    /// Values the stack code would have held for the caller.
+    held: usize,
+}
+
+/// Runs function `entry` with `args`, ordered as on the stack, on the
+/// register file `registers`. Returns the result and how many register ops
+/// ran, or `None` when the call needs the stack code: an argument that is a
+/// string or a function, an operation on other values than numbers and
+/// booleans, a division by zero, the budget running out or `interrupted`
+/// returning true, which is asked now and then.
+///
+/// Each call, the first included, is first offered to `native`, with its
+/// callee, arguments in order and what is left of the budget, which may run
+/// it elsewhere and return its result and steps instead.
+pub fn run(
+    functions: &Translations,
+    entry: usize,
+    args: &[Value],
+    registers: &mut Vec<NanBox>,
+    budget: Budget,
+    interrupted: impl Fn() -> bool,
+    mut native: impl FnMut(usize, &[NanBox], Budget) -> Option<(NanBox, u64)>,
+) -> Option<(Value, u64)> {
+    let mut index = entry;
+    let mut function = functions.get(entry)?;
+    if budget.calls == 0 || function.registers - function.locals > budget.values {
+        return None;
+    }
+    registers.clear();
+    registers.resize(function.registers, NanBox::number(0.0));
+    for (param, arg) in args.iter().rev().enumerate() {
+        registers[param] = NanBox::from_value(arg)?;
+    }
+    if let Some((value, steps)) = native(entry, &registers[..function.arity], budget) {
+        return Some((value.to_value(), steps));
+    }
+    let mut frames: Vec<Frame> = Vec::new();
+    let mut base = 0;
+    let mut held = 0;
+    let mut pc = 0;
+    let mut steps: u64 = 0;
+    loop {
+        steps += 1;
+        if steps > budget.instructions
+            || (steps.is_multiple_of(LIMIT_CHECK_INTERVAL) && interrupted())
+        {
+            return None;
+        }
+        let load = |registers: &[NanBox], operand: Operand| match operand {
+            Operand::Reg(r) => registers[base + r],
+            Operand::Number(n) => NanBox::number(n),
+            Operand::Boolean(b) => NanBox::boolean(b),
+        };
+        match function.code[pc] {
+            RegOp::Move { dst, src } => registers[base + dst] = load(registers, src),
+            RegOp::Binary { op, dst, a, b } => {
+                registers[base + dst] = binary(op, load(registers, a), load(registers, b))?;
+            }
+            RegOp::Not { dst, a } => {
+                let b = load(registers, a).as_boolean()?;
+                registers[base + dst] = NanBox::boolean(!b);
+            }
+            RegOp::Jump(target) => {
+                pc = target;
+                continue;
+            }
+            RegOp::JumpIf { cond, when, target } => {
+                if load(registers, cond).as_boolean()? == when {
+                    pc = target;
+                    continue;
+                }
+            }
+            RegOp::Call {
+                dst,
+                function: callee,
+                args,
+                count,
+            } => {
+                let next = functions.get(callee)?;
+                let next_held = held + args - function.locals;
+                if frames.len() + 1 >= budget.calls
+                    || next_held + next.registers - next.locals > budget.values
+                {
+                    return None;
+                }
+                let next_base = base + function.registers;
+                registers.resize(next_base + next.registers, NanBox::number(0.0));
+                for param in 0..count {
+                    registers[next_base + param] = registers[base + args + count - 1 - param];
+                }
+                let left = Budget {
+                    instructions: budget.instructions - steps,
+                    calls: budget.calls - frames.len() - 1,
+                    values: budget.values - next_held,
+                };
+                if let Some((value, taken)) =
+                    native(callee, &registers[next_base..next_base + count], left)
+                {
+                    registers.truncate(next_base);
+                    registers[base + dst] = value;
+                    steps += taken;
+                    pc += 1;
+                    continue;
+                }
+                frames.push(Frame {
+                    function: index,
+                    pc,
+                    base,
+                    held,
+                });
+                index = callee;
+                function = next;
+                base = next_base;
+                held = next_held;
+                pc = 0;
+                continue;
+            }
+            RegOp::Return(value) => {
+                let value = load(registers, value);
+                let Some(frame) = frames.pop() else {
+                    return Some((value.to_value(), steps));
+                };
+                registers.truncate(base);
+                index = frame.function;
+                function = functions.get(index)?;
+                base = frame.base;
+                held = frame.held;
+                pc = frame.pc;
+                let RegOp::Call { dst, .. } = function.code[pc] else {
+                    unreachable!("returning to a call");
+                };
+                registers[base + dst] = value;
+            }
+        }
+        pc += 1;
+    }
+}
+
+/// `a op b` as the stack code computes it, or `None` where it would fail or
+/// needs the heap to tell.
+fn binary(op: BinaryOp, a: NanBox, b: NanBox) -> Option<NanBox> {
+    if let (Some(a), Some(b)) = (a.as_number(), b.as_number()) {
+        return match op {
+            BinaryOp::Add => Some(NanBox::number(a + b)),
+            BinaryOp::Sub => Some(NanBox::number(a - b)),
+            BinaryOp::Mul => Some(NanBox::number(a * b)),
+            BinaryOp::Div if b != 0.0 => Some(NanBox::number(a / b)),
+            BinaryOp::Div => None,
+            BinaryOp::Less => Some(NanBox::boolean(a < b)),
+            BinaryOp::Greater => Some(NanBox::boolean(a > b)),
+            BinaryOp::Equal => Some(NanBox::boolean(a == b)),
+        };
+    }
+    match (op, a.as_boolean(), b.as_boolean()) {
+        (BinaryOp::Equal, Some(a), Some(b)) => Some(NanBox::boolean(a == b)),
+        // A number never equals a boolean
+        (BinaryOp::Equal, Some(_), None) if b.as_number().is_some() => Some(NanBox::boolean(false)),
+        (BinaryOp::Equal, None, Some(_)) if a.as_number().is_some() => Some(NanBox::boolean(false)),
+        _ => None,
+    }
+}
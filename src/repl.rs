@@ -0,0 +1,55 @@
+use crate::engine::{Engine, Error};
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::types::compiler::HeapObject;
+use std::io::{self, BufRead, Write};
+
+/// Line-oriented front end over an `Engine`: each input sees the definitions
+/// of the previous ones, and errors are reported without ending the session.
+#[derive(Default)]
+pub struct Repl {
+    engine: Engine,
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs one input. Returns the rendered value of a trailing expression,
+    /// unless it is `null` (what `print` returns, having shown its argument
+    /// already), or the error message; in both cases the session can keep
+    /// going.
+    pub fn eval(&mut self, source: &str) -> Result<Option<String>, String> {
+        let tokens = Lexer::new(source).tokenize();
+        let program = Parser::new(tokens)
+            .parse()
+            .map_err(|e| Error::Parse(e).to_string())?;
+        let value = self
+            .engine
+            .eval_program(&program)
+            .map_err(|e| e.to_string())?;
+        Ok(value
+            .filter(|value| *value != HeapObject::Null)
+            .map(|value| self.engine.display(&value)))
+    }
+
+    /// Reads lines from `input` until EOF, printing results and errors.
+    pub fn run(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        write!(output, "> ")?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                match self.eval(&line) {
+                    Ok(Some(value)) => writeln!(output, "{}", value)?,
+                    Ok(None) => {}
+                    Err(e) => writeln!(output, "{}", e)?,
+                }
+            }
+            write!(output, "> ")?;
+            output.flush()?;
+        }
+        writeln!(output)
+    }
+}
@@ -0,0 +1,96 @@
+//! Record and replay of what makes a run differ from the last: the results
+//! of natives that read the clock, the environment, files, processes or the
+//! network. A recording taken where a script failed, fed back with
+//! `VirtualMachine::replay`, makes it run the same way again anywhere.
+//!
+//! A recording is text, one JSON object per native call in call order:
+//!
+//! ```text
+//! {"native":"Time.now","ok":1760000000000}
+//! {"native":"FS.read_file","error":"'a.txt': No such file or directory"}
+//! ```
+
+use crate::stdlib::json;
+use crate::types::compiler::HeapObject;
+use std::collections::{HashMap, VecDeque};
+
+/// Natives whose results are recorded. Replaying returns the recorded result
+/// instead of calling them; every other native runs as usual.
+pub const NONDETERMINISTIC: &[&str] = &[
+    "OS.args",
+    "OS.env",
+    "OS.exec",
+    "FS.read_file",
+    "FS.exists",
+    "FS.list_dir",
+    "Http.get",
+    "Http.post",
+    "Time.now",
+    "Time.elapsed",
+];
+
+/// One recorded native call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub native: String,
+    pub result: Result<HeapObject, String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    pub events: VecDeque<Event>,
+}
+
+impl Recording {
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for event in &self.events {
+            let (key, value) = match &event.result {
+                Ok(object) => ("ok", object.clone()),
+                Err(e) => ("error", HeapObject::String(e.clone())),
+            };
+            let line = HeapObject::Object(HashMap::from([
+                (
+                    "native".to_string(),
+                    HeapObject::String(event.native.clone()),
+                ),
+                (key.to_string(), value),
+            ]));
+            out.push_str(&json::stringify(&line));
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut events = VecDeque::new();
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = || format!("Invalid recording at line {}", number + 1);
+            let HeapObject::Object(mut fields) = json::parse(line).map_err(|_| invalid())? else {
+                return Err(invalid());
+            };
+            let Some(HeapObject::String(native)) = fields.remove("native") else {
+                return Err(invalid());
+            };
+            let result = match (fields.remove("ok"), fields.remove("error")) {
+                (Some(object), None) => Ok(object),
+                (None, Some(HeapObject::String(e))) => Err(e),
+                _ => return Err(invalid()),
+            };
+            events.push_back(Event { native, result });
+        }
+        Ok(Recording { events })
+    }
+}
+
+/// Whether a VM records natives, replays them or neither.
+#[derive(Debug, Clone, Default)]
+pub enum ReplayMode {
+    #[default]
+    Off,
+    Record(Recording),
+    Replay(Recording),
+}
@@ -0,0 +1,72 @@
+//! `FS` module: file system access. Failures are runtime errors that name the
+//! path and the OS reason.
+
+use crate::natives::{NativeContext, NativeRegistry};
+use crate::types::compiler::{HeapObject, Value};
+use std::fs;
+use std::io::Write;
+
+pub fn register(registry: &mut NativeRegistry) {
+    registry.register("FS.read_file", Some(1), |context, args| {
+        let path = path_arg(context, &args[0])?;
+        fs::read_to_string(&path)
+            .map(Value::String)
+            .map_err(|e| io_error(&path, e))
+    });
+    registry.register("FS.write_file", Some(2), |context, args| {
+        let path = path_arg(context, &args[0])?;
+        let content = context.display(&args[1]);
+        fs::write(&path, content).map_err(|e| io_error(&path, e))?;
+        Ok(Value::Boolean(true))
+    });
+    registry.register("FS.append_file", Some(2), |context, args| {
+        let path = path_arg(context, &args[0])?;
+        let content = context.display(&args[1]);
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(content.as_bytes()))
+            .map_err(|e| io_error(&path, e))?;
+        Ok(Value::Boolean(true))
+    });
+    registry.register("FS.exists", Some(1), |context, args| {
+        let path = path_arg(context, &args[0])?;
+        Ok(Value::Boolean(fs::exists(&path).unwrap_or(false)))
+    });
+    registry.register("FS.list_dir", Some(1), |context, args| {
+        let path = path_arg(context, &args[0])?;
+        let mut names = fs::read_dir(&path)
+            .and_then(|entries| {
+                entries
+                    .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(|e| io_error(&path, e))?;
+        names.sort();
+        let names = names.into_iter().map(HeapObject::String).collect();
+        Ok(context.heap.store(HeapObject::Array(names)))
+    });
+    registry.register("FS.remove", Some(1), |context, args| {
+        let path = path_arg(context, &args[0])?;
+        let metadata = fs::symlink_metadata(&path).map_err(|e| io_error(&path, e))?;
+        if metadata.is_dir() {
+            fs::remove_dir(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+        .map_err(|e| io_error(&path, e))?;
+        Ok(Value::Boolean(true))
+    });
+}
+
+fn path_arg(context: &NativeContext, value: &Value) -> Result<String, String> {
+    context
+        .heap
+        .load_as(value)
+        .map_err(|_| "path must be a string".to_string())
+}
+
+fn io_error(path: &str, error: std::io::Error) -> String {
+    format!("'{}': {}", path, error)
+}
@@ -0,0 +1,212 @@
+//! `Http` module: a minimal HTTP/1.1 client over plain TCP.
+//!
+//! Requests block the whole VM until the response has been read. Tasks take
+//! turns on the one VM thread rather than running in parallel, so a request
+//! made inside `Task.spawn` blocks the other tasks too. Only `http://` URLs
+//! are supported since the crate carries no TLS implementation. A request
+//! waits no longer than the run's `wall_clock_timeout` allows, and stops when
+//! the run is cancelled, with the same error as any other instruction.
+
+use crate::natives::{NativeContext, NativeRegistry};
+use crate::types::compiler::{HeapObject, Value};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest a read waits before checking whether the run must stop.
+const READ_SLICE: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl From<Response> for HeapObject {
+    fn from(response: Response) -> Self {
+        HeapObject::Object(HashMap::from([
+            (
+                "status".to_string(),
+                HeapObject::Number(response.status as f64),
+            ),
+            ("headers".to_string(), response.headers.into()),
+            ("body".to_string(), HeapObject::String(response.body)),
+        ]))
+    }
+}
+
+pub fn register(registry: &mut NativeRegistry) {
+    registry.register("Http.get", Some(1), |context, args| {
+        let url = string_arg(context, &args[0], "url")?;
+        let response = request(context, "GET", &url, None)?;
+        Ok(context.heap.store(response))
+    });
+    registry.register("Http.post", Some(2), |context, args| {
+        let url = string_arg(context, &args[0], "url")?;
+        let body = context.display(&args[1]);
+        let response = request(context, "POST", &url, Some(&body))?;
+        Ok(context.heap.store(response))
+    });
+}
+
+fn string_arg(context: &NativeContext, value: &Value, name: &str) -> Result<String, String> {
+    context
+        .heap
+        .load_as(value)
+        .map_err(|_| format!("{} must be a string", name))
+}
+
+/// Splits `http://host[:port][/path]` into its parts.
+fn parse_url(url: &str) -> Result<(String, u16, String), String> {
+    let rest = match url.split_once("://") {
+        Some(("http", rest)) => rest,
+        Some((scheme, _)) => return Err(format!("unsupported scheme '{}'", scheme)),
+        None => return Err(format!("invalid URL '{}'", url)),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .map_err(|_| format!("invalid port in '{}'", url))?,
+        ),
+        None => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err(format!("missing host in '{}'", url));
+    }
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+/// `TIMEOUT`, or the time the run has left if that is shorter.
+fn timeout(context: &NativeContext) -> Duration {
+    context
+        .remaining()
+        .map_or(TIMEOUT, |left| left.min(TIMEOUT))
+        // A zero timeout is an error to the socket; the stop check catches it
+        .max(Duration::from_millis(1))
+}
+
+pub fn request(
+    context: &mut NativeContext,
+    method: &str,
+    url: &str,
+    body: Option<&str>,
+) -> Result<Response, String> {
+    let (host, port, path) = parse_url(url)?;
+    let address = (host.as_str(), port)
+        .to_socket_addrs()
+        .map_err(|e| format!("cannot resolve '{}': {}", host, e))?
+        .next()
+        .ok_or_else(|| format!("cannot resolve '{}'", host))?;
+    let connected = TcpStream::connect_timeout(&address, timeout(context));
+    context.check_stop()?;
+    let mut stream = connected.map_err(|e| format!("cannot connect to '{}': {}", url, e))?;
+    stream
+        .set_read_timeout(Some(READ_SLICE))
+        .and_then(|_| stream.set_write_timeout(Some(timeout(context))))
+        .map_err(|e| e.to_string())?;
+
+    // Virtual hosts on another port need it in the header to match
+    let host = match port {
+        80 => host,
+        port => format!("{}:{}", host, port),
+    };
+    let body = body.unwrap_or("");
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        host,
+        body.len(),
+        body
+    );
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|e| format!("request to '{}' failed: {}", url, e))?;
+
+    // In slices, so the run's time limit and cancellation still stop it
+    let mut raw = Vec::new();
+    let mut buf = [0; 4096];
+    let mut last_read = Instant::now();
+    loop {
+        context.check_stop()?;
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                raw.extend_from_slice(&buf[..n]);
+                last_read = Instant::now();
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if last_read.elapsed() > TIMEOUT {
+                    return Err(format!("reading response from '{}' timed out", url));
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(format!("reading response from '{}' failed: {}", url, e)),
+        }
+    }
+    parse_response(&raw)
+}
+
+fn parse_response(raw: &[u8]) -> Result<Response, String> {
+    let split = raw
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or("malformed HTTP response")?;
+    let head = String::from_utf8_lossy(&raw[..split]);
+    let mut lines = head.split("\r\n");
+
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or("malformed HTTP status line")?;
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let payload = &raw[split + 4..];
+    let body = if headers
+        .get("transfer-encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        decode_chunked(payload)?
+    } else {
+        payload.to_vec()
+    };
+
+    Ok(Response {
+        status,
+        headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+fn decode_chunked(mut payload: &[u8]) -> Result<Vec<u8>, String> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = payload
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or("malformed chunked body")?;
+        let size_line = String::from_utf8_lossy(&payload[..line_end]);
+        let size_hex = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_hex, 16).map_err(|_| "malformed chunk size")?;
+        payload = &payload[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        let chunk = payload.get(..size).ok_or("truncated chunk")?;
+        body.extend_from_slice(chunk);
+        payload = payload.get(size + 2..).ok_or("truncated chunk")?;
+    }
+}
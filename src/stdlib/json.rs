@@ -0,0 +1,320 @@
+//! `JSON.parse` and `JSON.stringify`. Objects become `HeapObject::Object`,
+//! arrays `HeapObject::Array` and `null` `HeapObject::Null`.
+
+use crate::natives::NativeRegistry;
+use crate::types::compiler::{HeapObject, Value};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+pub fn register(registry: &mut NativeRegistry) {
+    registry.register("JSON.parse", Some(1), |context, args| {
+        let text: String = context.heap.load_as(&args[0])?;
+        let object = parse(&text)?;
+        Ok(context.heap.store(object))
+    });
+    registry.register("JSON.stringify", Some(1), |context, args| {
+        let object = context.heap.load(&args[0])?;
+        Ok(Value::String(stringify(&object)))
+    });
+}
+
+/// How deeply arrays and objects may nest in `parse`'s input. The parser
+/// recurses once per level, so untrusted input could otherwise overflow the
+/// stack.
+pub const MAX_DEPTH: usize = 512;
+
+pub fn parse(text: &str) -> Result<HeapObject, String> {
+    let mut parser = JsonParser {
+        text,
+        pos: 0,
+        depth: 0,
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos != text.len() {
+        return Err(parser.error("unexpected trailing characters"));
+    }
+    Ok(value)
+}
+
+pub fn stringify(object: &HeapObject) -> String {
+    let mut out = String::new();
+    write_object(&mut out, object);
+    out
+}
+
+fn write_object(out: &mut String, object: &HeapObject) {
+    match object {
+        HeapObject::Null => out.push_str("null"),
+        HeapObject::Boolean(b) => out.push_str(if *b { "true" } else { "false" }),
+        HeapObject::Number(n) if n.is_finite() => {
+            let _ = write!(out, "{}", n);
+        }
+        // Values JSON cannot represent
+        HeapObject::Number(_)
+        | HeapObject::Task(_)
+        | HeapObject::Channel(_)
+        | HeapObject::Generator(_)
+        | HeapObject::Opaque(_) => out.push_str("null"),
+        HeapObject::String(s) => write_string(out, s),
+        HeapObject::Array(items) => write_items(out, items.iter()),
+        HeapObject::Tuple(items) => write_items(out, items.iter()),
+        // `{"Enum::Variant":[fields]}`
+        HeapObject::Variant { name, values } => {
+            out.push('{');
+            write_string(out, name);
+            out.push(':');
+            write_items(out, values.iter());
+            out.push('}');
+        }
+        HeapObject::Record { fields, .. } => {
+            out.push('{');
+            for (i, (name, value)) in fields.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(out, name);
+                out.push(':');
+                write_object(out, value);
+            }
+            out.push('}');
+        }
+        HeapObject::Object(map) => {
+            // Sorted keys keep the output deterministic
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(out, key);
+                out.push(':');
+                write_object(out, &map[key]);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_items<'a>(out: &mut String, items: impl Iterator<Item = &'a HeapObject>) {
+    out.push('[');
+    for (i, item) in items.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_object(out, item);
+    }
+    out.push(']');
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+struct JsonParser<'a> {
+    text: &'a str,
+    pos: usize,   // Byte offset into `text`
+    depth: usize, // Arrays and objects the current value is in
+}
+
+impl JsonParser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("Invalid JSON at byte {}: {}", self.pos, message)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        if self.peek() == Some(byte) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", byte as char)))
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: HeapObject) -> Result<HeapObject, String> {
+        if self.text[self.pos..].starts_with(word) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(self.error("unexpected token"))
+        }
+    }
+
+    fn value(&mut self) -> Result<HeapObject, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{' | b'[') => self.nested(),
+            Some(b'"') => self.string().map(HeapObject::String),
+            Some(b't') => self.literal("true", HeapObject::Boolean(true)),
+            Some(b'f') => self.literal("false", HeapObject::Boolean(false)),
+            Some(b'n') => self.literal("null", HeapObject::Null),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn nested(&mut self) -> Result<HeapObject, String> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(&format!("nested more than {} deep", MAX_DEPTH)));
+        }
+        self.depth += 1;
+        let value = match self.peek() {
+            Some(b'{') => self.object(),
+            _ => self.array(),
+        };
+        self.depth -= 1;
+        value
+    }
+
+    fn object(&mut self) -> Result<HeapObject, String> {
+        self.expect(b'{')?;
+        let mut map = HashMap::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(HeapObject::Object(map));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            let value = self.value()?;
+            map.insert(key, value);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(HeapObject::Object(map));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<HeapObject, String> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(HeapObject::Array(items.into()));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(HeapObject::Array(items.into()));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<HeapObject, String> {
+        let start = self.pos;
+        while matches!(
+            self.peek(),
+            Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+        ) {
+            self.pos += 1;
+        }
+        self.text[start..self.pos]
+            .parse::<f64>()
+            .map(HeapObject::Number)
+            .map_err(|_| self.error("invalid number"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| self.error("truncated \\u escape"))?;
+        let code = u32::from_str_radix(digits, 16).map_err(|_| self.error("invalid \\u escape"))?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut out = String::new();
+        loop {
+            let rest = &self.text[self.pos..];
+            let Some(ch) = rest.chars().next() else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += ch.len_utf8();
+            match ch {
+                '"' => return Ok(out),
+                '\\' => {
+                    let escape = self
+                        .peek()
+                        .ok_or_else(|| self.error("unterminated escape"))?;
+                    self.pos += 1;
+                    match escape {
+                        b'"' => out.push('"'),
+                        b'\\' => out.push('\\'),
+                        b'/' => out.push('/'),
+                        b'b' => out.push('\u{8}'),
+                        b'f' => out.push('\u{c}'),
+                        b'n' => out.push('\n'),
+                        b'r' => out.push('\r'),
+                        b't' => out.push('\t'),
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // Surrogate pair: a high surrogate must be followed by `\uDC00`-`\uDFFF`
+                            if (0xD800..0xDC00).contains(&code) {
+                                if !self.text[self.pos..].starts_with("\\u") {
+                                    return Err(self.error("unpaired surrogate"));
+                                }
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err(self.error("invalid low surrogate"));
+                                }
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            out.push(
+                                char::from_u32(code)
+                                    .ok_or_else(|| self.error("invalid code point"))?,
+                            );
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                c if (c as u32) < 0x20 => return Err(self.error("control character in string")),
+                c => out.push(c),
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Standard modules that scripts opt into with `import "Name"`. Importing a
+//! module registers its natives so `Name.function(...)` calls resolve.
+
+use crate::natives::NativeRegistry;
+
+#[cfg(feature = "fs")]
+pub mod fs;
+pub mod http;
+pub mod json;
+pub mod os;
+pub mod string;
+pub mod time;
+
+/// Module names accepted by `import`. `FS` needs the `fs` feature.
+pub const MODULES: &[&str] = &[
+    #[cfg(feature = "fs")]
+    "FS",
+    "Http",
+    "JSON",
+    "OS",
+    "String",
+    "Time",
+];
+
+/// The modules a sandbox allows, see `CompileOptions::sandbox`: those that
+/// cannot reach files, processes, the environment or the network. `Time` is
+/// one since `Time.sleep` gives up at the run's `wall_clock_timeout` and on
+/// cancellation like any other instruction; a host running untrusted code
+/// sets the timeout for both.
+pub const SANDBOX_MODULES: &[&str] = &["JSON", "String", "Time"];
+
+pub fn import(registry: &mut NativeRegistry, module: &str) -> Result<(), String> {
+    match module {
+        #[cfg(feature = "fs")]
+        "FS" => fs::register(registry),
+        "Http" => http::register(registry),
+        "JSON" => json::register(registry),
+        "OS" => os::register(registry),
+        "String" => string::register(registry),
+        "Time" => time::register(registry),
+        _ => return Err(format!("Unknown module '{}'", module)),
+    }
+    Ok(())
+}
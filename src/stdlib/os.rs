@@ -0,0 +1,76 @@
+//! `OS` module: program arguments, environment, subprocesses and exiting.
+
+use crate::natives::{NativeContext, NativeRegistry};
+use crate::types::compiler::{HeapObject, Value};
+use std::collections::HashMap;
+use std::process::Command;
+use std::sync::OnceLock;
+
+static ARGS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Sets what `OS.args()` returns. The CLI passes the arguments after the script
+/// path; embedders may pass anything. Only the first call has an effect.
+pub fn set_args(args: Vec<String>) {
+    let _ = ARGS.set(args);
+}
+
+fn args() -> &'static [String] {
+    ARGS.get_or_init(|| std::env::args().skip(1).collect())
+}
+
+/// Registers `OS.args` alone; the compiler needs it to pass `args` to `main`.
+pub fn register_args(registry: &mut NativeRegistry) {
+    registry.register("OS.args", Some(0), |context, _| {
+        Ok(context.heap.store(args().to_vec()))
+    });
+}
+
+pub fn register(registry: &mut NativeRegistry) {
+    register_args(registry);
+    registry.register("OS.env", Some(1), |context, args| {
+        let name = string_arg(context, &args[0], "variable name")?;
+        let value = std::env::var(&name).ok();
+        Ok(context.heap.store(value))
+    });
+    registry.register("OS.exec", Some(2), |context, args| {
+        let command = string_arg(context, &args[0], "command")?;
+        let arguments: Vec<String> = context
+            .heap
+            .load_as(&args[1])
+            .map_err(|_| "arguments must be a list of strings".to_string())?;
+        let output = Command::new(&command)
+            .args(&arguments)
+            .output()
+            .map_err(|e| format!("cannot run '{}': {}", command, e))?;
+        let result = HashMap::from([
+            (
+                "code".to_string(),
+                // Killed by a signal: there is no exit code to report
+                HeapObject::Number(output.status.code().unwrap_or(-1) as f64),
+            ),
+            (
+                "stdout".to_string(),
+                String::from_utf8_lossy(&output.stdout).as_ref().into(),
+            ),
+            (
+                "stderr".to_string(),
+                String::from_utf8_lossy(&output.stderr).as_ref().into(),
+            ),
+        ]);
+        Ok(context.heap.store(result))
+    });
+    registry.register("OS.exit", Some(1), |context, args| match args[0] {
+        Value::Number(code) if code.fract() == 0.0 => {
+            *context.exit_code = Some(code as i32);
+            Ok(Value::Number(code))
+        }
+        _ => Err("exit code must be an integer".to_string()),
+    });
+}
+
+fn string_arg(context: &NativeContext, value: &Value, name: &str) -> Result<String, String> {
+    context
+        .heap
+        .load_as(value)
+        .map_err(|_| format!("{} must be a string", name))
+}
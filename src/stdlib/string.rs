@@ -0,0 +1,63 @@
+//! `String` module. Lengths and positions count characters (Unicode scalar
+//! values), so `"héllo"` has length 5 and an emoji is one character;
+//! `byte_length` and `bytes` give the UTF-8 encoding instead.
+
+use crate::natives::NativeRegistry;
+use crate::types::compiler::Value;
+
+pub fn register(registry: &mut NativeRegistry) {
+    registry.register("String.length", Some(1), |context, args| {
+        let text: String = context.heap.load_as(&args[0])?;
+        Ok(Value::Number(text.chars().count() as f64))
+    });
+    registry.register("String.byte_length", Some(1), |context, args| {
+        let text: String = context.heap.load_as(&args[0])?;
+        Ok(Value::Number(text.len() as f64))
+    });
+    registry.register("String.chars", Some(1), |context, args| {
+        let text: String = context.heap.load_as(&args[0])?;
+        let chars: Vec<String> = text.chars().map(String::from).collect();
+        Ok(context.heap.store(chars))
+    });
+    registry.register("String.bytes", Some(1), |context, args| {
+        let text: String = context.heap.load_as(&args[0])?;
+        let bytes: Vec<f64> = text.bytes().map(f64::from).collect();
+        Ok(context.heap.store(bytes))
+    });
+    registry.register("String.char_at", Some(2), |context, args| {
+        let text: String = context.heap.load_as(&args[0])?;
+        let index = position(&args[1])?;
+        text.chars()
+            .nth(index)
+            .map(|ch| Value::String(ch.to_string()))
+            .ok_or_else(|| format!("index {} is past the end of the string", index))
+    });
+    registry.register("String.slice", Some(3), |context, args| {
+        let text: String = context.heap.load_as(&args[0])?;
+        let (start, end) = (position(&args[1])?, position(&args[2])?);
+        slice(&text, start, end).map(Value::String)
+    });
+}
+
+/// Characters `start` up to, not including, `end`.
+pub fn slice(text: &str, start: usize, end: usize) -> Result<String, String> {
+    let length = text.chars().count();
+    if start > end || end > length {
+        return Err(format!(
+            "cannot slice {}..{} of a string of length {}",
+            start, end, length
+        ));
+    }
+    Ok(text.chars().skip(start).take(end - start).collect())
+}
+
+fn position(value: &Value) -> Result<usize, String> {
+    match value {
+        Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Ok(*n as usize),
+        Value::Number(n) => Err(format!("index must be a whole number >= 0, got {}", n)),
+        other => Err(format!(
+            "index must be a number, got {}",
+            other.type_name_stack()
+        )),
+    }
+}
@@ -0,0 +1,141 @@
+//! `Time` module. Timestamps are milliseconds since the Unix epoch, UTC.
+
+use crate::natives::NativeRegistry;
+use crate::types::compiler::Value;
+
+/// Longest `Time.sleep` waits before checking whether the run must stop.
+const SLEEP_SLICE_MS: f64 = 10.0;
+
+pub fn register(registry: &mut NativeRegistry) {
+    clock::start();
+
+    registry.register("Time.now", Some(0), |_, _| clock::now().map(Value::Number));
+    registry.register("Time.elapsed", Some(0), |_, _| {
+        Ok(Value::Number(clock::elapsed()))
+    });
+    registry.register("Time.sleep", Some(1), |context, args| match args[0] {
+        Value::Number(ms) if ms >= 0.0 && ms.is_finite() => {
+            // In slices, so the run's time limit and cancellation still stop it
+            let end = clock::elapsed() + ms;
+            loop {
+                context.check_stop()?;
+                let left = end - clock::elapsed();
+                if left <= 0.0 {
+                    break;
+                }
+                clock::sleep(left.min(SLEEP_SLICE_MS))?;
+            }
+            Ok(Value::Number(ms))
+        }
+        _ => Err("expects a non-negative number of milliseconds".to_string()),
+    });
+    registry.register("Time.format", Some(2), |context, args| {
+        let Value::Number(timestamp) = args[0] else {
+            return Err("timestamp must be a number".to_string());
+        };
+        let pattern: String = context.heap.load_as(&args[1])?;
+        format(timestamp, &pattern).map(Value::String)
+    });
+}
+
+/// Formats a timestamp with `%Y %m %d %H %M %S %L` (milliseconds) and `%%`.
+pub fn format(timestamp: f64, pattern: &str) -> Result<String, String> {
+    if !timestamp.is_finite() {
+        return Err("timestamp must be finite".to_string());
+    }
+    let millis = timestamp.floor() as i64;
+    let days = millis.div_euclid(86_400_000);
+    let ms_of_day = millis.rem_euclid(86_400_000);
+    let (year, month, day) = civil_from_days(days);
+
+    let mut out = String::new();
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '%' {
+            out.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => out.push_str(&format!("{:04}", year)),
+            Some('m') => out.push_str(&format!("{:02}", month)),
+            Some('d') => out.push_str(&format!("{:02}", day)),
+            Some('H') => out.push_str(&format!("{:02}", ms_of_day / 3_600_000)),
+            Some('M') => out.push_str(&format!("{:02}", ms_of_day / 60_000 % 60)),
+            Some('S') => out.push_str(&format!("{:02}", ms_of_day / 1000 % 60)),
+            Some('L') => out.push_str(&format!("{:03}", ms_of_day % 1000)),
+            Some('%') => out.push('%'),
+            Some(other) => return Err(format!("unknown format directive '%{}'", other)),
+            None => return Err("format string ends with '%'".to_string()),
+        }
+    }
+    Ok(out)
+}
+
+/// Days since 1970-01-01 to a proleptic Gregorian (year, month, day).
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Milliseconds from the system clock.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+mod clock {
+    use std::sync::OnceLock;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    static START: OnceLock<Instant> = OnceLock::new();
+
+    pub fn start() {
+        START.get_or_init(Instant::now);
+    }
+
+    pub fn now() -> Result<f64, String> {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| e.to_string())?;
+        Ok(since_epoch.as_millis() as f64)
+    }
+
+    /// Monotonic, so differences are safe to use for measuring work.
+    pub fn elapsed() -> f64 {
+        START.get_or_init(Instant::now).elapsed().as_secs_f64() * 1000.0
+    }
+
+    pub fn sleep(ms: f64) -> Result<(), String> {
+        std::thread::sleep(Duration::from_secs_f64(ms / 1000.0));
+        Ok(())
+    }
+}
+
+/// Milliseconds from the browser's clock; `std::time` panics on this target.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+mod clock {
+    use std::sync::OnceLock;
+
+    static START: OnceLock<f64> = OnceLock::new();
+
+    pub fn start() {
+        START.get_or_init(js_sys::Date::now);
+    }
+
+    pub fn now() -> Result<f64, String> {
+        Ok(js_sys::Date::now().floor())
+    }
+
+    pub fn elapsed() -> f64 {
+        // Never negative, even if the system clock is set back
+        (js_sys::Date::now() - START.get_or_init(js_sys::Date::now)).max(0.0)
+    }
+
+    pub fn sleep(_ms: f64) -> Result<(), String> {
+        Err("cannot sleep in the browser, which would freeze the page".to_string())
+    }
+}
@@ -0,0 +1,292 @@
+use crate::bytecode;
+use crate::coverage::Coverage;
+use crate::interpreter::{VirtualMachine, VmLimits};
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::runtime::{compile_source, compile_source_with, options_for_file};
+use crate::types::ast::{Stmt, find_attribute};
+use crate::types::compiler::CompileOptions;
+use crate::verify;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct CorpusOptions {
+    /// Run each program after it compiles instead of only compiling it.
+    pub execute: bool,
+    /// Limits for each run, so a program that never ends fails instead of
+    /// hanging the runner.
+    pub limits: VmLimits,
+}
+
+impl Default for CorpusOptions {
+    fn default() -> Self {
+        Self {
+            execute: true,
+            limits: VmLimits {
+                max_instructions: Some(10_000_000),
+                max_heap_bytes: None,
+                wall_clock_timeout: Some(Duration::from_secs(10)),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CorpusOutcome {
+    Passed,
+    CompileError(String),
+    /// The program does not survive `bytecode::encode` and `decode`
+    /// unchanged, or the decoded program fails `verify::verify`.
+    BytecodeError(String),
+    RuntimeError(String),
+    /// A run went over `CorpusOptions::limits`, with the level it ran at.
+    LimitExceeded(String),
+    ReadError(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct CorpusEntry {
+    pub path: PathBuf,
+    pub outcome: CorpusOutcome,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CorpusReport {
+    pub entries: Vec<CorpusEntry>,
+}
+
+impl CorpusReport {
+    pub fn passed(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|e| e.outcome == CorpusOutcome::Passed)
+            .count()
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &CorpusEntry> {
+        self.entries
+            .iter()
+            .filter(|e| e.outcome != CorpusOutcome::Passed)
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failures().next().is_none()
+    }
+}
+
+/// Compiles, round-trips through the bytecode format, verifies and runs
+/// every `.n` file below `path`, so community-contributed programs can be
+/// kept as regression tests. Programs run once per `levels()`.
+pub fn run_corpus(path: impl AsRef<Path>) -> io::Result<CorpusReport> {
+    run_corpus_with(path, &CorpusOptions::default())
+}
+
+pub fn run_corpus_with(
+    path: impl AsRef<Path>,
+    options: &CorpusOptions,
+) -> io::Result<CorpusReport> {
+    let mut files = Vec::new();
+    collect_programs(path.as_ref(), &mut files)?;
+    // Sort so reports are stable regardless of directory iteration order
+    files.sort();
+
+    let entries = files
+        .into_iter()
+        .map(|path| {
+            let outcome = run_program(&path, options);
+            CorpusEntry { path, outcome }
+        })
+        .collect();
+
+    Ok(CorpusReport { entries })
+}
+
+fn collect_programs(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_programs(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "n") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The ways a program can be run, which must all succeed: on the stack
+/// and register code as built, and with the `jit` feature, with every
+/// function the register code runs compiled to native code on its first call.
+pub fn levels() -> &'static [&'static str] {
+    if cfg!(feature = "jit") {
+        &["default", "jit"]
+    } else {
+        &["default"]
+    }
+}
+
+fn run_program(path: &Path, options: &CorpusOptions) -> CorpusOutcome {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => return CorpusOutcome::ReadError(e.to_string()),
+    };
+
+    let (bytecode, compiler) = match compile_source(&source) {
+        Ok(compiled) => compiled,
+        Err(e) => return CorpusOutcome::CompileError(e),
+    };
+
+    let decoded = match bytecode::encode(&bytecode).and_then(|bytes| bytecode::decode(&bytes)) {
+        Ok(decoded) => decoded,
+        Err(e) => return CorpusOutcome::BytecodeError(e),
+    };
+    if decoded != bytecode {
+        return CorpusOutcome::BytecodeError("the decoded program differs".to_string());
+    }
+    if let Err(e) = verify::verify(&decoded) {
+        return CorpusOutcome::BytecodeError(e);
+    }
+
+    if options.execute {
+        for &level in levels() {
+            let mut vm = VirtualMachine::new(decoded.clone(), compiler.clone());
+            vm.set_output(Box::new(io::sink()));
+            vm.set_limits(options.limits.clone());
+            #[cfg(feature = "jit")]
+            if level == "jit" {
+                vm.set_jit_threshold(1);
+            }
+            if let Err(e) = vm.run() {
+                return match vm.stop_reason() {
+                    Some(_) => CorpusOutcome::LimitExceeded(format!("{}: {}", level, e)),
+                    None => CorpusOutcome::RuntimeError(format!("{}: {}", level, e)),
+                };
+            }
+        }
+    }
+
+    CorpusOutcome::Passed
+}
+
+/// Result of one `@test` function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestResult {
+    pub path: PathBuf,
+    pub name: String,
+    /// The runtime error the test stopped with, or `None` if it passed.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TestOptions {
+    /// Count the lines each test runs, into `TestReport::coverage`.
+    pub coverage: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TestReport {
+    pub results: Vec<TestResult>,
+    /// Files with tests that could not be read or parsed, with the error.
+    pub errors: Vec<(PathBuf, String)>,
+    /// Lines run by the tests of each file, with `TestOptions::coverage`.
+    pub coverage: Option<Coverage>,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|r| r.error.is_none()).count()
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &TestResult> {
+        self.results.iter().filter(|r| r.error.is_some())
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.errors.is_empty() && self.failures().next().is_none()
+    }
+}
+
+/// Runs the `@test` functions of the `.n` file at `path`, or of every `.n`
+/// file below it. Each test gets a fresh VM with the file's top-level code run
+/// first, and passes unless it stops with an error, such as a failed
+/// `assert`. Files without tests are not run.
+pub fn run_tests(path: impl AsRef<Path>) -> io::Result<TestReport> {
+    run_tests_with(path, &TestOptions::default())
+}
+
+pub fn run_tests_with(path: impl AsRef<Path>, options: &TestOptions) -> io::Result<TestReport> {
+    let path = path.as_ref();
+    let mut files = Vec::new();
+    if path.is_dir() {
+        collect_programs(path, &mut files)?;
+        files.sort();
+    } else {
+        files.push(path.to_path_buf());
+    }
+
+    let mut report = TestReport {
+        coverage: options.coverage.then(Coverage::new),
+        ..TestReport::default()
+    };
+    for path in files {
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) => {
+                report.errors.push((path, e.to_string()));
+                continue;
+            }
+        };
+        let program = match Parser::new(Lexer::new(&source).tokenize()).parse() {
+            Ok(program) => program,
+            Err(e) => {
+                report.errors.push((path, format!("Parse error: {}", e)));
+                continue;
+            }
+        };
+        for stmt in &program.statements {
+            if let Stmt::Func {
+                name, attributes, ..
+            } = stmt
+                && find_attribute(attributes, "test").is_some()
+            {
+                let error = run_test(&path, &source, name, report.coverage.as_mut()).err();
+                report.results.push(TestResult {
+                    path: path.clone(),
+                    name: name.to_string(),
+                    error,
+                });
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn run_test(
+    path: &Path,
+    source: &str,
+    name: &str,
+    coverage: Option<&mut Coverage>,
+) -> Result<(), String> {
+    let lines = source.lines().count();
+    let options = CompileOptions {
+        call_main: false,
+        ..options_for_file(&path.to_string_lossy())
+    };
+    // The call goes on a line of its own after the file, so errors in the
+    // file keep their line numbers
+    let source = format!("{}\n{}()\n", source, name);
+    let (bytecode, compiler) = compile_source_with(&source, options)?;
+    let mut vm = VirtualMachine::new(bytecode, compiler);
+    if coverage.is_some() {
+        vm.enable_coverage();
+    }
+    let result = vm.run().map(|_| ());
+    if let (Some(coverage), Some(mut hits)) = (coverage, vm.line_hits()) {
+        // Leave out the line calling the test
+        hits.retain(|&line, _| line <= lines);
+        coverage.add(&path.to_string_lossy(), &hits);
+    }
+    result
+}
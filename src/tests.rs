@@ -1,9 +1,7 @@
-use crate::runtime::compile_and_run;
-use std::path::Path;
+use crate::runtime::{Capture, compile_and_run};
 
 #[derive(Debug)]
 pub struct TestResult {
-    pub name: String,
     pub passed: bool,
     pub output: String,
     pub exit_code: i32,
@@ -18,11 +16,6 @@ pub fn run_n_file(file_path: &str) -> TestResult {
     };
 
     TestResult {
-        name: Path::new(file_path)
-            .file_stem()
-            .unwrap()
-            .to_string_lossy()
-            .to_string(),
         passed,
         output,
         exit_code,
@@ -30,6 +23,7 @@ pub fn run_n_file(file_path: &str) -> TestResult {
 }
 
 #[cfg(test)]
+#[allow(clippy::module_inception)] // The file holds helpers, its `tests` module the cases
 mod tests {
     use super::*;
 
@@ -135,4 +129,4372 @@ mod tests {
             result.output
         );
     }
+
+    #[test]
+    fn test_generational_gc() {
+        use crate::compiler::Compiler;
+        use crate::interpreter::VirtualMachine;
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+
+        // Every statement allocates an array that is immediately garbage, except the
+        // first one which stays reachable for the whole program.
+        let mut source = String::from("let keep = [1, 2, 3]\n");
+        for i in 0..400 {
+            source.push_str(&format!("[{}, {}, {}]\n", i, i + 1, i + 2));
+        }
+        source.push_str("let check = keep <- [4]\n");
+
+        let tokens = Lexer::new(&source).tokenize();
+        let ast = Parser::new(tokens).parse().expect("parse failed");
+        let mut compiler = Compiler::new();
+        let bytecode = compiler.compile(&ast).expect("compile failed");
+        let mut vm = VirtualMachine::new(bytecode, compiler);
+        vm.run().expect("run failed");
+
+        let stats = vm.gc_stats();
+        assert!(stats.minor_collections > 0, "no minor collection ran");
+        assert_eq!(
+            stats.major_collections, 0,
+            "short-lived garbage reached a major collection"
+        );
+        assert!(stats.freed_objects > 0);
+    }
+
+    #[test]
+    fn test_corpus() {
+        use crate::VmLimits;
+        use crate::testing::{CorpusOptions, CorpusOutcome, run_corpus_with};
+
+        let report = crate::testing::run_corpus("tests/corpus").expect("corpus directory missing");
+        assert!(report.passed() > 0, "corpus is empty");
+        let failures: Vec<_> = report.failures().collect();
+        assert!(failures.is_empty(), "corpus failures: {:#?}", failures);
+
+        // A program that never ends is reported instead of hanging the runner
+        let dir = std::env::temp_dir().join(format!("n-corpus-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("loop.n"),
+            "for n in unfold(0, fn(n) => [n, n + 1]) { n }",
+        )
+        .unwrap();
+        std::fs::write(dir.join("ok.n"), "let x = 1 + 2").unwrap();
+        let options = CorpusOptions {
+            limits: VmLimits {
+                max_instructions: Some(10_000),
+                ..VmLimits::default()
+            },
+            ..CorpusOptions::default()
+        };
+        let report = run_corpus_with(&dir, &options).unwrap();
+        assert_eq!(report.passed(), 1);
+        let failures: Vec<_> = report.failures().collect();
+        assert!(failures[0].path.ends_with("loop.n"));
+        assert!(
+            matches!(&failures[0].outcome, CorpusOutcome::LimitExceeded(e) if e.contains("Instruction limit exceeded")),
+            "{:?}",
+            failures[0].outcome
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_feature_detection() {
+        use crate::features::{VERSION, has_feature};
+        use crate::types::compiler::HeapObject;
+
+        let mut engine = crate::Engine::new();
+        assert_eq!(
+            engine.eval("Lang.version()"),
+            Ok(Some(HeapObject::from(VERSION)))
+        );
+        for name in ["async", "channels", "tuples", "spread", "currying"] {
+            let source = format!("Lang.has_feature(\"{}\")", name);
+            assert_eq!(
+                engine.eval(&source),
+                Ok(Some(HeapObject::Boolean(true))),
+                "{}",
+                name
+            );
+        }
+        assert!(!has_feature("teleportation"));
+
+        // Cargo features are reported only when they are compiled in
+        assert_eq!(has_feature("ffi"), cfg!(feature = "ffi"));
+        assert_eq!(has_feature("fs"), cfg!(feature = "fs"));
+        assert_eq!(has_feature("jit"), cfg!(feature = "jit"));
+        assert_eq!(has_feature("registers"), cfg!(feature = "registers"));
+        assert_eq!(has_feature("tui"), cfg!(feature = "tui"));
+    }
+
+    #[test]
+    fn test_interned_symbols() {
+        use crate::lexer::Lexer;
+
+        let mut lexer = Lexer::new("let x = \"hi\"\nlet y = x + x + \"hi\"");
+        lexer.tokenize();
+        // x, y and "hi" are the only distinct strings in the source
+        assert_eq!(lexer.interner().len(), 3);
+    }
+
+    #[test]
+    fn test_performance_characteristics() {
+        use crate::compiler::Compiler;
+        use crate::types::ast::{BinaryOp, Expr, Program, Stmt};
+        use crate::types::compiler::CompileOptions;
+        use crate::types::interner::Interner;
+
+        // 10k functions with a distinct constant each used to be quadratic in the
+        // constant table size. The AST is built directly so only the compiler is measured.
+        let mut interner = Interner::new();
+        let x = interner.intern("x");
+        let mut program = Program::default();
+        for i in 0..10_000 {
+            let left = program.add_expr(Expr::Identifier(x.clone()));
+            let right = program.add_expr(Expr::Number(i as f64));
+            let sum = program.add_expr(Expr::Binary {
+                left,
+                op: BinaryOp::Add,
+                right,
+            });
+            program.statements.push(Stmt::Func {
+                name: interner.intern(&format!("f{}", i)),
+                params: vec![x.clone()],
+                defaults: Vec::new(),
+                variadic: false,
+                body: vec![Stmt::Expr(sum, i + 2)],
+                doc: None,
+                attributes: Vec::new(),
+                line: i + 1,
+            });
+        }
+
+        let options = CompileOptions {
+            prelude: Some(String::new()),
+            ..CompileOptions::default()
+        };
+        let bytecode = Compiler::with_options(options)
+            .compile(&program)
+            .expect("compile failed");
+        assert_eq!(bytecode.functions.len(), 10_000);
+        assert_eq!(bytecode.constants.len(), 10_000);
+    }
+
+    #[test]
+    fn test_lang_natives() {
+        let result = run_n_file("tests/lang_natives.n");
+        assert!(result.passed, "Lang natives test failed: {}", result.output);
+
+        let unknown = crate::runtime::compile_source("Lang.missing()");
+        assert!(unknown.is_err(), "unknown native should not compile");
+    }
+
+    #[test]
+    fn test_string_concat() {
+        let result = run_n_file("tests/string_concat.n");
+        assert!(
+            result.passed,
+            "String concat test failed: {}",
+            result.output
+        );
+    }
+
+    #[test]
+    fn test_string_building() {
+        use crate::types::compiler::{HeapObject, Instruction};
+
+        let mut engine = crate::Engine::new();
+        let source = "let name = \"Ann\"\nlet n = 2\n$\"{name} has {n + 1} {if n > 1 { \"new\" } else { \"old\" }}!\"";
+        assert_eq!(
+            engine.eval(source),
+            Ok(Some(HeapObject::String("Ann has 3 new!".to_string())))
+        );
+        assert_eq!(
+            engine.eval("$\"{n}\" ++ $\"\""),
+            Ok(Some(HeapObject::String("2".to_string())))
+        );
+        assert_eq!(
+            engine.eval("\"a\" + name + \"b\" + name"),
+            Ok(Some(HeapObject::String("aAnnbAnn".to_string())))
+        );
+
+        // Strings over 1024 characters are kept on the heap and still add up
+        let long = "x".repeat(1025);
+        engine.eval(&format!("let long = \"{}\"", long)).unwrap();
+        assert_eq!(
+            engine.eval("long + \"y\""),
+            Ok(Some(HeapObject::String(format!("{}y", long))))
+        );
+        assert_eq!(
+            engine.eval("let twice = long + long\n\"<\" + twice"),
+            Ok(Some(HeapObject::String(format!("<{}{}", long, long))))
+        );
+
+        // A chain of `++` is built by a single instruction
+        let (bytecode, _) =
+            crate::runtime::compile_source("let s = \"a\"\nlet t = s ++ 1 ++ (true ++ s)").unwrap();
+        assert!(bytecode.instructions.contains(&Instruction::ConcatN(4)));
+    }
+
+    #[test]
+    fn test_interpolated_quotes() {
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+        use crate::types::compiler::HeapObject;
+
+        let mut engine = crate::Engine::new();
+        engine.eval("let name = \"Ann\"").unwrap();
+        assert_eq!(
+            engine.eval("$'say \"hi\" to {name}'"),
+            Ok(Some(HeapObject::from("say \"hi\" to Ann")))
+        );
+        // The first line break of a triple-quoted string is dropped, the rest
+        // are kept, and `"` needs no escaping
+        assert_eq!(
+            engine.eval("$\"\"\"\n<p class=\"x\">\n  {name ++ \"!\"}\n</p>\"\"\""),
+            Ok(Some(HeapObject::from("<p class=\"x\">\n  Ann!\n</p>")))
+        );
+        assert_eq!(
+            engine.eval("$\"\"\"WHERE name = '{name}'\"\"\" ++ $''"),
+            Ok(Some(HeapObject::from("WHERE name = 'Ann'")))
+        );
+
+        // Lines after a multi-line string keep their numbers
+        let source = "let a = $\"\"\"\none\ntwo {1}\n\"\"\"\nlet b = \"x\ny\"\nlet c = )";
+        let err = Parser::new(Lexer::new(source).tokenize())
+            .parse()
+            .unwrap_err();
+        assert!(err.ends_with("at line 7"), "{}", err);
+    }
+
+    #[test]
+    fn test_strict_concat() {
+        use crate::interpreter::VirtualMachine;
+        use crate::runtime::compile_source_with;
+        use crate::types::compiler::CompileOptions;
+
+        let strict = CompileOptions {
+            strict_concat: true,
+            ..CompileOptions::default()
+        };
+
+        // Literal operands are rejected at compile time
+        assert!(compile_source_with("let s = \"a\" + \"b\"", strict.clone()).is_err());
+
+        // Everything else is caught when the operands turn out to be strings
+        let (bytecode, compiler) =
+            compile_source_with("let a = \"a\"\nlet s = a + a", strict.clone()).unwrap();
+        let mut vm = VirtualMachine::new(bytecode, compiler);
+        assert!(vm.run().is_err());
+
+        let long = format!("let a = \"{}\"\nlet s = a + a", "a".repeat(1025));
+        let (bytecode, compiler) = compile_source_with(&long, strict.clone()).unwrap();
+        let mut vm = VirtualMachine::new(bytecode, compiler);
+        assert!(vm.run().unwrap_err().contains("strict mode"));
+
+        let (bytecode, compiler) = compile_source_with("let s = \"a\" ++ \"b\"", strict).unwrap();
+        let mut vm = VirtualMachine::new(bytecode, compiler);
+        assert!(vm.run().is_ok());
+    }
+
+    #[test]
+    fn test_lexer_byte_offsets() {
+        use crate::lexer::Lexer;
+        use crate::types::token::Token;
+
+        // Multi-byte characters must not throw off the byte offsets that follow them
+        let tokens = Lexer::new("let s = \"héllo 😀\" ++ \"ok\"\nlet n = 4.5").tokenize();
+        let strings: Vec<&str> = tokens
+            .iter()
+            .filter_map(|t| match t {
+                Token::String(s) => Some(s.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(strings, vec!["héllo 😀", "ok"]);
+        assert!(tokens.contains(&Token::Number(4.5)));
+        assert_eq!(tokens.last(), Some(&Token::Eof));
+    }
+
+    #[test]
+    fn test_unicode_escapes() {
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+        use crate::types::compiler::HeapObject;
+        use crate::types::token::Token;
+
+        let tokens = Lexer::new("\"\\u{1F600}!\\u{e9}\" \"C:\\temp\"").tokenize();
+        assert_eq!(tokens[0], Token::String("😀!é".into()));
+        assert_eq!(tokens[1], Token::String("C:\\temp".into()));
+
+        let mut engine = crate::Engine::new();
+        assert_eq!(
+            engine.eval("let n = 1\n$\"\\u{48}\\u{49} {n}\\u{7D}\""),
+            Ok(Some(HeapObject::from("HI 1}")))
+        );
+        for (source, message) in [
+            ("\"\\u{zz}\"", "Malformed escape '\\u{zz}'"),
+            ("\"\\u{}\"", "Malformed escape '\\u{}'"),
+            ("\"\\u{1234567}\"", "Malformed escape"),
+            (
+                "\"\\u{D800}\"",
+                "Invalid escape '\\u{D800}': not a Unicode character",
+            ),
+            (
+                "let a = 1\n$\"\\u{110000}\"",
+                "not a Unicode character at line 2",
+            ),
+            ("\"\\u{41\"", "Unterminated escape"),
+        ] {
+            let err = Parser::new(Lexer::new(source).tokenize())
+                .parse()
+                .unwrap_err();
+            assert!(err.contains(message), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn test_bytecode_roundtrip() {
+        use crate::bytecode;
+
+        let source = std::fs::read_to_string("tests/function_definitions.n").unwrap();
+        let (compiled, _) = crate::runtime::compile_source(&source).expect("compile failed");
+        let bytes = bytecode::encode(&compiled).expect("encode failed");
+        assert_eq!(&bytes[0..2], bytecode::MAGIC);
+        assert_eq!(bytecode::decode(&bytes), Ok(compiled));
+
+        let listing = bytecode::inspect(&bytes).expect("inspect failed");
+        let prelude_functions = crate::compiler::PRELUDE.matches("func ").count();
+        assert!(listing.contains(&format!("=== FUNCTIONS ({}) ===", prelude_functions + 3)));
+        assert!(listing.contains("LOAD_ARG 2"));
+
+        assert!(bytecode::decode(b"XX\x01\x00").is_err());
+        assert!(bytecode::decode(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_bytecode_versions() {
+        use crate::bytecode::{self, HEADER_SIZE, MIN_VERSION, VERSION};
+        use crate::interpreter::VirtualMachine;
+        use crate::types::compiler::{Exports, Value};
+
+        let (compiled, _) = crate::runtime::compile_source("IO.print(1)\nsquare(3)").unwrap();
+        let bytes = bytecode::encode(&compiled).unwrap();
+        assert_eq!(&bytes[2..4], VERSION.to_le_bytes());
+
+        // Before v4 each constant had a fixed-width encoding after a u32 count
+        let mut legacy = (compiled.constants.len() as u32).to_le_bytes().to_vec();
+        for constant in &compiled.constants {
+            match constant {
+                Value::String(s) => {
+                    legacy.push(0);
+                    legacy.extend_from_slice(&(s.len() as u16).to_le_bytes());
+                    legacy.extend_from_slice(s.as_bytes());
+                }
+                Value::Number(n) => {
+                    legacy.push(1);
+                    legacy.extend_from_slice(&n.to_le_bytes());
+                }
+                Value::Boolean(b) => legacy.extend_from_slice(&[2, *b as u8]),
+                other => panic!("unexpected constant {}", other),
+            }
+        }
+        let (_, sizes) = bytecode::decode_with_sizes(&bytes).unwrap();
+        let rest = &bytes[HEADER_SIZE + sizes.constants..];
+        let v3 = [
+            &bytes[..2],
+            &3u16.to_le_bytes(),
+            &bytes[4..HEADER_SIZE],
+            &legacy,
+            rest,
+        ]
+        .concat();
+        assert_eq!(bytecode::decode(&v3), Ok(compiled.clone()));
+        assert!(v3.len() > bytes.len());
+
+        // A v2 file is a v3 one without the export table
+        let mut v2 = v3[..v3.len() - sizes.exports].to_vec();
+        v2[2..4].copy_from_slice(&2u16.to_le_bytes());
+        let migrated = bytecode::decode(&v2).unwrap();
+        assert_eq!(migrated.instructions, compiled.instructions);
+        assert_eq!(
+            migrated.exports,
+            Exports {
+                natives: compiled.exports.natives.clone(),
+                ..Exports::default()
+            }
+        );
+        let mut vm = VirtualMachine::new(migrated, crate::compiler::Compiler::new());
+        assert_eq!(vm.run(), Ok(None));
+        let listing = bytecode::inspect(&v2).unwrap();
+        assert!(listing.contains("version: 2 (read as 4)"), "{}", listing);
+
+        let mut newer = bytes.clone();
+        newer[2..4].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let err = bytecode::decode(&newer).unwrap_err();
+        assert!(
+            err.contains("newer than the 4 this build of n reads"),
+            "{}",
+            err
+        );
+        let mut older = v2.clone();
+        older[2..4].copy_from_slice(&(MIN_VERSION - 1).to_le_bytes());
+        let err = bytecode::decode(&older).unwrap_err();
+        assert!(err.contains("too old"), "{}", err);
+    }
+
+    #[test]
+    fn test_constant_pool_encoding() {
+        use crate::bytecode;
+        use crate::types::compiler::{ByteCode, Instruction, Value};
+
+        let constants = vec![
+            Value::Number(0.0),
+            Value::Number(-0.0),
+            Value::Number(-1.0),
+            Value::Number(300.0),
+            Value::Number(9_007_199_254_740_992.0),
+            Value::Number(9_007_199_254_740_994.0),
+            Value::Number(-1e300),
+            Value::Number(2.5),
+            Value::String(String::new()),
+            Value::String("Shape::Circle".to_string()),
+            Value::String("Shape::Square".to_string()),
+            Value::String("héllo".to_string()),
+            Value::String("hé😀".to_string()),
+            Value::String("Shape::Circle".to_string()),
+            Value::Boolean(true),
+        ];
+        let program = ByteCode {
+            instruction_lines: vec![1; constants.len() * 2 + 1],
+            instructions: (0..constants.len())
+                .flat_map(|i| [Instruction::LoadConst(i), Instruction::Pop])
+                .chain([Instruction::Halt])
+                .collect(),
+            constants,
+            ..ByteCode::default()
+        };
+        let bytes = bytecode::encode(&program).unwrap();
+        let (decoded, sizes) = bytecode::decode_with_sizes(&bytes).unwrap();
+        for (read, written) in decoded.constants.iter().zip(&program.constants) {
+            match (read, written) {
+                (Value::Number(a), Value::Number(b)) => assert_eq!(a.to_bits(), b.to_bits()),
+                _ => assert_eq!(read, written),
+            }
+        }
+        assert_eq!(bytecode::decode(&bytes), Ok(program.clone()));
+
+        // The count, then a tag and one varint byte for a small whole number
+        let one = ByteCode {
+            constants: vec![Value::Number(1.0)],
+            ..ByteCode::default()
+        };
+        let (_, sizes_of_one) =
+            bytecode::decode_with_sizes(&bytecode::encode(&one).unwrap()).unwrap();
+        assert_eq!(sizes_of_one.constants, 3);
+        // -0.0, numbers past 2^53 and fractions keep all 8 bytes
+        let numbers = 2 + 9 + 2 + 3 + 9 + 9 + 9 + 9;
+        // Tag, shared prefix and length, then the bytes not shared with the
+        // string before: "Square" after "Shape::Circle", "😀" after "hé"
+        let strings = [0, 13, 6, 6, 4].iter().map(|len| len + 3).sum::<usize>();
+        let repeat = 2;
+        assert_eq!(sizes.constants, 1 + numbers + strings + repeat + 2);
+
+        let mut truncated = bytes.clone();
+        truncated.truncate(bytecode::HEADER_SIZE + 4);
+        assert!(bytecode::decode(&truncated).is_err());
+        // A string claiming a length of 2^64 - 1
+        let mut huge = bytes[..bytecode::HEADER_SIZE].to_vec();
+        huge.extend_from_slice(&[
+            1, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01,
+        ]);
+        assert!(bytecode::decode(&huge).is_err());
+    }
+
+    #[test]
+    fn test_inspector() {
+        use crate::inspector::Inspector;
+
+        let source = "func double(n) { n * 2 }\nlet f = fn(x) => x ++ \"!\"\ndouble(21)";
+        let (compiled, _) = crate::runtime::compile_source(source).expect("compile failed");
+        let bytes = crate::bytecode::encode(&compiled).unwrap();
+        let inspector = Inspector::new(&bytes).unwrap();
+        let double = compiled.functions.len() - 2;
+
+        let two = compiled
+            .constants
+            .iter()
+            .position(|c| *c == crate::types::compiler::Value::Number(2.0))
+            .unwrap();
+        let answer = inspector.command(&format!("constant {}", two));
+        assert!(
+            answer.starts_with(&format!("constant {} = 2", two)),
+            "{}",
+            answer
+        );
+        assert!(
+            answer.contains(&format!("in function {}", double)),
+            "{}",
+            answer
+        );
+
+        let answer = inspector.command(&format!("function {}", double));
+        assert!(answer.contains("MUL"), "{}", answer);
+        assert!(!answer.contains("CONCAT"), "{}", answer);
+        assert!(
+            answer.contains("referenced by 1 instruction(s)"),
+            "{}",
+            answer
+        );
+        let answer = inspector.command("function top");
+        assert!(answer.contains("in top level"), "{}", answer);
+        assert!(!answer.contains("MUL"), "{}", answer);
+        assert!(
+            inspector
+                .command("function 999")
+                .starts_with("No function '999'")
+        );
+        assert!(inspector.command("bogus").starts_with("Unknown command"));
+
+        let mut output = Vec::new();
+        let input = std::io::Cursor::new("enums\nquit\nfunctions\n");
+        inspector.run(input, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.contains("inspect> no enums\ninspect> "),
+            "{}",
+            output
+        );
+        assert!(!output.contains("[top]"), "{}", output);
+
+        let source = "enum Shape { Circle(r), Square(s) }\nShape::Circle(1)";
+        let (compiled, _) = crate::runtime::compile_source(source).unwrap();
+        let shapes = Inspector::from_bytecode(compiled);
+        let answer = shapes.command("enum 0");
+        assert!(answer.starts_with("enum 0 Shape"), "{}", answer);
+        assert!(
+            answer.contains("Circle with 1 field(s), used by 1 instruction(s)"),
+            "{}",
+            answer
+        );
+        assert!(answer.contains("MAKE_VARIANT"), "{}", answer);
+
+        #[cfg(feature = "tui")]
+        {
+            use crate::tui::App;
+            use ratatui::Terminal;
+            use ratatui::backend::TestBackend;
+            use ratatui::crossterm::event::KeyCode;
+
+            // Both panes follow the keys: the second list, its first entry
+            let mut app = App::new(&shapes);
+            let mut terminal = Terminal::new(TestBackend::new(100, 20)).unwrap();
+            assert!(app.key(KeyCode::Tab) && app.key(KeyCode::Tab));
+            terminal.draw(|frame| app.draw(frame)).unwrap();
+            let screen: String = terminal
+                .backend()
+                .buffer()
+                .content()
+                .iter()
+                .map(|cell| cell.symbol())
+                .collect();
+            assert!(screen.contains("> [0] Shape"), "{}", screen);
+            assert!(screen.contains("enum 0 Shape"), "{}", screen);
+            assert!(!app.key(KeyCode::Char('q')));
+        }
+    }
+
+    #[test]
+    fn test_nested_function_captures() {
+        use crate::Engine;
+
+        // Nested functions get the enclosing function's locals, not the slots
+        // of their own frame
+        let mut engine = Engine::new();
+        let source = "let x = 1
+func shadow(x) { x }
+func outer(a, c) {
+    func inner(b) { a + b + c }
+    func count(i) { if i > a { 0 } else { 1 + count(i + 1) } }
+    let named = inner
+    [inner(10), named(20), [1, 2].map(inner), count(0), shadow(5)]
+}
+outer(1, 100)";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(engine.display(&value), "[111, 121, [102, 103], 2, 5]");
+
+        let err = engine
+            .eval("func early(a) {\n let r = later(1)\n func later(b) { a + b }\n r\n}")
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("'later' is used before its declaration"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_repl_recovers_after_error() {
+        use crate::repl::Repl;
+
+        let mut repl = Repl::new();
+        assert_eq!(repl.eval("let x = 2"), Ok(None));
+        assert_eq!(repl.eval("func half(n) { n / 2 }"), Ok(None));
+        assert_eq!(repl.eval("half(x * 21)"), Ok(Some("21".to_string())));
+
+        // A runtime error inside a call unwinds back to the top level...
+        let err = repl
+            .eval("func broken(n) { n / 0 }\nbroken(x)")
+            .unwrap_err();
+        assert!(
+            err.contains("Division by zero"),
+            "unexpected error: {}",
+            err
+        );
+        // ...and compile errors leave the session untouched.
+        assert!(repl.eval("missing(1)").is_err());
+
+        assert_eq!(repl.eval("x + 1"), Ok(Some("3".to_string())));
+        assert_eq!(repl.eval("half(10)"), Ok(Some("5".to_string())));
+        assert_eq!(repl.eval("print(x)"), Ok(None));
+        // Whatever ends in a print has shown its value already
+        assert_eq!(repl.eval("x |> print"), Ok(None));
+        assert_eq!(
+            repl.eval(
+                "func show(n) { print(n) }
+show(x)"
+            ),
+            Ok(None)
+        );
+        assert_eq!(
+            repl.eval("if x > 1 { print(x) } else { IO.print(0) }"),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_operator_precedence() {
+        use crate::repl::Repl;
+
+        let mut repl = Repl::new();
+        for (source, expected) in [
+            ("10 - 2 - 3", "5"),
+            ("8 / 2 / 2", "2"),
+            ("2 * 3 + 1", "7"),
+            ("1 + 2 * 3", "7"),
+            ("(1 + 2) * 3", "9"),
+            ("1 + 2 == 3", "true"),
+        ] {
+            assert_eq!(
+                repl.eval(source),
+                Ok(Some(expected.to_string())),
+                "{}",
+                source
+            );
+        }
+        assert!(repl.eval("1 2").is_err());
+    }
+
+    #[test]
+    fn test_lexer_iterator() {
+        use crate::lexer::Lexer;
+        use crate::types::token::Token;
+
+        let mut lexer = Lexer::new("let x = 1\nx");
+        let identifiers = lexer
+            .by_ref()
+            .filter(|token| matches!(token, Token::Identifier(_)))
+            .count();
+        assert_eq!(identifiers, 2);
+        assert_eq!(lexer.next(), None);
+        assert_eq!(lexer.next(), None);
+
+        let tokens = Lexer::new("1 + 2").tokenize();
+        assert_eq!(tokens.len(), 4);
+        assert_eq!(tokens.last(), Some(&Token::Eof));
+        assert_eq!(Lexer::new("").tokenize(), vec![Token::Eof]);
+    }
+
+    #[test]
+    fn test_embedding_hooks() {
+        use crate::compiler::Compiler;
+        use crate::interpreter::VirtualMachine;
+        use crate::types::compiler::Value;
+        use std::sync::{Arc, Mutex};
+
+        let mut compiler = Compiler::new();
+        compiler
+            .natives
+            .register("Host.double", Some(1), |_, args| match args[0] {
+                Value::Number(n) => Ok(Value::Number(n * 2.0)),
+                _ => Err("expected a number".to_string()),
+            });
+        let base = compiler.declare_global("base");
+        let bytecode = crate::runtime::compile_with(
+            &mut compiler,
+            "let result = Host.double(base)\nIO.print(\"result: \" ++ result)",
+        )
+        .expect("compile failed");
+        let result = compiler.declare_global("result");
+
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let mut vm = VirtualMachine::new(bytecode, compiler);
+        vm.set_output(Box::new(Capture(buffer.clone())));
+        vm.set_global(base, Value::Number(21.0));
+        vm.run().expect("run failed");
+
+        assert_eq!(vm.global(result), Some(&Value::Number(42.0)));
+        assert_eq!(&*buffer.lock().unwrap(), b"result: 42\n");
+    }
+
+    #[test]
+    fn test_engine() {
+        use crate::types::compiler::{HeapObject, Value};
+        use crate::{Engine, Error};
+        use std::sync::{Arc, Mutex};
+
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let sink = logged.clone();
+
+        let mut engine = Engine::new();
+        engine.register_fn("host_log", move |args| {
+            sink.lock().unwrap().extend(args.iter().cloned());
+            Ok(Value::Boolean(true))
+        });
+        engine.set_global("limit", HeapObject::Number(10.0));
+
+        assert_eq!(engine.eval("func twice(n) { n * 2 }"), Ok(None));
+        assert_eq!(
+            engine.eval("host_log(twice(limit), \"done\")"),
+            Ok(Some(HeapObject::Boolean(true)))
+        );
+        assert_eq!(
+            *logged.lock().unwrap(),
+            vec![Value::Number(20.0), Value::String("done".to_string())]
+        );
+
+        assert!(matches!(engine.eval("1 +"), Err(Error::Parse(_))));
+        assert!(matches!(engine.eval("nothing(1)"), Err(Error::Compile(_))));
+        assert!(matches!(engine.eval("limit / 0"), Err(Error::Runtime(_))));
+        assert_eq!(
+            engine.eval("twice(limit)"),
+            Ok(Some(HeapObject::Number(20.0)))
+        );
+    }
+
+    #[test]
+    fn test_eval_with() {
+        use crate::types::compiler::HeapObject;
+        use crate::{Engine, Error};
+
+        let mut engine = Engine::new();
+        assert_eq!(
+            engine.eval_with("x + y * 2", &[("x", 3.0.into()), ("y", 4.0.into())]),
+            Ok(HeapObject::Number(11.0))
+        );
+        // The variables do not outlive the call
+        assert!(matches!(engine.eval("x"), Err(Error::Compile(_))));
+
+        engine
+            .eval("let rate = 2\nfunc scaled(n) { n * rate }")
+            .unwrap();
+        let name = engine.eval_with("name ++ \"!\"", &[("name", "ada".into())]);
+        assert_eq!(name, Ok(HeapObject::String("ada!".to_string())));
+        assert_eq!(
+            engine.eval_with("scaled(rate)", &[("rate", 10.0.into())]),
+            Ok(HeapObject::Number(20.0))
+        );
+        assert_eq!(engine.eval("rate"), Ok(Some(HeapObject::Number(2.0))));
+
+        assert!(matches!(
+            engine.eval_with("let z = 1", &[]),
+            Err(Error::Parse(_))
+        ));
+        assert!(matches!(
+            engine.eval_with("x / 0", &[("x", 1.0.into())]),
+            Err(Error::Runtime(_))
+        ));
+        assert!(matches!(engine.eval("x"), Err(Error::Compile(_))));
+    }
+
+    #[test]
+    fn test_value_conversions() {
+        use crate::Engine;
+        use crate::heap::Heap;
+        use crate::types::compiler::{HeapObject, Value};
+        use std::collections::HashMap;
+
+        assert_eq!(Value::from(2.5), Value::Number(2.5));
+        assert_eq!(Value::from("hi"), Value::String("hi".to_string()));
+        assert_eq!(bool::try_from(Value::Boolean(true)), Ok(true));
+        assert!(f64::try_from(Value::from("nope")).is_err());
+
+        let mut heap = Heap::new();
+        let list = heap.store(vec![1.0, 2.0, 3.0]);
+        assert!(matches!(list, Value::HeapPointer(_)));
+        assert_eq!(heap.load_as::<Vec<f64>>(&list), Ok(vec![1.0, 2.0, 3.0]));
+        let err = heap.load_as::<Vec<String>>(&list).unwrap_err();
+        assert_eq!(err, "[0]: Expected string, got number");
+
+        let scores = HashMap::from([("ada".to_string(), vec![true, false])]);
+        let object = heap.store(scores.clone());
+        assert_eq!(heap.load_as(&object), Ok(scores));
+        let none = heap.store(None::<f64>);
+        assert_eq!(heap.load(&none), Ok(HeapObject::Null));
+        assert_eq!(
+            heap.load_as::<Option<f64>>(&Value::Number(1.0)),
+            Ok(Some(1.0))
+        );
+
+        let mut engine = Engine::new();
+        engine.set_global("names", vec!["a", "b"]);
+        let result = engine.eval("names <- [\"c\"]").unwrap().unwrap();
+        assert_eq!(
+            engine.convert::<Vec<String>>(&result),
+            Ok(vec!["a".to_string(), "b".to_string(), "c".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_json_module() {
+        use crate::Engine;
+        use crate::stdlib::json;
+        use crate::types::compiler::HeapObject;
+        use std::collections::HashMap;
+
+        let result = run_n_file("tests/json.n");
+        assert!(result.passed, "JSON test failed: {}", result.output);
+
+        let parsed =
+            json::parse(r#"{"b": [1, "x\n\u00e9\ud83d\ude00"], "a": {"ok": false}, "c": null}"#)
+                .expect("parse failed");
+        assert_eq!(
+            json::stringify(&parsed),
+            r#"{"a":{"ok":false},"b":[1,"x\né😀"],"c":null}"#
+        );
+        for bad in ["", "[1,", "{\"a\" 1}", "tru", "\"\\ud800\"", "[1] 2"] {
+            assert!(json::parse(bad).is_err(), "accepted {:?}", bad);
+        }
+
+        // Nesting is limited rather than overflowing the stack
+        let nested = |depth: usize| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(json::parse(&nested(json::MAX_DEPTH)).is_ok());
+        let err = json::parse(&nested(json::MAX_DEPTH + 1)).unwrap_err();
+        assert!(err.contains("nested more than 512 deep"), "{}", err);
+        let err = json::parse(&"{\"a\":[".repeat(10_000)).unwrap_err();
+        assert!(err.contains("nested more than"), "{}", err);
+
+        let mut engine = Engine::new();
+        engine.set_global(
+            "payload",
+            HeapObject::from(r#"{"name": "n", "tags": ["a", "b"]}"#),
+        );
+        assert!(engine.eval("JSON.parse(payload)").is_err(), "needs import");
+        engine.eval("import \"JSON\"").unwrap();
+        let value = engine.eval("JSON.parse(payload)").unwrap().unwrap();
+        let tags = engine.convert::<HashMap<String, Vec<String>>>(&value);
+        assert!(tags.is_err(), "name is not a list");
+        assert_eq!(
+            engine.eval("JSON.stringify(JSON.parse(payload))"),
+            Ok(Some(HeapObject::from(r#"{"name":"n","tags":["a","b"]}"#)))
+        );
+        assert!(engine.eval("import \"Nope\"").is_err());
+    }
+
+    #[test]
+    fn test_fs_module() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+
+        let dir = std::env::temp_dir().join(format!("n-fs-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("notes.txt");
+
+        let mut engine = Engine::new();
+        engine.set_global("dir", HeapObject::from(dir.to_string_lossy().as_ref()));
+        engine.set_global("file", HeapObject::from(file.to_string_lossy().as_ref()));
+        engine.eval("import \"FS\"").unwrap();
+
+        assert_eq!(
+            engine.eval("FS.exists(file)"),
+            Ok(Some(HeapObject::Boolean(false)))
+        );
+        engine.eval("FS.write_file(file, \"one\")").unwrap();
+        engine.eval("FS.append_file(file, 2)").unwrap();
+        assert_eq!(
+            engine.eval("FS.read_file(file)"),
+            Ok(Some(HeapObject::from("one2")))
+        );
+
+        let listing = engine.eval("FS.list_dir(dir)").unwrap().unwrap();
+        assert_eq!(
+            engine.convert::<Vec<String>>(&listing),
+            Ok(vec!["notes.txt".to_string()])
+        );
+
+        engine.eval("FS.remove(file)").unwrap();
+        let err = engine.eval("FS.read_file(file)").unwrap_err().to_string();
+        assert!(
+            err.contains("FS.read_file") && err.contains("notes.txt"),
+            "{}",
+            err
+        );
+        assert!(engine.eval("FS.remove(file)").is_err());
+        assert!(engine.eval("FS.read_file(1)").is_err());
+
+        engine.eval("FS.remove(dir)").unwrap();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_time_module() {
+        use crate::Engine;
+        use crate::stdlib::time;
+        use crate::types::compiler::HeapObject;
+
+        assert_eq!(
+            time::format(0.0, "%Y-%m-%d %H:%M:%S.%L"),
+            Ok("1970-01-01 00:00:00.000".to_string())
+        );
+        assert_eq!(
+            time::format(951_782_400_123.0, "%d/%m/%Y %%"),
+            Ok("29/02/2000 %".to_string())
+        );
+        assert_eq!(
+            time::format(-1.0, "%Y-%m-%d %H:%M:%S"),
+            Ok("1969-12-31 23:59:59".to_string())
+        );
+        assert!(time::format(0.0, "%Q").is_err());
+
+        let mut engine = Engine::new();
+        engine.eval("import \"Time\"").unwrap();
+        let Some(HeapObject::Number(now)) = engine.eval("Time.now()").unwrap() else {
+            panic!("Time.now should return a number");
+        };
+        assert!(now > 1.6e12);
+        engine.eval("let start = Time.elapsed()").unwrap();
+        engine.eval("Time.sleep(5)").unwrap();
+        let Some(HeapObject::Number(waited)) = engine.eval("Time.elapsed() - start").unwrap()
+        else {
+            panic!("Time.elapsed should return a number");
+        };
+        assert!(waited >= 5.0, "waited {}", waited);
+        assert!(engine.eval("Time.sleep(-1)").is_err());
+        assert_eq!(
+            engine.eval("Time.format(0, \"%Y\")"),
+            Ok(Some(HeapObject::from("1970")))
+        );
+    }
+
+    #[test]
+    fn test_unicode_text() {
+        use crate::Engine;
+        use crate::lexer::Lexer;
+        use crate::stdlib::string;
+        use crate::types::compiler::HeapObject;
+        use crate::types::token::Token;
+
+        let tokens = Lexer::new("let 名前 = café_2 + π").tokenize();
+        let names: Vec<&str> = tokens
+            .iter()
+            .filter_map(|t| match t {
+                Token::Identifier(s) => Some(s.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(names, vec!["名前", "café_2", "π"]);
+        // Emoji are not identifier characters, and digits cannot start a name
+        assert!(!crate::lexer::is_identifier_start('😀'));
+        assert!(!crate::lexer::is_identifier_start('1'));
+
+        assert_eq!(string::slice("日本語です", 1, 3), Ok("本語".to_string()));
+        assert!(string::slice("日本", 1, 3).is_err());
+
+        let mut engine = Engine::new();
+        engine
+            .eval("import \"String\"\nlet 挨拶 = \"héllo 👋\"")
+            .unwrap();
+        let number = |n: f64| Ok(Some(HeapObject::Number(n)));
+        assert_eq!(engine.eval("String.length(挨拶)"), number(7.0));
+        assert_eq!(engine.eval("String.byte_length(挨拶)"), number(11.0));
+        assert_eq!(
+            engine.eval("String.char_at(挨拶, 6)"),
+            Ok(Some(HeapObject::from("👋")))
+        );
+        assert_eq!(
+            engine.eval("String.slice(挨拶, 1, 5)"),
+            Ok(Some(HeapObject::from("éllo")))
+        );
+        let chars = engine.eval("String.chars(\"日本\")").unwrap().unwrap();
+        assert_eq!(engine.display(&chars), "[\"日\", \"本\"]");
+        let bytes = engine.eval("String.bytes(\"é\")").unwrap().unwrap();
+        assert_eq!(engine.display(&bytes), "[195, 169]");
+        assert!(engine.eval("String.char_at(挨拶, 7)").is_err());
+        assert!(engine.eval("String.slice(挨拶, 1.5, 2)").is_err());
+    }
+
+    #[test]
+    fn test_http_module() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        // Serves two requests: a plain response and a chunked echo of the body
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let url = format!("http://{}/items", address);
+        let server = std::thread::spawn(move || {
+            for (chunked, stream) in [false, true].into_iter().zip(listener.incoming()) {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request).into_owned();
+                let response = if chunked {
+                    let body = request.split("\r\n\r\n").nth(1).unwrap_or("");
+                    format!(
+                        "HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                        body.len(),
+                        body
+                    )
+                } else {
+                    assert!(request.starts_with("GET /items HTTP/1.1\r\n"));
+                    assert!(request.contains(&format!("\r\nHost: {}\r\n", address)));
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nhello".to_string()
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        let mut engine = Engine::new();
+        engine.set_global("url", HeapObject::from(url.as_str()));
+        engine.eval("import \"Http\"\nimport \"JSON\"").unwrap();
+        assert_eq!(
+            engine.eval("JSON.stringify(Http.get(url))"),
+            Ok(Some(HeapObject::from(
+                r#"{"body":"hello","headers":{"content-type":"text/plain"},"status":200}"#
+            )))
+        );
+        assert_eq!(
+            engine.eval("JSON.stringify(Http.post(url, \"ping\"))"),
+            Ok(Some(HeapObject::from(
+                r#"{"body":"ping","headers":{"transfer-encoding":"chunked"},"status":201}"#
+            )))
+        );
+        server.join().unwrap();
+
+        assert!(engine.eval("Http.get(\"https://example.com\")").is_err());
+        assert!(engine.eval("Http.get(\"not a url\")").is_err());
+    }
+
+    #[test]
+    fn test_http_stops_with_run() {
+        use crate::types::compiler::HeapObject;
+        use crate::{Engine, Error, VmLimits};
+        use std::net::TcpListener;
+        use std::time::{Duration, Instant};
+
+        // Accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        let mut engine = Engine::new();
+        engine.set_global("url", HeapObject::from(url.as_str()));
+        engine.eval("import \"Http\"").unwrap();
+        engine.set_limits(VmLimits {
+            wall_clock_timeout: Some(Duration::from_millis(50)),
+            ..VmLimits::default()
+        });
+        let started = Instant::now();
+        assert_eq!(
+            engine.eval("Http.get(url)"),
+            Err(Error::LimitExceeded(
+                "[line 1] Timed out after 50ms".to_string()
+            ))
+        );
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        engine.set_limits(VmLimits::default());
+        let token = engine.cancellation_token();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            token.cancel();
+        });
+        let started = Instant::now();
+        assert_eq!(engine.eval("Http.get(url)"), Err(Error::Cancelled));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        canceller.join().unwrap();
+        drop(listener);
+    }
+
+    #[test]
+    fn test_os_module() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+
+        let mut engine = Engine::new();
+        engine.eval("import \"OS\"\nimport \"JSON\"").unwrap();
+
+        let args = engine.eval("OS.args()").unwrap().unwrap();
+        assert!(engine.convert::<Vec<String>>(&args).is_ok());
+
+        let path = engine.eval("OS.env(\"PATH\")").unwrap().unwrap();
+        assert!(matches!(path, HeapObject::String(_)));
+        assert_eq!(
+            engine.eval("JSON.stringify(OS.env(\"N_SURELY_UNSET_VARIABLE\"))"),
+            Ok(Some(HeapObject::from("null")))
+        );
+
+        assert_eq!(
+            engine.eval("JSON.stringify(OS.exec(\"sh\", [\"-c\", \"echo out; exit 3\"]))"),
+            Ok(Some(HeapObject::from(
+                r#"{"code":3,"stderr":"","stdout":"out\n"}"#
+            )))
+        );
+        assert!(engine.eval("OS.exec(\"n-no-such-command\", [])").is_err());
+
+        // Exiting stops the script but leaves the engine usable
+        assert_eq!(engine.eval("OS.exit(4)\nOS.exec(\"false\", [])"), Ok(None));
+        assert_eq!(engine.exit_code(), Some(4));
+        assert_eq!(engine.eval("1 + 1"), Ok(Some(HeapObject::Number(2.0))));
+        assert_eq!(engine.exit_code(), None);
+    }
+
+    #[test]
+    fn test_prelude() {
+        use crate::Engine;
+        use crate::compiler::Compiler;
+        use crate::runtime::{compile_source_with, compile_with};
+        use crate::types::compiler::{CompileOptions, HeapObject};
+
+        let mut engine = Engine::new();
+        assert_eq!(
+            engine.eval("square(3) + identity(1)"),
+            Ok(Some(HeapObject::Number(10.0)))
+        );
+        assert_eq!(
+            engine.eval("join(\"a\", 1)"),
+            Ok(Some(HeapObject::from("a1")))
+        );
+        let list = engine.eval("append([1], 2)").unwrap().unwrap();
+        assert_eq!(engine.convert::<Vec<f64>>(&list), Ok(vec![1.0, 2.0]));
+        // Programs may shadow prelude functions
+        engine.eval("func square(x) { x }").unwrap();
+        assert_eq!(engine.eval("square(3)"), Ok(Some(HeapObject::Number(3.0))));
+
+        let custom = CompileOptions {
+            prelude: Some("func twice(x) { x * 2 }".to_string()),
+            ..CompileOptions::default()
+        };
+        assert!(compile_source_with("twice(2)", custom.clone()).is_ok());
+        assert!(compile_source_with("square(2)", custom).is_err());
+
+        let broken = CompileOptions {
+            prelude: Some("func (".to_string()),
+            ..CompileOptions::default()
+        };
+        let err = compile_with(&mut Compiler::with_options(broken), "1").unwrap_err();
+        assert!(err.contains("Prelude error"), "{}", err);
+    }
+
+    #[test]
+    fn test_module_search_path() {
+        use crate::Engine;
+        use crate::types::compiler::{CompileOptions, HeapObject};
+
+        let dir = std::env::temp_dir().join(format!("n-modules-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(
+            dir.join("lib/shapes.n"),
+            "import \"shapes\"\nfunc area(w, h) { w * h }\nlet unit = 1",
+        )
+        .unwrap();
+
+        let mut engine = Engine::with_options(CompileOptions {
+            module_paths: vec![dir.join("lib")],
+            ..CompileOptions::default()
+        });
+        // Importing twice, and the module importing itself, compile it only once
+        engine.eval("import \"shapes\"\nimport \"shapes\"").unwrap();
+        assert_eq!(
+            engine.eval("area(2, 3) + unit"),
+            Ok(Some(HeapObject::Number(7.0)))
+        );
+
+        let err = engine.eval("import \"missing\"").unwrap_err().to_string();
+        assert!(err.contains("Module 'missing' not found"), "{}", err);
+        assert!(
+            err.contains(&dir.join("lib").join("missing.n").display().to_string()),
+            "{}",
+            err
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_shebang_scripts() {
+        use crate::lexer::Lexer;
+        use crate::runtime::{RunOptions, run_file};
+        use crate::types::token::Token;
+
+        let tokens = Lexer::new("#!/usr/bin/env -S n --any-extension\nlet x = 1").tokenize();
+        assert_eq!(&tokens[..2], &[Token::Newline, Token::Let]);
+        // Only a first line is a shebang
+        let tokens = Lexer::new("\n#!x").tokenize();
+        assert!(tokens.contains(&Token::Hash));
+
+        let dir = std::env::temp_dir().join(format!("n-shebang-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("script");
+        std::fs::write(&script, "#!/usr/bin/env n\nfunc main() { 3 }").unwrap();
+        let script = script.to_str().unwrap();
+
+        let err = run_file(script, &mut RunOptions::default()).unwrap_err();
+        assert!(err.contains("--any-extension"), "{}", err);
+        let mut options = RunOptions {
+            any_extension: true,
+            ..RunOptions::default()
+        };
+        assert_eq!(run_file(script, &mut options), Ok(Some(3)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_debug_output() {
+        use crate::debug::{write_bytecode, write_tokens};
+        use crate::interpreter::VirtualMachine;
+        use crate::lexer::Lexer;
+        use crate::runtime::compile_source;
+        use std::sync::{Arc, Mutex};
+
+        let mut dump = Vec::new();
+        write_tokens(&mut dump, &Lexer::new("let x = 1").tokenize()).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        assert!(dump.contains("  0: Let\n"), "{}", dump);
+
+        let (bytecode, compiler) = compile_source("IO.print(1 + 2)").unwrap();
+        let mut dump = Vec::new();
+        write_bytecode(&mut dump, &bytecode).unwrap();
+        assert!(
+            String::from_utf8(dump)
+                .unwrap()
+                .contains("--- Instructions ---\nJUMP")
+        );
+
+        // The stack dump goes to its own sink, never the program's output
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let debug = Arc::new(Mutex::new(Vec::new()));
+        let mut vm = VirtualMachine::new(bytecode, compiler);
+        vm.set_output(Box::new(Capture(output.clone())));
+        vm.set_debug_output(Box::new(Capture(debug.clone())));
+        vm.run().unwrap();
+        vm.debug_stack().unwrap();
+        assert_eq!(&*output.lock().unwrap(), b"3\n");
+        let debug = String::from_utf8(debug.lock().unwrap().clone()).unwrap();
+        assert!(debug.starts_with("=== VM DEBUG ===\nPC: "), "{}", debug);
+    }
+
+    #[test]
+    fn test_run_source() {
+        use crate::runtime::{RunOptions, render_source_error, run_source};
+        use std::sync::{Arc, Mutex};
+
+        let mut options = RunOptions::default();
+        assert_eq!(
+            run_source("func main() { 4 }", "<eval>", &mut options),
+            Ok(Some(4))
+        );
+        assert_eq!(run_source("let x = 1", "<stdin>", &mut options), Ok(None));
+
+        let source = "let x = 1\nlet y = nope";
+        let err = run_source(source, "<eval>", &mut options).unwrap_err();
+        let rendered = render_source_error(source, "<eval>", &err);
+        assert!(rendered.contains("<eval>:2:9"), "{}", rendered);
+        assert!(rendered.contains("let y = nope"), "{}", rendered);
+
+        // Embedders get everything a run writes through the two sinks
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let err = Arc::new(Mutex::new(Vec::new()));
+        let mut options = RunOptions {
+            debug: true,
+            echo: true,
+            out: Box::new(Capture(out.clone())),
+            err: Box::new(Capture(err.clone())),
+            ..RunOptions::default()
+        };
+        let source = "func f() { let unused = 1\n 2 }\nprint(\"hi\")\nf() + 1";
+        assert_eq!(run_source(source, "<eval>", &mut options), Ok(None));
+        assert_eq!(&*out.lock().unwrap(), b"hi\n3\n");
+        let err = String::from_utf8(err.lock().unwrap().clone()).unwrap();
+        for part in [
+            "--- Source Code ---",
+            "--- AST ---",
+            "warning[W0002]",
+            "=== VM DEBUG ===",
+        ] {
+            assert!(err.contains(part), "{}: {}", part, err);
+        }
+
+        // Print returns null, which is never echoed, so a value is not shown
+        // twice however the print is reached
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let mut options = RunOptions {
+            echo: true,
+            out: Box::new(Capture(out.clone())),
+            ..RunOptions::default()
+        };
+        for source in [
+            "print(\"once\")",
+            "IO.print(7)",
+            "5 |> print",
+            "func show(x) { print(x) }\nshow(6)",
+            "if true { print(8) } else { print(0) }",
+        ] {
+            run_source(source, "<eval>", &mut options).unwrap();
+        }
+        assert_eq!(&*out.lock().unwrap(), b"once\n7\n5\n6\n8\n");
+    }
+
+    #[test]
+    fn test_project_build() {
+        use crate::bytecode::decode;
+        use crate::interpreter::VirtualMachine;
+        use crate::manifest::Manifest;
+        use crate::runtime::{build_project, compile_source};
+
+        let dir = std::env::temp_dir().join(format!("n-project-test-{}", std::process::id()));
+        let app = dir.join("app");
+        let utils = dir.join("utils");
+        std::fs::create_dir_all(app.join("src")).unwrap();
+        std::fs::create_dir_all(&utils).unwrap();
+        std::fs::write(
+            app.join("n.toml"),
+            "# the app\n[package]\nname = \"app\"\nentry = \"src/main.n\"\nmodule_dirs = [\"src\"]\n\n[dependencies]\nutils = { path = \"../utils\" }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            app.join("src/main.n"),
+            "import \"shapes\"\nlet result = area(6, 7)",
+        )
+        .unwrap();
+        std::fs::write(
+            app.join("src/shapes.n"),
+            "import \"math\"\nfunc area(w, h) { times(w, h) }",
+        )
+        .unwrap();
+        std::fs::write(
+            utils.join("n.toml"),
+            "[package]\nname = \"utils\"\nentry = \"math.n\"\n",
+        )
+        .unwrap();
+        std::fs::write(utils.join("math.n"), "func times(a, b) { a * b }").unwrap();
+
+        let manifest = Manifest::load(&app).unwrap();
+        assert_eq!(manifest.name, "app");
+        assert_eq!(
+            manifest.module_paths().unwrap(),
+            vec![app.join("src"), app.join("../utils")]
+        );
+
+        let written = build_project(app.to_str().unwrap(), None).unwrap();
+        let bytecode = decode(&std::fs::read(&written).unwrap()).unwrap();
+        let (_, compiler) = compile_source("").unwrap();
+        let result = 0;
+        let mut vm = VirtualMachine::new(bytecode, compiler);
+        vm.run().unwrap();
+        assert_eq!(
+            vm.global(result).map(|v| v.to_string()),
+            Some("42".to_string())
+        );
+
+        std::fs::write(utils.join("n.toml"), "[package]\nentry = \"math.n\"\n").unwrap();
+        let err = build_project(app.to_str().unwrap(), None).unwrap_err();
+        assert!(err.contains("Missing 'name'"), "{}", err);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_module_cache() {
+        use crate::cache::{ModuleCache, content_hash};
+        use crate::compiler::Compiler;
+        use crate::runtime::compile_with;
+        use crate::types::compiler::CompileOptions;
+        use std::sync::Arc;
+
+        let dir = std::env::temp_dir().join(format!("n-cache-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("shapes.n"), "func area(w, h) { w * h }").unwrap();
+        let options = CompileOptions {
+            module_paths: vec![dir.clone()],
+            ..CompileOptions::default()
+        };
+
+        let cache = ModuleCache::new();
+        let compile = |source: &str| {
+            let mut compiler = Compiler::with_options(options.clone());
+            compiler.module_cache = cache.clone();
+            compile_with(&mut compiler, source)
+        };
+
+        // The prelude and the module are parsed once, then reused
+        let first = compile("import \"shapes\"\narea(2, 3)").unwrap();
+        assert_eq!((cache.len(), cache.hits()), (2, 0));
+        let second = compile("import \"shapes\"\narea(2, 3)").unwrap();
+        assert_eq!((cache.len(), cache.hits()), (2, 2));
+        assert_eq!(first, second);
+
+        // Edited modules are parsed again
+        std::fs::write(dir.join("shapes.n"), "func area(w, h) { w * h * 1 }").unwrap();
+        compile("import \"shapes\"").unwrap();
+        assert_eq!((cache.len(), cache.hits()), (3, 3));
+
+        assert_eq!(content_hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_ne!(content_hash("a"), content_hash("b"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // A full cache drops the module used least recently
+        let cache = ModuleCache::with_capacity(2);
+        let a = cache.parse("let a = 1").unwrap();
+        cache.parse("let b = 2").unwrap();
+        assert!(Arc::ptr_eq(&a, &cache.parse("let a = 1").unwrap()));
+        cache.parse("let c = 3").unwrap();
+        assert_eq!((cache.len(), cache.hits()), (2, 1));
+        assert!(Arc::ptr_eq(&a, &cache.parse("let a = 1").unwrap()));
+        cache.parse("let b = 2").unwrap();
+        assert_eq!((cache.len(), cache.hits()), (2, 2));
+    }
+
+    #[test]
+    fn test_precompiled_module() {
+        use crate::Engine;
+        use crate::runtime::compile_source_with;
+        use crate::types::compiler::{CompileOptions, HeapObject};
+
+        let dir = std::env::temp_dir().join(format!("n-precompiled-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let module = "import \"JSON\"
+let base = 10
+let shown = JSON.stringify([base])
+func twice(x) { x * 2 + base }
+func adder(n) { fn(x) => x + n }
+enum Shape { Circle(r), Square(s) }
+func area(shape) {
+    match shape {
+        Shape::Circle(r) -> r * r * 3
+        Shape::Square(s) -> s * s
+    }
+}";
+        let (compiled, _) = compile_source_with(module, CompileOptions::default()).unwrap();
+        assert!(
+            compiled
+                .exports
+                .natives
+                .iter()
+                .any(|name| name == "JSON.stringify")
+        );
+        std::fs::write(
+            dir.join("utils.nb"),
+            crate::bytecode::encode(&compiled).unwrap(),
+        )
+        .unwrap();
+
+        let mut engine = Engine::with_options(CompileOptions {
+            module_paths: vec![dir.clone()],
+            ..CompileOptions::default()
+        });
+        // The importer's own variables and constants come first, so every index moves
+        engine
+            .eval("let a = 1\nlet b = \"b\"\nimport \"utils.nb\"")
+            .unwrap();
+        assert_eq!(engine.eval("twice(a)"), Ok(Some(HeapObject::Number(12.0))));
+        assert_eq!(
+            engine.eval("adder(5)(base)"),
+            Ok(Some(HeapObject::Number(15.0)))
+        );
+        assert_eq!(
+            engine.eval("shown ++ b"),
+            Ok(Some(HeapObject::String("[10]b".to_string())))
+        );
+        assert_eq!(
+            engine.eval("area(Shape::Square(3)) + area(Shape::Circle(1))"),
+            Ok(Some(HeapObject::Number(12.0)))
+        );
+        assert_eq!(engine.eval("a"), Ok(Some(HeapObject::Number(1.0))));
+
+        std::fs::write(dir.join("broken.nb"), b"NB\x03\x00").unwrap();
+        let err = engine.eval("import \"broken.nb\"").unwrap_err();
+        assert!(err.to_string().contains("In module"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reload_module() {
+        use crate::Engine;
+        use crate::types::compiler::{CompileOptions, HeapObject};
+
+        let dir = std::env::temp_dir().join(format!("n-reload-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let module = dir.join("rules.n");
+        std::fs::write(
+            &module,
+            "let base = 10\nfunc bonus(x) { x + 10 }\nfunc score(x) { bonus(x) * 2 }",
+        )
+        .unwrap();
+
+        let mut engine = Engine::with_options(CompileOptions {
+            module_paths: vec![dir.clone()],
+            ..CompileOptions::default()
+        });
+        engine
+            .eval("import \"rules\"\nlet total = score(1)\nfunc run(x) { score(x) }")
+            .unwrap();
+        assert_eq!(engine.eval("run(1)"), Ok(Some(HeapObject::Number(22.0))));
+
+        // Callers compiled before the reload pick up the new bodies, globals survive
+        std::fs::write(
+            &module,
+            "let base = 1000\nfunc bonus(x) { x + 11 }\nfunc score(x) { bonus(x) * 3 }",
+        )
+        .unwrap();
+        engine.reload_module("rules").unwrap();
+        assert_eq!(engine.eval("run(1)"), Ok(Some(HeapObject::Number(36.0))));
+        assert_eq!(engine.eval("total"), Ok(Some(HeapObject::Number(22.0))));
+        assert_eq!(engine.eval("base"), Ok(Some(HeapObject::Number(10.0))));
+
+        // A broken edit is rejected and the previous definitions stay live
+        std::fs::write(&module, "func bonus(x) { x + }").unwrap();
+        assert!(engine.reload_module("rules").is_err());
+        assert_eq!(engine.eval("run(1)"), Ok(Some(HeapObject::Number(36.0))));
+        assert!(engine.reload_module("JSON").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sandboxed_compile() {
+        use crate::types::compiler::{CompileOptions, HeapObject};
+        use crate::{Engine, Error};
+        use std::collections::HashMap;
+
+        // A file the sandbox must not see, though it is on the search path
+        let dir = std::env::temp_dir().join(format!("n-sandbox-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("secret.n"), "let secret = 1").unwrap();
+
+        let modules = HashMap::from([
+            (
+                "rules".to_string(),
+                "import \"util.n\"\nfunc score(x) { twice(x) + 1 }".to_string(),
+            ),
+            ("util.n".to_string(), "func twice(x) { x * 2 }".to_string()),
+        ]);
+        let mut engine = Engine::with_options(CompileOptions {
+            module_paths: vec![dir.clone()],
+            sandbox: Some(modules),
+            ..CompileOptions::default()
+        });
+        assert_eq!(
+            engine.eval("import \"rules\"\nimport \"util\"\nscore(4)"),
+            Ok(Some(HeapObject::Number(9.0)))
+        );
+        assert_eq!(
+            engine.eval("import \"JSON\"\nJSON.stringify(1)"),
+            Ok(Some(HeapObject::String("1".to_string())))
+        );
+
+        let Err(Error::Compile(err)) = engine.eval("import \"secret\"") else {
+            panic!("a file outside the sandbox was imported");
+        };
+        assert!(
+            err.to_string()
+                .contains("Module 'secret' is not in the sandbox"),
+            "{}",
+            err
+        );
+        for source in [
+            "import \"FS\"",
+            "import \"OS\"\nOS.exec(\"cat\", [\"/etc/hostname\"])",
+            "import \"OS\"\nOS.env(\"HOME\")",
+            "import \"Http\"\nHttp.get(\"http://localhost/\")",
+            "OS.exec(\"cat\", [\"/etc/hostname\"])",
+        ] {
+            let Err(Error::Compile(err)) = engine.eval(source) else {
+                panic!("{} compiled in a sandbox", source);
+            };
+            let err = err.to_string();
+            assert!(
+                err.contains("not available in a sandbox") || err.contains("Undefined native"),
+                "{}: {}",
+                source,
+                err
+            );
+        }
+        let source = "extern \"libc.so.6\" {\n    func abs(n: int) -> int\n}";
+        assert!(matches!(engine.eval(source), Err(Error::Compile(_))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_main_entry_point() {
+        use crate::Engine;
+        use crate::types::compiler::CompileOptions;
+        use std::sync::{Arc, Mutex};
+
+        let options = CompileOptions {
+            call_main: true,
+            ..CompileOptions::default()
+        };
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Engine::with_options(options.clone());
+        engine.set_output(Box::new(Capture(buffer.clone())));
+
+        // main runs after the other top-level code and receives the arguments
+        engine
+            .eval("func main(args) { IO.print(\"main\") }\nIO.print(\"top\")")
+            .unwrap();
+        engine.eval("import \"JSON\"\nimport \"OS\"\nfunc main(args) { IO.print(JSON.stringify(args) == JSON.stringify(OS.args())) }").unwrap();
+        assert_eq!(
+            String::from_utf8(buffer.lock().unwrap().clone()).unwrap(),
+            "top\nmain\ntrue\n"
+        );
+
+        let err = Engine::with_options(options)
+            .eval("func main(a, b) { a }")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("'main' takes"), "{}", err);
+    }
+
+    #[test]
+    fn test_program_result() {
+        use crate::interpreter::VirtualMachine;
+        use crate::runtime::{compile_source_with, exit_status};
+        use crate::types::compiler::{CompileOptions, Value};
+
+        let options = CompileOptions {
+            call_main: true,
+            ..CompileOptions::default()
+        };
+        let run = |source: &str| {
+            let (bytecode, compiler) = compile_source_with(source, options.clone()).unwrap();
+            VirtualMachine::new(bytecode, compiler).run()
+        };
+
+        assert_eq!(run("func main() { 40 + 2 }"), Ok(Some(Value::Number(42.0))));
+        assert_eq!(run("IO.print(1)"), Ok(None));
+        assert_eq!(
+            run("import \"OS\"\nfunc main() { OS.exit(3)\n7 }"),
+            Ok(None)
+        );
+
+        assert_eq!(exit_status(&Value::Number(2.0)), Some(2));
+        assert_eq!(exit_status(&Value::Number(2.5)), None);
+        assert_eq!(exit_status(&Value::from("2")), None);
+    }
+
+    #[test]
+    fn test_print_builtin() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+        use std::sync::{Arc, Mutex};
+
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Engine::new();
+        engine.set_output(Box::new(Capture(buffer.clone())));
+        assert_eq!(
+            engine.eval("print(\"fib: \" ++ 55)"),
+            Ok(Some(HeapObject::Null))
+        );
+        engine.eval("print([1, 2])").unwrap();
+        assert_eq!(
+            String::from_utf8(buffer.lock().unwrap().clone()).unwrap(),
+            "fib: 55\n[1, 2]\n"
+        );
+    }
+
+    #[test]
+    fn test_composite_equality() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+        use std::collections::HashMap;
+
+        let mut engine = Engine::new();
+        let check = |engine: &mut Engine, source: &str, expected: bool| {
+            assert_eq!(
+                engine.eval(source),
+                Ok(Some(HeapObject::Boolean(expected))),
+                "{}",
+                source
+            );
+        };
+
+        check(&mut engine, "true == true", true);
+        check(&mut engine, "true != false", true);
+        check(&mut engine, "1 == true", false);
+        check(&mut engine, "[1, [2, \"a\"]] == [1, [2, \"a\"]]", true);
+        check(&mut engine, "[1, 2] != [1, 3]", true);
+        for (name, value) in [("a", 1.0), ("same", 1.0), ("other", 2.0)] {
+            engine.set_global(name, HashMap::from([("key".to_string(), vec![value])]));
+        }
+        check(&mut engine, "a == same", true);
+        check(&mut engine, "a == other", false);
+        let long = "x".repeat(40);
+        check(&mut engine, &format!("\"{0}\" == \"{0}\"", long), true);
+
+        check(&mut engine, "\"apple\" < \"banana\"", true);
+        check(&mut engine, "[1, 2] < [1, 3]", true);
+        check(&mut engine, "[1, 2, 0] > [1, 2]", true);
+        check(&mut engine, "2 <= 2", true);
+        check(&mut engine, "3 >= 4", false);
+        assert!(engine.eval("1 < \"2\"").is_err());
+        assert!(engine.eval("true < false").is_err());
+    }
+
+    #[test]
+    fn test_logical_short_circuit() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+
+        let mut engine = Engine::new();
+        engine.set_global("zero", HeapObject::Number(0.0));
+        engine.set_global("five", HeapObject::Number(5.0));
+        let check = |engine: &mut Engine, source: &str, expected: bool| {
+            assert_eq!(
+                engine.eval(source),
+                Ok(Some(HeapObject::Boolean(expected))),
+                "{}",
+                source
+            );
+        };
+
+        // The right side would divide by zero if it ran
+        check(&mut engine, "zero != 0 && 10 / zero > 1", false);
+        check(&mut engine, "zero == 0 || 10 / zero > 1", true);
+        check(&mut engine, "five != 0 && 10 / five > 1", true);
+        check(&mut engine, "false || five < 3", false);
+        // && binds tighter than ||
+        check(&mut engine, "true || false && false", true);
+        check(&mut engine, "(true || false) && false", false);
+
+        let err = engine.eval("1 && true").unwrap_err().to_string();
+        assert!(
+            err.contains("Condition must be a boolean, got number"),
+            "{}",
+            err
+        );
+        assert!(engine.eval("true && \"yes\"").is_err());
+    }
+
+    #[test]
+    fn test_if_expression() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+        use std::sync::{Arc, Mutex};
+
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Engine::new();
+        engine.set_output(Box::new(Capture(buffer.clone())));
+        engine
+            .eval(
+                "func sign(n) {\n    if n < 0 { -1 } else if n == 0 { 0 }\n    else {\n        print(\"positive\")\n        1\n    }\n}",
+            )
+            .unwrap();
+        assert_eq!(engine.eval("sign(-5)"), Ok(Some(HeapObject::Number(-1.0))));
+        assert_eq!(engine.eval("sign(0)"), Ok(Some(HeapObject::Number(0.0))));
+        assert_eq!(
+            engine.eval("sign(3) * 10"),
+            Ok(Some(HeapObject::Number(10.0)))
+        );
+        assert_eq!(
+            engine.eval("let label = if true { \"yes\" } else { \"no\" }\nlabel"),
+            Ok(Some(HeapObject::from("yes")))
+        );
+
+        // Without else, an if only runs for its effect
+        assert_eq!(engine.eval("if 1 > 0 { print(\"ran\") }"), Ok(None));
+        engine.eval("if 1 < 0 { print(\"skipped\") }\n2").unwrap();
+        assert_eq!(
+            String::from_utf8(buffer.lock().unwrap().clone()).unwrap(),
+            "positive\nran\n"
+        );
+
+        let err = engine
+            .eval("let x = if true { 1 }")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("without 'else'"), "{}", err);
+        let err = engine
+            .eval("let y = if true { let z = 1 } else { 2 }")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("must end with an expression"), "{}", err);
+        assert!(engine.eval("if 1 { 2 } else { 3 }").is_err());
+    }
+
+    #[test]
+    fn test_pipeline_threading() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+
+        let mut engine = Engine::new();
+        engine
+            .eval("func sub(a, b) { a - b }\nfunc double(x) { x * 2 }")
+            .unwrap();
+        // The piped value becomes the first argument
+        assert_eq!(
+            engine.eval("10 |> sub(3)"),
+            Ok(Some(HeapObject::Number(7.0)))
+        );
+        assert_eq!(
+            engine.eval("1 |> double |> sub(5) |> double"),
+            Ok(Some(HeapObject::Number(-6.0)))
+        );
+        assert_eq!(
+            engine.eval("\"a\" |> join(\"b\") |> print"),
+            Ok(Some(HeapObject::Null))
+        );
+        // A lambda is called with the piped value, with or without parentheses
+        assert_eq!(
+            engine.eval("5 |> fn(x) => x * 2"),
+            Ok(Some(HeapObject::Number(10.0)))
+        );
+        assert_eq!(
+            engine.eval("5 |> (fn(x) => x * 2) |> double"),
+            Ok(Some(HeapObject::Number(20.0)))
+        );
+        assert!(engine.eval("1 |> 2").is_err());
+    }
+
+    #[test]
+    fn test_currying() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+
+        let mut engine = Engine::new();
+        engine
+            .eval("func add3(a, b, c) { a * 100 + b * 10 + c }\nfunc apply(f, x) { f(x) }")
+            .unwrap();
+        engine
+            .eval("let add1 = add3(1)\nlet add1and2 = add1(2)")
+            .unwrap();
+        assert_eq!(
+            engine.eval("add1and2(3)"),
+            Ok(Some(HeapObject::Number(123.0)))
+        );
+        assert_eq!(
+            engine.eval("add1(4, 5)"),
+            Ok(Some(HeapObject::Number(145.0)))
+        );
+        assert_eq!(
+            engine.eval("add3(7)(8)(9)"),
+            Ok(Some(HeapObject::Number(789.0)))
+        );
+        // Closures can be passed around and piped into
+        assert_eq!(
+            engine.eval("apply(add1and2, 6)"),
+            Ok(Some(HeapObject::Number(126.0)))
+        );
+        assert_eq!(
+            engine.eval("6 |> add1and2"),
+            Ok(Some(HeapObject::Number(126.0)))
+        );
+        engine.eval("let waiting = 5 |> add3(2)").unwrap();
+        assert_eq!(
+            engine.eval("waiting(1)"),
+            Ok(Some(HeapObject::Number(521.0)))
+        );
+        // Naming a function without calling it gives a closure too
+        assert_eq!(
+            engine.eval("apply(square, 3)"),
+            Ok(Some(HeapObject::Number(9.0)))
+        );
+
+        let err = engine.eval("add3(1, 2, 3, 4)").unwrap_err().to_string();
+        assert!(err.contains("expects 3 argument(s), got 4"), "{}", err);
+        assert!(engine.eval("add1(1, 2, 3)").is_err());
+        assert!(engine.eval("let n = 1\nn(2)").is_err());
+    }
+
+    #[test]
+    fn test_list_methods() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+
+        let mut engine = Engine::new();
+        let show = |engine: &mut Engine, source: &str| {
+            let value = engine.eval(source).unwrap().unwrap();
+            engine.display(&value)
+        };
+        assert_eq!(
+            show(&mut engine, "[1, 2, 3].map(fn(x) => x * 2)"),
+            "[2, 4, 6]"
+        );
+        assert_eq!(
+            show(&mut engine, "[1, 2, 3, 4].filter(fn(x) -> x > 2)"),
+            "[3, 4]"
+        );
+        assert_eq!(
+            engine.eval("[1, 2, 3].reduce(fn(acc, x) => acc + x, 10)"),
+            Ok(Some(HeapObject::Number(16.0)))
+        );
+        assert_eq!(
+            engine.eval("[1, 2].length()"),
+            Ok(Some(HeapObject::Number(2.0)))
+        );
+        // Lambdas capture the variables they use
+        engine.eval("let factor = 3").unwrap();
+        assert_eq!(
+            show(&mut engine, "[1, 2] |> map(fn(x) => x * factor)"),
+            "[3, 6]"
+        );
+        assert_eq!(
+            show(&mut engine, "let xs = [5, 6]\nfilter(xs, fn(x) => x == 6)"),
+            "[6]"
+        );
+        // Named functions and closures work as callbacks
+        engine.eval("func inc(x) { x + 1 }").unwrap();
+        assert_eq!(show(&mut engine, "[1].map(inc)"), "[2]");
+
+        let err = engine
+            .eval("[1].filter(fn(x) => x)")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("must return a boolean"), "{}", err);
+        assert!(engine.eval("5.map(inc)").is_err());
+        assert!(engine.eval("[1].shuffle()").is_err());
+    }
+
+    #[test]
+    fn test_spread() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+
+        let mut engine = Engine::new();
+        let show = |engine: &mut Engine, source: &str| {
+            let value = engine.eval(source).unwrap().unwrap();
+            engine.display(&value)
+        };
+        engine.eval("let rest = [2, 3]").unwrap();
+        assert_eq!(show(&mut engine, "[1, ...rest, 9]"), "[1, 2, 3, 9]");
+        assert_eq!(show(&mut engine, "[...rest, ...rest]"), "[2, 3, 2, 3]");
+        assert_eq!(show(&mut engine, "[...[]]"), "[]");
+
+        engine
+            .eval("func add3(a, b, c) { a * 100 + b * 10 + c }")
+            .unwrap();
+        assert_eq!(
+            engine.eval("add3(1, ...rest)"),
+            Ok(Some(HeapObject::Number(123.0)))
+        );
+        assert_eq!(
+            engine.eval("add3(...[4, 5, 6])"),
+            Ok(Some(HeapObject::Number(456.0)))
+        );
+        // Too few arguments curry, as with a plain call
+        assert_eq!(
+            engine.eval("let f = add3(...rest)\nf(4)"),
+            Ok(Some(HeapObject::Number(234.0)))
+        );
+        assert_eq!(
+            engine.eval("let g = fn(a, b) => a - b\ng(...[5, 3])"),
+            Ok(Some(HeapObject::Number(2.0)))
+        );
+
+        let err = engine
+            .eval("add3(...[1, 2, 3, 4])")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("expects 3 argument(s), got 4"), "{}", err);
+        assert!(engine.eval("add3(...5)").is_err());
+        assert!(engine.eval("[1, ...2]").is_err());
+        assert!(engine.eval("print(...rest)").is_err());
+        assert!(engine.eval("let x = ...rest").is_err());
+    }
+
+    #[test]
+    fn test_default_and_named_arguments() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+
+        let mut engine = Engine::new();
+        engine
+            .eval("func greet(name, greeting = \"Hello\", mark = \"!\") { greeting ++ \" \" ++ name ++ mark }")
+            .unwrap();
+        assert_eq!(
+            engine.eval("greet(\"Ada\")"),
+            Ok(Some(HeapObject::from("Hello Ada!")))
+        );
+        assert_eq!(
+            engine.eval("greet(\"Ada\", \"Hi\")"),
+            Ok(Some(HeapObject::from("Hi Ada!")))
+        );
+        assert_eq!(
+            engine.eval("greet(mark = \"?\", name = \"Ada\")"),
+            Ok(Some(HeapObject::from("Hello Ada?")))
+        );
+        assert_eq!(
+            engine.eval("\"Ada\" |> greet(greeting = \"Hey\")"),
+            Ok(Some(HeapObject::from("Hey Ada!")))
+        );
+
+        engine.eval("func scale(x, by = -2) { x * by }").unwrap();
+        assert_eq!(engine.eval("scale(3)"), Ok(Some(HeapObject::Number(-6.0))));
+        // Leaving out a parameter without a default still curries, and the
+        // closure waits for every parameter
+        engine.eval("func sub(a, b, c = 0) { a - b - c }").unwrap();
+        assert_eq!(engine.eval("sub(10, 4)"), Ok(Some(HeapObject::Number(6.0))));
+        assert_eq!(
+            engine.eval("sub(10)(4, 1)"),
+            Ok(Some(HeapObject::Number(5.0)))
+        );
+
+        let err = engine
+            .eval("greet(greeting = \"Hi\")")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Missing argument 'name'"), "{}", err);
+        let err = engine.eval("greet(nme = \"Ada\")").unwrap_err().to_string();
+        assert!(err.contains("has no parameter 'nme'"), "{}", err);
+        assert!(engine.eval("greet(name = \"A\", \"B\")").is_err());
+        assert!(engine.eval("greet(\"A\", name = \"B\")").is_err());
+        assert!(engine.eval("greet(\"A\", \"B\", \"C\", \"D\")").is_err());
+        assert!(engine.eval("func bad(a = 1, b) { a }").is_err());
+        assert!(engine.eval("func bad(a = [1]) { a }").is_err());
+        assert!(engine.eval("print(x = 1)").is_err());
+    }
+
+    #[test]
+    fn test_variadic_functions() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+
+        let mut engine = Engine::new();
+        let show = |engine: &mut Engine, source: &str| {
+            let value = engine.eval(source).unwrap().unwrap();
+            engine.display(&value)
+        };
+        engine
+            .eval("func sum(...nums) { nums.reduce(fn(a, b) => a + b, 0) }")
+            .unwrap();
+        assert_eq!(engine.eval("sum()"), Ok(Some(HeapObject::Number(0.0))));
+        assert_eq!(
+            engine.eval("sum(1, 2, 3)"),
+            Ok(Some(HeapObject::Number(6.0)))
+        );
+        assert_eq!(
+            engine.eval("sum(...[4, 5], 6)"),
+            Ok(Some(HeapObject::Number(15.0)))
+        );
+
+        engine
+            .eval("func tag(label, sep = \":\", ...items) { [label, sep, items.length()] }")
+            .unwrap();
+        assert_eq!(show(&mut engine, "tag(\"a\")"), "[\"a\", \":\", 0]");
+        assert_eq!(
+            show(&mut engine, "tag(\"a\", \"-\", 1, 2)"),
+            "[\"a\", \"-\", 2]"
+        );
+        // Closures collect the surplus too, once the fixed parameters are bound
+        assert_eq!(
+            show(&mut engine, "let t = tag\nt(\"b\", \"=\", 7)"),
+            "[\"b\", \"=\", 1]"
+        );
+        engine
+            .eval("func pair(first, ...others) { others }")
+            .unwrap();
+        assert_eq!(show(&mut engine, "let p = pair()\np(1, 2, 3)"), "[2, 3]");
+        assert_eq!(show(&mut engine, "[1, 2].map(pair)"), "[[], []]");
+
+        assert!(engine.eval("func bad(...a, b) { a }").is_err());
+        assert!(engine.eval("func bad(...) { 1 }").is_err());
+    }
+
+    #[test]
+    fn test_recursion_limit() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+
+        let mut engine = Engine::new();
+        engine
+            .eval("func down(n) { if n == 0 { 0 } else { 1 + down(n - 1) } }\nfunc forever(n) { forever(n + 1) }")
+            .unwrap();
+        assert_eq!(
+            engine.eval("down(500)"),
+            Ok(Some(HeapObject::Number(500.0)))
+        );
+
+        let err = engine.eval("forever(0)").unwrap_err().to_string();
+        assert!(
+            err.contains("Maximum recursion depth exceeded (1000)"),
+            "{}",
+            err
+        );
+        // Callbacks count towards the depth as well
+        let err = engine
+            .eval("func nest(n) { [n].map(nest) }\nnest(1)")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Maximum recursion depth exceeded"), "{}", err);
+
+        // The engine is usable again and the limit can be changed
+        engine.set_max_call_depth(100);
+        assert!(engine.eval("down(200)").is_err());
+        assert_eq!(engine.eval("down(50)"), Ok(Some(HeapObject::Number(50.0))));
+    }
+
+    #[test]
+    fn test_execution_limits() {
+        use crate::types::compiler::HeapObject;
+        use crate::{Engine, Error, VmLimits};
+        use std::time::Duration;
+
+        let mut engine = Engine::new();
+        engine
+            .eval("func down(n) { if n == 0 { 0 } else { 1 + down(n - 1) } }\nfunc grow(xs, n) { if n == 0 { xs.length() } else { grow(xs <- [n, n, n, n], n - 1) } }")
+            .unwrap();
+
+        engine.set_limits(VmLimits {
+            max_instructions: Some(1000),
+            ..VmLimits::default()
+        });
+        assert_eq!(engine.eval("down(10)"), Ok(Some(HeapObject::Number(10.0))));
+        let err = engine.eval("down(900)").unwrap_err();
+        assert!(matches!(err, Error::LimitExceeded(_)), "{:?}", err);
+        assert!(
+            err.to_string()
+                .contains("Instruction limit exceeded (1000)")
+        );
+        // The budget is per call and ordinary errors stay runtime errors
+        assert_eq!(engine.eval("down(20)"), Ok(Some(HeapObject::Number(20.0))));
+        assert!(matches!(engine.eval("1 / true"), Err(Error::Runtime(_))));
+
+        engine.set_limits(VmLimits {
+            wall_clock_timeout: Some(Duration::ZERO),
+            ..VmLimits::default()
+        });
+        let err = engine.eval("down(900)").unwrap_err();
+        assert!(matches!(err, Error::LimitExceeded(_)), "{:?}", err);
+
+        engine.set_limits(VmLimits {
+            max_heap_bytes: Some(10_000),
+            ..VmLimits::default()
+        });
+        assert_eq!(
+            engine.eval("grow([], 10)"),
+            Ok(Some(HeapObject::Number(40.0)))
+        );
+        let err = engine.eval("grow([], 500)").unwrap_err();
+        assert!(err.to_string().contains("Heap limit exceeded"), "{}", err);
+
+        engine.set_limits(VmLimits::default());
+        assert_eq!(
+            engine.eval("grow([], 500)"),
+            Ok(Some(HeapObject::Number(2000.0)))
+        );
+
+        // Callbacks are held to the heap limit too, and collecting garbage
+        // while one runs keeps what the callback captured
+        engine
+            .eval("let upto = fn(limit) => unfold(1, fn(n) => if n > limit { [] } else { [n, n + 1] })\nlet tag = [\"x\", \"y\"]")
+            .unwrap();
+        let source = "upto(3000).reduce(fn(acc, n) => acc <- [tag.length() + n], []).length()";
+        assert_eq!(engine.eval(source), Ok(Some(HeapObject::Number(3000.0))));
+        engine.set_limits(VmLimits {
+            max_heap_bytes: Some(64 * 1024),
+            ..VmLimits::default()
+        });
+        let source = "upto(200000).reduce(fn(acc, n) => acc <- [n], []).length()";
+        let err = engine.eval(source).unwrap_err();
+        assert!(matches!(err, Error::LimitExceeded(_)), "{:?}", err);
+        assert!(err.to_string().contains("Heap limit exceeded"), "{}", err);
+    }
+
+    #[test]
+    fn test_cancellation() {
+        use crate::types::compiler::{HeapObject, Value};
+        use crate::{Engine, Error};
+        use std::sync::{Arc, Mutex};
+
+        let mut engine = Engine::new();
+        let token = engine.cancellation_token();
+        engine.register_fn("stop", move |_| {
+            token.cancel();
+            Ok(Value::Boolean(true))
+        });
+        let output = Arc::new(Mutex::new(Vec::new()));
+        engine.set_output(Box::new(Capture(output.clone())));
+        assert_eq!(engine.eval("stop()\nprint(1)"), Err(Error::Cancelled));
+        assert!(output.lock().unwrap().is_empty());
+
+        // A request from another thread stops the next instruction, once
+        let token = engine.cancellation_token();
+        std::thread::spawn(move || token.cancel()).join().unwrap();
+        assert!(engine.cancellation_token().is_cancelled());
+        assert_eq!(engine.eval("1 + 1"), Err(Error::Cancelled));
+        assert_eq!(engine.eval("1 + 1"), Ok(Some(HeapObject::Number(2.0))));
+    }
+
+    #[test]
+    fn test_sleep_stops_with_run() {
+        use crate::{Engine, Error, VmLimits};
+        use std::time::{Duration, Instant};
+
+        let mut engine = Engine::new();
+        engine.eval("import \"Time\"").unwrap();
+        engine.set_limits(VmLimits {
+            wall_clock_timeout: Some(Duration::from_millis(50)),
+            ..VmLimits::default()
+        });
+        let started = Instant::now();
+        assert_eq!(
+            engine.eval("Time.sleep(60000)"),
+            Err(Error::LimitExceeded(
+                "[line 1] Timed out after 50ms".to_string()
+            ))
+        );
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        engine.set_limits(VmLimits::default());
+        let token = engine.cancellation_token();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            token.cancel();
+        });
+        let started = Instant::now();
+        assert_eq!(engine.eval("Time.sleep(60000)"), Err(Error::Cancelled));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        canceller.join().unwrap();
+    }
+
+    #[test]
+    fn test_engine_spawn() {
+        use crate::Engine;
+        use crate::types::compiler::{HeapObject, Value};
+
+        let mut engine = Engine::new();
+        engine.register_fn("host_double", |args| match args {
+            [Value::Number(n)] => Ok(Value::Number(n * 2.0)),
+            _ => Err("expected a number".to_string()),
+        });
+        engine.eval("let local = 1").unwrap();
+
+        let handles: Vec<_> = (1..=4)
+            .map(|i| engine.spawn(&format!("func sq(x) {{ x * x }}\nhost_double(sq({}))", i)))
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(
+            results,
+            vec![
+                Ok(Some(HeapObject::Number(2.0))),
+                Ok(Some(HeapObject::Number(8.0))),
+                Ok(Some(HeapObject::Number(18.0))),
+                Ok(Some(HeapObject::Number(32.0))),
+            ]
+        );
+        assert_eq!(
+            engine.spawn("[1, 2]").join().unwrap(),
+            Ok(Some(HeapObject::Array(
+                vec![HeapObject::Number(1.0), HeapObject::Number(2.0)].into()
+            )))
+        );
+        // Spawned scripts start fresh
+        assert!(engine.spawn("local").join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_tasks() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+        use std::sync::{Arc, Mutex};
+
+        let mut engine = Engine::new();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        engine.set_output(Box::new(Capture(output.clone())));
+        assert_eq!(
+            engine.eval("let t = Task.spawn(fn() => 6 * 7)\nTask.join(t)"),
+            Ok(Some(HeapObject::Number(42.0)))
+        );
+        // Tasks run in spawn order, whichever is joined first
+        let source = "let a = Task.spawn(fn() => print(\"a\"))
+let b = Task.spawn(fn() => print(\"b\"))
+Task.join(b)
+print(\"c\")";
+        engine.eval(source).unwrap();
+        assert_eq!(
+            String::from_utf8(output.lock().unwrap().clone()).unwrap(),
+            "a\nb\nc\n"
+        );
+
+        let value = engine
+            .eval("Task.all([Task.spawn(fn() => 1), Task.spawn(fn() => \"two\")])")
+            .unwrap()
+            .unwrap();
+        assert_eq!(engine.display(&value), "[1, \"two\"]");
+
+        // A failed task raises its error where it is joined
+        let err = engine
+            .eval("let bad = Task.spawn(fn() => 1 + true)\nTask.join(bad)")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Task") && err.contains("failed"), "{}", err);
+        let err = engine
+            .eval("Task.all([Task.spawn(fn() => 1), Task.spawn(fn() => 1 + true)])")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("failed"), "{}", err);
+        // Tasks nobody joins still run, and their failures fail the program
+        output.lock().unwrap().clear();
+        assert!(engine.eval("Task.spawn(fn() => 1 + true)\n1").is_err());
+        engine.eval("Task.spawn(fn() => print(\"late\"))").unwrap();
+        assert_eq!(
+            String::from_utf8(output.lock().unwrap().clone()).unwrap(),
+            "late\n"
+        );
+
+        let err = engine.eval("Task.join(1)").unwrap_err().to_string();
+        assert!(err.contains("expects a task"), "{}", err);
+    }
+
+    #[test]
+    fn test_channels() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+
+        let mut engine = Engine::new();
+        let source = "let ch = Channel.new()
+Task.spawn(fn() => ch.send(20))
+Task.spawn(fn() => send(ch, 22))
+ch.recv() + recv(ch)";
+        assert_eq!(engine.eval(source), Ok(Some(HeapObject::Number(42.0))));
+
+        // Values come out in the order they were sent
+        let source = "let ordered = Channel.new()
+ordered.send(\"a\")
+ordered.send(\"b\")
+ordered.close()
+[ordered.recv(), ordered.recv()]";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(engine.display(&value), "[\"a\", \"b\"]");
+
+        let err = engine.eval("ordered.recv()").unwrap_err().to_string();
+        assert!(err.contains("closed"), "{}", err);
+        let err = engine.eval("ordered.send(1)").unwrap_err().to_string();
+        assert!(err.contains("closed"), "{}", err);
+        let err = engine.eval("Channel.new().recv()").unwrap_err().to_string();
+        assert!(err.contains("Deadlock"), "{}", err);
+    }
+
+    #[test]
+    fn test_generators() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+        use std::sync::{Arc, Mutex};
+
+        let mut engine = Engine::new();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        engine.set_output(Box::new(Capture(output.clone())));
+        let source = "func count(n) {
+    yield 1
+    yield 2
+    yield n
+}
+let g = count(3)
+[g.next(), next(g), g.next(), g.done()]";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(engine.display(&value), "[1, 2, 3, true]");
+        let err = engine.eval("g.next()").unwrap_err().to_string();
+        assert!(err.contains("no more values"), "{}", err);
+
+        // Loops pull one value at a time from the generator
+        let source = "func noisy(xs) {
+    for x in xs {
+        print(\"made \" ++ x)
+        yield x * 10
+    }
+}
+for y in noisy([1, 2]) {
+    print(\"got \" ++ y)
+}";
+        engine.eval(source).unwrap();
+        assert_eq!(
+            String::from_utf8(output.lock().unwrap().clone()).unwrap(),
+            "made 1\ngot 10\nmade 2\ngot 20\n"
+        );
+
+        // Generators compose, and lists work as iterables too
+        let source = "func evens(xs) {
+    for x in xs {
+        if x / 2 == 1 || x == 4 {
+            yield x
+        }
+    }
+}
+func sum_all(items, ...rest) {
+    yield items.length() + rest.length()
+}
+[evens([1, 2, 3, 4]).next(), sum_all([1, 2], 3, 4).next()]";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(engine.display(&value), "[2, 4]");
+        assert_eq!(
+            engine.eval("iter([7]).next()"),
+            Ok(Some(HeapObject::Number(7.0)))
+        );
+
+        let err = engine.eval("yield 1").unwrap_err().to_string();
+        assert!(err.contains("'yield'"), "{}", err);
+        let err = engine.eval("for x in 5 { x }").unwrap_err().to_string();
+        assert!(err.contains("Cannot iterate over a number"), "{}", err);
+    }
+
+    #[test]
+    fn test_iteration_protocol() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+
+        let mut engine = Engine::new();
+        let source =
+            "let upto = fn(limit) => unfold(1, fn(n) => if n > limit { [] } else { [n, n + 1] })
+for n in upto(3) {
+    n * 2
+}
+upto(4) |> map(fn(n) => n * n)";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(engine.display(&value), "[1, 4, 9, 16]");
+
+        // Collection methods take generators as well as lists
+        let source = "func odds(xs) {
+    for x in xs {
+        if x != 2 {
+            yield x
+        }
+    }
+}
+[odds([1, 2, 3]).length(), odds([1, 2, 3]).reduce(fn(a, b) => a + b, 0)]";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(engine.display(&value), "[2, 4]");
+        assert_eq!(
+            engine.eval("upto(2).filter(fn(n) => n > 1).length()"),
+            Ok(Some(HeapObject::Number(1.0)))
+        );
+
+        let err = engine
+            .eval("unfold(0, fn(n) => n).next()")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("[value, next_state]"), "{}", err);
+
+        // User types with a `next` method iterate without becoming lists
+        let source = "enum Chain { Link(value, rest), End }
+impl Chain {
+    func next(self) {
+        match self {
+            Chain::Link(value, rest) -> [value, rest]
+            Chain::End -> []
+        }
+    }
+}
+struct Span { from, to }
+impl Span {
+    func next(self) {
+        if self.from >= self.to { [] } else { [self.from, self <- { from = self.from + 1 }] }
+    }
+}
+func labels(items) {
+    for item in items {
+        yield \"#\" ++ item
+    }
+}
+let chain = Chain::Link(1, Chain::Link(2, Chain::Link(3, Chain::End)))
+[labels(chain) |> map(identity), chain.map(fn(n) => n * 10), Span { from = 2, to = 5 }.reduce(fn(a, b) => a + b, 0)]";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(
+            engine.display(&value),
+            "[[\"#1\", \"#2\", \"#3\"], [10, 20, 30], 9]"
+        );
+        assert_eq!(
+            engine.eval("iter(Chain::End).done()"),
+            Ok(Some(HeapObject::Boolean(true)))
+        );
+        // Long enough for collections to run while it steps
+        assert_eq!(
+            engine.eval("Span { from = 0, to = 3000 }.length()"),
+            Ok(Some(HeapObject::Number(3000.0)))
+        );
+    }
+
+    #[test]
+    fn test_records_and_methods() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+
+        let mut engine = Engine::new();
+        let source = "struct Circle { radius }
+struct Rect { width, height }
+
+impl Circle {
+    func new(radius) {
+        Circle { radius = radius }
+    }
+    func area(self) {
+        self.radius * self.radius * 3
+    }
+    func scaled(self, factor) {
+        Circle { radius = self.radius * factor }
+    }
+}
+
+impl Rect {
+    func area(self) {
+        self.width * self.height
+    }
+}
+
+let c = Circle.new(2)
+[c.area(), Rect { height = 3, width = 4 }.area(), c.scaled(2).radius]";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(engine.display(&value), "[12, 12, 4]");
+
+        let value = engine
+            .eval("Rect { width = 1, height = 2 }")
+            .unwrap()
+            .unwrap();
+        assert_eq!(engine.display(&value), "Rect { width = 1, height = 2 }");
+        assert_eq!(
+            engine.eval("{ name = \"n\", tags = [1] }.name"),
+            Ok(Some(HeapObject::String("n".into())))
+        );
+        // Built-in methods still work on other values
+        assert_eq!(
+            engine.eval("[1, 2].length()"),
+            Ok(Some(HeapObject::Number(2.0)))
+        );
+
+        for (source, expected) in [
+            ("Circle { radius = 1, color = 2 }", "has no field 'color'"),
+            ("Rect { width = 1 }", "Missing field 'height'"),
+            ("Square { side = 1 }", "Unknown struct 'Square'"),
+            ("c.perimeter()", "'Circle' has no method 'perimeter'"),
+            ("c.diameter", "No field 'diameter'"),
+        ] {
+            let err = engine.eval(source).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn test_traits() {
+        use crate::Engine;
+
+        let mut engine = Engine::new();
+        let source = "trait Shape {
+    func area(self)
+    func name(self)
+}
+struct Square { side }
+struct Circle { radius }
+impl Shape for Square {
+    func area(self) { self.side * self.side }
+    func name(self) { \"square\" }
+}
+impl Shape for Circle {
+    func area(self) { self.radius * self.radius * 3 }
+    func name(self) { \"circle\" }
+}
+[Square { side = 2 }, Circle { radius = 1 }].map(fn(s) => s.name() ++ \" \" ++ s.area())";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(engine.display(&value), "[\"square 4\", \"circle 3\"]");
+
+        for (source, expected) in [
+            (
+                "impl Shape for Square { func area(self) { 1 } }",
+                "'Square' is missing method 'name' of trait 'Shape'",
+            ),
+            (
+                "impl Shape for Square { func area(self, x) { 1 } func name(self) { 1 } }",
+                "takes 2 parameter(s), trait 'Shape' declares 1",
+            ),
+            (
+                "impl Shape for Square { func area(self) { 1 } func name(self) { 1 } func extra(self) { 1 } }",
+                "'extra' is not a method of trait 'Shape'",
+            ),
+            ("impl Drawable for Square { }", "Unknown trait 'Drawable'"),
+        ] {
+            let err = engine.eval(source).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn test_enum_tuple_variants() {
+        use crate::Engine;
+
+        let mut engine = Engine::new();
+        let source = "enum Expr {
+    Lit(value),
+    Add(left, right),
+    Neg(inner),
+    Zero,
+}
+func eval(e) {
+    match e {
+        Expr::Lit(v) -> v
+        Expr::Add(l, r) -> eval(l) + eval(r)
+        Expr::Neg(Expr::Lit(v)) -> 0 - v
+        Expr::Neg(inner) -> 0 - eval(inner)
+        Expr::Zero -> 0
+    }
+}
+let tree = Expr::Add(Expr::Lit(2), Expr::Neg(Expr::Add(Expr::Lit(3), Expr::Zero)))
+[tree, eval(tree), match 3 { 1 -> \"one\", 3 -> \"three\", _ -> \"other\" }]";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(
+            engine.display(&value),
+            "[Expr::Add(Expr::Lit(2), Expr::Neg(Expr::Add(Expr::Lit(3), Expr::Zero))), -1, \"three\"]"
+        );
+
+        let (bytecode, _) = crate::runtime::compile_source(source).unwrap();
+        let bytes = crate::bytecode::encode(&bytecode).unwrap();
+        let decoded = crate::bytecode::decode(&bytes).unwrap();
+        assert_eq!(decoded.enums.len(), 1);
+        assert_eq!(decoded.enums[0].variants[1].field_count, 2);
+
+        for (source, expected) in [
+            ("Expr::Add(1)", "'Expr::Add' has 2 field(s), got 1"),
+            ("Expr::Mul(1, 2)", "Enum 'Expr' has no variant 'Mul'"),
+            ("match 1 { Color::Red -> 1 }", "Unknown enum 'Color'"),
+            (
+                "match Expr::Zero { Expr::Lit(x) -> x }",
+                "No pattern matched Expr::Zero",
+            ),
+        ] {
+            let err = engine.eval(source).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn test_enum_methods_and_constants() {
+        use crate::Engine;
+
+        let mut engine = Engine::new();
+        let source = "enum Color { Rgb(r, g, b) }
+impl Color {
+    let WHITE = Color::Rgb(255, 255, 255)
+    func brightness(self) {
+        match self { Color::Rgb(r, g, b) -> (r + g + b) / 3 }
+    }
+}
+enum Shape { Square(side), Circle(radius) }
+impl Shape {
+    func area(shape) {
+        match shape {
+            Shape::Square(s) -> s * s
+            Shape::Circle(r) -> 3 * r * r
+        }
+    }
+}
+func white() { Color::WHITE }
+[white(), Color::WHITE.brightness(), Shape::area(Shape::Square(3)), [Shape::Circle(1)].map(Shape::area)]";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(
+            engine.display(&value),
+            "[Color::Rgb(255, 255, 255), 255, 9, [3]]"
+        );
+
+        for (source, expected) in [
+            ("Color::WHITE()", "Constant 'Color::WHITE' cannot be called"),
+            ("Color::BLACK", "Enum 'Color' has no variant 'BLACK'"),
+            ("Palette::RED", "Unknown type 'Palette'"),
+            ("impl Palette { }", "Unknown type 'Palette' in impl"),
+        ] {
+            let err = engine.eval(source).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn test_tuples() {
+        use crate::Engine;
+
+        let mut engine = Engine::new();
+        let source =
+            "func bounds(xs) { (xs.reduce(fn(a, b) => if a < b { a } else { b }, 99), xs.length()) }
+let (lo, count) = bounds([3, 1, 4])
+let pair = (lo, (count, \"x\"))
+let (_, inner) = pair
+[pair, inner.1, (1,), (), (2), pair == (1, (3, \"x\"))]";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(
+            engine.display(&value),
+            "[(1, (3, \"x\")), \"x\", (1,), (), 2, true]"
+        );
+
+        for (source, expected) in [
+            ("let (a, b) = (1, 2, 3)", "No pattern matched (1, 2, 3)"),
+            ("let (c, d) = [1, 2]", "No pattern matched [1, 2]"),
+            ("(1, 2).2", "Cannot read field 2 of a tuple"),
+        ] {
+            let err = engine.eval(source).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn test_let_destructuring() {
+        use crate::Engine;
+
+        let mut engine = Engine::new();
+        let source = "struct Person { name, age }
+let { name, age = years } = Person { name = \"Ann\", age = 30 }
+let [first, ...rest] = [1, 2, 3]
+let [(a, b), _] = [(4, 5), 6]
+let kind = match { kind = \"dog\" } { { label } -> label, { kind = k } -> k }
+let size = match [1, 2] { [] -> 0, [one] -> 1, [h, ...t] -> 1 + t.length() }
+[name, years, first, rest, a, b, kind, size]";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(
+            engine.display(&value),
+            "[\"Ann\", 30, 1, [2, 3], 4, 5, \"dog\", 2]"
+        );
+
+        for (source, expected) in [
+            ("let [p, q] = [1]", "No pattern matched [1]"),
+            (
+                "let { missing } = { here = 1 }",
+                "No pattern matched {here: 1}",
+            ),
+            ("let [x, x] = [1, 2]", "Variable 'x' is already defined"),
+            ("let [...r, s] = [1]", "The rest pattern must come last"),
+        ] {
+            let err = engine.eval(source).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn test_block_expressions() {
+        use crate::Engine;
+
+        let mut engine = Engine::new();
+        let source = "let area = { let w = 3; let h = 4; w * h }
+let double = fn (n) => {
+    let twice = n * 2
+    twice
+}
+let empty = {}
+let point = { x = 1 }
+[area, double(5), { 7 }, empty, point.x]";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(engine.display(&value), "[12, 10, 7, {}, 1]");
+
+        let err = engine
+            .eval("let bad = { let z = 1 }")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("A block used as a value must end with an expression"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_block_scoping() {
+        use crate::Engine;
+
+        let mut engine = Engine::new();
+        let source = "let x = 1
+let y = { let x = x + 10; x * 2 }
+let z = if true { let x = 5; x } else { 0 }
+let w = match (1, 2) { (x, 2) -> x + 100, _ -> 0 }
+for x in [7, 8] { let x = x * 3 }
+let f = fn (n) => { let x = n; { let x = x + 1; x } + x }
+[x, y, z, w, f(1)]";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(engine.display(&value), "[1, 22, 5, 101, 3]");
+
+        for (source, expected) in [
+            ("if true { let inner = 1 }\ninner", "inner"),
+            ("for item in [1] { 0 }\nitem", "item"),
+            ("let v = match 1 { m -> m }\nm", "m"),
+            (
+                "let u = { let a = 1; let a = 2; a }",
+                "Variable 'a' is already defined in the current scope",
+            ),
+        ] {
+            let err = engine.eval(source).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn test_globals_in_functions() {
+        use crate::Engine;
+
+        let mut engine = Engine::new();
+        // `base` shares its slot index with the locals of `offset`
+        let source = "let base = 100
+func offset(a, b) { let c = a + b; base + c }
+func scaled() { limit * 2 }
+let limit = 21
+let adder = fn (n) => n + base
+[offset(1, 2), scaled(), adder(5)]";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(engine.display(&value), "[103, 42, 105]");
+
+        for (source, expected) in [
+            ("func broken() { nowhere }", "Undefined variable 'nowhere'"),
+            ("missing + 1", "Undefined variable 'missing'"),
+            (
+                "func early() { late }\nearly()\nlet late = 1",
+                "Undefined global 'late'",
+            ),
+        ] {
+            let err = engine.eval(source).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn test_const_declarations() {
+        use crate::Engine;
+
+        let mut engine = Engine::new();
+        let source = "const PI = 3.5
+const TAU = PI * 2
+const LABEL = \"v\" ++ 2 ++ \"!\"
+func circle(r) { TAU * r + OFFSET }
+const OFFSET = -1
+const WIDE = TAU > 6 && !false
+[TAU, LABEL, circle(2), WIDE]";
+        let value = engine.eval(source).unwrap().unwrap();
+        assert_eq!(engine.display(&value), "[7, \"v2!\", 13, true]");
+
+        for (source, expected) in [
+            (
+                "const NOW = Time.now()",
+                "Const 'NOW' must be computable at compile time",
+            ),
+            ("const HALF = 1 / 0", "Const 'HALF' divides by zero"),
+            (
+                "const MIX = 1 + \"a\"",
+                "cannot apply '+' to number and string",
+            ),
+            ("const PI = 3", "'PI' is already defined"),
+            ("let TAU = 1", "'TAU' is already defined as a const"),
+            (
+                "func inner() { const K = 1 }",
+                "only allowed at the top level",
+            ),
+        ] {
+            let err = engine.eval(source).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn test_doc_comments() {
+        use crate::doc::ModuleDoc;
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+        use crate::types::ast::Stmt;
+
+        let source = "//! Geometry helpers.
+/// Ratio of a circle's circumference to its diameter.
+const PI = 3.14159
+/// Not attached: a statement comes between.
+let scale = 2
+func bare(x) { x }
+/// Area of a circle.
+///
+/// Radius `r`, <scaled>.
+func circle(r, by = 1) { PI * r * r * by }
+func _hidden() { 0 }
+/// A point.
+struct Point { x, y }
+impl Point {
+    /// Distance from the origin.
+    func norm(self) { self.x + self.y }
+}";
+        let program = Parser::new(Lexer::new(source).tokenize()).parse().unwrap();
+        let docs: Vec<Option<&str>> = program
+            .statements
+            .iter()
+            .filter_map(|stmt| match stmt {
+                Stmt::Func { doc, .. } => Some(doc.as_deref()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            docs,
+            [
+                None,
+                Some("Area of a circle.\n\nRadius `r`, <scaled>."),
+                None
+            ]
+        );
+
+        let module = ModuleDoc::new("geo", &program);
+        let markdown = module.to_markdown();
+        for expected in [
+            "# geo\n\nGeometry helpers.",
+            "### `circle(r, by = 1)`\n\nArea of a circle.",
+            "### `bare(x)`",
+            "### `struct Point { x, y }`\n\nA point.",
+            "#### `Point.norm(self)`\n\nDistance from the origin.",
+            "### `const PI`\n\nRatio",
+        ] {
+            assert!(markdown.contains(expected), "{}\n{}", expected, markdown);
+        }
+        assert!(!markdown.contains("_hidden"));
+        assert!(
+            module
+                .to_html()
+                .contains("<p>Radius `r`, &lt;scaled&gt;.</p>")
+        );
+    }
+
+    #[test]
+    fn test_attributes() {
+        use crate::Engine;
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+        use crate::types::ast::{Attribute, Stmt};
+
+        let source = "/// Old name.
+@deprecated(\"use area\")
+@inline
+func surface(w, h) { area(w, h) }
+func area(w, h) { w * h }
+@test
+func checks_area() { area(2, 3) == 6 }";
+        let program = Parser::new(Lexer::new(source).tokenize()).parse().unwrap();
+        let Stmt::Func {
+            attributes, doc, ..
+        } = &program.statements[0]
+        else {
+            panic!("expected a function");
+        };
+        assert_eq!(
+            attributes,
+            &[
+                Attribute {
+                    name: "deprecated".into(),
+                    argument: Some("use area".into()),
+                },
+                Attribute {
+                    name: "inline".into(),
+                    argument: None,
+                },
+            ]
+        );
+        assert_eq!(doc.as_deref(), Some("Old name."));
+
+        let mut engine = Engine::new();
+        let result = engine
+            .eval(&format!(
+                "{}
+@deprecated
+enum Shape {{ Square(side) }}
+let a = surface(2, 3)
+let s = Shape::Square(1)
+let f = surface
+a",
+                source
+            ))
+            .unwrap();
+        assert_eq!(engine.display(&result.unwrap()), "6");
+        let warnings: Vec<String> = engine.warnings().iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            [
+                "'surface' is deprecated: use area (line 10)",
+                "'Shape' is deprecated (line 11)",
+                "'surface' is deprecated: use area (line 12)",
+            ]
+        );
+        engine.eval("area(1, 1)").unwrap();
+        assert!(engine.warnings().is_empty());
+
+        for (source, error) in [
+            (
+                "@pure\nfunc f() { 1 }",
+                "Unknown attribute '@pure' at line 1",
+            ),
+            (
+                "@test\nlet x = 1",
+                "Attributes can only be placed on 'func' and 'enum' declarations",
+            ),
+            (
+                "@test\nfunc t(x) { x }",
+                "Test function 't' must not take parameters",
+            ),
+            ("@test\nenum E { A }", "'@test' does not apply to enum 'E'"),
+        ] {
+            let err = Parser::new(Lexer::new(source).tokenize())
+                .parse()
+                .unwrap_err();
+            assert!(err.contains(error), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_test_runner() {
+        use crate::testing::run_tests;
+
+        let dir = std::env::temp_dir().join(format!("n_test_runner_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("math.n"),
+            "func add(a, b) { a + b }
+let base = 10
+@test
+func adds() { assert_eq(add(1, 2), 3) }
+@test
+func uses_globals() { assert(base == 10, \"base\") }
+@test
+func compares_lists() { assert_eq([1, \"a\"], [1, \"b\"]) }
+@test
+func fails_assert() { assert(add(1, 1) == 3, \"wrong sum\") }",
+        )
+        .unwrap();
+        std::fs::write(dir.join("script.n"), "IO.print(\"not a test file\")").unwrap();
+        std::fs::write(dir.join("broken.n"), "@test\nfunc t() {").unwrap();
+
+        let report = run_tests(&dir).unwrap();
+        let names: Vec<&str> = report.results.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["adds", "uses_globals", "compares_lists", "fails_assert"]
+        );
+        assert_eq!(report.passed(), 2);
+        assert!(!report.is_success());
+        let errors: Vec<&str> = report
+            .failures()
+            .map(|r| r.error.as_deref().unwrap())
+            .collect();
+        assert!(
+            errors[0].contains("left [1, \"a\"] is not equal to right [1, \"b\"]"),
+            "{}",
+            errors[0]
+        );
+        assert!(
+            errors[1].contains("Assertion failed: wrong sum"),
+            "{}",
+            errors[1]
+        );
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].0.ends_with("broken.n"));
+
+        let single = run_tests(dir.join("math.n")).unwrap();
+        assert_eq!(single.results.len(), 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_assertion_messages() {
+        use crate::{Engine, Error};
+        use std::sync::{Arc, Mutex};
+
+        let cases = [
+            (
+                "let x = 1\n\nassert(x + 1 > 2)",
+                "[line 3] assert: Assertion failed: 2 > 2",
+            ),
+            (
+                "func check(xs) {\n  assert(length(xs) == 3, \"three items\")\n}\ncheck([1])",
+                "[line 2] assert: Assertion failed: three items (1 == 3)",
+            ),
+            (
+                "let a = \"x\"\nassert_eq([a, 1], [\"y\", 1])",
+                "[line 2] assert_eq: left [\"x\", 1] is not equal to right [\"y\", 1]",
+            ),
+            (
+                "assert(false, \"plain\")",
+                "[line 1] assert: Assertion failed: plain",
+            ),
+            ("assert(1)", "assert: assert expects a boolean, got number"),
+        ];
+        for (source, expected) in cases {
+            match Engine::new().eval(source) {
+                Err(Error::Runtime(message)) => assert!(message.contains(expected), "{}", message),
+                other => panic!("expected a runtime error for {:?}, got {:?}", source, other),
+            }
+        }
+
+        // Operands are evaluated once, and a passing assert gives no output
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Engine::new();
+        engine.set_output(Box::new(Capture(buffer.clone())));
+        engine
+            .eval("func two() { IO.print(\"once\")\n 2 }\nassert(two() == 2, \"two\")")
+            .unwrap();
+        assert_eq!(&*buffer.lock().unwrap(), b"once\n");
+    }
+
+    #[test]
+    fn test_bench_programs() {
+        use crate::bench;
+
+        for source in [
+            bench::fibonacci(10),
+            bench::big_structs(8, 10),
+            bench::many_functions(50),
+            bench::arithmetic(10),
+            bench::templating(4, 10),
+        ] {
+            let times = bench::measure(&source, 2).unwrap();
+            assert_eq!(
+                times.total(),
+                times.lex + times.parse + times.compile + times.run
+            );
+        }
+        assert_eq!(bench::programs().len(), 5);
+        assert!(bench::measure("let = 1", 1).is_err());
+    }
+
+    #[test]
+    fn test_fuzz_entry_points() {
+        use crate::fuzz;
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+        use crate::types::constants::MAX_NESTING_DEPTH;
+
+        // Inputs that used to hang or overflow the stack
+        let deep = MAX_NESTING_DEPTH * 4;
+        let inputs = [
+            "func f( {".to_string(),
+            "func f(a, ".to_string(),
+            format!("{}1{}", "(".repeat(deep), ")".repeat(deep)),
+            format!("1{}", "+1".repeat(deep)),
+            "func f() {".repeat(deep),
+            format!("{}1", "-".repeat(deep)),
+        ];
+        for input in &inputs {
+            fuzz::fuzz_lex(input.as_bytes());
+            fuzz::fuzz_parse(input.as_bytes());
+            fuzz::fuzz_compile(input.as_bytes());
+            fuzz::fuzz_run(input.as_bytes());
+        }
+        let err = Parser::new(Lexer::new(&inputs[2]).tokenize())
+            .parse()
+            .unwrap_err();
+        assert!(err.contains("nested more than"), "{}", err);
+        let err = Parser::new(Lexer::new("func f( {").tokenize())
+            .parse()
+            .unwrap_err();
+        assert!(err.contains("Expected parameter name"), "{}", err);
+
+        // A sleep would only use up the time limit, so it is never run
+        let started = std::time::Instant::now();
+        fuzz::fuzz_run(b"import \"Time\"\nTime.sleep(60000)");
+        assert!(started.elapsed() < std::time::Duration::from_millis(500));
+
+        // Nesting just under the limit still compiles and runs
+        let depth = MAX_NESTING_DEPTH / 2;
+        let source = format!("{}1{}", "(".repeat(depth), " + 1)".repeat(depth));
+        let (bytecode, compiler) = crate::runtime::compile_source(&source).unwrap();
+        crate::interpreter::VirtualMachine::new(bytecode, compiler)
+            .run()
+            .unwrap();
+
+        // Mutations of a valid program and of its bytecode
+        let source = "func add(a, b = 1) { a + b }\nlet xs = [1, 2] <- [3]\nmatch xs { [x, ...rest] -> add(x), _ -> 0 }\nfor x in xs { $\"{x}\" }";
+        let bytes =
+            crate::bytecode::encode(&crate::runtime::compile_source(source).unwrap().0).unwrap();
+        let mut seed: u32 = 0x9e37_79b9;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as usize
+        };
+        for _ in 0..300 {
+            let mut text = source.as_bytes().to_vec();
+            let at = next() % text.len();
+            match next() % 3 {
+                0 => text.truncate(at),
+                1 => text[at] = b"(){}[],=+.\"$"[next() % 12],
+                _ => {
+                    text.remove(at);
+                }
+            }
+            fuzz::fuzz_run(&text);
+            let mut encoded = bytes.clone();
+            let at = next() % encoded.len();
+            encoded[at] = next() as u8;
+            fuzz::fuzz_decode(&encoded);
+            encoded.truncate(at);
+            fuzz::fuzz_decode(&encoded);
+        }
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        use crate::compiler::Compiler;
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+        use crate::types::compiler::{CompileError, CompileOptions};
+        use crate::{Engine, Error};
+
+        let compile = |source: &str, options: CompileOptions| {
+            let program = Parser::new(Lexer::new(source).tokenize()).parse().unwrap();
+            Compiler::with_options(options)
+                .compile(&program)
+                .unwrap_err()
+        };
+
+        let err = compile(
+            "let a = 1\nfunc f() {\n  let x = 1\n  nothing(x)\n}",
+            CompileOptions::default(),
+        );
+        assert_eq!(
+            err,
+            CompileError::Statement {
+                line: 4,
+                message: "Undefined function 'nothing'".to_string()
+            }
+        );
+        assert_eq!(err.line(), Some(4));
+
+        let err = compile("func f() { missing }\n\nf()", CompileOptions::default());
+        assert_eq!(
+            err,
+            CompileError::UndefinedGlobal {
+                line: 1,
+                name: "missing".to_string()
+            }
+        );
+        assert_eq!(err.to_string(), "Undefined variable 'missing'");
+
+        let err = compile(
+            "1",
+            CompileOptions {
+                prelude: Some("func broken() { nope() }".to_string()),
+                ..CompileOptions::default()
+            },
+        );
+        assert!(matches!(err, CompileError::Prelude(_)));
+        assert_eq!(err.line(), None);
+        assert_eq!(err.to_string(), "Prelude error: Undefined function 'nope'");
+
+        let err = compile(
+            "func main(a, b) { 0 }",
+            CompileOptions {
+                call_main: true,
+                ..CompileOptions::default()
+            },
+        );
+        assert_eq!(err.line(), Some(1));
+
+        let mut engine = Engine::new();
+        match engine.eval("let y = 2\nundefined_call(y)") {
+            Err(Error::Compile(err)) => {
+                assert_eq!(err.line(), Some(2));
+                assert_eq!(
+                    Error::Compile(err).to_string(),
+                    "Compile error: Undefined function 'undefined_call'"
+                );
+            }
+            other => panic!("expected a compile error, got {:?}", other),
+        }
+        assert!(matches!(
+            engine.reload_module("never_imported"),
+            Err(Error::Compile(CompileError::Reload(_)))
+        ));
+    }
+
+    #[test]
+    fn test_jumps_past_u16_range() {
+        use crate::types::compiler::Instruction;
+
+        let body = "x + 1\n".repeat(20_000);
+        let source = format!("let x = 0\nif x == 1 {{\n{}x\n}} else {{ -1 }}", body);
+        let (compiled, _) = crate::runtime::compile_source(&source).unwrap();
+        assert!(compiled.instructions.iter().any(|instruction| matches!(
+            instruction,
+            Instruction::JumpIfFalse(target) if *target > u16::MAX as usize
+        )));
+        let bytes = crate::bytecode::encode(&compiled).unwrap();
+        assert_eq!(crate::bytecode::decode(&bytes), Ok(compiled));
+
+        let mut engine = crate::engine::Engine::new();
+        let value = engine.eval(&source).unwrap().unwrap();
+        assert_eq!(engine.display(&value), "-1");
+    }
+
+    #[test]
+    fn test_wide_indices() {
+        use crate::types::compiler::Instruction;
+
+        let source = crate::bench::many_functions(70_000);
+        let (compiled, _) = crate::runtime::compile_source(&source).unwrap();
+        assert!(compiled.functions.len() > u16::MAX as usize);
+        let function = compiled
+            .instructions
+            .iter()
+            .find_map(|instruction| match instruction {
+                Instruction::Call(function) if *function > u16::MAX as usize => Some(*function),
+                _ => None,
+            })
+            .unwrap();
+        let bytes = crate::bytecode::encode(&compiled).unwrap();
+        let mut wide_call = vec![0xFF, 0x04];
+        wide_call.extend_from_slice(&(function as u32).to_le_bytes());
+        assert!(bytes.windows(6).any(|window| window == wide_call));
+        assert_eq!(crate::bytecode::decode(&bytes), Ok(compiled));
+    }
+
+    #[test]
+    fn test_verify_bytecode() {
+        use crate::types::compiler::{ByteCode, Instruction, Value};
+        use crate::verify::verify;
+
+        let (compiled, _) = crate::runtime::compile_source(
+            "func add(a, b) { a + b }\nlet pair = (1, 2)\nmatch pair { (a, b) -> add(a, b) }",
+        )
+        .unwrap();
+        assert_eq!(verify(&compiled), Ok(()));
+
+        let program = |instructions: Vec<Instruction>| ByteCode {
+            constants: vec![Value::Number(1.0)],
+            functions: vec![Value::Function {
+                params: Vec::new(),
+                offset: 0,
+            }],
+            instruction_lines: vec![1; instructions.len()],
+            instructions,
+            ..ByteCode::default()
+        };
+        for (instructions, expected) in [
+            (
+                vec![Instruction::Jump(5), Instruction::Halt],
+                "Jump at instruction 0 targets 5, past the end (2 instructions)",
+            ),
+            (
+                vec![Instruction::LoadConst(3), Instruction::Halt],
+                "Instruction 0 (LOAD_CONST 3) refers to a missing constant",
+            ),
+            (
+                vec![Instruction::HasField(0), Instruction::Halt],
+                "Instruction 0 (HAS_FIELD 0) names a number constant",
+            ),
+            (
+                vec![Instruction::Call(1), Instruction::Halt],
+                "Instruction 0 (CALL 1) refers to a missing function",
+            ),
+            (
+                vec![Instruction::Pop, Instruction::Halt],
+                "Instruction 0 (POP) pops 1 value(s) but the stack holds 0",
+            ),
+            (
+                vec![
+                    Instruction::Push(Value::Boolean(true)),
+                    Instruction::JumpIfFalse(3),
+                    Instruction::LoadConst(0),
+                    Instruction::Halt,
+                ],
+                "Instruction 3 is reached with 1 and with 0 value(s) on the stack",
+            ),
+        ] {
+            let bytecode = program(instructions);
+            assert_eq!(verify(&bytecode), Err(expected.to_string()));
+            let bytes = crate::bytecode::encode(&bytecode).unwrap();
+            assert_eq!(crate::bytecode::decode(&bytes), Err(expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_deterministic_bytecode() {
+        let mut sources = vec![
+            "const LIMIT = 3
+trait Shape { func area(self) }
+struct Square { side }
+impl Shape for Square { func area(self) { self.side * self.side } }
+enum Tree { Leaf, Node(left, value, right) }
+func sum(tree) {
+    match tree { Tree::Leaf -> 0, Tree::Node(l, v, r) -> sum(l) + v + sum(r) }
+}
+let offset = 1
+let shift = fn(x) => x + offset + LIMIT
+[Square { side = 2 }.area(), sum(Tree::Node(Tree::Leaf, 4, Tree::Leaf)), shift(1)]"
+                .to_string(),
+        ];
+        for file in ["tests/json.n", "tests/function_definitions.n"] {
+            sources.push(std::fs::read_to_string(file).unwrap());
+        }
+        for source in sources {
+            let encode = || {
+                let (compiled, _) = crate::runtime::compile_source(&source).unwrap();
+                crate::bytecode::encode(&compiled).unwrap()
+            };
+            let first = encode();
+            for _ in 0..4 {
+                assert_eq!(encode(), first);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ast_visitor() {
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+        use crate::types::ast::{
+            Expr, ExprId, Pattern, Program, Visitor, walk_expr, walk_pattern, walk_program,
+        };
+
+        #[derive(Default)]
+        struct Counts {
+            numbers: Vec<f64>,
+            bindings: usize,
+        }
+
+        impl Visitor for Counts {
+            fn visit_expr(&mut self, program: &Program, expr: ExprId) {
+                if let Expr::Number(n) = program.expr(expr) {
+                    self.numbers.push(*n);
+                }
+                walk_expr(self, program, expr);
+            }
+
+            fn visit_pattern(&mut self, program: &Program, pattern: &Pattern) {
+                if let Pattern::Binding(_) = pattern {
+                    self.bindings += 1;
+                }
+                walk_pattern(self, program, pattern);
+            }
+        }
+
+        let source = "func f(a, b = 1) { a + b }
+struct Point { x }
+impl Point { func get(self) { self.x * 2 } let ORIGIN = 3 }
+let (p, q) = (4, 5)
+for i in [6] { if i == 7 { { 8 } } else { fn(x) => x + 9 } }
+match f(10) { 11 -> 12, [first, ...rest] -> first, other -> Point { x = 13 } }";
+        let program = Parser::new(Lexer::new(source).tokenize()).parse().unwrap();
+        let mut counts = Counts::default();
+        walk_program(&mut counts, &program);
+        assert_eq!(
+            counts.numbers,
+            (1..=13).map(|n| n as f64).collect::<Vec<_>>()
+        );
+        assert_eq!(counts.bindings, 4);
+    }
+
+    #[test]
+    fn test_program_builder() {
+        use crate::types::ast::{BinaryOp, Program, Stmt};
+        use crate::{Engine, Error};
+
+        let mut program = Program::default();
+        let x = program.identifier("x");
+        let square = program.binary(x, BinaryOp::Mul, x);
+        program.push(Stmt::func("square", &["x"], vec![Stmt::expr(square)]));
+        let four = program.number(4.0);
+        let call = program.call("square", vec![four]);
+        program.push(Stmt::let_binding("n", call));
+        let n = program.identifier("n");
+        let ten = program.number(10.0);
+        let condition = program.binary(n, BinaryOp::Gt, ten);
+        let big = program.string("big");
+        let small = program.string("small");
+        let choice = program.if_else(
+            condition,
+            vec![Stmt::expr(big)],
+            Some(vec![Stmt::expr(small)]),
+        );
+        let label = program.string(" is ");
+        let text = program.binary(n, BinaryOp::Concat, label);
+        let text = program.binary(text, BinaryOp::Concat, choice);
+        program.push(Stmt::expr(text));
+
+        let mut engine = Engine::new();
+        let value = engine.eval_program(&program).unwrap().unwrap();
+        assert_eq!(engine.display(&value), "\"16 is big\"");
+
+        let mut program = Program::default();
+        let call = program.call("missing", Vec::new());
+        program.push(Stmt::expr(call));
+        assert!(matches!(
+            engine.eval_program(&program),
+            Err(Error::Compile(_))
+        ));
+    }
+
+    #[test]
+    fn test_ast_json() {
+        use crate::ast_json::to_json;
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+
+        let parse = |source: &str| Parser::new(Lexer::new(source).tokenize()).parse().unwrap();
+        assert_eq!(
+            to_json(&parse("let (a, _) = f(-1, \"q\")")),
+            "{\"kind\":\"Program\",\"doc\":null,\"statements\":[{\"kind\":\"LetPattern\",\"line\":1,\
+         \"pattern\":{\"kind\":\"Tuple\",\"elements\":[{\"kind\":\"Binding\",\"name\":\"a\"},\
+         {\"kind\":\"Wildcard\"}]},\"value\":{\"kind\":\"Call\",\"func\":{\"kind\":\"Identifier\",\
+         \"name\":\"f\"},\"args\":[{\"kind\":\"Unary\",\"op\":\"-\",\"right\":{\"kind\":\"Number\",\
+         \"value\":1}},{\"kind\":\"String\",\"value\":\"q\"}]}}]}"
+        );
+
+        for file in ["tests/json.n", "tests/function_definitions.n"] {
+            let source = std::fs::read_to_string(file).unwrap();
+            let json = to_json(&parse(&source));
+            assert!(crate::stdlib::json::parse(&json).is_ok(), "{}", file);
+        }
+    }
+
+    #[test]
+    fn test_incremental_reparse() {
+        use crate::ast_json::to_json;
+        use crate::incremental::{Document, TextEdit};
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+
+        let source = "func one() {\n  1\n}\n\n/// Two\nfunc two() {\n  if true { 2 } else { 0 }\n}\n\nlet three = one() + two()\n";
+        let mut doc = Document::new(source).unwrap();
+        let edit = |doc: &mut Document, old: &str, new: &str| {
+            let at = doc.source().find(old).unwrap();
+            let result = doc
+                .edit(TextEdit {
+                    range: at..at + old.len(),
+                    text: new.to_string(),
+                })
+                .map(to_json);
+            let full = Parser::new(Lexer::new(doc.source()).tokenize()).parse();
+            match (result, full) {
+                (Ok(json), Ok(full)) => assert_eq!(json, to_json(&full)),
+                (Err(_), Err(_)) => {}
+                (result, full) => {
+                    panic!("{:?} but a full parse gives {:?}", result.err(), full.err())
+                }
+            }
+            doc.last_reparsed()
+        };
+
+        // Only the edited statement is parsed again; those below move down
+        assert_eq!(edit(&mut doc, "1\n", "let x = 1\n  x + 1\n"), Some(1));
+        assert_eq!(edit(&mut doc, "/// Two", "/// The number two"), Some(1));
+        assert_eq!(
+            edit(&mut doc, "\nlet three", "\nlet zero = 0\nlet three"),
+            Some(2)
+        );
+        assert_eq!(edit(&mut doc, "func one", "func uno"), Some(1));
+        // An error keeps the old program until a later edit fixes it
+        let before = to_json(doc.program());
+        assert_eq!(edit(&mut doc, "{ 2 }", "{ 2 "), None);
+        assert_eq!(to_json(doc.program()), before);
+        assert_eq!(edit(&mut doc, "{ 2 ", "{ 2 }"), None);
+        assert_eq!(edit(&mut doc, "func two", "func dos"), Some(1));
+        // A string now running to the end of the file needs a full parse
+        assert_eq!(edit(&mut doc, "  x + 1", "  \"x + 1"), None);
+
+        // A doc comment read again after looking for an `else` was kept twice
+        let doc = Document::new("if true { 1 }\n/// d\nfunc f() { 1 }\n").unwrap();
+        assert!(to_json(doc.program()).contains("\"doc\":\"d\","));
+    }
+
+    #[test]
+    fn test_completions() {
+        use crate::complete::{CompletionKind, completions};
+
+        let source = "import \"JSON\"\n\
+                  enum Shape { Circle(radius), Empty }\n\
+                  struct User { name, age }\n\
+                  impl User {\n  func new(name) { User { name = name, age = 0 } }\n}\n\
+                  func greet(user, greeting = \"hi\") {\n  let text = greeting ++ user.name\n  \n}\n\
+                  func other(hidden) { hidden }\n\
+                  let admin = User { name = \"root\", age = 1 }\n";
+        let at = |marker: &str| source.find(marker).unwrap() + marker.len();
+        let labels = |source: &str, offset: usize| -> Vec<String> {
+            completions(source, offset)
+                .into_iter()
+                .map(|completion| completion.label)
+                .collect()
+        };
+
+        // Inside `greet`: its locals and every function, but not `other`'s
+        let inside = at("user.name\n  ");
+        let found = completions(source, inside);
+        for name in [
+            "text", "greeting", "user", "greet", "other", "square", "JSON", "User", "Shape",
+        ] {
+            assert!(found.iter().any(|c| c.label == name), "{}", name);
+        }
+        assert!(
+            !found
+                .iter()
+                .any(|c| c.label == "hidden" || c.label == "admin")
+        );
+        let greet = found.iter().find(|c| c.label == "greet").unwrap();
+        assert_eq!(greet.kind, CompletionKind::Function);
+        assert_eq!(greet.detail, "greet(user, greeting)");
+
+        // The word typed so far filters, even where the line does not parse
+        let typing = format!("{}gr", &source[..inside]);
+        assert_eq!(labels(&typing, typing.len()), ["greet", "greeting"]);
+
+        let typing = format!("{}Shape::", source);
+        let found = completions(&typing, typing.len());
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].detail, "Shape::Circle(_)");
+        let typing = format!("{}JSON.st", source);
+        assert_eq!(labels(&typing, typing.len()), ["stringify"]);
+        let typing = format!("{}User.", source);
+        assert_eq!(labels(&typing, typing.len()), ["new"]);
+        let typing = format!("{}User {{ age = 2, ", source);
+        assert_eq!(labels(&typing, typing.len()), ["name"]);
+        let typing = format!("{}admin <- {{ ", source);
+        assert_eq!(labels(&typing, typing.len()), ["age", "name"]);
+    }
+
+    #[test]
+    fn test_lint() {
+        use crate::lexer::Lexer;
+        use crate::lint::{LintConfig, Severity, lint, to_json};
+        use crate::parser::Parser;
+
+        let source = "import \"JSON\"\nimport \"Time\"\nlet x = 1\n\
+                  func f(x, y) {\n  let unused = 2\n  let _ignored = 3\n  if !false { y } else { 0 }\n}\n\
+                  func g(v) {\n  match v {\n    (a, _) -> a\n    (1, 2) -> 0\n    other -> 1\n  }\n}\n\
+                  JSON.stringify([x].map(fn(n) => n))\n";
+        let program = Parser::new(Lexer::new(source).tokenize()).parse().unwrap();
+        let findings = lint(&program, &LintConfig::default());
+        let found: Vec<(&str, usize)> = findings.iter().map(|f| (f.rule, f.line)).collect();
+        assert_eq!(
+            found,
+            [
+                ("unused-import", 2),
+                ("shadowed-binding", 4),
+                ("unused-variable", 5),
+                ("constant-condition", 7),
+                ("unreachable-match-arm", 10),
+                ("unused-variable", 10),
+            ]
+        );
+        assert_eq!(
+            findings[4].message,
+            "Arm 2 of the match is never reached: arm 1 accepts all its values"
+        );
+        assert_eq!(findings[5].message, "'other' is never read");
+
+        let mut config = LintConfig::default();
+        config.set("unused-variable", Severity::Allow).unwrap();
+        config.set("unused-import", Severity::Error).unwrap();
+        assert!(config.set("no-such-rule", Severity::Error).is_err());
+        let findings = lint(&program, &config);
+        assert_eq!(findings.len(), 4);
+        assert!(to_json(&findings[..1]).starts_with(
+            "[{\"rule\":\"unused-import\",\"severity\":\"error\",\"line\":2,\"message\":"
+        ));
+    }
+
+    #[test]
+    fn test_warnings() {
+        use crate::Engine;
+        use crate::Error;
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+        use crate::types::compiler::{CompileError, CompileOptions};
+
+        let source = "@deprecated
+func old(x) { x }
+func f(x) {
+    let unused = 1
+    let _ignored = 2
+    let used = x
+    let g = fn (y) => { let inner = y; used }
+    g(1)
+}
+@allow(\"unused-variable\")
+@allow(\"deprecated\")
+func quiet() {
+    let unused = old(1)
+    2
+}
+f(1) + quiet()";
+        let mut engine = Engine::new();
+        let result = engine.eval(source).unwrap().unwrap();
+        assert_eq!(engine.display(&result), "3");
+        let warnings: Vec<(&str, usize)> =
+            engine.warnings().iter().map(|w| (w.name, w.line)).collect();
+        assert_eq!(
+            warnings,
+            [("unused-variable", 7), ("unused-variable", 4)],
+            "{:?}",
+            engine.warnings()
+        );
+        assert_eq!(
+            engine.warnings()[1].to_string(),
+            "Variable 'unused' is never read (line 4)"
+        );
+
+        let mut engine = Engine::with_options(CompileOptions {
+            deny_warnings: true,
+            ..CompileOptions::default()
+        });
+        let Err(Error::Compile(CompileError::Warnings(denied))) = engine.eval(source) else {
+            panic!("expected the warnings to be denied");
+        };
+        assert_eq!(denied.len(), 2);
+        assert!(engine.eval("func g(x) { let y = x; y }\ng(1)").is_ok());
+
+        let err = Parser::new(Lexer::new("@allow(\"typo\")\nfunc f() { 1 }").tokenize())
+            .parse()
+            .unwrap_err();
+        assert_eq!(err, "Unknown warning 'typo' in '@allow' at line 1");
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        use crate::diagnostic::{CODES, Diagnostic};
+
+        let source = "let x = 1\nfunc f() {\n    x / missing\n}\n";
+        let diagnostic =
+            Diagnostic::from_error("Compile error: Undefined variable 'missing'").unwrap();
+        assert_eq!(diagnostic.code, "E0002");
+        assert_eq!(diagnostic.line, None);
+        let diagnostic =
+            Diagnostic::from_error("Compile error: Undefined variable 'missing' (line 3)").unwrap();
+        assert_eq!(
+            diagnostic.render(source, "main.n", false),
+            "error[E0002]: Undefined variable 'missing'
+ --> main.n:3:9
+  |
+3 |     x / missing
+  |         ^^^^^^^"
+        );
+
+        let diagnostic =
+            Diagnostic::from_error("Runtime error: [line 1] Division by zero").unwrap();
+        assert_eq!(diagnostic.code, "E0005");
+        assert!(
+            diagnostic
+                .render(source, "main.n", false)
+                .ends_with("1 | let x = 1\n  | ^^^^^^^^^")
+        );
+        let colored = diagnostic.render(source, "main.n", true);
+        assert!(
+            colored.starts_with("\x1b[1;31merror[E0005]\x1b[0m"),
+            "{}",
+            colored
+        );
+
+        let diagnostic = Diagnostic::from_error("Parse error: Unexpected token at line 9").unwrap();
+        assert_eq!(
+            diagnostic.render(source, "main.n", false),
+            "error[E0001]: Unexpected token\n --> main.n:9"
+        );
+        assert!(Diagnostic::from_error("Error reading file 'x.n'").is_none());
+        assert!(CODES.iter().any(|&(code, _)| code == "W0002"));
+    }
+
+    #[test]
+    fn test_json_diagnostics() {
+        use crate::diagnostic::{Diagnostic, MessageFormat};
+        use crate::runtime::format_error;
+
+        let source = "let x = 1\nfunc f() {\n    x / missing\n}\n";
+        let diagnostic =
+            Diagnostic::from_error("Compile error: Undefined variable 'missing' (line 3)").unwrap();
+        assert_eq!(
+            diagnostic.to_json(source, "main.n"),
+            r#"{"code":"E0002","file":"main.n","message":"Undefined variable 'missing'","notes":["E0002 means a name that nothing declares is read"],"severity":"error","span":{"column":9,"length":7,"line":3}}"#
+        );
+        assert_eq!(
+            diagnostic.format(source, "main.n", MessageFormat::Human),
+            diagnostic.render(source, "main.n", crate::diagnostic::use_color())
+        );
+
+        let json = format_error(
+            source,
+            "main.n",
+            "Parse error: Unexpected token at line 9",
+            MessageFormat::Json,
+        );
+        assert!(json.contains(r#""span":{"line":9}"#), "{}", json);
+        let json = format_error(source, "main.n", "Compile error: Bad", MessageFormat::Json);
+        assert!(json.contains(r#""span":null"#), "{}", json);
+        assert_eq!(
+            format_error("", "x.n", "Error reading file 'x.n'", MessageFormat::Json),
+            r#"{"code":null,"file":"x.n","message":"Error reading file 'x.n'","notes":[],"severity":"error","span":null}"#
+        );
+        assert_eq!(
+            format_error("", "x.n", "Error reading file 'x.n'", MessageFormat::Human),
+            "Error reading file 'x.n'"
+        );
+    }
+
+    #[test]
+    fn test_wasm_compile_and_run() {
+        use crate::wasm::compile_and_run;
+
+        assert_eq!(
+            compile_and_run("IO.print(\"hi\")\nlet xs = [1, 2]\nxs.length()"),
+            "hi\n2\n"
+        );
+        assert_eq!(
+            compile_and_run("IO.print(1)\nlet y = missing"),
+            "Compile error: Undefined variable 'missing'\n"
+        );
+    }
+
+    #[test]
+    fn test_extern() {
+        use crate::Engine;
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+        use crate::types::ast::{ForeignType, Stmt};
+
+        let source = "extern \"libc.so.6\" {
+    func abs(n: int) -> int
+    func strlen(s: string) -> long
+}
+extern \"libm.so.6\" { func pow(base, exp) }
+abs(-3) + strlen(\"four\") + pow(2, 3)";
+        let program = Parser::new(Lexer::new(source).tokenize()).parse().unwrap();
+        let Stmt::Extern {
+            library, functions, ..
+        } = &program.statements[0]
+        else {
+            panic!("expected an extern block");
+        };
+        assert_eq!(&**library, "libc.so.6");
+        assert_eq!(functions[1].params[0].1, ForeignType::String);
+        assert_eq!(functions[1].returns, ForeignType::Long);
+
+        let result = Engine::new().eval(source);
+        #[cfg(all(feature = "ffi", target_os = "linux"))]
+        assert_eq!(
+            result.unwrap(),
+            Some(crate::types::compiler::HeapObject::Number(15.0))
+        );
+        #[cfg(all(feature = "ffi", target_os = "linux"))]
+        {
+            // Each call goes through the pointer type of its own signature
+            let mixed = "extern \"libm.so.6\" {
+    func fma(a, b, c)
+    func ldexp(x: number, exp: int) -> number
+}
+extern \"libc.so.6\" { func atol(s: string) -> long }
+fma(2, 3, 4) + ldexp(1, 5) + atol(\"100\")";
+            assert_eq!(
+                Engine::new().eval(mixed).unwrap(),
+                Some(crate::types::compiler::HeapObject::Number(142.0))
+            );
+            let err = Engine::new()
+                .eval("extern \"libm.so.6\" { func f(a, b, c, d, e) }")
+                .unwrap_err();
+            assert!(
+                err.to_string().contains("more than 4 parameters"),
+                "{}",
+                err
+            );
+        }
+        #[cfg(not(feature = "ffi"))]
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("'extern' needs the 'ffi' feature")
+        );
+
+        let err = Parser::new(Lexer::new("extern \"x\" { func f(a: float) }").tokenize())
+            .parse()
+            .unwrap_err();
+        assert!(err.starts_with("Unknown type 'float' at line 1"), "{}", err);
+    }
+
+    #[test]
+    fn test_opaque_values() {
+        use crate::Engine;
+        use crate::types::compiler::{HeapObject, Value};
+        use std::sync::Mutex;
+
+        struct Counter(Mutex<u32>);
+
+        let mut engine = Engine::new();
+        engine.register_native("bump", |context, args| {
+            let counter = context.heap.downcast::<Counter>(&args[0])?;
+            let mut count = counter.0.lock().unwrap();
+            *count += 1;
+            Ok(Value::Number(*count as f64))
+        });
+        let counter = engine.opaque(Counter(Mutex::new(0)));
+        engine.set_global("counter", counter);
+
+        let result = engine
+            .eval("let c = { inner = counter }\nbump(counter)\nbump(c.inner)")
+            .unwrap()
+            .unwrap();
+        assert_eq!(result, HeapObject::Number(2.0));
+        let same = engine.eval("counter == c.inner").unwrap().unwrap();
+        assert_eq!(same, HeapObject::Boolean(true));
+
+        let handle = engine.eval("counter").unwrap().unwrap();
+        assert_eq!(
+            *engine
+                .downcast::<Counter>(&handle)
+                .unwrap()
+                .0
+                .lock()
+                .unwrap(),
+            2
+        );
+        assert!(engine.display(&handle).ends_with("Counter>"));
+        assert_eq!(
+            engine.downcast::<String>(&handle).unwrap_err(),
+            format!(
+                "Expected alloc::string::String, got {}",
+                std::any::type_name::<Counter>()
+            )
+        );
+        let err = engine.eval("bump(1)").unwrap_err().to_string();
+        assert!(err.contains("Expected opaque, got number"), "{}", err);
+    }
+
+    #[test]
+    fn test_engine_values_outlive_collections() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+
+        // Garbage enough for the collector to compact the heap under
+        // whatever the host held on to
+        let mut churn = String::new();
+        for i in 0..400 {
+            churn.push_str(&format!("[{}, {}, {}]\n", i, i + 1, i + 2));
+        }
+
+        let mut engine = Engine::new();
+        let list = engine.eval("[\"kept\", 1]").unwrap().unwrap();
+        let token = engine.opaque(String::from("token"));
+        engine.eval(&churn).unwrap();
+        assert_eq!(
+            list,
+            HeapObject::Array(vec![HeapObject::from("kept"), HeapObject::Number(1.0)].into())
+        );
+        assert_eq!(*engine.downcast::<String>(&token).unwrap(), "token");
+
+        // Passed in after the collections, and read back with another one
+        // in between
+        engine.set_global("token", token);
+        engine.eval(&churn).unwrap();
+        let total = engine.eval_with("length(list) + length(list)", &[("list", list)]);
+        assert_eq!(total, Ok(HeapObject::Number(4.0)));
+        let token = engine.eval("token").unwrap().unwrap();
+        assert_eq!(*engine.downcast::<String>(&token).unwrap(), "token");
+    }
+
+    #[test]
+    fn test_engine_convert_after_collection() {
+        use crate::Engine;
+        use std::collections::HashMap;
+
+        let mut churn = String::new();
+        for i in 0..400 {
+            churn.push_str(&format!("{{ key = [{}, \"{}\"] }}\n", i, i));
+        }
+
+        let mut engine = Engine::new();
+        let scores = engine.eval("{ ann = [1, 2], bob = [3] }").unwrap().unwrap();
+        let names = engine.eval("[\"ann\", \"bob\"]").unwrap().unwrap();
+        engine.eval(&churn).unwrap();
+        assert_eq!(
+            engine.convert::<HashMap<String, Vec<f64>>>(&scores),
+            Ok(HashMap::from([
+                ("ann".to_string(), vec![1.0, 2.0]),
+                ("bob".to_string(), vec![3.0]),
+            ]))
+        );
+        assert_eq!(engine.display(&scores), "{ann: [1, 2], bob: [3]}");
+        engine.eval(&churn).unwrap();
+        assert_eq!(
+            engine.convert::<Vec<String>>(&names),
+            Ok(vec!["ann".to_string(), "bob".to_string()])
+        );
+        assert_eq!(engine.display(&names), "[\"ann\", \"bob\"]");
+    }
+
+    #[test]
+    fn test_event_loop_hooks() {
+        use crate::Engine;
+        use crate::types::compiler::HeapObject;
+        use std::future::Future;
+        use std::task::{Context, Poll, Waker};
+
+        let mut engine = Engine::new();
+        engine.set_defer_tasks(true);
+        engine.register_waker(Waker::noop().clone());
+        engine
+            .eval("Task.spawn(fn() => 1)\nTask.spawn(fn() => 2)")
+            .unwrap();
+        assert_eq!(engine.poll().unwrap(), Poll::Pending);
+        assert_eq!(engine.poll().unwrap(), Poll::Ready(()));
+        assert_eq!(engine.poll().unwrap(), Poll::Ready(()));
+
+        engine.eval("func add(a, b) { a + b }").unwrap();
+        let mut future = std::pin::pin!(
+            engine
+                .call_async(
+                    "add",
+                    vec![HeapObject::Number(2.0), HeapObject::Number(3.0)]
+                )
+                .unwrap()
+        );
+        let mut cx = Context::from_waker(Waker::noop());
+        let result = loop {
+            if let Poll::Ready(result) = future.as_mut().poll(&mut cx) {
+                break result;
+            }
+        };
+        assert_eq!(result, Ok(HeapObject::Number(5.0)));
+
+        let err = engine.call_async("missing", Vec::new()).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Runtime error: Undefined function 'missing'"
+        );
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        use crate::Engine;
+        use crate::replay::Recording;
+
+        let source = "import \"Time\"\nimport \"OS\"\nlet t = Time.now()\nlet unset = OS.env(\"N_REPLAY_UNSET\")\nt";
+        let mut engine = Engine::new();
+        engine.record();
+        let recorded = engine.eval(source).unwrap().unwrap();
+        let recording = engine.take_recording().unwrap();
+        assert_eq!(recording.events.len(), 2);
+        assert_eq!(recording.events[0].native, "Time.now");
+
+        let text = recording.to_text();
+        assert_eq!(Recording::parse(&text).unwrap(), recording);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let mut replayed = Engine::new();
+        replayed.replay(Recording::parse(&text).unwrap());
+        assert_eq!(replayed.eval(source).unwrap(), Some(recorded));
+
+        // A run that reads more than the recording has fails instead of guessing
+        let mut diverged = Engine::new();
+        diverged.replay(recording);
+        let err = diverged
+            .eval("import \"Time\"\nTime.elapsed()")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Replay diverged"), "{}", err);
+        assert!(Recording::parse("{\"ok\":1}").is_err());
+    }
+
+    #[test]
+    fn test_line_coverage() {
+        use crate::coverage::Coverage;
+        use crate::interpreter::VirtualMachine;
+        use crate::runtime::compile_source;
+
+        let source = "func sign(n) {\n    if n < 0 {\n        \"negative\"\n    } else {\n        \"positive\"\n    }\n}\nsign(1)\nsign(2)\n";
+        let (bytecode, compiler) = compile_source(source).unwrap();
+        let mut vm = VirtualMachine::new(bytecode, compiler);
+        assert_eq!(vm.line_hits(), None);
+        vm.enable_coverage();
+        vm.run().unwrap();
+        let hits = vm.line_hits().unwrap();
+        assert_eq!(hits.get(&3), Some(&0));
+        assert_eq!(hits.get(&5), Some(&2));
+        assert_eq!(hits.get(&8), Some(&1));
+        assert_eq!(hits.get(&4), None);
+
+        let mut coverage = Coverage::new();
+        coverage.add("sign.n", &hits);
+        coverage.add("sign.n", &hits);
+        assert_eq!(coverage.lines("sign.n").unwrap()[&5], 4);
+        let lcov = coverage.to_lcov();
+        assert!(lcov.starts_with("SF:sign.n\nDA:1,"), "{}", lcov);
+        assert!(lcov.contains("DA:3,0\n"), "{}", lcov);
+        assert!(
+            lcov.ends_with(&format!(
+                "LF:{}\nLH:{}\nend_of_record\n",
+                hits.len(),
+                hits.len() - 1
+            )),
+            "{}",
+            lcov
+        );
+    }
+
+    #[test]
+    fn test_superinstructions() {
+        use crate::dispatch::{Op, decode};
+        use crate::interpreter::VirtualMachine;
+        use crate::types::compiler::{ByteCode, HeapObject, Instruction, Value};
+
+        let instructions = vec![
+            Instruction::LoadConst(1),
+            Instruction::Jump(3),
+            Instruction::LoadConst(0),
+            Instruction::StoreVar(0, 0),
+            Instruction::LoadVar(0, 0),
+            Instruction::LoadConst(0),
+            Instruction::Add,
+            Instruction::Halt,
+        ];
+        assert_eq!(
+            decode(&instructions),
+            vec![
+                Op::LoadConst(1),
+                Op::Jump(3),
+                Op::LoadConstStore(0, 0),
+                Op::StoreVar(0, 0),
+                Op::LoadVarConstAdd(0, 0, 0),
+                Op::LoadConst(0),
+                Op::Add,
+                Op::Halt,
+            ]
+        );
+        // The jump lands inside the fused `LOAD_CONST 0; STORE_VAR 0 0`
+        let bytecode = ByteCode {
+            constants: vec![Value::Number(1.0), Value::Number(2.0)],
+            instruction_lines: vec![1; instructions.len()],
+            instructions,
+            ..ByteCode::default()
+        };
+        let mut vm = VirtualMachine::new(bytecode, crate::compiler::Compiler::new());
+        assert_eq!(vm.run(), Ok(Some(Value::Number(3.0))));
+
+        let mut engine = crate::Engine::new();
+        assert_eq!(
+            engine.eval("let a = 2\nlet b = a + 1\nb + a"),
+            Ok(Some(HeapObject::Number(5.0)))
+        );
+        let err = engine.eval("let s = true\ns + 1").unwrap_err().to_string();
+        assert!(err.contains("Cannot add boolean and number"), "{}", err);
+    }
+
+    #[test]
+    #[cfg(feature = "registers")]
+    fn test_register_tier() {
+        use crate::compiler::Compiler;
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+        use crate::register::Translations;
+        use crate::types::compiler::HeapObject;
+        use crate::{Engine, VmLimits};
+
+        let source = "func dec(n) { n - 1 }\n\
+                  func fib(n) { if n < 2 { n } else { fib(dec(n)) + fib(n - 2) } }\n\
+                  func even(n) { if n == 0 { true } else { odd(n - 1) } }\n\
+                  func odd(n) { if n == 0 { false } else { even(n - 1) } }\n\
+                  func half(n) { n / 2 }\n\
+                  func greet(name) { \"hi \" ++ name }\n\
+                  func twice(n) { greet(n) ++ greet(n) }";
+        let program = Parser::new(Lexer::new(source).tokenize()).parse().unwrap();
+        let mut compiler = Compiler::new();
+        let bytecode = compiler.compile(&program).unwrap();
+        let mut translations = Translations::new(&bytecode.instructions, &bytecode.functions);
+        let mut translate = |name: &str| {
+            let index = compiler.functions[name];
+            translations
+                .translate(index, &bytecode.instructions, &bytecode.constants)
+                .map(|function| {
+                    let code: Vec<String> = function.code.iter().map(|op| op.to_string()).collect();
+                    code
+                })
+        };
+        assert_eq!(
+            translate("dec"),
+            Some(vec!["r1 = r0 - 1".to_string(), "return r1".to_string()])
+        );
+        assert!(translate("fib").is_some());
+        assert!(translate("even").is_some());
+        assert!(translate("greet").is_none());
+        assert!(translate("twice").is_none());
+
+        // Translated calls give what the stack code gives, and fall back to it
+        // for values and errors registers don't handle
+        let mut engine = Engine::new();
+        engine.eval(source).unwrap();
+        assert_eq!(engine.eval("fib(20)"), Ok(Some(HeapObject::Number(6765.0))));
+        assert_eq!(
+            engine.eval("even(101)"),
+            Ok(Some(HeapObject::Boolean(false)))
+        );
+        assert_eq!(engine.eval("half(3)"), Ok(Some(HeapObject::Number(1.5))));
+        let err = engine.eval("half(1) + 1 / 0").unwrap_err().to_string();
+        assert!(err.contains("Division by zero"), "{}", err);
+        let err = engine
+            .eval("func inv(n) { 1 / n }\ninv(0)")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("[line 1] Division by zero"), "{}", err);
+        let err = engine.eval("odd(true)").unwrap_err().to_string();
+        assert!(err.contains("Expected number on stack"), "{}", err);
+
+        // Limits still stop long calls
+        engine.set_limits(VmLimits {
+            max_instructions: Some(10_000),
+            ..VmLimits::default()
+        });
+        let err = engine.eval("fib(25)").unwrap_err().to_string();
+        assert!(
+            err.contains("Instruction limit exceeded (10000)"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_nanbox() {
+        use crate::nanbox::NanBox;
+        use crate::types::compiler::Value;
+
+        assert_eq!(std::mem::size_of::<NanBox>(), 8);
+        for value in [
+            Value::Number(1.5),
+            Value::Number(-0.0),
+            Value::Number(f64::INFINITY),
+            Value::Number(f64::MIN_POSITIVE),
+            Value::Boolean(true),
+            Value::Boolean(false),
+            Value::HeapPointer(0),
+            Value::HeapPointer((1 << 48) - 1),
+            Value::Task(3),
+            Value::Channel(4),
+            Value::Generator(5),
+        ] {
+            let boxed = NanBox::from_value(&value).unwrap();
+            assert_eq!(boxed.to_value(), value);
+        }
+        assert!(NanBox::number(-0.0).as_number().unwrap().is_sign_negative());
+
+        // Every NaN, including the negative one 0.0 / 0.0 gives on x86-64, stays a number
+        let negative_nan = f64::from_bits(0xFFF8_0000_0000_0001);
+        for nan in [f64::NAN, negative_nan, -f64::NAN] {
+            let boxed = NanBox::number(nan);
+            assert!(boxed.as_number().unwrap().is_nan());
+            assert_eq!(boxed.as_boolean(), None);
+        }
+        assert_eq!(NanBox::boolean(true).as_number(), None);
+        assert_eq!(NanBox::from_value(&Value::HeapPointer(1 << 48)), None);
+        assert_eq!(NanBox::from_value(&Value::String("a".to_string())), None);
+    }
+
+    #[test]
+    #[cfg(feature = "jit")]
+    fn test_jit() {
+        use crate::compiler::Compiler;
+        use crate::interpreter::VirtualMachine;
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+        use crate::types::compiler::{CompileOptions, Value};
+
+        let run = |source: &str, max_call_depth: Option<usize>| {
+            let program = Parser::new(Lexer::new(source).tokenize()).parse().unwrap();
+            let mut compiler = Compiler::with_options(CompileOptions {
+                keep_last_value: true,
+                ..Default::default()
+            });
+            let bytecode = compiler.compile(&program).unwrap();
+            let functions = compiler.functions.clone();
+            let mut vm = VirtualMachine::new(bytecode, compiler);
+            vm.set_jit_threshold(1);
+            if let Some(depth) = max_call_depth {
+                vm.set_max_call_depth(depth);
+            }
+            let result = vm.run();
+            let compiled = |name: &str| vm.jit_compiled(functions[name]);
+            (result, compiled("f"))
+        };
+
+        let (result, compiled) = run(
+            "func f(n) { if n < 2 { n } else { f(n - 1) + f(n - 2) } }\nf(20)",
+            None,
+        );
+        assert_eq!(result, Ok(Some(Value::Number(6765.0))));
+        assert!(compiled);
+
+        // Booleans come back as booleans, and never equal numbers
+        let (result, compiled) = run(
+            "func g(n) { n == 1 }\nfunc f(n) { if n == 0 { g(1) == true } else { !f(n - 1) } }\nf(2)",
+            None,
+        );
+        assert_eq!(result, Ok(Some(Value::Boolean(true))));
+        assert!(compiled);
+
+        // Functions that may return either stay on the register code
+        let (result, compiled) = run("func f(n) { if n > 0 { n } else { true } }\nf(0)", None);
+        assert_eq!(result, Ok(Some(Value::Boolean(true))));
+        assert!(!compiled);
+
+        // Failures fall back to the stack code, which reports them
+        let (result, _) = run("func f(n) { 1 / n }\nf(2) + f(0)", None);
+        assert!(
+            result.as_ref().unwrap_err().contains("Division by zero"),
+            "{:?}",
+            result
+        );
+        let down = "func f(n) { if n == 0 { 0 } else { f(n - 1) } }\nf(900)";
+        assert_eq!(run(down, None).0, Ok(Some(Value::Number(0.0))));
+        let (result, _) = run(down, Some(100));
+        assert!(
+            result
+                .as_ref()
+                .unwrap_err()
+                .contains("Maximum recursion depth exceeded (100)"),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn test_persistent_vector() {
+        use crate::persistent::Vector;
+
+        // Enough elements to grow the tree past one and two levels
+        let original: Vector<usize> = (0..2000).collect();
+        let mut updated = original.clone();
+        for i in 2000..2100 {
+            updated.push(i);
+        }
+        assert!(updated.set(5, 50));
+        assert!(updated.set(1500, 15));
+        assert!(!updated.set(2100, 0));
+        assert_eq!(original.len(), 2000);
+        assert_eq!((original[5], original[1500]), (5, 1500));
+        assert_eq!((updated[5], updated[1500], updated[2099]), (50, 15, 2099));
+        assert_eq!(
+            original.iter().copied().collect::<Vec<_>>(),
+            (0..2000).collect::<Vec<_>>()
+        );
+        assert_eq!(updated.skip(2098).to_vec(), vec![2098, 2099]);
+        assert_eq!(updated.get(2100), None);
+        assert_ne!(original, updated);
+        assert_eq!(original, (0..2000).collect());
+
+        let mut engine = crate::Engine::new();
+        let source = "struct User { name, age }
+let u = User { name = \"Ann\", age = 30 }
+let older = u <- { age = 31 }
+let list = [1, 2] <- [3] <- [4]
+let summary = [u.age, older.age, older.name, list]
+summary";
+        let result = engine.eval(source).unwrap().unwrap();
+        assert_eq!(engine.display(&result), "[30, 31, \"Ann\", [1, 2, 3, 4]]",);
+        let err = engine
+            .eval("struct P { x }\nP { x = 1 } <- { y = 2 }")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("P has no field 'y'"), "{}", err);
+        assert!(engine.eval("[1] <- 2").is_err());
+    }
 }
@@ -0,0 +1,187 @@
+//! Terminal UI of `n inspect -i`: the functions, constants and enums of an
+//! encoded program in one pane, and what the selected one holds and which
+//! instructions refer to it in the other.
+//!
+//! ```text
+//! ┌ Functions │ Constants │ Enums ────┐┌ function 0 ───────────────────────┐
+//! │> [top] 12 instruction(s)          ││function 0 fn(n) @3, instructions … │
+//! │  [0] fn(n) @3   instructions 3..8 ││  0003 LOAD_ARG 1      ; line 1 …  │
+//! └───────────────────────────────────┘└───────────────────────────────────┘
+//!  tab next list  ↑↓ select  pgup/pgdn scroll  q quit
+//! ```
+
+use crate::inspector::Inspector;
+use ratatui::Frame;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListState, Paragraph, Tabs};
+use std::io;
+
+const KEYS: &str = " tab next list  ↑↓ select  pgup/pgdn scroll  q quit";
+
+/// The lists of the left pane, in tab order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pane {
+    Functions,
+    Constants,
+    Enums,
+}
+
+impl Pane {
+    const ALL: [Pane; 3] = [Pane::Functions, Pane::Constants, Pane::Enums];
+
+    fn title(self) -> &'static str {
+        match self {
+            Pane::Functions => "Functions",
+            Pane::Constants => "Constants",
+            Pane::Enums => "Enums",
+        }
+    }
+}
+
+/// What the UI shows, apart from the terminal: which list is open, the
+/// selection in each and how far the details are scrolled.
+pub struct App<'a> {
+    inspector: &'a Inspector,
+    pane: Pane,
+    selected: [ListState; 3],
+    scroll: u16,
+}
+
+impl<'a> App<'a> {
+    pub fn new(inspector: &'a Inspector) -> Self {
+        let mut selected: [ListState; 3] = Default::default();
+        for (list, pane) in selected.iter_mut().zip(Pane::ALL) {
+            list.select((Self::len(inspector, pane) > 0).then_some(0));
+        }
+        App {
+            inspector,
+            pane: Pane::Functions,
+            selected,
+            scroll: 0,
+        }
+    }
+
+    /// Applies one key press. Returns false once the UI should close.
+    pub fn key(&mut self, code: KeyCode) -> bool {
+        let len = Self::len(self.inspector, self.pane);
+        let list = &mut self.selected[self.pane as usize];
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Tab | KeyCode::Right => {
+                self.pane = Pane::ALL[(self.pane as usize + 1) % Pane::ALL.len()];
+                self.scroll = 0;
+            }
+            KeyCode::BackTab | KeyCode::Left => {
+                self.pane = Pane::ALL[(self.pane as usize + Pane::ALL.len() - 1) % Pane::ALL.len()];
+                self.scroll = 0;
+            }
+            KeyCode::Down | KeyCode::Char('j') if len > 0 => {
+                list.select(Some(list.selected().map_or(0, |i| (i + 1).min(len - 1))));
+                self.scroll = 0;
+            }
+            KeyCode::Up | KeyCode::Char('k') if len > 0 => {
+                list.select(Some(list.selected().map_or(0, |i| i.saturating_sub(1))));
+                self.scroll = 0;
+            }
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_add(10),
+            KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(10),
+            _ => {}
+        }
+        true
+    }
+
+    pub fn draw(&mut self, frame: &mut Frame) {
+        let [header, body, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+                .areas(body);
+
+        frame.render_widget(
+            Tabs::new(Pane::ALL.map(Pane::title))
+                .select(self.pane as usize)
+                .highlight_style(Style::new().add_modifier(Modifier::REVERSED)),
+            header,
+        );
+
+        let items = self.items();
+        let list = List::new(items)
+            .block(Block::bordered().title(self.inspector.command("summary")))
+            .highlight_symbol("> ")
+            .highlight_style(Style::new().add_modifier(Modifier::BOLD));
+        frame.render_stateful_widget(list, left, &mut self.selected[self.pane as usize]);
+
+        let (title, details) = match self.command() {
+            Some(command) => (command.clone(), self.inspector.command(&command)),
+            None => (String::new(), String::new()),
+        };
+        let lines: Vec<Line> = details.lines().map(Line::from).collect();
+        frame.render_widget(
+            Paragraph::new(lines)
+                .block(Block::bordered().title(title))
+                .scroll((self.scroll, 0)),
+            right,
+        );
+
+        frame.render_widget(Line::from(KEYS), footer);
+    }
+
+    /// One line per entry of the open list.
+    fn items(&self) -> Vec<String> {
+        if Self::len(self.inspector, self.pane) == 0 {
+            return Vec::new();
+        }
+        let command = match self.pane {
+            Pane::Functions => "functions",
+            Pane::Constants => "constants",
+            Pane::Enums => "enums",
+        };
+        let answer = self.inspector.command(command);
+        answer.lines().map(|line| line.trim().to_string()).collect()
+    }
+
+    /// The inspector command whose answer details the selected entry.
+    fn command(&self) -> Option<String> {
+        let index = self.selected[self.pane as usize].selected()?;
+        Some(match (self.pane, index) {
+            (Pane::Functions, 0) => "function top".to_string(),
+            (Pane::Functions, i) => format!("function {}", i - 1),
+            (Pane::Constants, i) => format!("constant {}", i),
+            (Pane::Enums, i) => format!("enum {}", i),
+        })
+    }
+
+    fn len(inspector: &Inspector, pane: Pane) -> usize {
+        let bytecode = inspector.bytecode();
+        match pane {
+            // The top level comes first
+            Pane::Functions => bytecode.functions.len() + 1,
+            Pane::Constants => bytecode.constants.len(),
+            Pane::Enums => bytecode.enums.len(),
+        }
+    }
+}
+
+/// Takes over the terminal until the user quits, restoring it even when
+/// drawing fails.
+pub fn run(inspector: &Inspector) -> io::Result<()> {
+    let mut app = App::new(inspector);
+    ratatui::run(|terminal| {
+        loop {
+            terminal.draw(|frame| app.draw(frame))?;
+            if let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+                && !app.key(key.code)
+            {
+                return Ok(());
+            }
+        }
+    })
+}
@@ -1,33 +1,115 @@
+use crate::types::interner::Symbol;
+
+/// Index of an expression in its program's arena.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExprId(pub usize);
+
 #[derive(Debug, Clone)]
 pub enum Expr {
-    Identifier(String),
+    Identifier(Symbol),
     Number(f64),
-    String(String),
+    String(Symbol),
     Boolean(bool),
     Update {
-        left: Box<Expr>,
-        right: Box<Expr>,
+        left: ExprId,
+        right: ExprId,
     },
     Unary {
         op: UnaryOp,
-        right: Box<Expr>,
+        right: ExprId,
     },
     Binary {
-        left: Box<Expr>,
+        left: ExprId,
         op: BinaryOp,
-        right: Box<Expr>,
+        right: ExprId,
     },
     Call {
-        func: Box<Expr>,
-        args: Vec<Expr>,
+        func: ExprId,
+        args: Vec<ExprId>,
+    },
+    Member {
+        object: ExprId,
+        property: Symbol,
     },
     Pipeline {
-        left: Box<Expr>,
-        right: Box<Expr>,
+        left: ExprId,
+        right: ExprId,
     },
     Array {
-        elements: Vec<Expr>,
+        elements: Vec<ExprId>,
+    },
+    /// `(a, b)`, `(a,)` or `()`. Elements are read as `tuple.0`.
+    Tuple(Vec<ExprId>),
+    /// `...list`, inside a list literal or a call's arguments.
+    Spread(ExprId),
+    /// `name = value` among a call's arguments.
+    NamedArg {
+        name: Symbol,
+        value: ExprId,
+    },
+    /// `fn (a, b) => a + b`. `->` works in place of `=>`.
+    Lambda {
+        params: Vec<Symbol>,
+        body: ExprId,
+    },
+    /// `Name { field = value, ... }`, or a plain object without the name.
+    Record {
+        name: Option<Symbol>,
+        fields: Vec<(Symbol, ExprId)>,
+    },
+    /// `Type::member`, with `args` when followed by parentheses: an enum
+    /// variant such as `Shape::Circle(2)`, an associated constant such as
+    /// `Color::WHITE`, or a function of the type's `impl`.
+    Path {
+        type_name: Symbol,
+        member: Symbol,
+        args: Option<Vec<ExprId>>,
+    },
+    /// `match subject { pattern -> value ... }`; yields the value of the first
+    /// arm whose pattern accepts the subject.
+    Match {
+        subject: ExprId,
+        arms: Vec<(Pattern, ExprId)>,
+    },
+    /// `yield value` in a `func` body, which makes the function a generator.
+    Yield(ExprId),
+    /// `if condition { ... } else { ... }`; yields the last expression of the
+    /// branch taken. `else if` nests another `If` as the only else statement.
+    If {
+        condition: ExprId,
+        then_branch: Vec<Stmt>,
+        else_branch: Option<Vec<Stmt>>,
+    },
+    /// `{ statement* }` in expression position; yields its last expression.
+    /// Told apart from a record literal by `Parser::starts_record`.
+    Block(Vec<Stmt>),
+}
+
+/// The left side of a `match` arm.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// `_`, accepting anything.
+    Wildcard,
+    /// A name, accepting anything and binding it.
+    Binding(Symbol),
+    /// A number, string or boolean literal, accepting an equal value.
+    Literal(ExprId),
+    /// `Enum::Variant(p, q)`, accepting that variant when each field matches.
+    Variant {
+        enum_name: Symbol,
+        variant: Symbol,
+        fields: Vec<Pattern>,
+    },
+    /// `(p, q)`, accepting a tuple of that many matching elements.
+    Tuple(Vec<Pattern>),
+    /// `[p, q]`, or `[p, ...rest]` binding the elements past the others.
+    List {
+        elements: Vec<Pattern>,
+        rest: Option<Symbol>,
     },
+    /// `{ name, age = p }`, accepting a record or object that has the
+    /// fields. A field without a pattern binds its own name.
+    Record(Vec<(Symbol, Pattern)>),
 }
 
 #[derive(Debug, Clone)]
@@ -36,9 +118,10 @@ pub enum UnaryOp {
     Not, // Logical not
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BinaryOp {
     Add,
+    Concat,
     Sub,
     Mul,
     Div,
@@ -48,25 +131,494 @@ pub enum BinaryOp {
     Gt,
     Le,
     Ge,
+    And, // Short-circuiting, see `Compiler::compile_logical`
+    Or,
+}
+
+impl UnaryOp {
+    /// The operator as it is written in source.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "!",
+        }
+    }
+}
+
+impl BinaryOp {
+    /// The operator as it is written in source.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Concat => "++",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Gt => ">",
+            BinaryOp::Le => "<=",
+            BinaryOp::Ge => ">=",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum Stmt {
     Let {
-        name: String,
-        value: Expr,
+        name: Symbol,
+        value: ExprId,
+        line: usize,
+    },
+    /// `const NAME = value`, whose value the compiler works out and puts
+    /// wherever the name is read.
+    Const {
+        name: Symbol,
+        value: ExprId,
+        doc: Option<String>,
+        line: usize,
+    },
+    /// `let (a, b) = value`, or a list or record pattern, taking the value
+    /// apart. The program stops if the pattern does not match.
+    LetPattern {
+        pattern: Pattern,
+        value: ExprId,
         line: usize,
     },
     Func {
-        name: String,
-        params: Vec<String>,
+        name: Symbol,
+        params: Vec<Symbol>,
+        /// Literal defaults of the last `defaults.len()` parameters, or of
+        /// those before a rest parameter.
+        defaults: Vec<ExprId>,
+        /// The last parameter is `...name`, collecting surplus arguments.
+        variadic: bool,
+        body: Vec<Stmt>,
+        /// The `///` comment lines above the declaration.
+        doc: Option<String>,
+        attributes: Vec<Attribute>,
+        line: usize,
+    },
+    Import {
+        module: Symbol,
+        line: usize,
+    },
+    /// `extern "library" { func name(param: type, ...) -> type }`, binding
+    /// functions of a native shared library.
+    Extern {
+        library: Symbol,
+        functions: Vec<ForeignFunction>,
+        line: usize,
+    },
+    /// `struct Name { field, ... }`, declaring a record type.
+    Struct {
+        name: Symbol,
+        fields: Vec<Symbol>,
+        doc: Option<String>,
+        line: usize,
+    },
+    /// `impl Name { func ... }`, or `impl Trait for Name { ... }`. Each
+    /// method is a `Func` named `Name.method`, and each `let NAME = value`
+    /// constant a parameterless `Func` named `Name::NAME`.
+    Impl {
+        name: Symbol,
+        trait_name: Option<Symbol>,
+        methods: Vec<Stmt>,
+        constants: Vec<Stmt>,
+        line: usize,
+    },
+    /// `trait Name { func method(self, ...) ... }`: the methods, with their
+    /// parameters, that an `impl Name for Type` must define.
+    Trait {
+        name: Symbol,
+        methods: Vec<(Symbol, Vec<Symbol>)>,
+        line: usize,
+    },
+    /// `enum Name { Variant(field, ...), Unit, ... }`. Field names only
+    /// document the positions.
+    Enum {
+        name: Symbol,
+        variants: Vec<(Symbol, Vec<Symbol>)>,
+        doc: Option<String>,
+        attributes: Vec<Attribute>,
+        line: usize,
+    },
+    /// `for name in iterable { ... }` over a list or generator.
+    For {
+        name: Symbol,
+        iterable: ExprId,
         body: Vec<Stmt>,
         line: usize,
     },
-    Expr(Expr, usize),
+    Expr(ExprId, usize),
 }
 
-#[derive(Debug, Clone)]
+impl Stmt {
+    pub fn line(&self) -> usize {
+        match self {
+            Stmt::Let { line, .. }
+            | Stmt::Const { line, .. }
+            | Stmt::LetPattern { line, .. }
+            | Stmt::Func { line, .. }
+            | Stmt::Import { line, .. }
+            | Stmt::Extern { line, .. }
+            | Stmt::Struct { line, .. }
+            | Stmt::Impl { line, .. }
+            | Stmt::Trait { line, .. }
+            | Stmt::Enum { line, .. }
+            | Stmt::For { line, .. }
+            | Stmt::Expr(_, line) => *line,
+        }
+    }
+}
+
+/// A function of an `extern` block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForeignFunction {
+    pub name: Symbol,
+    pub params: Vec<(Symbol, ForeignType)>,
+    /// `number` when the declaration names no return type.
+    pub returns: ForeignType,
+}
+
+/// A C type an `extern` function takes or returns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ForeignType {
+    /// `number`, a `double`. Parameters without a type are numbers.
+    Number,
+    /// `int`, a C `int`.
+    Int,
+    /// `long`, a 64-bit integer.
+    Long,
+    /// `string`, a NUL-terminated `const char *`.
+    String,
+}
+
+impl ForeignType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "number" => Some(ForeignType::Number),
+            "int" => Some(ForeignType::Int),
+            "long" => Some(ForeignType::Long),
+            "string" => Some(ForeignType::String),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ForeignType::Number => "number",
+            ForeignType::Int => "int",
+            ForeignType::Long => "long",
+            ForeignType::String => "string",
+        }
+    }
+}
+
+/// `@name`, or `@name("text")`, on the lines above a `func` or `enum`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub name: Symbol,
+    pub argument: Option<Symbol>,
+}
+
+/// Attributes the parser accepts. `@deprecated` makes the compiler warn where
+/// the declaration is used, `@allow("warning")` silences a warning inside a
+/// function, `@test` marks a function for `n test`, and `@inline` is a hint
+/// the compiler may ignore.
+pub const ATTRIBUTES: [&str; 4] = ["allow", "deprecated", "inline", "test"];
+
+/// Names of the warnings the compiler gives, which `@allow` takes.
+pub const WARNINGS: [&str; 2] = ["deprecated", "unused-variable"];
+
+pub fn find_attribute<'a>(attributes: &'a [Attribute], name: &str) -> Option<&'a Attribute> {
+    attributes.iter().find(|attribute| &*attribute.name == name)
+}
+
+/// A parsed program. Expressions are stored flat in `exprs` and refer to their
+/// children by `ExprId`, so building and walking the tree does not allocate a
+/// box per node.
+#[derive(Debug, Clone, Default)]
 pub struct Program {
     pub statements: Vec<Stmt>,
+    pub exprs: Vec<Expr>,
+    /// The file's `//!` comment lines.
+    pub doc: Option<String>,
+}
+
+impl Program {
+    pub fn expr(&self, id: ExprId) -> &Expr {
+        &self.exprs[id.0]
+    }
+
+    pub fn add_expr(&mut self, expr: Expr) -> ExprId {
+        self.exprs.push(expr);
+        ExprId(self.exprs.len() - 1)
+    }
+
+    /// Appends a top-level statement, for programs built without source.
+    pub fn push(&mut self, stmt: Stmt) {
+        self.statements.push(stmt);
+    }
+
+    pub fn number(&mut self, n: f64) -> ExprId {
+        self.add_expr(Expr::Number(n))
+    }
+
+    pub fn string(&mut self, s: &str) -> ExprId {
+        self.add_expr(Expr::String(s.into()))
+    }
+
+    pub fn boolean(&mut self, b: bool) -> ExprId {
+        self.add_expr(Expr::Boolean(b))
+    }
+
+    pub fn identifier(&mut self, name: &str) -> ExprId {
+        self.add_expr(Expr::Identifier(name.into()))
+    }
+
+    pub fn unary(&mut self, op: UnaryOp, right: ExprId) -> ExprId {
+        self.add_expr(Expr::Unary { op, right })
+    }
+
+    pub fn binary(&mut self, left: ExprId, op: BinaryOp, right: ExprId) -> ExprId {
+        self.add_expr(Expr::Binary { left, op, right })
+    }
+
+    /// `name(args...)`, a call of the function or variable `name`.
+    pub fn call(&mut self, name: &str, args: Vec<ExprId>) -> ExprId {
+        let func = self.identifier(name);
+        self.add_expr(Expr::Call { func, args })
+    }
+
+    pub fn member(&mut self, object: ExprId, property: &str) -> ExprId {
+        self.add_expr(Expr::Member {
+            object,
+            property: property.into(),
+        })
+    }
+
+    pub fn array(&mut self, elements: Vec<ExprId>) -> ExprId {
+        self.add_expr(Expr::Array { elements })
+    }
+
+    pub fn lambda(&mut self, params: &[&str], body: ExprId) -> ExprId {
+        self.add_expr(Expr::Lambda {
+            params: params.iter().map(|param| Symbol::from(*param)).collect(),
+            body,
+        })
+    }
+
+    pub fn if_else(
+        &mut self,
+        condition: ExprId,
+        then_branch: Vec<Stmt>,
+        else_branch: Option<Vec<Stmt>>,
+    ) -> ExprId {
+        self.add_expr(Expr::If {
+            condition,
+            then_branch,
+            else_branch,
+        })
+    }
+}
+
+/// Statements for programs built without source, see `Program::push`. They
+/// have no source line, so errors in them report line 0.
+impl Stmt {
+    pub fn let_binding(name: &str, value: ExprId) -> Stmt {
+        Stmt::Let {
+            name: name.into(),
+            value,
+            line: 0,
+        }
+    }
+
+    pub fn expr(value: ExprId) -> Stmt {
+        Stmt::Expr(value, 0)
+    }
+
+    pub fn func(name: &str, params: &[&str], body: Vec<Stmt>) -> Stmt {
+        Stmt::Func {
+            name: name.into(),
+            params: params.iter().map(|param| Symbol::from(*param)).collect(),
+            defaults: Vec::new(),
+            variadic: false,
+            body,
+            doc: None,
+            attributes: Vec::new(),
+            line: 0,
+        }
+    }
+
+    pub fn import(module: &str) -> Stmt {
+        Stmt::Import {
+            module: module.into(),
+            line: 0,
+        }
+    }
+}
+
+/// A pass over a program's tree. Each method's default visits the node's
+/// children through the matching `walk_` function, so a pass overrides only
+/// the nodes it cares about, calling `walk_` itself to keep descending:
+///
+/// ```
+/// use n::types::ast::{Expr, ExprId, Program, Visitor, walk_expr};
+///
+/// struct Numbers(usize);
+///
+/// impl Visitor for Numbers {
+///     fn visit_expr(&mut self, program: &Program, expr: ExprId) {
+///         if let Expr::Number(_) = program.expr(expr) {
+///             self.0 += 1;
+///         }
+///         walk_expr(self, program, expr);
+///     }
+/// }
+/// ```
+pub trait Visitor {
+    fn visit_stmt(&mut self, program: &Program, stmt: &Stmt) {
+        walk_stmt(self, program, stmt);
+    }
+
+    fn visit_expr(&mut self, program: &Program, expr: ExprId) {
+        walk_expr(self, program, expr);
+    }
+
+    fn visit_pattern(&mut self, program: &Program, pattern: &Pattern) {
+        walk_pattern(self, program, pattern);
+    }
+}
+
+/// Visits each top-level statement in order.
+pub fn walk_program<V: Visitor + ?Sized>(visitor: &mut V, program: &Program) {
+    walk_stmts(visitor, program, &program.statements);
+}
+
+pub fn walk_stmts<V: Visitor + ?Sized>(visitor: &mut V, program: &Program, statements: &[Stmt]) {
+    for stmt in statements {
+        visitor.visit_stmt(program, stmt);
+    }
+}
+
+/// Visits the expressions, patterns and nested statements of `stmt`, in
+/// source order. The methods of an `impl` come before its constants.
+pub fn walk_stmt<V: Visitor + ?Sized>(visitor: &mut V, program: &Program, stmt: &Stmt) {
+    match stmt {
+        Stmt::Let { value, .. } | Stmt::Const { value, .. } | Stmt::Expr(value, _) => {
+            visitor.visit_expr(program, *value)
+        }
+        Stmt::LetPattern { pattern, value, .. } => {
+            visitor.visit_pattern(program, pattern);
+            visitor.visit_expr(program, *value);
+        }
+        Stmt::Func { defaults, body, .. } => {
+            for default in defaults {
+                visitor.visit_expr(program, *default);
+            }
+            walk_stmts(visitor, program, body);
+        }
+        Stmt::Impl {
+            methods, constants, ..
+        } => {
+            walk_stmts(visitor, program, methods);
+            walk_stmts(visitor, program, constants);
+        }
+        Stmt::For { iterable, body, .. } => {
+            visitor.visit_expr(program, *iterable);
+            walk_stmts(visitor, program, body);
+        }
+        Stmt::Import { .. }
+        | Stmt::Extern { .. }
+        | Stmt::Struct { .. }
+        | Stmt::Trait { .. }
+        | Stmt::Enum { .. } => {}
+    }
+}
+
+/// Visits the sub-expressions, patterns and statements of `expr`, in source
+/// order.
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, program: &Program, expr: ExprId) {
+    match program.expr(expr) {
+        Expr::Identifier(_) | Expr::Number(_) | Expr::String(_) | Expr::Boolean(_) => {}
+        Expr::Update { left, right }
+        | Expr::Binary { left, right, .. }
+        | Expr::Pipeline { left, right } => {
+            visitor.visit_expr(program, *left);
+            visitor.visit_expr(program, *right);
+        }
+        Expr::Unary { right: value, .. }
+        | Expr::Member { object: value, .. }
+        | Expr::Spread(value)
+        | Expr::NamedArg { value, .. }
+        | Expr::Lambda { body: value, .. }
+        | Expr::Yield(value) => visitor.visit_expr(program, *value),
+        Expr::Call { func, args } => {
+            visitor.visit_expr(program, *func);
+            for arg in args {
+                visitor.visit_expr(program, *arg);
+            }
+        }
+        Expr::Array { elements } | Expr::Tuple(elements) => {
+            for element in elements {
+                visitor.visit_expr(program, *element);
+            }
+        }
+        Expr::Record { fields, .. } => {
+            for (_, value) in fields {
+                visitor.visit_expr(program, *value);
+            }
+        }
+        Expr::Path { args, .. } => {
+            for arg in args.iter().flatten() {
+                visitor.visit_expr(program, *arg);
+            }
+        }
+        Expr::Match { subject, arms } => {
+            visitor.visit_expr(program, *subject);
+            for (pattern, value) in arms {
+                visitor.visit_pattern(program, pattern);
+                visitor.visit_expr(program, *value);
+            }
+        }
+        Expr::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => {
+            visitor.visit_expr(program, *condition);
+            walk_stmts(visitor, program, then_branch);
+            if let Some(else_branch) = else_branch {
+                walk_stmts(visitor, program, else_branch);
+            }
+        }
+        Expr::Block(statements) => walk_stmts(visitor, program, statements),
+    }
+}
+
+/// Visits the nested patterns of `pattern` and the expression of a literal.
+pub fn walk_pattern<V: Visitor + ?Sized>(visitor: &mut V, program: &Program, pattern: &Pattern) {
+    match pattern {
+        Pattern::Wildcard | Pattern::Binding(_) => {}
+        Pattern::Literal(expr) => visitor.visit_expr(program, *expr),
+        Pattern::Variant { fields, .. } | Pattern::Tuple(fields) => {
+            for field in fields {
+                visitor.visit_pattern(program, field);
+            }
+        }
+        Pattern::List { elements, .. } => {
+            for element in elements {
+                visitor.visit_pattern(program, element);
+            }
+        }
+        Pattern::Record(fields) => {
+            for (_, field) in fields {
+                visitor.visit_pattern(program, field);
+            }
+        }
+    }
 }
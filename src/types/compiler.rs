@@ -1,4 +1,9 @@
+use crate::persistent::Vector;
+use crate::types::interner::Symbol;
+use std::any::Any;
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 #[repr(u8)]
 #[derive(Debug, Clone, PartialEq)]
@@ -9,6 +14,15 @@ pub enum Instruction {
     Call(usize) = 0x04,
     Return = 0x05,
     LoadConst(usize) = 0x06,
+    CallNative(usize, usize) = 0x07,  // Native index, argument count
+    MakeClosure(usize, usize) = 0x08, // Function index, bound argument count
+    CallValue(usize) = 0x09,          // Argument count; the callee is on top
+    CallMethod(usize) = 0x0A,         // List method index; the receiver is on top
+    Apply = 0x0B,                     // Pop a callee, then a list of its arguments
+    Yield = 0x0C,                     // Suspend the running generator with the value on top
+    Invoke(usize, usize) = 0x0D, // Method name constant, argument count; the receiver is on top
+    DefineMethod(usize) = 0x0E,  // Pop a closure, the method named by a `Type.method` constant
+    NoMatch = 0x0F,              // Pop the value no `match` arm accepted and fail
     Add = 0x10,
     Sub = 0x11,
     Div = 0x12,
@@ -19,6 +33,12 @@ pub enum Instruction {
     Not = 0x17,
     CreateArray(usize) = 0x18, // Create array with N elements from stack
     ConcatArray = 0x19,        // Pop two arrays, concatenate, push result
+    Concat = 0x1A,             // Pop two values, push their string concatenation
+    MakeRecord(usize) = 0x1B,  // Pop a type name, then N field name and value pairs
+    GetField(usize) = 0x1C,    // Pop a record, object or variant, push the field a constant names
+    MakeVariant(usize, usize) = 0x1D, // `Enum::Variant` name constant, field count
+    TestVariant(usize) = 0x1E, // Pop a value, push whether it is the variant a constant names
+    MakeTuple(usize) = 0x1F,   // Pop N elements, pushed in order
     Jump(usize) = 0x20,
     JumpIfFalse(usize) = 0x21,
     JumpIfTrue(usize) = 0x22,
@@ -26,6 +46,12 @@ pub enum Instruction {
     Push(Value) = 0x31,
     Dup = 0x32,
     Halt = 0x33,
+    TestTuple(usize) = 0x40, // Pop a value, push whether it is a tuple of N elements
+    TestList(usize, bool) = 0x41, // Pop a value, push whether it is a list of N, or at least N, elements
+    HasField(usize) = 0x42,       // Pop a value, push whether it has the field a constant names
+    ListFrom(usize) = 0x43,       // Pop a list, push a list of its elements from position N on
+    LoadGlobal(usize) = 0x44,     // Push top-level variable N, from inside a function
+    ConcatN(usize) = 0x45,        // Pop N values, pushed in order, push their string concatenation
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -35,13 +61,30 @@ pub enum VarOutput {
     GotOuterScope { index: usize, depth: usize },
 }
 
+/// A value on the stack VM's stack. Short strings and function parameter
+/// lists are held inline; only the register VM packs values, see `NanBox`.
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Number(f64),
     String(String),
     Boolean(bool),
-    Function { params: Vec<String>, offset: usize },
+    Function {
+        params: Vec<String>,
+        offset: usize,
+    },
+    /// A function index with the leading arguments already supplied, made by
+    /// calling a function with fewer arguments than it takes.
+    Closure {
+        function: usize,
+        bound: Vec<Value>,
+    },
     HeapPointer(usize),
+    /// Handle of a task made by `Task.spawn`, indexing the VM's task list.
+    Task(usize),
+    /// Handle of a channel made by `Channel.new`, indexing the VM's channels.
+    Channel(usize),
+    /// Handle of a generator, indexing the VM's generators.
+    Generator(usize),
 }
 
 impl Value {
@@ -50,22 +93,17 @@ impl Value {
             Value::Number(_) => "number",
             Value::String(_) => "string",
             Value::Boolean(_) => "boolean",
-            Value::Function { .. } => "function",
+            Value::Function { .. } | Value::Closure { .. } => "function",
             Value::HeapPointer(_) => "heap pointer",
+            Value::Task(_) => "task",
+            Value::Channel(_) => "channel",
+            Value::Generator(_) => "generator",
         }
     }
 
     pub fn type_name<'a>(&'a self, heap: &'a [HeapObject]) -> &'static str {
         match self {
-            Value::HeapPointer(idx) => match heap.get(*idx) {
-                Some(HeapObject::String(_)) => "string",
-                Some(HeapObject::Number(_)) => "number",
-                Some(HeapObject::Boolean(_)) => "boolean",
-                Some(HeapObject::Null) => "null",
-                Some(HeapObject::Array(_)) => "array",
-                Some(HeapObject::Object(_)) => "object",
-                None => "unknown",
-            },
+            Value::HeapPointer(idx) => heap.get(*idx).map_or("unknown", HeapObject::type_name),
             _ => self.type_name_stack(),
         }
     }
@@ -77,14 +115,311 @@ pub enum HeapObject {
     Number(f64),
     Boolean(bool),
     Null,
-    Array(Vec<HeapObject>),
+    /// A list. Copies share their elements, see `persistent`.
+    Array(Vector<HeapObject>),
     Object(HashMap<String, HeapObject>),
+    /// A value of a `struct` type, with its fields in declaration order.
+    Record {
+        type_name: String,
+        fields: Vector<(String, HeapObject)>,
+    },
+    /// A fixed-size group of values, `(a, b)`.
+    Tuple(Vec<HeapObject>),
+    /// A value of an `enum`, named `Enum::Variant`, with its positional fields.
+    Variant {
+        name: String,
+        values: Vec<HeapObject>,
+    },
+    /// A task handle held in a list or object, see `Value::Task`.
+    Task(usize),
+    /// A channel handle held in a list or object, see `Value::Channel`.
+    Channel(usize),
+    /// A generator handle held in a list or object, see `Value::Generator`.
+    Generator(usize),
+    /// A host value scripts can hold and pass back but not look inside.
+    Opaque(Opaque),
+}
+
+/// A host value handed to scripts without converting it, see
+/// `Engine::opaque`. Copies share the value, and are equal only to copies of
+/// the same value.
+#[derive(Clone)]
+pub struct Opaque {
+    value: Arc<dyn Any + Send + Sync>,
+    type_name: &'static str,
+}
+
+impl Opaque {
+    pub fn new<T: Any + Send + Sync>(value: T) -> Self {
+        Opaque {
+            value: Arc::new(value),
+            type_name: std::any::type_name::<T>(),
+        }
+    }
+
+    /// Rust type name of the value, e.g. `app::Window`.
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    /// The value, if it is a `T`.
+    pub fn downcast<T: Any + Send + Sync>(&self) -> Result<Arc<T>, String> {
+        Arc::clone(&self.value).downcast::<T>().map_err(|_| {
+            format!(
+                "Expected {}, got {}",
+                std::any::type_name::<T>(),
+                self.type_name
+            )
+        })
+    }
+}
+
+impl PartialEq for Opaque {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.value, &other.value)
+    }
+}
+
+impl std::fmt::Debug for Opaque {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Opaque({})", self.type_name)
+    }
+}
+
+/// Numbers, strings and lists are ordered among their own kind; lists compare
+/// element by element. Other values are only ever equal or unordered.
+impl PartialOrd for HeapObject {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        match (self, other) {
+            (HeapObject::Number(a), HeapObject::Number(b)) => a.partial_cmp(b),
+            (HeapObject::String(a), HeapObject::String(b)) => a.partial_cmp(b),
+            (HeapObject::Array(a), HeapObject::Array(b)) => a.partial_cmp(b),
+            _ if self == other => Some(std::cmp::Ordering::Equal),
+            _ => None,
+        }
+    }
+}
+
+impl HeapObject {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            HeapObject::String(_) => "string",
+            HeapObject::Number(_) => "number",
+            HeapObject::Boolean(_) => "boolean",
+            HeapObject::Null => "null",
+            HeapObject::Array(_) => "array",
+            HeapObject::Tuple(_) => "tuple",
+            HeapObject::Object(_) => "object",
+            HeapObject::Record { .. } => "record",
+            HeapObject::Variant { .. } => "enum",
+            HeapObject::Task(_) => "task",
+            HeapObject::Channel(_) => "channel",
+            HeapObject::Generator(_) => "generator",
+            HeapObject::Opaque(_) => "opaque",
+        }
+    }
+}
+
+/// Deduplicated constant table. Each value kind has its own index so finding
+/// an existing constant is a hash lookup rather than a scan of the table.
+#[derive(Debug, Clone, Default)]
+pub struct ConstantPool {
+    values: Vec<Value>,
+    numbers: HashMap<u64, usize>,
+    strings: HashMap<Symbol, usize>,
+    booleans: [Option<usize>; 2],
+}
+
+impl ConstantPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the index of `n`, adding it to the pool if it isn't there yet.
+    pub fn add_number(&mut self, n: f64) -> usize {
+        // Keyed by bit pattern so 0.0 and -0.0 stay distinct constants
+        if let Some(index) = self.numbers.get(&n.to_bits()) {
+            return *index;
+        }
+        let index = self.push(Value::Number(n));
+        self.numbers.insert(n.to_bits(), index);
+        index
+    }
+
+    pub fn add_string(&mut self, s: &Symbol) -> usize {
+        if let Some(index) = self.strings.get(s) {
+            return *index;
+        }
+        let index = self.push(Value::String(s.to_string()));
+        self.strings.insert(s.clone(), index);
+        index
+    }
+
+    pub fn add_boolean(&mut self, b: bool) -> usize {
+        if let Some(index) = self.booleans[b as usize] {
+            return index;
+        }
+        let index = self.push(Value::Boolean(b));
+        self.booleans[b as usize] = Some(index);
+        index
+    }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    fn push(&mut self, value: Value) -> usize {
+        self.values.push(value);
+        self.values.len() - 1
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompileOptions {
+    /// Reject `+` on strings so concatenation must use `++`.
+    pub strict_concat: bool,
+    /// Leave the value of a trailing top-level expression on the stack instead
+    /// of popping it, so a REPL can show it.
+    pub keep_last_value: bool,
+    /// Source compiled ahead of the program. `None` uses the built-in prelude;
+    /// `Some(String::new())` compiles without one.
+    pub prelude: Option<String>,
+    /// Directories searched for `import "name"` files, before those listed in
+    /// the `N_PATH` environment variable and the current directory.
+    pub module_paths: Vec<PathBuf>,
+    /// Call the program's top-level `func main()` or `func main(args)`, if it
+    /// has one, after the rest of its top-level code. `args` gets `OS.args()`.
+    pub call_main: bool,
+    /// Fail the compile when it gives any warning not silenced by `@allow`.
+    pub deny_warnings: bool,
+    /// Compile without touching the filesystem, for untrusted code: `import
+    /// "name"` takes the module's source from this map, under `name` or
+    /// `name.n`, instead of a file. `extern` blocks and the standard modules
+    /// other than `stdlib::SANDBOX_MODULES`, such as `FS`, `OS` and `Http`,
+    /// are rejected.
+    pub sandbox: Option<HashMap<String, String>>,
+}
+
+/// Something `Compiler::compile` noticed that does not stop the program from
+/// compiling, unless warnings are denied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    /// One of `ast::WARNINGS`, the name `@allow` silences it by.
+    pub name: &'static str,
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (line {})", self.message, self.line)
+    }
+}
+
+/// Why `Compiler::compile` failed. Displays as the message scripts have
+/// always seen; the variants let hosts tell the causes apart and find the line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    /// The prelude the program is compiled against did not compile.
+    Prelude(String),
+    /// A statement, or a module it imports, could not be compiled.
+    Statement { line: usize, message: String },
+    /// A function reads a name no top-level `let` or `const` declares.
+    UndefinedGlobal { line: usize, name: String },
+    /// `Compiler::reload_module` could not reload the module.
+    Reload(String),
+    /// The program compiled with warnings while `deny_warnings` was set.
+    Warnings(Vec<Warning>),
+}
+
+impl CompileError {
+    /// Line of the program the error is on, if it is about one.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            CompileError::Statement { line, .. } | CompileError::UndefinedGlobal { line, .. } => {
+                Some(*line)
+            }
+            CompileError::Warnings(warnings) => warnings.first().map(|warning| warning.line),
+            CompileError::Prelude(_) | CompileError::Reload(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::Prelude(message) => write!(f, "Prelude error: {}", message),
+            CompileError::Statement { message, .. } | CompileError::Reload(message) => {
+                write!(f, "{}", message)
+            }
+            CompileError::UndefinedGlobal { name, .. } => {
+                write!(f, "Undefined variable '{}'", name)
+            }
+            CompileError::Warnings(warnings) => {
+                let warnings: Vec<String> = warnings.iter().map(|w| w.to_string()).collect();
+                write!(f, "Warnings are denied: {}", warnings.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Prefix of a rest parameter's name in a function's `params`. Such a last
+/// parameter collects the arguments past the others into a list.
+pub const REST_PREFIX: &str = "...";
+
+/// Number of parameters before any rest parameter, and whether there is one.
+pub fn arity(params: &[String]) -> (usize, bool) {
+    match params.last() {
+        Some(last) if last.starts_with(REST_PREFIX) => (params.len() - 1, true),
+        _ => (params.len(), false),
+    }
+}
+
+/// Header flag bits carried from the compile options to the VM.
+pub const FLAG_STRICT_CONCAT: u16 = 0x0001;
+
+/// An `enum` in the bytecode's enum table. Names are constant indices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnumDef {
+    pub name: usize,
+    pub variants: Vec<VariantDef>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
+pub struct VariantDef {
+    pub name: usize,
+    pub field_count: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct ByteCode {
+    pub flags: u16,
     pub constants: Vec<Value>,
     pub functions: Vec<Value>,
+    pub enums: Vec<EnumDef>,
     pub instructions: Vec<Instruction>,
     pub instruction_lines: Vec<usize>,
+    pub exports: Exports,
+}
+
+/// What another program needs to link a compiled one in place of compiling
+/// its source, for `import "name.nb"`. Names are sorted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Exports {
+    /// The native each `CALL_NATIVE` index stands for.
+    pub natives: Vec<String>,
+    /// Top-level functions with their function table index.
+    pub functions: Vec<(String, usize)>,
+    /// Top-level variables with their slot.
+    pub globals: Vec<(String, usize)>,
 }
@@ -1,10 +1,11 @@
 pub const UNDERFLOW_ERROR: &str = "Stack underflow";
 pub const INVALID_HEAP_POINTER_ERROR: &str = "Invalid heap pointer";
+pub const CANCELLED_ERROR: &str = "Cancelled";
 
 // Garbage Collection Configuration
-pub const GC_CHECK_INTERVAL: usize = 12;
-pub const GC_THRESHOLD: usize = 4000;
-pub const GC_HISTORY_BUFFER_SIZE: usize = 10;
+pub const GC_CHECK_INTERVAL: u64 = 12; // Instructions between checks for a collection
+pub const GC_THRESHOLD: usize = 4000; // Initial tenured score that triggers a major collection
+pub const GC_NURSERY_THRESHOLD: usize = 2048; // Nursery score that triggers a minor collection
 
 // Heap Scoring Weights (for GC heuristics)
 pub const HEAP_SCORE_ARRAY_BASE: usize = 16;
@@ -14,6 +15,17 @@ pub const HEAP_SCORE_MAP_BASE: usize = 32;
 pub const HEAP_SCORE_MAP_PER_ELEMENT: usize = 16;
 pub const HEAP_SCORE_OTHER_OBJECT: usize = 32;
 
+// Call Limits
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1000; // Nested script calls before a runtime error
+pub const DEFAULT_MAX_STACK_SIZE: usize = 1 << 20; // Values on the operand stack
+pub const LIMIT_CHECK_INTERVAL: u64 = 1024; // Instructions between wall clock checks
+pub const DEFAULT_JIT_THRESHOLD: u32 = 100; // Calls of a function before it is compiled to native code
+pub const MAX_JIT_CALL_DEPTH: usize = 512; // Nested calls of native code, which recurse on the host stack
+pub const MAX_CALLBACK_DEPTH: usize = 48; // Nested `map`-style callbacks, which recurse on the host stack (2 MB on test threads)
+
+// Parser Limits
+pub const MAX_NESTING_DEPTH: usize = 256; // Nested statements, expressions and operator chains, which the parser and compiler recurse on
+
 // String Processing
 pub const MAX_STRING_LENGTH: usize = 1024;
 
@@ -1,5 +1,6 @@
 pub mod ast;
 pub mod compiler;
 pub mod constants;
+pub mod interner;
 pub mod token;
 pub mod traits;
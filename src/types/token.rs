@@ -1,14 +1,17 @@
+use crate::types::interner::Symbol;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     // Literals
-    Identifier(String),
-    String(String),
+    Identifier(Symbol),
+    String(Symbol),
     Number(f64),
     True,
     False,
 
     // Keywords
     Let,
+    Const,
     LetBang,
     Func,
     Fn,
@@ -20,9 +23,17 @@ pub enum Token {
     Return,
     Async,
     Await,
+    For,
+    In,
+    Yield,
+    Struct,
+    Impl,
+    Trait,
+    Extern,
 
     // Operators
     Plus,
+    PlusPlus, // ++
     Minus,
     Multiply,
     Divide,
@@ -40,6 +51,7 @@ pub enum Token {
     Pipeline,    // |>
     Update,      // <-
     DoubleColon, // ::
+    Colon,       // :, before the type of an `extern` parameter
 
     // Delimiters
     LeftParen,
@@ -50,11 +62,17 @@ pub enum Token {
     RightBracket,
     Comma,
     Dot,
-    Arrow,    // ->
-    FatArrow, // =>
-    Hash,     // #
+    Spread,    // ...
+    Arrow,     // ->
+    FatArrow,  // =>
+    Hash,      // #
+    At,        // @, before an attribute name
+    Semicolon, // ;, separating statements like a newline
 
     // Misc
+    DocComment(Symbol), // `/// text`, documenting the declaration below
+    ModuleDoc(Symbol),  // `//! text`, documenting the whole file
+    Error(Symbol),      // A malformed literal, with the message the parser fails with
     Newline,
     Eof,
 }
@@ -1,4 +1,5 @@
-use crate::types::compiler::Value;
+use crate::types::compiler::{HeapObject, Opaque, Value};
+use std::collections::HashMap;
 
 pub trait IntoResult<T> {
     fn into_result(self) -> Result<T, String>;
@@ -30,3 +31,199 @@ impl IntoResult<bool> for Value {
         }
     }
 }
+
+// Conversions between host Rust types and script values. Scalars map onto
+// `Value` directly; lists and maps only exist on the heap, so they convert to and
+// from `HeapObject` and go through `Heap::store` / `Heap::load` to become values.
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, String> {
+        match value {
+            Value::Number(n) => Ok(n),
+            other => Err(format!("Expected number, got {}", other.type_name_stack())),
+        }
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, String> {
+        match value {
+            Value::Boolean(b) => Ok(b),
+            other => Err(format!("Expected boolean, got {}", other.type_name_stack())),
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, String> {
+        match value {
+            Value::String(s) => Ok(s),
+            other => Err(format!("Expected string, got {}", other.type_name_stack())),
+        }
+    }
+}
+
+impl From<f64> for HeapObject {
+    fn from(n: f64) -> Self {
+        HeapObject::Number(n)
+    }
+}
+
+impl From<bool> for HeapObject {
+    fn from(b: bool) -> Self {
+        HeapObject::Boolean(b)
+    }
+}
+
+impl From<String> for HeapObject {
+    fn from(s: String) -> Self {
+        HeapObject::String(s)
+    }
+}
+
+impl From<&str> for HeapObject {
+    fn from(s: &str) -> Self {
+        HeapObject::String(s.to_string())
+    }
+}
+
+impl<T: Into<HeapObject>> From<Vec<T>> for HeapObject {
+    fn from(items: Vec<T>) -> Self {
+        HeapObject::Array(items.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<HeapObject>> From<HashMap<String, T>> for HeapObject {
+    fn from(map: HashMap<String, T>) -> Self {
+        HeapObject::Object(map.into_iter().map(|(k, v)| (k, v.into())).collect())
+    }
+}
+
+impl<T: Into<HeapObject>> From<Option<T>> for HeapObject {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(HeapObject::Null, Into::into)
+    }
+}
+
+impl TryFrom<HeapObject> for f64 {
+    type Error = String;
+
+    fn try_from(object: HeapObject) -> Result<Self, String> {
+        match object {
+            HeapObject::Number(n) => Ok(n),
+            other => Err(format!("Expected number, got {}", other.type_name())),
+        }
+    }
+}
+
+impl TryFrom<HeapObject> for bool {
+    type Error = String;
+
+    fn try_from(object: HeapObject) -> Result<Self, String> {
+        match object {
+            HeapObject::Boolean(b) => Ok(b),
+            other => Err(format!("Expected boolean, got {}", other.type_name())),
+        }
+    }
+}
+
+impl TryFrom<HeapObject> for String {
+    type Error = String;
+
+    fn try_from(object: HeapObject) -> Result<Self, String> {
+        match object {
+            HeapObject::String(s) => Ok(s),
+            other => Err(format!("Expected string, got {}", other.type_name())),
+        }
+    }
+}
+
+impl From<Opaque> for HeapObject {
+    fn from(opaque: Opaque) -> Self {
+        HeapObject::Opaque(opaque)
+    }
+}
+
+impl TryFrom<HeapObject> for Opaque {
+    type Error = String;
+
+    fn try_from(object: HeapObject) -> Result<Self, String> {
+        match object {
+            HeapObject::Opaque(opaque) => Ok(opaque),
+            other => Err(format!("Expected opaque, got {}", other.type_name())),
+        }
+    }
+}
+
+impl<T: TryFrom<HeapObject, Error = String>> TryFrom<HeapObject> for Vec<T> {
+    type Error = String;
+
+    fn try_from(object: HeapObject) -> Result<Self, String> {
+        match object {
+            HeapObject::Array(items) => items
+                .into_iter()
+                .enumerate()
+                .map(|(i, item)| T::try_from(item).map_err(|e| format!("[{}]: {}", i, e)))
+                .collect(),
+            other => Err(format!("Expected array, got {}", other.type_name())),
+        }
+    }
+}
+
+impl<T: TryFrom<HeapObject, Error = String>> TryFrom<HeapObject> for HashMap<String, T> {
+    type Error = String;
+
+    fn try_from(object: HeapObject) -> Result<Self, String> {
+        match object {
+            HeapObject::Object(map) => map
+                .into_iter()
+                .map(|(k, v)| {
+                    let v = T::try_from(v).map_err(|e| format!(".{}: {}", k, e))?;
+                    Ok((k, v))
+                })
+                .collect(),
+            other => Err(format!("Expected object, got {}", other.type_name())),
+        }
+    }
+}
+
+impl<T: TryFrom<HeapObject, Error = String>> TryFrom<HeapObject> for Option<T> {
+    type Error = String;
+
+    fn try_from(object: HeapObject) -> Result<Self, String> {
+        match object {
+            HeapObject::Null => Ok(None),
+            other => T::try_from(other).map(Some),
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! Checks that a `ByteCode` is well formed before it runs: every jump lands
+//! inside the program, every constant, function and method index exists,
+//! and the stack holds the same number of values whichever path reaches an
+//! instruction. The compiler only produces such programs; this guards those
+//! read from a file, which `bytecode::decode` verifies.
+
+use crate::methods::METHODS;
+use crate::types::compiler::{ByteCode, Instruction, Value};
+
+pub fn verify(bytecode: &ByteCode) -> Result<(), String> {
+    let len = bytecode.instructions.len();
+    if bytecode.instruction_lines.len() != len {
+        return Err(format!(
+            "Line table has {} entries for {} instructions",
+            bytecode.instruction_lines.len(),
+            len
+        ));
+    }
+    let constant = |index: usize| bytecode.constants.get(index);
+    for def in &bytecode.enums {
+        for name in std::iter::once(def.name).chain(def.variants.iter().map(|v| v.name)) {
+            if !matches!(constant(name), Some(Value::String(_))) {
+                return Err(format!("Enum name {} is not a string constant", name));
+            }
+        }
+    }
+    let mut entries = vec![(0, 0)];
+    for (i, function) in bytecode.functions.iter().enumerate() {
+        match function {
+            Value::Function { params, offset } if *offset < len => {
+                entries.push((*offset, params.len()))
+            }
+            Value::Function { offset, .. } => {
+                return Err(format!(
+                    "Function {} starts at {}, past the end ({} instructions)",
+                    i, offset, len
+                ));
+            }
+            other => {
+                return Err(format!(
+                    "Function table entry {} is a {}",
+                    i,
+                    other.type_name_stack()
+                ));
+            }
+        }
+    }
+    for (pc, instruction) in bytecode.instructions.iter().enumerate() {
+        check_operands(bytecode, pc, instruction)?;
+    }
+
+    // Stack depth on entry to each instruction, relative to the start of
+    // the function it belongs to
+    let mut depths: Vec<Option<usize>> = vec![None; len];
+    for (entry, depth) in entries {
+        let mut pending = vec![(entry, depth)];
+        while let Some((pc, depth)) = pending.pop() {
+            if pc == len {
+                continue;
+            }
+            match depths[pc] {
+                Some(seen) if seen == depth => continue,
+                Some(seen) => {
+                    return Err(format!(
+                        "Instruction {} is reached with {} and with {} value(s) on the stack",
+                        pc, seen, depth
+                    ));
+                }
+                None => depths[pc] = Some(depth),
+            }
+            let instruction = &bytecode.instructions[pc];
+            let (pops, pushes) = stack_effect(bytecode, instruction);
+            let depth = depth.checked_sub(pops).ok_or_else(|| {
+                format!(
+                    "Instruction {} ({}) pops {} value(s) but the stack holds {}",
+                    pc, instruction, pops, depth
+                )
+            })? + pushes;
+            match instruction {
+                Instruction::Jump(target) => pending.push((*target, depth)),
+                Instruction::JumpIfFalse(target) | Instruction::JumpIfTrue(target) => {
+                    pending.push((*target, depth));
+                    pending.push((pc + 1, depth));
+                }
+                Instruction::Return | Instruction::Halt | Instruction::NoMatch => {}
+                _ => pending.push((pc + 1, depth)),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_operands(bytecode: &ByteCode, pc: usize, instruction: &Instruction) -> Result<(), String> {
+    let string_constant = |index: usize| match bytecode.constants.get(index) {
+        Some(Value::String(_)) => Ok(()),
+        Some(other) => Err(format!(
+            "Instruction {} ({}) names a {} constant",
+            pc,
+            instruction,
+            other.type_name_stack()
+        )),
+        None => Err(missing(pc, instruction, "constant")),
+    };
+    match instruction {
+        Instruction::Jump(target)
+        | Instruction::JumpIfFalse(target)
+        | Instruction::JumpIfTrue(target)
+            if *target > bytecode.instructions.len() =>
+        {
+            Err(format!(
+                "Jump at instruction {} targets {}, past the end ({} instructions)",
+                pc,
+                target,
+                bytecode.instructions.len()
+            ))
+        }
+        Instruction::LoadConst(index) | Instruction::GetField(index)
+            if *index >= bytecode.constants.len() =>
+        {
+            Err(missing(pc, instruction, "constant"))
+        }
+        Instruction::Invoke(name, _)
+        | Instruction::DefineMethod(name)
+        | Instruction::MakeVariant(name, _)
+        | Instruction::TestVariant(name)
+        | Instruction::HasField(name) => string_constant(*name),
+        Instruction::Call(function) | Instruction::MakeClosure(function, _)
+            if *function >= bytecode.functions.len() =>
+        {
+            Err(missing(pc, instruction, "function"))
+        }
+        Instruction::CallMethod(method) if *method >= METHODS.len() => {
+            Err(missing(pc, instruction, "method"))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn missing(pc: usize, instruction: &Instruction, what: &str) -> String {
+    format!(
+        "Instruction {} ({}) refers to a missing {}",
+        pc, instruction, what
+    )
+}
+
+/// Values an instruction pops and pushes. A call pops its arguments and
+/// pushes the callee's result; `check_operands` has already made sure its
+/// function and method indices exist.
+fn stack_effect(bytecode: &ByteCode, instruction: &Instruction) -> (usize, usize) {
+    match instruction {
+        Instruction::Push(_)
+        | Instruction::LoadConst(_)
+        | Instruction::LoadVar(..)
+        | Instruction::LoadGlobal(_)
+        | Instruction::Dup => (0, 1),
+        Instruction::StoreVar(..)
+        | Instruction::Pop
+        | Instruction::DefineMethod(_)
+        | Instruction::JumpIfFalse(_)
+        | Instruction::JumpIfTrue(_)
+        | Instruction::NoMatch => (1, 0),
+        Instruction::LoadArg(count) => (*count, 0),
+        Instruction::Add
+        | Instruction::Sub
+        | Instruction::Mul
+        | Instruction::Div
+        | Instruction::Equal
+        | Instruction::Less
+        | Instruction::Greater
+        | Instruction::Concat
+        | Instruction::ConcatArray
+        | Instruction::Apply => (2, 1),
+        Instruction::Not
+        | Instruction::GetField(_)
+        | Instruction::TestVariant(_)
+        | Instruction::TestTuple(_)
+        | Instruction::TestList(..)
+        | Instruction::HasField(_)
+        | Instruction::ListFrom(_)
+        | Instruction::Yield
+        | Instruction::Return => (1, 1),
+        Instruction::Call(function) => match &bytecode.functions[*function] {
+            Value::Function { params, .. } => (params.len(), 1),
+            _ => (0, 1),
+        },
+        Instruction::CallMethod(method) => (METHODS[*method].arity, 1),
+        Instruction::CallNative(_, count)
+        | Instruction::MakeClosure(_, count)
+        | Instruction::CreateArray(count)
+        | Instruction::MakeTuple(count)
+        | Instruction::ConcatN(count)
+        | Instruction::MakeVariant(_, count) => (*count, 1),
+        Instruction::CallValue(count) | Instruction::Invoke(_, count) => (count + 1, 1),
+        Instruction::MakeRecord(count) => (count * 2 + 1, 1),
+        Instruction::Jump(_) | Instruction::Halt => (0, 0),
+    }
+}
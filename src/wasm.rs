@@ -0,0 +1,44 @@
+//! Entry point for running the language in a browser. Build the crate for
+//! WebAssembly without file system access, then generate the JavaScript
+//! bindings with `wasm-bindgen`:
+//!
+//! ```text
+//! cargo rustc --lib --release --target wasm32-unknown-unknown \
+//!     --no-default-features --crate-type cdylib
+//! wasm-bindgen --target web --out-dir pkg \
+//!     target/wasm32-unknown-unknown/release/n.wasm
+//! ```
+//!
+//! The generated module exports `compileAndRun`, which takes and returns
+//! JavaScript strings:
+//!
+//! ```js
+//! import init, { compileAndRun } from "./pkg/n.js";
+//! await init();
+//! const text = compileAndRun(source);
+//! ```
+
+use crate::Engine;
+use crate::runtime::Capture;
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+/// Runs `source` in a fresh engine and returns what it printed, then the
+/// value of its trailing expression, if any, or the error that stopped it.
+#[cfg_attr(
+    all(target_arch = "wasm32", target_os = "unknown"),
+    wasm_bindgen(js_name = compileAndRun)
+)]
+pub fn compile_and_run(source: &str) -> String {
+    let output = Capture::default();
+    let mut engine = Engine::new();
+    engine.set_output(Box::new(output.clone()));
+    let result = engine.eval(source);
+    let mut text = output.text();
+    match result {
+        Ok(Some(value)) => text.push_str(&format!("{}\n", engine.display(&value))),
+        Ok(None) => {}
+        Err(error) => text.push_str(&format!("{}\n", error)),
+    }
+    text
+}